@@ -0,0 +1,85 @@
+//! Scoring throughput of each metric against plain loops, the way a query
+//! scans an index without a graph. Run with `cargo bench --bench score`.
+
+use assistant_core::metric::Metric;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// Vectors scored per round.
+const VECTORS: usize = 20_000;
+/// Rounds per measurement; the fastest is reported.
+const ROUNDS: usize = 5;
+
+/// Deterministic floats in -1..1 (xorshift).
+fn floats(count: usize, seed: u64) -> Vec<f32> {
+    let mut state = seed | 1;
+    (0..count)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+        })
+        .collect()
+}
+
+fn plain(metric: Metric, a: &[f32], b: &[f32]) -> f32 {
+    match metric {
+        Metric::Cosine => {
+            let (mut ab, mut aa, mut bb) = (0.0f32, 0.0f32, 0.0f32);
+            for (x, y) in a.iter().zip(b) {
+                ab += x * y;
+                aa += x * x;
+                bb += y * y;
+            }
+            ab / (aa * bb).sqrt()
+        }
+        Metric::Dot => a.iter().zip(b).map(|(x, y)| x * y).sum(),
+        Metric::Euclidean => -a
+            .iter()
+            .zip(b)
+            .map(|(x, y)| (x - y) * (x - y))
+            .sum::<f32>()
+            .sqrt(),
+    }
+}
+
+/// Fastest of `ROUNDS` scans of `vectors` with `score`.
+fn time(vectors: &[f32], q: &[f32], score: impl Fn(&[f32], &[f32]) -> f32) -> Duration {
+    (0..ROUNDS)
+        .map(|_| {
+            let start = Instant::now();
+            let mut best = f32::NEG_INFINITY;
+            for v in vectors.chunks_exact(q.len()) {
+                best = best.max(score(black_box(q), black_box(v)));
+            }
+            black_box(best);
+            start.elapsed()
+        })
+        .min()
+        .unwrap_or_default()
+}
+
+fn main() {
+    println!("metric     dim  plain ns/vec  simd ns/vec  speedup  max diff");
+    for dim in [256, 384, 768, 1024] {
+        let vectors = floats(VECTORS * dim, dim as u64);
+        let q = floats(dim, 7);
+        for metric in [Metric::Cosine, Metric::Dot, Metric::Euclidean] {
+            let diff = vectors
+                .chunks_exact(dim)
+                .map(|v| (metric.score(&q, v) - plain(metric, &q, v)).abs())
+                .fold(0.0f32, f32::max);
+            let slow = time(&vectors, &q, |a, b| plain(metric, a, b));
+            let fast = time(&vectors, &q, |a, b| metric.score(a, b));
+            let per = |d: Duration| d.as_nanos() as f64 / VECTORS as f64;
+            println!(
+                "{:<9} {dim:>4}  {:>12.1}  {:>11.1}  {:>6.1}x  {diff:.1e}",
+                metric.name(),
+                per(slow),
+                per(fast),
+                per(slow) / per(fast)
+            );
+        }
+    }
+}
@@ -3,6 +3,6 @@ fn main() {
     tonic_build::configure()
         .build_server(true)
         .build_client(true)
-        .compile(&["../proto/assistant.proto"], &["../proto"]) 
+        .compile(&["../proto/assistant.proto"], &["../proto"])
         .expect("failed to compile protos");
 }
@@ -0,0 +1,241 @@
+//! Archives ingested whole, such as a Notion export or a Google Takeout:
+//! zip, tar, and gzip-compressed tar or single files. Entries are read one
+//! at a time and never extracted to disk. Each goes through the
+//! [`loader`] for its media type, so an archive's PDFs and photos are
+//! skipped like any other unsupported file. Entries past a size cap are
+//! skipped without being read whole, and an archive that expands too far
+//! or holds too many entries is given up on, so a decompression bomb
+//! cannot exhaust memory.
+
+use crate::loader::{self, Loaded};
+use flate2::read::GzDecoder;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+/// Entries larger than this are skipped, as watched files are.
+pub const MAX_ENTRY_BYTES: u64 = crate::watch::MAX_FILE_BYTES;
+/// Entries one archive may hold, counting folders and skipped ones.
+pub const MAX_ENTRIES: usize = 100_000;
+/// Bytes one archive may expand to.
+pub const MAX_EXPANDED_BYTES: u64 = 16 * 1024 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Zip,
+    Tar,
+    /// A gzip-compressed tar, or a single gzip-compressed file.
+    Gzip,
+}
+
+/// An entry loaded as text.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    /// Path within the archive.
+    pub path: String,
+    pub loaded: Loaded,
+}
+
+/// An entry left out, and why.
+#[derive(Clone, Debug)]
+pub struct Skipped {
+    pub path: String,
+    pub reason: String,
+}
+
+/// The kind of archive a file named `name` is, from `head`, its first
+/// 512 bytes or more. Office documents and e-books are zip files too,
+/// but not archives to expand.
+pub fn kind(name: &str, head: &[u8]) -> Option<Kind> {
+    match loader::sniff(name, head) {
+        "application/zip" => Some(Kind::Zip),
+        "application/x-tar" => Some(Kind::Tar),
+        "application/gzip" => Some(Kind::Gzip),
+        _ => None,
+    }
+}
+
+/// The kind of archive the file at `path`, named `name`, is.
+pub fn kind_of(path: &Path, name: &str) -> io::Result<Option<Kind>> {
+    let mut head = Vec::with_capacity(512);
+    File::open(path)?.take(512).read_to_end(&mut head)?;
+    Ok(kind(name, &head))
+}
+
+/// Reads the archive at `path`, named `name`, passing `each` every entry
+/// in order: loaded, or skipped as too large, of a type no loader
+/// handles, or unreadable. Folders, links, hidden files and macOS
+/// `__MACOSX` forks are passed over. Fails on an archive that cannot be
+/// read, holds more than [`MAX_ENTRIES`] entries or expands past
+/// [`MAX_EXPANDED_BYTES`], and with the first error `each` returns.
+pub fn read(
+    path: &Path,
+    name: &str,
+    kind: Kind,
+    each: impl FnMut(Result<Entry, Skipped>) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut reader = Reader {
+        each,
+        entries: 0,
+        expanded: 0,
+    };
+    let file = File::open(path)?;
+    match kind {
+        Kind::Zip => reader.zip(file),
+        Kind::Tar => reader.tar(Capped::new(BufReader::new(file))),
+        Kind::Gzip => {
+            let mut stream = GzDecoder::new(BufReader::new(file));
+            let mut head = Vec::with_capacity(512);
+            (&mut stream).take(512).read_to_end(&mut head)?;
+            let is_tar = head.get(257..262) == Some(b"ustar");
+            let stream = Capped::new(io::Cursor::new(head).chain(stream));
+            if is_tar {
+                return reader.tar(stream);
+            }
+            let inner = name
+                .rsplit('/')
+                .next()
+                .unwrap_or(name)
+                .trim_end_matches(".gz")
+                .trim_end_matches(".GZ");
+            reader.entry(inner, 0, stream)
+        }
+    }
+}
+
+struct Reader<F> {
+    each: F,
+    entries: usize,
+    /// Bytes read out of zip entries; tar streams count their own.
+    expanded: u64,
+}
+
+impl<F: FnMut(Result<Entry, Skipped>) -> io::Result<()>> Reader<F> {
+    fn zip(&mut self, file: File) -> io::Result<()> {
+        let mut archive = zip::ZipArchive::new(BufReader::new(file)).map_err(invalid)?;
+        for i in 0..archive.len() {
+            self.count()?;
+            let path = archive.name_for_index(i).unwrap_or_default().to_string();
+            let entry = match archive.by_index(i) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    self.skip(path, e.to_string())?;
+                    continue;
+                }
+            };
+            if !entry.is_file() {
+                continue;
+            }
+            let size = entry.size();
+            let before = self.expanded;
+            let mut counted = entry.take(MAX_ENTRY_BYTES + 1);
+            self.entry(&path, size, &mut counted)?;
+            self.expanded = before + (MAX_ENTRY_BYTES + 1 - counted.limit());
+            if self.expanded > MAX_EXPANDED_BYTES {
+                return Err(expanded_too_far());
+            }
+        }
+        Ok(())
+    }
+
+    fn tar<R: Read>(&mut self, stream: Capped<R>) -> io::Result<()> {
+        let mut archive = tar::Archive::new(stream);
+        for entry in archive.entries()? {
+            self.count()?;
+            let entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let path = entry.path()?.to_string_lossy().into_owned();
+            let size = entry.size();
+            self.entry(&path, size, entry)?;
+        }
+        Ok(())
+    }
+
+    fn count(&mut self) -> io::Result<()> {
+        self.entries += 1;
+        if self.entries > MAX_ENTRIES {
+            return Err(invalid(format!(
+                "archive holds more than {MAX_ENTRIES} entries"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Loads entry `path` of `size` bytes, as the archive says, from
+    /// `data`, unless it is to be passed over or skipped.
+    fn entry(&mut self, path: &str, size: u64, data: impl Read) -> io::Result<()> {
+        let path = path.trim_start_matches("./").trim_start_matches('/');
+        if path.is_empty() || hidden(path) {
+            return Ok(());
+        }
+        if size > MAX_ENTRY_BYTES {
+            return self.skip(path.to_string(), too_big());
+        }
+        let mut bytes = Vec::new();
+        data.take(MAX_ENTRY_BYTES + 1).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 > MAX_ENTRY_BYTES {
+            return self.skip(path.to_string(), too_big());
+        }
+        match loader::load(path, &bytes) {
+            Ok(loaded) => (self.each)(Ok(Entry {
+                path: path.to_string(),
+                loaded,
+            })),
+            Err(e) => {
+                let reason = e
+                    .reason
+                    .unwrap_or_else(|| format!("{} cannot be loaded", e.mime_type));
+                self.skip(path.to_string(), reason)
+            }
+        }
+    }
+
+    fn skip(&mut self, path: String, reason: String) -> io::Result<()> {
+        (self.each)(Err(Skipped { path, reason }))
+    }
+}
+
+fn hidden(path: &str) -> bool {
+    path.split('/')
+        .any(|part| part.starts_with('.') || part == "__MACOSX")
+}
+
+fn too_big() -> String {
+    format!("larger than {MAX_ENTRY_BYTES} bytes")
+}
+
+fn expanded_too_far() -> io::Error {
+    invalid(format!(
+        "archive expands to more than {MAX_EXPANDED_BYTES} bytes"
+    ))
+}
+
+fn invalid(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+}
+
+/// A stream that fails once more than [`MAX_EXPANDED_BYTES`] have been
+/// read from it, counting what a tar reader skips over.
+struct Capped<R> {
+    inner: R,
+    read: u64,
+}
+
+impl<R> Capped<R> {
+    fn new(inner: R) -> Self {
+        Capped { inner, read: 0 }
+    }
+}
+
+impl<R: Read> Read for Capped<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        if self.read > MAX_EXPANDED_BYTES {
+            return Err(expanded_too_far());
+        }
+        Ok(n)
+    }
+}
@@ -0,0 +1,398 @@
+//! Generated artifacts (drafts, summaries, briefings, patches, and the
+//! actions of dry runs) kept as
+//! `<dir>/<id>.json`, one file per artifact, with a link back to the
+//! session or plan that produced it. Each kind has a retention period,
+//! after which its artifacts are removed unless they were saved with one
+//! of their own.
+
+use crate::assistant::artifacts_server::Artifacts;
+use crate::assistant::{
+    Artifact as ArtifactMessage, DeleteArtifactRequest, DeleteArtifactResponse, GetArtifactRequest,
+    ListArtifactsRequest, ListArtifactsResponse, SaveArtifactRequest,
+};
+use crate::indexer::timestamp;
+use crate::session;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::OpenOptions;
+use std::hash::{BuildHasher, RandomState};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tonic::{Request, Response, Status};
+
+pub const KINDS: &[&str] = &["draft", "summary", "briefing", "patch", "actions"];
+
+/// How often artifacts past their retention are looked for.
+const RETENTION_SWEEP: Duration = Duration::from_secs(3600);
+const DAY_MS: i64 = 24 * 60 * 60 * 1000;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Artifact {
+    pub id: String,
+    pub kind: String,
+    #[serde(default)]
+    pub title: String,
+    pub content: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub mime_type: String,
+    /// Session the artifact came out of, if any.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub session_id: String,
+    /// Plan or tool run the artifact came out of, if any.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub plan_id: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: i64,
+    /// When the artifact is removed, in milliseconds since the Unix epoch;
+    /// `None` keeps it until deleted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+}
+
+impl Artifact {
+    fn expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+
+    fn to_message(&self, with_content: bool) -> ArtifactMessage {
+        ArtifactMessage {
+            id: self.id.clone(),
+            kind: self.kind.clone(),
+            title: self.title.clone(),
+            content: match with_content {
+                true => self.content.clone(),
+                false => String::new(),
+            },
+            mime_type: self.mime_type.clone(),
+            session_id: self.session_id.clone(),
+            plan_id: self.plan_id.clone(),
+            created_at: timestamp(Some(self.created_at)),
+            expires_at: timestamp(self.expires_at),
+            metadata: self.metadata.clone().into_iter().collect(),
+            size_bytes: self.content.len() as u64,
+        }
+    }
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+/// What to save; the store assigns the id and times.
+#[derive(Clone, Debug, Default)]
+pub struct NewArtifact {
+    pub kind: String,
+    pub title: String,
+    pub content: String,
+    pub mime_type: String,
+    pub session_id: String,
+    pub plan_id: String,
+    pub metadata: BTreeMap<String, String>,
+    /// Days to keep it; `None` takes its kind's retention, and 0 keeps it
+    /// until deleted.
+    pub retain_days: Option<u32>,
+}
+
+/// Days each kind is kept; kinds not listed are kept until deleted.
+#[derive(Clone, Debug, Default)]
+pub struct Retention {
+    days: HashMap<String, u32>,
+}
+
+impl Retention {
+    /// Parses `{"retention_days": {"<kind>": days, ...}}`.
+    pub fn from_config(config: &Value) -> Result<Self, String> {
+        let mut retention = Retention::default();
+        let Some(days) = config["retention_days"].as_object() else {
+            return match config["retention_days"] {
+                Value::Null => Ok(retention),
+                _ => Err("retention_days must be an object".into()),
+            };
+        };
+        for (kind, n) in days {
+            if !KINDS.contains(&kind.as_str()) {
+                return Err(format!(
+                    "unknown artifact kind {kind:?}; kinds: {}",
+                    KINDS.join(", ")
+                ));
+            }
+            let n = n
+                .as_u64()
+                .ok_or_else(|| format!("retention for {kind} must be a number of days"))?;
+            retention.days.insert(kind.clone(), n as u32);
+        }
+        Ok(retention)
+    }
+
+    fn days(&self, kind: &str) -> u32 {
+        self.days.get(kind).copied().unwrap_or(0)
+    }
+}
+
+pub struct ArtifactStore {
+    dir: PathBuf,
+    retention: Retention,
+}
+
+impl ArtifactStore {
+    pub fn new(dir: impl Into<PathBuf>, retention: Retention) -> Self {
+        ArtifactStore {
+            dir: dir.into(),
+            retention,
+        }
+    }
+
+    fn path(&self, id: &str) -> io::Result<PathBuf> {
+        let valid = !id.is_empty()
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'));
+        if !valid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid artifact id: {id:?}"),
+            ));
+        }
+        Ok(self.dir.join(format!("{id}.json")))
+    }
+
+    /// Saves a new artifact and returns it with its id. Without a title,
+    /// it is titled after its first words.
+    pub fn save(&self, new: NewArtifact) -> io::Result<Artifact> {
+        if !KINDS.contains(&new.kind.as_str()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "unknown artifact kind {:?}; kinds: {}",
+                    new.kind,
+                    KINDS.join(", ")
+                ),
+            ));
+        }
+        let created_at = now_ms();
+        let days = new
+            .retain_days
+            .unwrap_or_else(|| self.retention.days(&new.kind));
+        let mut artifact = Artifact {
+            id: String::new(),
+            kind: new.kind,
+            title: match new.title.trim() {
+                "" => session::title_for(&new.content),
+                title => title.to_string(),
+            },
+            content: new.content,
+            mime_type: new.mime_type,
+            session_id: new.session_id,
+            plan_id: new.plan_id,
+            metadata: new.metadata,
+            created_at,
+            expires_at: (days > 0).then(|| created_at.saturating_add(days as i64 * DAY_MS)),
+        };
+        std::fs::create_dir_all(&self.dir)?;
+        // Ids of a kind sort by creation time; the random suffix keeps artifacts
+        // saved in the same millisecond apart.
+        loop {
+            let suffix = RandomState::new().hash_one(created_at) & 0xffff;
+            artifact.id = format!("{}-{created_at:x}-{suffix:04x}", artifact.kind);
+            let file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(self.path(&artifact.id)?);
+            match file {
+                Ok(mut file) => {
+                    file.write_all(&serde_json::to_vec_pretty(&artifact)?)?;
+                    return Ok(artifact);
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Returns the artifact, or `NotFound` if there is none or it expired.
+    pub fn get(&self, id: &str) -> io::Result<Artifact> {
+        let not_found = || io::Error::new(io::ErrorKind::NotFound, format!("no artifact {id}"));
+        let data = std::fs::read(self.path(id)?).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => not_found(),
+            _ => e,
+        })?;
+        let artifact: Artifact = serde_json::from_slice(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        match artifact.expired(now_ms()) {
+            true => Err(not_found()),
+            false => Ok(artifact),
+        }
+    }
+
+    /// Artifacts matching the non-empty filters, newest first.
+    pub fn list(&self, kind: &str, session_id: &str, plan_id: &str) -> io::Result<Vec<Artifact>> {
+        let mut out: Vec<Artifact> = self
+            .all()?
+            .into_iter()
+            .filter(|a| kind.is_empty() || a.kind == kind)
+            .filter(|a| session_id.is_empty() || a.session_id == session_id)
+            .filter(|a| plan_id.is_empty() || a.plan_id == plan_id)
+            .collect();
+        out.sort_by(|a, b| b.created_at.cmp(&a.created_at).then(a.id.cmp(&b.id)));
+        Ok(out)
+    }
+
+    /// Every artifact that has not expired. Files that no longer parse
+    /// are skipped rather than failing the whole list.
+    fn all(&self) -> io::Result<Vec<Artifact>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut out = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let Some(id) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            if let Ok(artifact) = self.get(id) {
+                out.push(artifact);
+            }
+        }
+        Ok(out)
+    }
+
+    /// Deletes the artifact; `NotFound` if there is none.
+    pub fn delete(&self, id: &str) -> io::Result<()> {
+        std::fs::remove_file(self.path(id)?).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => {
+                io::Error::new(io::ErrorKind::NotFound, format!("no artifact {id}"))
+            }
+            _ => e,
+        })
+    }
+
+    /// Deletes the artifacts past their retention. Returns how many.
+    pub fn expire(&self) -> io::Result<usize> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let now = now_ms();
+        let mut removed = 0;
+        for entry in entries {
+            let path = entry?.path();
+            let Ok(data) = std::fs::read(&path) else {
+                continue;
+            };
+            let Ok(artifact) = serde_json::from_slice::<Artifact>(&data) else {
+                continue;
+            };
+            if artifact.expired(now) {
+                std::fs::remove_file(&path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Starts a background task removing expired artifacts every hour,
+    /// until the store is dropped.
+    pub fn spawn_retention_sweeper(self: &Arc<Self>) {
+        let store: Weak<Self> = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RETENTION_SWEEP);
+            loop {
+                interval.tick().await;
+                let Some(store) = store.upgrade() else {
+                    return;
+                };
+                match store.expire() {
+                    Ok(0) => {}
+                    Ok(n) => log::info!("{n} expired artifacts removed"),
+                    Err(e) => log::error!("removing expired artifacts failed: {e}"),
+                }
+            }
+        });
+    }
+}
+
+/// gRPC `Artifacts` service over an [`ArtifactStore`].
+pub struct ArtifactService {
+    store: Arc<ArtifactStore>,
+}
+
+impl ArtifactService {
+    pub fn new(store: Arc<ArtifactStore>) -> Self {
+        ArtifactService { store }
+    }
+}
+
+fn status(e: io::Error) -> Status {
+    match e.kind() {
+        io::ErrorKind::NotFound => Status::not_found(e.to_string()),
+        io::ErrorKind::InvalidInput => Status::invalid_argument(e.to_string()),
+        _ => Status::internal(e.to_string()),
+    }
+}
+
+#[tonic::async_trait]
+impl Artifacts for ArtifactService {
+    async fn save_artifact(
+        &self,
+        req: Request<SaveArtifactRequest>,
+    ) -> Result<Response<ArtifactMessage>, Status> {
+        let req = req.into_inner();
+        let artifact = self
+            .store
+            .save(NewArtifact {
+                kind: req.kind,
+                title: req.title,
+                content: req.content,
+                mime_type: req.mime_type,
+                session_id: req.session_id,
+                plan_id: req.plan_id,
+                metadata: req.metadata.into_iter().collect(),
+                retain_days: req.retain_days,
+            })
+            .map_err(status)?;
+        Ok(Response::new(artifact.to_message(false)))
+    }
+
+    async fn list_artifacts(
+        &self,
+        req: Request<ListArtifactsRequest>,
+    ) -> Result<Response<ListArtifactsResponse>, Status> {
+        let req = req.into_inner();
+        let artifacts = self
+            .store
+            .list(&req.kind, &req.session_id, &req.plan_id)
+            .map_err(status)?;
+        Ok(Response::new(ListArtifactsResponse {
+            artifacts: artifacts.iter().map(|a| a.to_message(false)).collect(),
+        }))
+    }
+
+    async fn get_artifact(
+        &self,
+        req: Request<GetArtifactRequest>,
+    ) -> Result<Response<ArtifactMessage>, Status> {
+        let artifact = self.store.get(&req.into_inner().id).map_err(status)?;
+        Ok(Response::new(artifact.to_message(true)))
+    }
+
+    async fn delete_artifact(
+        &self,
+        req: Request<DeleteArtifactRequest>,
+    ) -> Result<Response<DeleteArtifactResponse>, Status> {
+        self.store.delete(&req.into_inner().id).map_err(status)?;
+        Ok(Response::new(DeleteArtifactResponse {}))
+    }
+}
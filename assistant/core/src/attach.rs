@@ -0,0 +1,207 @@
+//! Documents attached to a chat session, for "here is a file, answer
+//! questions about it". Each session's attachments are chunked and
+//! embedded into an index of its own, held in memory only, and searched
+//! on every turn of the session. They never reach a collection or the
+//! disk: they are dropped when detached, once the session has been idle
+//! for [`IDLE`], or when the core restarts.
+
+use crate::chat;
+use crate::chunk::{self, ChunkParams};
+use crate::docid;
+use crate::embed::Embedder;
+use crate::filter::Filter;
+use crate::index::{NewEntry, QueryOptions, VectorIndex};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Sessions whose attachments have not been used for this long lose them.
+pub const IDLE: Duration = Duration::from_secs(60 * 60);
+
+/// Most text a session may have attached at once.
+pub const MAX_BYTES: usize = 16 * 1024 * 1024;
+
+/// Attached chunks added to the context of each turn.
+pub const CHUNKS_PER_TURN: usize = 4;
+
+/// One session's attachments.
+struct Attached {
+    /// Workspace the attachments were made in; only turns in it see them.
+    workspace: String,
+    index: VectorIndex,
+    /// Attachment names with the size of their text.
+    names: BTreeMap<String, usize>,
+    used: Instant,
+}
+
+impl Attached {
+    fn bytes(&self) -> usize {
+        self.names.values().sum()
+    }
+}
+
+pub struct Attachments {
+    embedder: Arc<dyn Embedder>,
+    sessions: Mutex<HashMap<String, Attached>>,
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.into())
+}
+
+fn check_workspace(session: &str, attached: &Attached, workspace: &str) -> io::Result<()> {
+    if attached.workspace == workspace {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("session {session} belongs to another workspace"),
+    ))
+}
+
+impl Attachments {
+    /// Attachments embedded with `embedder`, normally the one new
+    /// collections use.
+    pub fn new(embedder: Arc<dyn Embedder>) -> Self {
+        Attachments {
+            embedder,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Attaches `text` to `session` as `name`, replacing an attachment of
+    /// the same name, and returns how many chunks it was split into.
+    /// Embeds on the calling thread.
+    pub fn attach(
+        &self,
+        session: &str,
+        workspace: &str,
+        name: &str,
+        text: &str,
+    ) -> io::Result<usize> {
+        if session.is_empty() {
+            return Err(invalid("attachments need a session_id"));
+        }
+        if name.is_empty() || name.contains('#') {
+            return Err(invalid(format!("invalid attachment name: {name:?}")));
+        }
+        if text.trim().is_empty() {
+            return Err(invalid(format!("attachment {name} is empty")));
+        }
+        // Chunks are embedded before the lock is taken, so one large
+        // attachment does not hold up other sessions.
+        let entries: Vec<NewEntry> = (0u32..)
+            .zip(chunk::split(text, ChunkParams::default()))
+            .map(|(n, part)| NewEntry {
+                id: docid::with_chunk(name.to_string(), Some(n)),
+                text: part.to_string(),
+                provenance: docid::Provenance {
+                    source: name.to_string(),
+                    chunk: Some(n),
+                    mime_type: docid::mime_type(name).to_string(),
+                },
+                metadata: BTreeMap::new(),
+                ttl: None,
+                content_hash: String::new(),
+            })
+            .collect();
+        let chunks = entries.len();
+        let embedded = entries
+            .into_iter()
+            .map(|entry| {
+                let embedding = self.embedder.embed(&entry.text);
+                (entry, embedding)
+            })
+            .collect();
+        let mut sessions = self.sessions.lock().unwrap();
+        sweep(&mut sessions);
+        let attached = sessions
+            .entry(session.to_string())
+            .or_insert_with(|| Attached {
+                workspace: workspace.to_string(),
+                index: VectorIndex::in_memory(Arc::clone(&self.embedder)),
+                names: BTreeMap::new(),
+                used: Instant::now(),
+            });
+        check_workspace(session, attached, workspace)?;
+        let kept = attached.bytes() - attached.names.get(name).copied().unwrap_or(0);
+        if kept + text.len() > MAX_BYTES {
+            return Err(invalid(format!(
+                "session {session} would have more than {MAX_BYTES} bytes attached"
+            )));
+        }
+        attached.index.delete(Some(name), &Filter::default())?;
+        attached.index.upsert_embedded(embedded)?;
+        attached.names.insert(name.to_string(), text.len());
+        attached.used = Instant::now();
+        Ok(chunks)
+    }
+
+    /// Removes attachment `name` from `session`; false if it had none.
+    pub fn detach(&self, session: &str, workspace: &str, name: &str) -> io::Result<bool> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(attached) = sessions.get_mut(session) else {
+            return Ok(false);
+        };
+        check_workspace(session, attached, workspace)?;
+        if attached.names.remove(name).is_none() {
+            return Ok(false);
+        }
+        attached.index.delete(Some(name), &Filter::default())?;
+        if attached.names.is_empty() {
+            sessions.remove(session);
+        }
+        Ok(true)
+    }
+
+    /// `[{"name", "bytes"}, ...]` for `session`'s attachments.
+    pub fn list(&self, session: &str, workspace: &str) -> io::Result<Value> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sweep(&mut sessions);
+        let Some(attached) = sessions.get(session) else {
+            return Ok(json!([]));
+        };
+        check_workspace(session, attached, workspace)?;
+        let names = attached
+            .names
+            .iter()
+            .map(|(name, bytes)| json!({ "name": name, "bytes": bytes }))
+            .collect();
+        Ok(Value::Array(names))
+    }
+
+    /// The chunks of `session`'s attachments that best match `prompt`,
+    /// best first, as context for a turn.
+    pub fn context(&self, session: &str, workspace: &str, prompt: &str) -> Vec<chat::Chunk> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sweep(&mut sessions);
+        let Some(attached) = sessions.get_mut(session) else {
+            return Vec::new();
+        };
+        if attached.workspace != workspace {
+            return Vec::new();
+        }
+        attached.used = Instant::now();
+        let options = QueryOptions {
+            k: CHUNKS_PER_TURN,
+            ..QueryOptions::default()
+        };
+        attached
+            .index
+            .query(prompt, &options)
+            .into_iter()
+            .map(|hit| chat::Chunk {
+                text: hit.text,
+                source: hit.id,
+                score: Some(hit.score),
+            })
+            .collect()
+    }
+}
+
+/// Drops the attachments of sessions idle for longer than [`IDLE`].
+fn sweep(sessions: &mut HashMap<String, Attached>) {
+    sessions.retain(|_, attached| attached.used.elapsed() < IDLE);
+}
@@ -0,0 +1,337 @@
+//! Differential backups of index snapshots. A backup repository keeps each
+//! snapshot as a manifest listing its files as content-defined chunks, and
+//! stores every chunk once under its SHA-256. A nightly backup of a large
+//! index therefore only adds the chunks that changed since earlier ones.
+//!
+//! ```text
+//! <repo>/chunks/<first two hex digits>/<sha256 hex>
+//! <repo>/snapshots/<name>.json     manifest, written after its chunks
+//! ```
+//!
+//! Chunk boundaries are picked by a rolling (gear) hash of the content,
+//! so an entry added in the middle of an index file only changes the
+//! chunks around it instead of shifting every chunk after it.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+const MIN_CHUNK: usize = 256 << 10;
+const MAX_CHUNK: usize = 4 << 20;
+/// A boundary falls where these bits of the rolling hash are all zero,
+/// about every 1 MiB past the minimum. The top bits depend on the last 64
+/// bytes read.
+const BOUNDARY_MASK: u64 = ((1 << 20) - 1) << 44;
+
+/// Random values per byte for the rolling hash, from splitmix64 with a
+/// fixed seed; changing them would stop chunks from matching older ones.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6d61_6869_6964_7800;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Length of the chunk starting `data`, which holds at least
+/// [`MAX_CHUNK`] bytes unless it is the end of the file.
+fn cut(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK {
+        return data.len();
+    }
+    let end = data.len().min(MAX_CHUNK);
+    let mut hash = 0u64;
+    for (at, &b) in data[..end].iter().enumerate().skip(MIN_CHUNK - 64) {
+        hash = (hash << 1).wrapping_add(GEAR[b as usize]);
+        if at >= MIN_CHUNK && hash & BOUNDARY_MASK == 0 {
+            return at + 1;
+        }
+    }
+    end
+}
+
+/// Calls `f` with each chunk of the file at `path`, in order.
+fn for_each_chunk(path: &Path, mut f: impl FnMut(&[u8]) -> io::Result<()>) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let mut buf = Vec::with_capacity(2 * MAX_CHUNK);
+    let mut eof = false;
+    loop {
+        while !eof && buf.len() < MAX_CHUNK {
+            let filled = buf.len();
+            buf.resize(filled + MAX_CHUNK, 0);
+            let read = file.read(&mut buf[filled..])?;
+            buf.truncate(filled + read);
+            eof = read == 0;
+        }
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let len = cut(&buf);
+        f(&buf[..len])?;
+        buf.drain(..len);
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Where chunk `hash` is stored, relative to the repository.
+pub fn chunk_path(hash: &str) -> String {
+    format!("chunks/{}/{hash}", hash.get(..2).unwrap_or_default())
+}
+
+/// Whether `hash` reads as a SHA-256: 64 lowercase hex digits.
+fn is_sha256(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileEntry {
+    /// Relative to the snapshot directory, with `/` separators.
+    pub path: String,
+    pub size: u64,
+    /// SHA-256 of each chunk, in order.
+    pub chunks: Vec<String>,
+}
+
+/// One backed-up snapshot.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub created_at: String,
+    pub files: Vec<FileEntry>,
+}
+
+impl Manifest {
+    pub fn size(&self) -> u64 {
+        self.files.iter().map(|f| f.size).sum()
+    }
+
+    /// Fails unless every file is a relative path inside the snapshot and
+    /// every chunk a SHA-256, so a tampered manifest cannot write outside
+    /// the target or read outside the repository.
+    fn check(&self) -> Result<(), String> {
+        for file in &self.files {
+            let plain = Path::new(&file.path)
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)));
+            if file.path.is_empty() || !plain {
+                return Err(format!("{:?} is not a path inside the snapshot", file.path));
+            }
+            if let Some(hash) = file.chunks.iter().find(|hash| !is_sha256(hash)) {
+                return Err(format!("chunk {hash:?} of {} is not a SHA-256", file.path));
+            }
+        }
+        Ok(())
+    }
+
+    /// Repository paths of the chunks it needs, each once.
+    pub fn chunk_paths(&self) -> Vec<String> {
+        let mut seen = HashSet::new();
+        self.files
+            .iter()
+            .flat_map(|f| &f.chunks)
+            .filter(|hash| seen.insert(hash.as_str()))
+            .map(|hash| chunk_path(hash))
+            .collect()
+    }
+}
+
+/// What a push wrote.
+#[derive(Clone, Debug, Default)]
+pub struct Pushed {
+    /// Name the snapshot is stored under.
+    pub name: String,
+    pub files: usize,
+    pub bytes: u64,
+    pub chunks: usize,
+    /// Chunks the repository did not have yet, and their size.
+    pub new_chunks: usize,
+    pub new_bytes: u64,
+}
+
+/// Files under `dir`, relative to it, sorted.
+fn files_in(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(rel) = pending.pop() {
+        for entry in std::fs::read_dir(dir.join(&rel))? {
+            let entry = entry?;
+            let rel = rel.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                pending.push(rel);
+            } else {
+                files.push(rel);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Writes via a temporary file and a rename, so a file under its final
+/// name is always complete.
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::rename(tmp, path)
+}
+
+/// Adds the snapshot directory `snapshot` to the repository at `repo` as
+/// `name`, writing only chunks it lacks. Chunks in `known` are taken to
+/// be stored elsewhere already, for a `repo` that only stages new files
+/// for upload. The manifest is written last, so an interrupted push
+/// leaves no snapshot behind, only chunks the next push reuses.
+pub fn push(
+    snapshot: &Path,
+    repo: &Path,
+    name: &str,
+    known: &HashSet<String>,
+) -> io::Result<Pushed> {
+    let manifest_path = repo.join("snapshots").join(format!("{name}.json"));
+    if manifest_path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("backup {name} already exists"),
+        ));
+    }
+    let mut pushed = Pushed {
+        name: name.to_string(),
+        ..Pushed::default()
+    };
+    let mut files = Vec::new();
+    for rel in files_in(snapshot)? {
+        let mut entry = FileEntry {
+            path: rel.to_string_lossy().replace('\\', "/"),
+            size: 0,
+            chunks: Vec::new(),
+        };
+        for_each_chunk(&snapshot.join(&rel), |chunk| {
+            let hash = sha256_hex(chunk);
+            let path = repo.join(chunk_path(&hash));
+            if !known.contains(&hash) && !path.exists() {
+                write_atomic(&path, chunk)?;
+                pushed.new_chunks += 1;
+                pushed.new_bytes += chunk.len() as u64;
+            }
+            entry.size += chunk.len() as u64;
+            entry.chunks.push(hash);
+            Ok(())
+        })?;
+        pushed.files += 1;
+        pushed.bytes += entry.size;
+        pushed.chunks += entry.chunks.len();
+        files.push(entry);
+    }
+    let manifest = Manifest {
+        created_at: chrono::Utc::now().to_rfc3339(),
+        files,
+    };
+    write_atomic(&manifest_path, &serde_json::to_vec_pretty(&manifest)?)?;
+    Ok(pushed)
+}
+
+/// Name for a backup taken now, which sorts by time and is safe in file
+/// names and remote paths.
+pub fn name_now() -> String {
+    chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// The backups in the repository at `repo`, oldest first.
+pub fn list(repo: &Path) -> io::Result<Vec<(String, Manifest)>> {
+    let dir = repo.join("snapshots");
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut backups = Vec::new();
+    for entry in entries {
+        let file_name = entry?.file_name();
+        let Some(name) = file_name.to_str().and_then(|f| f.strip_suffix(".json")) else {
+            continue;
+        };
+        backups.push((name.to_string(), manifest(repo, name)?));
+    }
+    backups.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(backups)
+}
+
+/// The manifest of backup `name`.
+pub fn manifest(repo: &Path, name: &str) -> io::Result<Manifest> {
+    let path = repo.join("snapshots").join(format!("{name}.json"));
+    let data = match std::fs::read(&path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no backup {name}"),
+            ))
+        }
+        data => data?,
+    };
+    let manifest: Manifest =
+        serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    manifest
+        .check()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("backup {name}: {e}")))?;
+    Ok(manifest)
+}
+
+/// Reassembles backup `name` from the repository at `repo` into `target`,
+/// a directory that must not exist yet, checking every chunk's hash. Like
+/// a snapshot, it is written under `<target>.partial` and renamed once
+/// complete.
+pub fn pull(repo: &Path, name: &str, target: &Path) -> io::Result<Manifest> {
+    if target.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", target.display()),
+        ));
+    }
+    let manifest = manifest(repo, name)?;
+    let mut partial = target.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    if partial.exists() {
+        std::fs::remove_dir_all(&partial)?;
+    }
+    for entry in &manifest.files {
+        let path = partial.join(&entry.path);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = File::create(&path)?;
+        for hash in &entry.chunks {
+            let chunk = std::fs::read(repo.join(chunk_path(hash)))?;
+            if sha256_hex(&chunk) != *hash {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("chunk {hash} of {} is corrupt", entry.path),
+                ));
+            }
+            file.write_all(&chunk)?;
+        }
+        file.sync_all()?;
+    }
+    std::fs::rename(&partial, target)?;
+    Ok(manifest)
+}
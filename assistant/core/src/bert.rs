@@ -0,0 +1,224 @@
+//! Sentence-transformer embeddings and cross-encoder reranking from
+//! BERT-family models (such as all-MiniLM-L6-v2 and
+//! ms-marco-MiniLM-L-6-v2) run on the CPU with candle. Built with the
+//! `bert` feature.
+//!
+//! A model is a directory holding the Hugging Face `config.json`,
+//! `tokenizer.json` and `model.safetensors`. Embeddings are the mean of
+//! the token states, as sentence-transformers pools them, unless an
+//! `Embed` request asks for the `[CLS]` state or the maximum instead; the
+//! cosine metric normalizes them as models of this kind expect. A
+//! cross-encoder reads the query and a text as one pair and scores it with
+//! its pooler and single-label classifier head, squashed to 0..1 by a
+//! sigmoid.
+
+use crate::embed::{Embedder, Pooling};
+use crate::index::fnv1a;
+use crate::rerank::Reranker;
+use candle_core::{DType, Device, Module, Tensor};
+use candle_nn::{Linear, VarBuilder};
+use candle_transformers::models::bert::{BertModel, Config, DTYPE};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use tokenizers::{Tokenizer, TruncationParams};
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+pub struct BertEmbedder {
+    name: String,
+    dim: usize,
+    /// See [`model_fingerprint`].
+    fingerprint: String,
+    /// Most tokens the model accepts; longer texts are truncated.
+    max_tokens: usize,
+    model: BertModel,
+    tokenizer: Tokenizer,
+}
+
+fn invalid(path: &Path, e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("loading model {}: {e}", path.display()),
+    )
+}
+
+/// The parts of the model in `dir` every use needs, with its name: that
+/// of the directory.
+fn load_parts(dir: &Path) -> io::Result<(String, Config, Tokenizer, VarBuilder<'static>)> {
+    let name = dir
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| invalid(dir, "the model directory needs a name"))?
+        .to_string();
+    let config: Config = serde_json::from_slice(&std::fs::read(dir.join("config.json"))?)
+        .map_err(|e| invalid(dir, e))?;
+    let tokenizer =
+        Tokenizer::from_file(dir.join("tokenizer.json")).map_err(|e| invalid(dir, e))?;
+    let weights = dir.join("model.safetensors");
+    // SAFETY: the weights file is mapped read-only and must not be
+    // modified while the server runs, as with any model file.
+    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[weights], DTYPE, &Device::Cpu) }
+        .map_err(|e| invalid(dir, e))?;
+    Ok((name, config, tokenizer, vb))
+}
+
+/// A hash of the model's config and tokenizer, and of the size, start and
+/// end of its weights: enough to tell models apart without reading all of
+/// the weights.
+fn model_fingerprint(dir: &Path) -> io::Result<String> {
+    const SAMPLE: u64 = 1024 * 1024;
+    let mut bytes = std::fs::read(dir.join("config.json"))?;
+    bytes.extend(std::fs::read(dir.join("tokenizer.json"))?);
+    let mut weights = std::fs::File::open(dir.join("model.safetensors"))?;
+    let len = weights.metadata()?.len();
+    bytes.extend(len.to_le_bytes());
+    for at in [0, len.saturating_sub(SAMPLE)] {
+        weights.seek(SeekFrom::Start(at))?;
+        (&mut weights).take(SAMPLE).read_to_end(&mut bytes)?;
+    }
+    Ok(format!("{:016x}", fnv1a(&bytes)))
+}
+
+impl BertEmbedder {
+    /// Loads the model in `dir`, named after the directory.
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let (name, config, tokenizer, vb) = load_parts(dir)?;
+        let model = BertModel::load(vb, &config).map_err(|e| invalid(dir, e))?;
+        Ok(BertEmbedder {
+            fingerprint: format!("{name}/{}", model_fingerprint(dir)?),
+            name,
+            dim: config.hidden_size,
+            max_tokens: config.max_position_embeddings,
+            model,
+            tokenizer,
+        })
+    }
+
+    fn try_embed(&self, text: &str, pooling: Pooling) -> Result<Vec<f32>, Error> {
+        let encoding = self.tokenizer.encode(text, true)?;
+        let mut ids = encoding.get_ids().to_vec();
+        ids.truncate(self.max_tokens);
+        let len = ids.len();
+        let device = &Device::Cpu;
+        let input = Tensor::from_vec(ids, (1, len), device)?;
+        let token_types = input.zeros_like()?;
+        // (1, tokens, hidden) -> (1, hidden).
+        let states = self.model.forward(&input, &token_types, None)?;
+        let pooled = match pooling {
+            Pooling::Mean => (states.sum(1)? / len as f64)?,
+            Pooling::Cls => states.narrow(1, 0, 1)?.squeeze(1)?,
+            Pooling::Max => states.max(1)?,
+        };
+        Ok(pooled.squeeze(0)?.to_dtype(DType::F32)?.to_vec1::<f32>()?)
+    }
+}
+
+impl Embedder for BertEmbedder {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn dim(&self) -> usize {
+        self.dim
+    }
+
+    fn fingerprint(&self) -> String {
+        self.fingerprint.clone()
+    }
+
+    fn tokens(&self, text: &str) -> usize {
+        self.tokenizer
+            .encode(text, true)
+            .map_or(0, |encoding| encoding.get_ids().len())
+    }
+
+    fn max_tokens(&self) -> Option<usize> {
+        Some(self.max_tokens)
+    }
+
+    fn poolings(&self) -> &'static [Pooling] {
+        &[Pooling::Mean, Pooling::Cls, Pooling::Max]
+    }
+
+    fn embed(&self, text: &str) -> Vec<f32> {
+        self.embed_pooled(text, Pooling::Mean)
+    }
+
+    /// A text the model fails on gets a zero vector, which matches
+    /// nothing by cosine or dot product; keyword search still finds it.
+    fn embed_pooled(&self, text: &str, pooling: Pooling) -> Vec<f32> {
+        self.try_embed(text, pooling).unwrap_or_else(|e| {
+            log::error!("embedding with {} failed: {e}", self.name);
+            vec![0.0; self.dim]
+        })
+    }
+}
+
+/// Scores query-text pairs with a sequence-classification model that has
+/// one output, as sentence-transformers cross-encoders do.
+pub struct BertCrossEncoder {
+    name: String,
+    model: BertModel,
+    pooler: Linear,
+    classifier: Linear,
+    tokenizer: Tokenizer,
+}
+
+impl BertCrossEncoder {
+    /// Loads the model in `dir`, named after the directory. Its weights are
+    /// stored under `bert.` with the head under `classifier.`, as Hugging
+    /// Face saves `BertForSequenceClassification`.
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let (name, config, mut tokenizer, vb) = load_parts(dir)?;
+        let model = BertModel::load(vb.pp("bert"), &config).map_err(|e| invalid(dir, e))?;
+        let hidden = config.hidden_size;
+        let pooler = candle_nn::linear(hidden, hidden, vb.pp("bert.pooler.dense"))
+            .map_err(|e| invalid(dir, e))?;
+        let classifier =
+            candle_nn::linear(hidden, 1, vb.pp("classifier")).map_err(|e| invalid(dir, e))?;
+        // Long pairs lose tokens from the longer side, keeping the
+        // separators the model expects.
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: config.max_position_embeddings,
+                ..TruncationParams::default()
+            }))
+            .map_err(|e| invalid(dir, e))?;
+        Ok(BertCrossEncoder {
+            name,
+            model,
+            pooler,
+            classifier,
+            tokenizer,
+        })
+    }
+
+    fn try_score(&self, query: &str, text: &str) -> Result<f32, Error> {
+        let encoding = self.tokenizer.encode((query, text), true)?;
+        let device = &Device::Cpu;
+        let len = encoding.get_ids().len();
+        let input = Tensor::from_vec(encoding.get_ids().to_vec(), (1, len), device)?;
+        let token_types = Tensor::from_vec(encoding.get_type_ids().to_vec(), (1, len), device)?;
+        // (1, tokens, hidden) -> the [CLS] token's state, (1, hidden).
+        let states = self.model.forward(&input, &token_types, None)?;
+        let cls = states.narrow(1, 0, 1)?.squeeze(1)?;
+        let pooled = self.pooler.forward(&cls)?.tanh()?;
+        let logit = self.classifier.forward(&pooled)?.flatten_all()?;
+        let logit = logit.to_dtype(DType::F32)?.to_vec1::<f32>()?[0];
+        Ok(1.0 / (1.0 + (-logit).exp()))
+    }
+}
+
+impl Reranker for BertCrossEncoder {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// A pair the model fails on scores 0, below any it can read.
+    fn score(&self, query: &str, text: &str) -> f32 {
+        self.try_score(query, text).unwrap_or_else(|e| {
+            log::error!("reranking with {} failed: {e}", self.name);
+            0.0
+        })
+    }
+}
@@ -0,0 +1,2496 @@
+//! `ondevice` — command-line client for the assistant core.
+
+use assistant_core::assistant::artifacts_client::ArtifactsClient;
+use assistant_core::assistant::assistant_client::AssistantClient;
+use assistant_core::assistant::indexer_client::IndexerClient;
+use assistant_core::assistant::{
+    BatchIndexRequest, CountRequest, CreateCollectionRequest, CreateSnapshotRequest,
+    DeleteAliasRequest, DeleteArtifactRequest, DeleteRequest, DeleteSnapshotRequest, Document,
+    DropCollectionRequest, EmbedRequest, ExistsRequest, ExportIndexRequest, FileChunk,
+    GetArtifactRequest, GetDocumentRequest, ImportIndexRequest, ImportRequest, IndexRequest,
+    ListArtifactsRequest, ListCollectionsRequest, ListDocumentsRequest, ListEmbeddingModelsRequest,
+    ListSnapshotsRequest, QueryAtRequest, QueryRequest, ReindexRequest, Request, RestoreRequest,
+    SaveArtifactRequest, SetAliasRequest, SnapshotRequest, StatsRequest, UploadStatusRequest,
+};
+use assistant_core::loader::{self, Loaded};
+use assistant_core::{archive, backup, docid, text};
+use clap::{Parser, Subcommand};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{BufRead, BufReader, IsTerminal, Read, Write};
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::Channel;
+
+#[derive(Parser)]
+#[command(name = "ondevice", about = "Talk to the on-device assistant")]
+struct Cli {
+    /// Core address; defaults to $ASSISTANT_ADDR or 127.0.0.1:50051.
+    #[arg(long, global = true)]
+    addr: Option<String>,
+
+    /// Server-side profile to run under (post-processing, etc.).
+    #[arg(long, global = true, default_value = "")]
+    profile: String,
+
+    /// Save turns to this session on the core.
+    #[arg(long, global = true)]
+    session: Option<String>,
+
+    /// Index collection to use; defaults to "default", or to the
+    /// workspace's first collection.
+    #[arg(long, global = true, default_value = "")]
+    collection: String,
+
+    /// Workspace to work in: its collections, tools, profile and sessions.
+    #[arg(long, global = true, default_value = "")]
+    workspace: String,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Ask a one-off question.
+    Ask {
+        question: String,
+        /// Attach the current clipboard text as context.
+        #[arg(long)]
+        clipboard: bool,
+        /// Say no grounding was found, rather than answer, when the
+        /// answer's confidence (0 to 1) is below this.
+        #[arg(long)]
+        min_confidence: Option<f64>,
+        /// Attach a file to the --session first, to ask about it.
+        #[arg(long, value_name = "PATH")]
+        attach: Vec<String>,
+    },
+    /// One-shot completion: reads stdin as context and streams the answer
+    /// to stdout, e.g. `git diff | ondevice run -p "write a commit message"`.
+    Run {
+        /// Instruction to apply to the piped input.
+        #[arg(short, long)]
+        prompt: Option<String>,
+        /// Template file, or the name of a template stored on the core.
+        #[arg(short, long)]
+        template: Option<String>,
+        /// Template variable as NAME=VALUE; VALUE `@-` reads stdin and
+        /// `@PATH` reads a file.
+        #[arg(long = "var", value_name = "NAME=VALUE")]
+        vars: Vec<String>,
+        /// Also stream the model's reasoning, to stderr.
+        #[arg(long)]
+        show_reasoning: bool,
+        /// Print the stream summary (latency, tokens/sec) to stderr.
+        #[arg(long)]
+        stats: bool,
+        /// Stream at most this many tokens per second; the profile's
+        /// ceiling still applies.
+        #[arg(long)]
+        tokens_per_second: Option<f64>,
+        /// Also save the answer as an artifact of this kind: draft,
+        /// summary, briefing or patch.
+        #[arg(long, value_name = "KIND")]
+        save_as: Option<String>,
+    },
+    /// Add documents to the core's index.
+    Index {
+        #[command(subcommand)]
+        command: IndexCommand,
+    },
+    /// Push a file to the core in pieces, for a core on another machine or
+    /// a file too large to send at once. Running it again after it was
+    /// interrupted sends only what the core is missing.
+    Upload {
+        path: String,
+        /// Upload id; defaults to one made from the file's path, size and
+        /// modification time.
+        #[arg(long)]
+        id: Option<String>,
+        /// Also index the file in --collection, under its file:// id.
+        #[arg(long)]
+        index: bool,
+    },
+    /// List indexed documents with their sizes and a preview.
+    List {
+        /// Documents per page.
+        #[arg(long, default_value_t = 50)]
+        page_size: u32,
+        /// Resume from the token printed with the previous page.
+        #[arg(long)]
+        page_token: Option<String>,
+        /// Fetch every page.
+        #[arg(long, conflicts_with = "page_token")]
+        all: bool,
+    },
+    /// Show each collection's size in memory and on disk, when it was last
+    /// saved, and whether queries can use its search graph.
+    Stats,
+    /// Embed each line of a file, or of stdin, printing one JSON array per
+    /// line in the same order. Lines are streamed to the core as they are
+    /// read, so the input can be any length.
+    Embed {
+        path: Option<String>,
+        /// Embedder to use; defaults to the one new collections get.
+        #[arg(long)]
+        embedder: Option<String>,
+        /// Scale each embedding to length 1.
+        #[arg(long)]
+        normalize: bool,
+        /// How a model pools its token states: mean (its own), cls or max.
+        #[arg(long)]
+        pooling: Option<String>,
+        /// Print usage (texts, tokens, truncated texts, tokens/sec) to
+        /// stderr.
+        #[arg(long)]
+        stats: bool,
+    },
+    /// List the embedders the core offers and the collections using each.
+    Embedders,
+    /// Manage index collections.
+    Collections {
+        #[command(subcommand)]
+        command: CollectionsCommand,
+    },
+    /// Search the index.
+    Query {
+        #[arg(required_unless_present = "cursor")]
+        query: Option<String>,
+        /// Number of hits.
+        #[arg(short, default_value_t = 5)]
+        k: u32,
+        /// Show how each hit was scored.
+        #[arg(long)]
+        explain: bool,
+        /// One hit per document: its best-matching chunk.
+        #[arg(long)]
+        documents: bool,
+        /// Print each hit with this many neighboring chunks per side.
+        #[arg(long, default_value_t = 0)]
+        context: u32,
+        /// Page through results this many at a time instead of taking k.
+        #[arg(long, default_value_t = 0)]
+        limit: u32,
+        /// Skip this many hits of a paged query.
+        #[arg(long, default_value_t = 0, requires = "limit")]
+        offset: u32,
+        /// Continue a paged query from the cursor printed with its last page.
+        #[arg(long, requires = "limit")]
+        cursor: Option<String>,
+        /// Only search entries matching a clause such as
+        /// `mime_type=text/markdown` or `indexed_at>=2024-05-01`.
+        #[arg(long)]
+        filter: Vec<String>,
+        /// Most recently indexed matches first.
+        #[arg(long)]
+        newest: bool,
+        /// Score every entry instead of using the approximate search graph.
+        #[arg(long)]
+        exact: bool,
+        /// vector, keyword (BM25) or hybrid.
+        #[arg(long, default_value = "vector")]
+        mode: String,
+        /// Fail unless the collection is embedded with this embedder.
+        #[arg(long, default_value = "")]
+        embedder: String,
+        /// Compare embeddings by cosine, dot or euclidean instead of the
+        /// collection's metric.
+        #[arg(long, default_value = "")]
+        metric: String,
+        /// Rescore the best --rerank-top-n hits with the server's
+        /// cross-encoder before taking k.
+        #[arg(long, conflicts_with = "explain")]
+        rerank: bool,
+        /// Hits to rerank (0 = 20).
+        #[arg(long, default_value_t = 0, requires = "rerank")]
+        rerank_top_n: u32,
+        /// Diversify hits by maximal marginal relevance, weighing relevance
+        /// against similarity to earlier hits (1 = relevance only).
+        #[arg(long, value_name = "LAMBDA", conflicts_with_all = ["explain", "newest"])]
+        mmr: Option<f32>,
+        /// Search a collection snapshot instead of the collection as it is.
+        #[arg(long, conflicts_with = "explain")]
+        at: Option<String>,
+        /// Return only the best --min-k hits when they stand out from the
+        /// rest, and k (reranked with --rerank) when they do not.
+        #[arg(long, conflicts_with_all = ["explain", "newest", "limit"])]
+        adaptive: bool,
+        /// Hits an adaptive query returns when they stand out (0 = 2).
+        #[arg(long, default_value_t = 0, requires = "adaptive")]
+        min_k: u32,
+        /// Score gap, relative to the best hit's, at which they stand out
+        /// (0 = 0.1).
+        #[arg(long, default_value_t = 0.0, requires = "adaptive")]
+        margin: f32,
+    },
+    /// Copy the whole index, as of now, to a new directory.
+    Snapshot { path: String },
+    /// Replace the whole index with a snapshot.
+    Restore { path: String },
+    /// Back up snapshots of the index, storing only what changed since
+    /// earlier backups.
+    Backup {
+        #[command(subcommand)]
+        command: BackupCommand,
+    },
+    /// Inspect chat sessions stored on the core.
+    Session {
+        #[command(subcommand)]
+        command: SessionCommand,
+    },
+    /// Manage generated artifacts (drafts, summaries, briefings, patches,
+    /// dry-run actions).
+    Artifacts {
+        #[command(subcommand)]
+        command: ArtifactsCommand,
+    },
+    /// Manage prompt templates stored on the core.
+    Template {
+        #[command(subcommand)]
+        command: TemplateCommand,
+    },
+    /// Preview a unified diff against the patch connector's project, from
+    /// FILE or stdin; with --apply, write it after the preview checks out.
+    Patch {
+        file: Option<String>,
+        #[arg(long)]
+        apply: bool,
+    },
+    /// List the tools available under --profile and --workspace.
+    Tools {
+        /// Print each tool's full description, including its JSON Schema.
+        #[arg(long)]
+        json: bool,
+    },
+    /// List the workspaces configured on the core.
+    Workspaces,
+    /// Show the core's connectors and how their syncs went, or sync one.
+    Sources {
+        #[command(subcommand)]
+        command: SourcesCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum IndexCommand {
+    /// Index (or replace) a document from TEXT, --file or stdin. A file's
+    /// id defaults to its `file://` URI, so re-adding it updates it.
+    Add {
+        #[arg(required_unless_present = "file")]
+        id: Option<String>,
+        text: Option<String>,
+        #[arg(long, conflicts_with = "text")]
+        file: Option<String>,
+        /// Metadata as KEY=VALUE, for filtering queries (`--filter KEY=VALUE`).
+        #[arg(long = "meta", value_name = "KEY=VALUE")]
+        metadata: Vec<String>,
+        /// Remove the document this many seconds after indexing it.
+        #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+        ttl: u64,
+        /// Only write if the document is at this version; 0 only writes a
+        /// new document.
+        #[arg(long, value_name = "VERSION")]
+        if_version: Option<u64>,
+    },
+    /// Index (or replace) many files, each under its `file://` URI, sending
+    /// them in batches.
+    AddFiles {
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// Files per BatchIndex request.
+        #[arg(long, default_value_t = 100)]
+        batch: usize,
+        #[arg(long = "meta", value_name = "KEY=VALUE")]
+        metadata: Vec<String>,
+        #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+        ttl: u64,
+    },
+    /// Index every file of a zip, tar or tar.gz archive, such as a Notion
+    /// export or a Google Takeout, each under `<archive's file:// URI>!/<path>`.
+    /// Files of a type no loader handles are skipped and listed.
+    AddArchive {
+        path: String,
+        #[arg(long = "meta", value_name = "KEY=VALUE")]
+        metadata: Vec<String>,
+        #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+        ttl: u64,
+    },
+    /// Stream documents into the collection from a JSON Lines file, or
+    /// stdin: one `{"id", "text", "source", "mime_type", "metadata"}`
+    /// object per line.
+    Import {
+        path: Option<String>,
+        #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+        ttl: u64,
+        /// The lines are entries from `index export`: store them as they
+        /// are, reusing their embeddings where the embedder matches.
+        #[arg(long, conflicts_with = "ttl")]
+        entries: bool,
+    },
+    /// Write the collection's entries as JSON Lines, to PATH or stdout.
+    Export {
+        path: Option<String>,
+        /// Include each entry's embedding.
+        #[arg(long)]
+        embeddings: bool,
+    },
+    /// Print a stored document, reassembled from its chunks.
+    Get {
+        id: String,
+        /// Print each chunk's stored embedding as JSON instead of the text.
+        #[arg(long)]
+        embedding: bool,
+    },
+    /// Count stored entries, optionally only those matching filters such
+    /// as `source^=file:///home/me/notes` or `chunk>=2`.
+    Stats {
+        #[arg(long)]
+        filter: Vec<String>,
+    },
+    /// Tell whether a document or chunk id is indexed.
+    Exists { id: String },
+    /// Remove a document (with all its chunks) or chunk by id, the entries
+    /// matching --filter, or only those of the document that match.
+    Delete {
+        #[arg(required_unless_present = "filter")]
+        id: Option<String>,
+        #[arg(long)]
+        filter: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum CollectionsCommand {
+    /// List collections with their settings and sizes.
+    List,
+    /// Create a collection.
+    Create {
+        name: String,
+        #[arg(long, default_value = "")]
+        embedder: String,
+        #[arg(long, default_value = "")]
+        metric: String,
+        #[arg(long, default_value = "")]
+        quantization: String,
+        /// Links per node in the approximate search graph (0 = default).
+        #[arg(long, default_value_t = 0)]
+        hnsw_m: u32,
+        #[arg(long, default_value_t = 0)]
+        hnsw_ef_construction: u32,
+        #[arg(long, default_value_t = 0)]
+        hnsw_ef_search: u32,
+        /// Split documents longer than this many characters (0 = default).
+        #[arg(long, default_value_t = 0)]
+        chunk_size: u32,
+        /// Characters each chunk repeats from the previous one.
+        #[arg(long)]
+        chunk_overlap: Option<u32>,
+        /// What the query log records about queries here: log_nothing,
+        /// log_metadata or log_full (default: the server's setting).
+        #[arg(long, default_value = "")]
+        privacy: String,
+        /// What to do with a document whose text is already stored under
+        /// another id: off (default), skip, merge or reject.
+        #[arg(long, default_value = "")]
+        dedup: String,
+        /// Normalize text before it is chunked and embedded, e.g.
+        /// nfc,whitespace,boilerplate,lowercase (default: none).
+        #[arg(long, value_delimiter = ',')]
+        normalize: Vec<String>,
+        /// Where entries are kept: index (default) or rocksdb, on servers
+        /// built with it.
+        #[arg(long, default_value = "")]
+        storage: String,
+    },
+    /// Delete a collection and all its documents.
+    Drop { name: String },
+    /// Embed a collection's text again with another embedder and switch
+    /// the collection to it.
+    Reindex {
+        name: String,
+        /// Embedder to move to; defaults to the server's default.
+        #[arg(long, default_value = "")]
+        embedder: String,
+        /// Return at once; `stats` shows progress.
+        #[arg(long)]
+        background: bool,
+    },
+    /// Point an alias at a collection, moving it if it already exists.
+    Alias { alias: String, collection: String },
+    /// Remove an alias; its collection is kept.
+    Unalias { alias: String },
+    /// Save the collection as it is now, to search later with `query --at`.
+    Snapshot { name: Option<String> },
+    /// List collection snapshots, of every collection unless one is named.
+    Snapshots { name: Option<String> },
+    /// Delete a collection snapshot.
+    DeleteSnapshot { id: String },
+}
+
+#[derive(Subcommand)]
+enum BackupCommand {
+    /// Snapshot the index and add it to DEST, a directory or an rclone
+    /// remote such as `b2:bucket/index`. Only chunks DEST lacks are
+    /// written. Without --from this must run on the core's machine, as the
+    /// snapshot is written there.
+    Push {
+        dest: String,
+        /// Back up this snapshot directory instead of taking a new one.
+        #[arg(long)]
+        from: Option<String>,
+    },
+    /// List the backups in DEST.
+    List { dest: String },
+    /// Rebuild backup NAME from DEST as a snapshot directory DIR, for
+    /// `ondevice restore DIR`.
+    Pull {
+        dest: String,
+        name: String,
+        dir: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum SessionCommand {
+    /// List saved sessions, most recently used first.
+    List {
+        /// Print the raw JSON list.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print a session's turns.
+    Show { id: String },
+    /// Replace a session's title.
+    Rename { id: String, title: String },
+    /// Attach a file to a session, kept in memory for its questions and
+    /// never indexed into a collection.
+    Attach {
+        id: String,
+        #[arg(required_unless_present = "upload")]
+        path: Option<String>,
+        /// Name to attach it as; defaults to the file name.
+        #[arg(long)]
+        name: Option<String>,
+        /// Attach a file sent with `upload` instead, by its upload id.
+        #[arg(long, conflicts_with = "path")]
+        upload: Option<String>,
+    },
+    /// Remove an attachment from a session.
+    Detach { id: String, name: String },
+    /// List a session's attachments.
+    Attachments { id: String },
+}
+
+#[derive(Subcommand)]
+enum ArtifactsCommand {
+    /// List artifacts, newest first, of --session if given.
+    List {
+        #[arg(long)]
+        kind: Option<String>,
+        #[arg(long)]
+        plan: Option<String>,
+    },
+    /// Print an artifact's content.
+    Get { id: String },
+    /// Save FILE, or stdin, as an artifact of KIND, linked to --session.
+    Save {
+        kind: String,
+        file: Option<String>,
+        #[arg(long, default_value = "")]
+        title: String,
+        #[arg(long, default_value = "")]
+        plan: String,
+        /// Keep it this many days instead of its kind's retention (0 =
+        /// until deleted).
+        #[arg(long)]
+        retain_days: Option<u32>,
+    },
+    /// Delete an artifact.
+    Delete { id: String },
+}
+
+#[derive(Subcommand)]
+enum SourcesCommand {
+    /// List each connector with when it last synced, what it fetched and
+    /// why it last failed.
+    List,
+    /// Sync a connector now.
+    Sync { name: String },
+}
+
+#[derive(Subcommand)]
+enum TemplateCommand {
+    /// List stored templates and their variables.
+    List,
+    /// Print a stored template.
+    Show { name: String },
+    /// Store (or replace) a template from a file.
+    Put { name: String, file: String },
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run(Cli::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            match e.downcast_ref::<tonic::Status>() {
+                Some(status) => eprintln!("ondevice: {}", status.message()),
+                None => eprintln!("ondevice: {e}"),
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let addr = cli
+        .addr
+        .or_else(|| std::env::var("ASSISTANT_ADDR").ok())
+        .unwrap_or_else(|| "127.0.0.1:50051".to_string());
+    // Backups work on files, and only reach the core to take a snapshot.
+    if let Command::Backup { command } = cli.command {
+        return backup(command, &addr).await;
+    }
+    let mut core = Core::connect(&addr, cli.profile, cli.session, cli.workspace).await?;
+
+    match cli.command {
+        Command::Ask {
+            question,
+            clipboard,
+            min_confidence,
+            attach,
+        } => {
+            let mut payload = json!({ "question": question });
+            if !attach.is_empty() {
+                if core.session.is_none() {
+                    return Err("--attach needs a --session to attach to".into());
+                }
+                let attachments = attach
+                    .iter()
+                    .map(|path| attachment(path))
+                    .collect::<Result<Vec<_>, _>>()?;
+                payload["attachments"] = json!(attachments);
+            }
+            if let Some(min_confidence) = min_confidence {
+                payload["answer_policy"] = json!({ "min_confidence": min_confidence });
+            }
+            if clipboard {
+                // Passing --clipboard is the user's consent for this one read.
+                payload["context"] = json!(assistant_core::clipboard::read()?);
+            }
+            let reply = core.send("query", payload).await?;
+            println!("{}", reply["answer"].as_str().unwrap_or_default());
+        }
+        Command::Run {
+            prompt,
+            template,
+            vars,
+            show_reasoning,
+            stats,
+            tokens_per_second,
+            save_as,
+        } => {
+            let mut vars = parse_vars(&vars)?;
+            let reads_stdin = vars.values().any(|v| v == "@-");
+            for value in vars.values_mut() {
+                *value = resolve_var(value)?;
+            }
+            let mut context = String::new();
+            if !reads_stdin && !std::io::stdin().is_terminal() {
+                std::io::stdin().read_to_string(&mut context)?;
+            }
+            let prompt = match template {
+                Some(template) => {
+                    let body = load_template(&mut core, &template).await?;
+                    let rendered = assistant_core::template::render(&body, &vars)?;
+                    Some(match prompt {
+                        Some(extra) => format!("{rendered}\n\n{extra}"),
+                        None => rendered,
+                    })
+                }
+                None => prompt,
+            };
+            if prompt.is_none() && context.trim().is_empty() {
+                return Err("nothing to run: pass -p PROMPT and/or pipe input on stdin".into());
+            }
+            let payload = json!({
+                "prompt": prompt.unwrap_or_default(),
+                "context": context,
+                "include_reasoning": show_reasoning,
+                "tokens_per_second": tokens_per_second,
+                "save_as": save_as,
+            });
+            let summary = core.stream("query", payload).await?;
+            if let Some(id) = summary["artifact_id"].as_str() {
+                eprintln!("saved as {id}");
+            } else if let Some(kind) = save_as {
+                eprintln!("the answer was not saved as a {kind}; see the core's log");
+            }
+            if stats {
+                eprintln!(
+                    "{} · {} tokens · first token {} ms · {:.1} tokens/s · {} ms total",
+                    summary["model"].as_str().unwrap_or_default(),
+                    summary["tokens"],
+                    summary["first_token_ms"],
+                    summary["tokens_per_sec"].as_f64().unwrap_or_default(),
+                    summary["wall_ms"],
+                );
+            }
+        }
+        Command::Index {
+            command:
+                IndexCommand::AddFiles {
+                    paths,
+                    batch,
+                    metadata,
+                    ttl,
+                },
+        } => {
+            let metadata = parse_metadata(&metadata)?;
+            let (mut documents, mut chunks) = (0, 0);
+            for paths in paths.chunks(batch.max(1)) {
+                let mut batch = Vec::new();
+                for path in paths {
+                    let loaded = load(path)?;
+                    let (id, provenance) = docid::file(path.as_ref(), None)?;
+                    batch.push(Document {
+                        id,
+                        text: loaded.text,
+                        source: provenance.source,
+                        mime_type: loaded.mime_type.to_string(),
+                        metadata: metadata.clone(),
+                        ..Default::default()
+                    });
+                }
+                let reply = core
+                    .indexer
+                    .batch_index(BatchIndexRequest {
+                        documents: batch,
+                        collection: cli.collection.clone(),
+                        ttl_seconds: ttl,
+                        ..Default::default()
+                    })
+                    .await?
+                    .into_inner();
+                documents += reply.documents;
+                chunks += reply.chunks;
+                for (id, of) in reply.duplicates {
+                    println!("{id} has the same text as {of}; not stored again");
+                }
+            }
+            println!("indexed {documents} documents in {chunks} entries");
+        }
+        Command::Index {
+            command:
+                IndexCommand::Add {
+                    id,
+                    text,
+                    file,
+                    metadata,
+                    ttl,
+                    if_version,
+                },
+        } => {
+            let mut document = Document {
+                id: id.clone().unwrap_or_default(),
+                metadata: parse_metadata(&metadata)?,
+                ..Default::default()
+            };
+            match (text, file) {
+                (Some(text), _) => document.text = text,
+                (None, Some(path)) => {
+                    let loaded = load(&path)?;
+                    let (uri, provenance) = docid::file(path.as_ref(), None)?;
+                    document.id = id.unwrap_or(uri);
+                    document.text = loaded.text;
+                    document.source = provenance.source;
+                    document.mime_type = loaded.mime_type.to_string();
+                }
+                (None, None) => {
+                    std::io::stdin().read_to_string(&mut document.text)?;
+                }
+            }
+            let document = Some(document);
+            let collection = cli.collection.clone();
+            let reply = core
+                .indexer
+                .index(IndexRequest {
+                    document,
+                    collection,
+                    ttl_seconds: ttl,
+                    if_version_matches: if_version,
+                })
+                .await?
+                .into_inner();
+            if !reply.duplicate_of.is_empty() {
+                println!(
+                    "{} has the same text as {}; not stored again",
+                    reply.id, reply.duplicate_of
+                );
+            } else if reply.chunks > 1 {
+                println!(
+                    "indexed {} in {} chunks (version {})",
+                    reply.id, reply.chunks, reply.version
+                );
+            } else {
+                println!("indexed {} (version {})", reply.id, reply.version);
+            }
+        }
+        Command::Index {
+            command:
+                IndexCommand::AddArchive {
+                    path,
+                    metadata,
+                    ttl,
+                },
+        } => {
+            let metadata = parse_metadata(&metadata)?;
+            let kind =
+                archive::kind_of(path.as_ref(), &path).map_err(|e| format!("{path}: {e}"))?;
+            let Some(kind) = kind else {
+                return Err(format!("{path} is not a zip, tar or tar.gz archive").into());
+            };
+            let (base, _) = docid::file(path.as_ref(), None)?;
+            // Entries are read on their own thread and sent as they load,
+            // so the archive's contents are never held at once.
+            let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+            let first = ImportRequest {
+                collection: cli.collection.clone(),
+                ttl_seconds: ttl,
+                ..Default::default()
+            };
+            let reader = std::thread::spawn(move || {
+                read_archive(&path, kind, &base, &metadata, import_sender(first, tx))
+            });
+            let requests = async_stream::stream! {
+                while let Some(request) = rx.recv().await {
+                    yield request;
+                }
+            };
+            let reply = core.indexer.import_documents(requests).await?.into_inner();
+            let skipped = reader.join().map_err(|_| "reading the archive failed")??;
+            for error in &reply.errors {
+                eprintln!("{}: {}", error.id, error.message);
+            }
+            if reply.failed > reply.errors.len() as u64 {
+                eprintln!("... {} more", reply.failed - reply.errors.len() as u64);
+            }
+            println!(
+                "indexed {} of {} files in {} entries ({} duplicates, {} failed, {} skipped)",
+                reply.documents,
+                reply.received,
+                reply.chunks,
+                reply.duplicates,
+                reply.failed,
+                skipped
+            );
+        }
+        Command::Index {
+            command: IndexCommand::Import { path, ttl, entries },
+        } => {
+            let input: Box<dyn BufRead + Send> = match &path {
+                Some(path) => Box::new(BufReader::new(
+                    std::fs::File::open(path).map_err(|e| format!("{path}: {e}"))?,
+                )),
+                None => Box::new(BufReader::new(std::io::stdin())),
+            };
+            if entries {
+                return import_entries(&mut core, input, cli.collection.clone()).await;
+            }
+            // Lines are read on their own thread and sent as they are
+            // parsed, so the whole input is never held at once.
+            let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+            let first = ImportRequest {
+                collection: cli.collection.clone(),
+                ttl_seconds: ttl,
+                ..Default::default()
+            };
+            let reader = std::thread::spawn(move || read_import(input, import_sender(first, tx)));
+            let requests = async_stream::stream! {
+                while let Some(request) = rx.recv().await {
+                    yield request;
+                }
+            };
+            let reply = core.indexer.import_documents(requests).await?.into_inner();
+            let skipped = reader.join().map_err(|_| "reading the input failed")??;
+            for error in &reply.errors {
+                match error.id.as_str() {
+                    "" => eprintln!("{}", error.message),
+                    id => eprintln!("{id}: {}", error.message),
+                }
+            }
+            if reply.failed > reply.errors.len() as u64 {
+                eprintln!("... {} more", reply.failed - reply.errors.len() as u64);
+            }
+            println!(
+                "imported {} of {} documents in {} entries ({} duplicates, {} failed, {} lines skipped)",
+                reply.documents,
+                reply.received,
+                reply.chunks,
+                reply.duplicates,
+                reply.failed,
+                skipped
+            );
+        }
+        Command::Index {
+            command: IndexCommand::Export { path, embeddings },
+        } => {
+            let mut out: Box<dyn Write> = match &path {
+                Some(path) => Box::new(std::io::BufWriter::new(
+                    std::fs::File::create(path).map_err(|e| format!("{path}: {e}"))?,
+                )),
+                None => Box::new(std::io::stdout().lock()),
+            };
+            let mut pages = core
+                .indexer
+                .export_index(ExportIndexRequest {
+                    collection: cli.collection.clone(),
+                    include_embeddings: embeddings,
+                })
+                .await?
+                .into_inner();
+            let mut exported = 0;
+            while let Some(page) = pages.message().await? {
+                exported += page.jsonl.lines().count();
+                out.write_all(page.jsonl.as_bytes())?;
+            }
+            out.flush()?;
+            eprintln!("exported {exported} entries");
+        }
+        Command::Index {
+            command: IndexCommand::Get { id, embedding },
+        } => {
+            let doc = core
+                .indexer
+                .get_document(GetDocumentRequest {
+                    id,
+                    collection: cli.collection.clone(),
+                    include_embedding: embedding,
+                })
+                .await?
+                .into_inner();
+            if !doc.source.is_empty() {
+                eprintln!(
+                    "source: {} · {} · {} bytes in {} chunk(s)",
+                    doc.source,
+                    doc.mime_type,
+                    doc.size_bytes,
+                    doc.chunks.len()
+                );
+            }
+            if let Some(t) = doc.indexed_at {
+                let t = chrono::DateTime::from_timestamp(t.seconds, t.nanos as u32);
+                eprintln!("indexed: {}", t.map(|t| t.to_rfc3339()).unwrap_or_default());
+            }
+            eprintln!("version: {}", doc.version);
+            if !doc.merged_ids.is_empty() {
+                eprintln!("merged: {}", doc.merged_ids.join(", "));
+            }
+            if embedding {
+                for c in &doc.chunks {
+                    println!("{}", json!({ "id": c.id, "embedding": c.embedding }));
+                }
+            } else {
+                println!("{}", doc.text);
+            }
+        }
+        Command::Index {
+            command: IndexCommand::Stats { filter },
+        } => {
+            let reply = core
+                .indexer
+                .count(CountRequest {
+                    filter,
+                    collection: cli.collection.clone(),
+                })
+                .await?
+                .into_inner();
+            println!("{} entries in {} documents", reply.count, reply.documents);
+        }
+        Command::Index {
+            command: IndexCommand::Exists { id },
+        } => {
+            let reply = core
+                .indexer
+                .exists(ExistsRequest {
+                    id,
+                    collection: cli.collection.clone(),
+                })
+                .await?
+                .into_inner();
+            println!("{}", if reply.exists { "yes" } else { "no" });
+        }
+        Command::Index {
+            command: IndexCommand::Delete { id, filter },
+        } => {
+            let reply = core
+                .indexer
+                .delete(DeleteRequest {
+                    id: id.unwrap_or_default(),
+                    filter,
+                    collection: cli.collection.clone(),
+                })
+                .await?
+                .into_inner();
+            println!("deleted {} entries", reply.deleted);
+        }
+        Command::Query {
+            query,
+            k,
+            explain,
+            documents,
+            context,
+            limit,
+            offset,
+            cursor,
+            filter,
+            newest,
+            exact,
+            mode,
+            embedder,
+            metric,
+            rerank,
+            rerank_top_n,
+            mmr,
+            at,
+            adaptive,
+            min_k,
+            margin,
+        } => {
+            let request = QueryRequest {
+                query: query.unwrap_or_default(),
+                k,
+                group_by_document: documents,
+                context_window: context,
+                collection: cli.collection.clone(),
+                after_write: String::new(),
+                limit,
+                offset,
+                cursor: cursor.unwrap_or_default(),
+                filter,
+                sort: if newest { "indexed_at" } else { "score" }.to_string(),
+                exact,
+                mode,
+                embedder,
+                metric,
+                rerank,
+                rerank_top_n,
+                mmr_lambda: mmr,
+                adaptive,
+                adaptive_min_k: min_k,
+                adaptive_margin: margin,
+            };
+            if explain {
+                let reply = core.indexer.explain_query(request).await?.into_inner();
+                let nonzero = reply.query_vector.iter().filter(|x| **x != 0.0).count();
+                println!("terms: {}", reply.terms.join(", "));
+                println!(
+                    "query vector: {nonzero} of {} dims set · {} candidates",
+                    reply.query_vector.len(),
+                    reply.candidates
+                );
+                for (rank, hit) in reply.hits.iter().enumerate() {
+                    println!(
+                        "{:>2}. {}  score {:.3} (vector {:.3}, keyword {:.3})  matched: {}",
+                        rank + 1,
+                        hit.id,
+                        hit.score,
+                        hit.vector_score,
+                        hit.keyword_score,
+                        hit.matched_terms.join(", ")
+                    );
+                }
+            } else {
+                let reply = match at {
+                    Some(snapshot_id) => {
+                        let request = QueryAtRequest {
+                            snapshot_id,
+                            query: Some(request),
+                        };
+                        core.indexer.query_at(request).await?
+                    }
+                    None => core.indexer.query(request).await?,
+                }
+                .into_inner();
+                if adaptive {
+                    let verdict = if reply.expanded { "ambiguous" } else { "clear" };
+                    eprintln!(
+                        "{verdict} (margin {:.3}): {} hits",
+                        reply.margin,
+                        reply.hits.len()
+                    );
+                }
+                for hit in reply.hits {
+                    if context == 0 {
+                        println!("{:.3}\t{}\t{}", hit.score, hit.id, preview(&hit.text));
+                        continue;
+                    }
+                    println!("── {:.3} {}", hit.score, hit.id);
+                    for c in &hit.before {
+                        println!("{}", c.text);
+                    }
+                    println!("{}", hit.text);
+                    for c in &hit.after {
+                        println!("{}", c.text);
+                    }
+                }
+                if !reply.next_cursor.is_empty() {
+                    eprintln!(
+                        "{} hits in all; next page: --limit {limit} --cursor {}",
+                        reply.total, reply.next_cursor
+                    );
+                }
+            }
+        }
+        Command::List {
+            page_size,
+            page_token,
+            all,
+        } => {
+            let mut page_token = page_token.unwrap_or_default();
+            loop {
+                let reply = core
+                    .indexer
+                    .list_documents(ListDocumentsRequest {
+                        collection: cli.collection.clone(),
+                        page_size,
+                        page_token,
+                    })
+                    .await?
+                    .into_inner();
+                for d in &reply.documents {
+                    println!("{}\t{} bytes\t{}", d.id, d.size_bytes, preview(&d.preview));
+                }
+                page_token = reply.next_page_token;
+                if page_token.is_empty() {
+                    break;
+                }
+                if !all {
+                    eprintln!("next page: --page-token {page_token}");
+                    break;
+                }
+            }
+        }
+        Command::Collections { command } => match command {
+            CollectionsCommand::List => {
+                let reply = core
+                    .indexer
+                    .list_collections(ListCollectionsRequest {})
+                    .await?
+                    .into_inner();
+                for c in reply.collections {
+                    let aliases = if c.aliases.is_empty() {
+                        String::new()
+                    } else {
+                        format!("\t(alias {})", c.aliases.join(", "))
+                    };
+                    let privacy = if c.privacy.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" · {}", c.privacy)
+                    };
+                    let dedup = match c.dedup.as_str() {
+                        "" | "off" => String::new(),
+                        dedup => format!(" · dedup {dedup}"),
+                    };
+                    let normalize = match c.normalize.len() {
+                        0 => String::new(),
+                        _ => format!(" · normalize {}", c.normalize.join(",")),
+                    };
+                    let storage = match c.storage.as_str() {
+                        "" | "index" => String::new(),
+                        storage => format!(" · {storage}"),
+                    };
+                    println!(
+                        "{}\t{} docs\t{} ({} dims) · {} · {} · hnsw m={} ef={}/{} · chunks {}/{}{privacy}{dedup}{normalize}{storage}{aliases}",
+                        c.name,
+                        c.documents,
+                        c.embedder,
+                        c.dimensions,
+                        c.metric,
+                        c.quantization,
+                        c.hnsw_m,
+                        c.hnsw_ef_construction,
+                        c.hnsw_ef_search,
+                        c.chunk_size,
+                        c.chunk_overlap
+                    );
+                }
+            }
+            CollectionsCommand::Create {
+                name,
+                embedder,
+                metric,
+                quantization,
+                hnsw_m,
+                hnsw_ef_construction,
+                hnsw_ef_search,
+                chunk_size,
+                chunk_overlap,
+                privacy,
+                dedup,
+                normalize,
+                storage,
+            } => {
+                let request = CreateCollectionRequest {
+                    name,
+                    embedder,
+                    metric,
+                    quantization,
+                    hnsw_m,
+                    hnsw_ef_construction,
+                    hnsw_ef_search,
+                    chunk_size,
+                    chunk_overlap,
+                    privacy,
+                    dedup,
+                    normalize,
+                    storage,
+                };
+                let info = core.indexer.create_collection(request).await?.into_inner();
+                println!("created {}", info.name);
+            }
+            CollectionsCommand::Drop { name } => {
+                core.indexer
+                    .drop_collection(DropCollectionRequest { name: name.clone() })
+                    .await?;
+                println!("dropped {name}");
+            }
+            CollectionsCommand::Reindex {
+                name,
+                embedder,
+                background,
+            } => {
+                let request = ReindexRequest {
+                    collection: name,
+                    embedder,
+                    background,
+                };
+                let reply = core.indexer.reindex(request).await?.into_inner();
+                if reply.done {
+                    println!(
+                        "reindexed {} with {}: {} entries (write token {})",
+                        reply.collection, reply.embedder, reply.entries, reply.write_token
+                    );
+                } else {
+                    println!(
+                        "reindexing {} with {} in the background: {} entries",
+                        reply.collection, reply.embedder, reply.entries
+                    );
+                }
+            }
+            CollectionsCommand::Alias { alias, collection } => {
+                let request = SetAliasRequest {
+                    alias: alias.clone(),
+                    collection: collection.clone(),
+                };
+                let reply = core.indexer.set_alias(request).await?.into_inner();
+                if reply.previous.is_empty() {
+                    println!("{alias} -> {collection}");
+                } else {
+                    println!("{alias} -> {collection} (was {})", reply.previous);
+                }
+            }
+            CollectionsCommand::Unalias { alias } => {
+                core.indexer
+                    .delete_alias(DeleteAliasRequest {
+                        alias: alias.clone(),
+                    })
+                    .await?;
+                println!("removed alias {alias}");
+            }
+            CollectionsCommand::Snapshot { name } => {
+                let request = CreateSnapshotRequest {
+                    collection: name.unwrap_or_else(|| cli.collection.clone()),
+                };
+                let s = core.indexer.create_snapshot(request).await?.into_inner();
+                println!("{}	{} entries", s.id, s.entries);
+            }
+            CollectionsCommand::Snapshots { name } => {
+                let request = ListSnapshotsRequest {
+                    collection: name.unwrap_or_default(),
+                };
+                let reply = core.indexer.list_snapshots(request).await?.into_inner();
+                for s in reply.snapshots {
+                    let created = s
+                        .created_at
+                        .and_then(|t| chrono::DateTime::from_timestamp(t.seconds, t.nanos as u32))
+                        .map(|t| t.to_rfc3339())
+                        .unwrap_or_default();
+                    println!("{}	{created}	{} entries	{}", s.id, s.entries, s.embedder);
+                }
+            }
+            CollectionsCommand::DeleteSnapshot { id } => {
+                core.indexer
+                    .delete_snapshot(DeleteSnapshotRequest { id: id.clone() })
+                    .await?;
+                println!("deleted snapshot {id}");
+            }
+        },
+        Command::Snapshot { path } => {
+            let path = std::path::absolute(&path)?;
+            let reply = core
+                .indexer
+                .snapshot(SnapshotRequest {
+                    path: path.display().to_string(),
+                })
+                .await?
+                .into_inner();
+            println!(
+                "saved {} collections ({} entries) to {}",
+                reply.collections,
+                reply.entries,
+                path.display()
+            );
+        }
+        Command::Restore { path } => {
+            let path = std::path::absolute(&path)?;
+            let reply = core
+                .indexer
+                .restore(RestoreRequest {
+                    path: path.display().to_string(),
+                })
+                .await?
+                .into_inner();
+            println!(
+                "restored {} collections ({} entries) from {}",
+                reply.collections,
+                reply.entries,
+                path.display()
+            );
+        }
+        Command::Backup { .. } => unreachable!("handled before connecting"),
+        Command::Session {
+            command: SessionCommand::List { json },
+        } => {
+            let reply = core.send("sessions", Value::Null).await?;
+            let sessions = reply.as_array().cloned().unwrap_or_default();
+            if json {
+                println!("{}", serde_json::to_string_pretty(&sessions)?);
+                return Ok(());
+            }
+            for session in &sessions {
+                println!(
+                    "{}\t{}\t{} turns\t{}",
+                    session["id"].as_str().unwrap_or_default(),
+                    session["title"].as_str().unwrap_or_default(),
+                    session["turns"],
+                    session["updated_at"].as_str().unwrap_or_default(),
+                );
+            }
+        }
+        Command::Session {
+            command: SessionCommand::Rename { id, title },
+        } => {
+            let reply = core
+                .send(
+                    "session_rename",
+                    json!({ "session_id": id, "title": title }),
+                )
+                .await?;
+            println!("{id}: {}", reply["title"].as_str().unwrap_or_default());
+        }
+        Command::Session {
+            command:
+                SessionCommand::Attach {
+                    id,
+                    path,
+                    name,
+                    upload,
+                },
+        } => {
+            let mut payload = match path {
+                Some(path) => attachment(&path)?,
+                None => json!({ "upload_id": upload }),
+            };
+            if let Some(name) = name {
+                payload["name"] = json!(name);
+            }
+            payload["session_id"] = json!(id);
+            let reply = core.send("attach", payload).await?;
+            println!(
+                "attached {} to {id} in {} chunks",
+                reply["name"].as_str().unwrap_or_default(),
+                reply["chunks"]
+            );
+        }
+        Command::Session {
+            command: SessionCommand::Detach { id, name },
+        } => {
+            let reply = core
+                .send("detach", json!({ "session_id": id, "name": name }))
+                .await?;
+            if reply["detached"].as_bool() == Some(true) {
+                println!("detached {name} from {id}");
+            } else {
+                println!("{id} has no attachment {name}");
+            }
+        }
+        Command::Session {
+            command: SessionCommand::Attachments { id },
+        } => {
+            let reply = core
+                .send("attachments", json!({ "session_id": id }))
+                .await?;
+            for item in reply.as_array().into_iter().flatten() {
+                println!(
+                    "{}\t{} bytes",
+                    item["name"].as_str().unwrap_or_default(),
+                    item["bytes"]
+                );
+            }
+        }
+        Command::Session {
+            command: SessionCommand::Show { id },
+        } => {
+            let session = core
+                .send("session_get", json!({ "session_id": id }))
+                .await?;
+            if let Some(title) = session["title"].as_str().filter(|t| !t.is_empty()) {
+                println!("# {title}\n");
+            }
+            for turn in session["turns"].as_array().into_iter().flatten() {
+                let partial = if turn["delivered"].as_bool() == Some(false) {
+                    " (client disconnected)"
+                } else {
+                    ""
+                };
+                println!(
+                    "[{}] {} · {} tokens · {} ms{partial}",
+                    turn["started_at"].as_str().unwrap_or_default(),
+                    turn["model"].as_str().unwrap_or_default(),
+                    turn["tokens"],
+                    turn["duration_ms"],
+                );
+                println!("> {}", turn["prompt"].as_str().unwrap_or_default());
+                println!("{}\n", turn["answer"].as_str().unwrap_or_default());
+            }
+        }
+        Command::Artifacts {
+            command: ArtifactsCommand::List { kind, plan },
+        } => {
+            let request = ListArtifactsRequest {
+                kind: kind.unwrap_or_default(),
+                session_id: core.session.clone().unwrap_or_default(),
+                plan_id: plan.unwrap_or_default(),
+            };
+            let reply = core.artifacts.list_artifacts(request).await?.into_inner();
+            for a in reply.artifacts {
+                let created = a
+                    .created_at
+                    .and_then(|t| chrono::DateTime::from_timestamp(t.seconds, t.nanos as u32))
+                    .map(|t| t.to_rfc3339())
+                    .unwrap_or_default();
+                let expires = a
+                    .expires_at
+                    .and_then(|t| chrono::DateTime::from_timestamp(t.seconds, t.nanos as u32))
+                    .map(|t| format!(" · expires {}", t.format("%Y-%m-%d")))
+                    .unwrap_or_default();
+                let link = match (a.session_id.is_empty(), a.plan_id.is_empty()) {
+                    (false, _) => format!(" · session {}", a.session_id),
+                    (true, false) => format!(" · plan {}", a.plan_id),
+                    (true, true) => String::new(),
+                };
+                println!(
+                    "{}\t{}\t{} bytes · {created}{link}{expires}",
+                    a.id, a.title, a.size_bytes
+                );
+            }
+        }
+        Command::Artifacts {
+            command: ArtifactsCommand::Get { id },
+        } => {
+            let artifact = core
+                .artifacts
+                .get_artifact(GetArtifactRequest { id })
+                .await?
+                .into_inner();
+            if !artifact.title.is_empty() {
+                eprintln!("title: {}", artifact.title);
+            }
+            for (label, link) in [
+                ("session", &artifact.session_id),
+                ("plan", &artifact.plan_id),
+            ] {
+                if !link.is_empty() {
+                    eprintln!("{label}: {link}");
+                }
+            }
+            print!("{}", artifact.content);
+            if !artifact.content.ends_with('\n') {
+                println!();
+            }
+        }
+        Command::Artifacts {
+            command:
+                ArtifactsCommand::Save {
+                    kind,
+                    file,
+                    title,
+                    plan,
+                    retain_days,
+                },
+        } => {
+            let content = match &file {
+                Some(path) => std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?,
+                None => {
+                    let mut content = String::new();
+                    std::io::stdin().read_to_string(&mut content)?;
+                    content
+                }
+            };
+            let request = SaveArtifactRequest {
+                kind,
+                title,
+                content,
+                session_id: core.session.clone().unwrap_or_default(),
+                plan_id: plan,
+                retain_days,
+                ..Default::default()
+            };
+            let artifact = core.artifacts.save_artifact(request).await?.into_inner();
+            println!("saved {}", artifact.id);
+        }
+        Command::Artifacts {
+            command: ArtifactsCommand::Delete { id },
+        } => {
+            core.artifacts
+                .delete_artifact(DeleteArtifactRequest { id: id.clone() })
+                .await?;
+            println!("deleted {id}");
+        }
+        Command::Patch { file, apply } => {
+            let patch = match &file {
+                Some(path) => std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?,
+                None => {
+                    let mut patch = String::new();
+                    std::io::stdin().read_to_string(&mut patch)?;
+                    patch
+                }
+            };
+            let session = core.session.clone().unwrap_or_default();
+            let call =
+                |args: Value| json!({ "tool": "apply_patch", "args": args, "session_id": session });
+            let preview = core.send("action", call(json!({ "patch": patch }))).await?;
+            for file in preview["files"].as_array().into_iter().flatten() {
+                println!(
+                    "{}\t{}\t+{} -{}",
+                    file["change"].as_str().unwrap_or_default(),
+                    file["path"].as_str().unwrap_or_default(),
+                    file["added"],
+                    file["removed"]
+                );
+            }
+            if let Some(id) = preview["artifact_id"].as_str() {
+                println!("preview saved as {id}");
+            }
+            if apply {
+                let args = json!({
+                    "patch": patch,
+                    "preview_id": preview["preview_id"],
+                    "consent": true,
+                });
+                core.send("action", call(args)).await?;
+                println!("applied");
+            }
+        }
+        Command::Tools { json } => {
+            let reply = core.send("tools", Value::Null).await?;
+            let tools = reply.as_array().cloned().unwrap_or_default();
+            if json {
+                println!("{}", serde_json::to_string_pretty(&tools)?);
+                return Ok(());
+            }
+            for tool in &tools {
+                let mut flags = Vec::new();
+                if tool["destructive"].as_bool() == Some(true) {
+                    flags.push("destructive");
+                }
+                if tool["requires_confirmation"].as_bool() == Some(true) {
+                    flags.push("needs confirmation");
+                }
+                let flags = if flags.is_empty() {
+                    String::new()
+                } else {
+                    format!(" ({})", flags.join(", "))
+                };
+                println!(
+                    "{}{flags}\t{}",
+                    tool["name"].as_str().unwrap_or_default(),
+                    tool["description"].as_str().unwrap_or_default()
+                );
+            }
+        }
+        Command::Upload { path, id, index } => {
+            let file = std::fs::File::open(&path).map_err(|e| format!("{path}: {e}"))?;
+            let size = file.metadata()?.len();
+            let upload_id = match id {
+                Some(id) => id,
+                None => upload_id(&path, &file.metadata()?)?,
+            };
+            let request = UploadStatusRequest {
+                upload_id: upload_id.clone(),
+            };
+            let (offset, complete) = match core.indexer.upload_status(request).await {
+                Ok(status) => {
+                    let status = status.into_inner();
+                    (status.received, status.complete)
+                }
+                Err(e) if e.code() == tonic::Code::NotFound => (0, false),
+                Err(e) => return Err(e.into()),
+            };
+            if offset > size {
+                return Err(format!(
+                    "upload {upload_id} has {offset} bytes but {path} only {size}; pass another --id"
+                )
+                .into());
+            }
+            if complete {
+                eprintln!("{upload_id} is already uploaded");
+            } else if offset > 0 {
+                eprintln!("resuming {upload_id} at {offset} of {size} bytes");
+            }
+            let mut first = FileChunk {
+                upload_id: upload_id.clone(),
+                name: std::path::Path::new(&path)
+                    .file_name()
+                    .map_or_else(|| path.clone(), |n| n.to_string_lossy().into_owned()),
+                total_size: size,
+                index,
+                collection: cli.collection.clone(),
+                ..Default::default()
+            };
+            if index {
+                let (id, provenance) = docid::file(path.as_ref(), None)?;
+                first.document_id = id;
+                first.source = provenance.source;
+            }
+            let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+            let reader = std::thread::spawn(move || read_upload(file, offset, first, tx));
+            let chunks = async_stream::stream! {
+                while let Some(chunk) = rx.recv().await {
+                    yield chunk;
+                }
+            };
+            let reply = core.indexer.upload_file(chunks).await;
+            reader.join().map_err(|_| "reading the file failed")??;
+            let reply = reply?.into_inner();
+            println!(
+                "uploaded {path} as {} ({} bytes)",
+                reply.upload_id, reply.received
+            );
+            for entry in &reply.skipped_entries {
+                eprintln!("{path}!/{}: skipped, {}", entry.id, entry.message);
+            }
+            if reply.skipped > reply.skipped_entries.len() as u64 {
+                eprintln!(
+                    "... {} more",
+                    reply.skipped - reply.skipped_entries.len() as u64
+                );
+            }
+            if reply.documents > 0 || reply.skipped > 0 {
+                println!(
+                    "indexed {} files of {path} under {}!/ in {} chunks, {} skipped (write token {})",
+                    reply.documents,
+                    reply.document_id,
+                    reply.chunks,
+                    reply.skipped,
+                    reply.write_token
+                );
+            } else if !reply.document_id.is_empty() {
+                println!(
+                    "indexed {} in {} chunks (write token {})",
+                    reply.document_id, reply.chunks, reply.write_token
+                );
+            }
+        }
+        Command::Embed {
+            path,
+            embedder,
+            normalize,
+            pooling,
+            stats,
+        } => {
+            let input: Box<dyn BufRead + Send> = match &path {
+                Some(path) => Box::new(BufReader::new(
+                    std::fs::File::open(path).map_err(|e| format!("{path}: {e}"))?,
+                )),
+                None => Box::new(BufReader::new(std::io::stdin())),
+            };
+            let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+            let reader = std::thread::spawn(move || -> std::io::Result<()> {
+                let mut request = EmbedRequest {
+                    texts: Vec::with_capacity(EMBED_MESSAGE),
+                    embedder: embedder.unwrap_or_default(),
+                    normalize,
+                    pooling: pooling.unwrap_or_default(),
+                };
+                let mut sent = false;
+                for line in input.lines() {
+                    request.texts.push(line?);
+                    if request.texts.len() == EMBED_MESSAGE {
+                        if tx.blocking_send(std::mem::take(&mut request)).is_err() {
+                            return Ok(());
+                        }
+                        sent = true;
+                    }
+                }
+                // An empty input still names the embedder, so it is checked.
+                if !request.texts.is_empty() || !sent {
+                    let _ = tx.blocking_send(request);
+                }
+                Ok(())
+            });
+            let requests = async_stream::stream! {
+                while let Some(request) = rx.recv().await {
+                    yield request;
+                }
+            };
+            let started = std::time::Instant::now();
+            let mut replies = core.indexer.embed(requests).await?.into_inner();
+            let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+            let (mut texts, mut tokens, mut truncated) = (0, 0, 0);
+            while let Some(reply) = replies.message().await? {
+                for embedding in &reply.embeddings {
+                    serde_json::to_writer(&mut out, &embedding.values)?;
+                    writeln!(out)?;
+                }
+                texts += reply.embeddings.len();
+                tokens = reply.total_tokens;
+                truncated += reply.truncated;
+            }
+            out.flush()?;
+            reader.join().map_err(|_| "reading the input failed")??;
+            if stats {
+                let secs = started.elapsed().as_secs_f64();
+                eprintln!(
+                    "{texts} texts · {tokens} tokens ({truncated} texts truncated) · {:.0} ms · {:.0} tokens/sec",
+                    secs * 1000.0,
+                    if secs > 0.0 { tokens as f64 / secs } else { 0.0 }
+                );
+            }
+        }
+        Command::Embedders => {
+            let reply = core
+                .indexer
+                .list_embedding_models(ListEmbeddingModelsRequest {})
+                .await?
+                .into_inner();
+            for e in &reply.embedders {
+                let kind = if e.model { "model" } else { "hashed" };
+                let default = if e.default { " · default" } else { "" };
+                let poolings = match e.poolings.len() {
+                    0 => String::new(),
+                    _ => format!(" · pooling {}", e.poolings.join("/")),
+                };
+                let collections = match e.collections.len() {
+                    0 => String::new(),
+                    _ => format!("\t{}", e.collections.join(", ")),
+                };
+                println!(
+                    "{}\t{} dims · {kind}{poolings}{default}{collections}",
+                    e.name, e.dimensions
+                );
+            }
+            println!(
+                "hash-<dim>\t{} to {} dims, made on demand",
+                reply.hash_min_dimensions, reply.hash_max_dimensions
+            );
+        }
+        Command::Stats => {
+            let request = StatsRequest {
+                collection: cli.collection.clone(),
+            };
+            let reply = core.indexer.stats(request).await?.into_inner();
+            for c in &reply.collections {
+                let saved = c
+                    .saved_at
+                    .as_ref()
+                    .and_then(|t| chrono::DateTime::from_timestamp(t.seconds, t.nanos as u32))
+                    .map_or_else(
+                        || "never saved".into(),
+                        |t| format!("saved {}", t.to_rfc3339()),
+                    );
+                let search = if c.search_graph {
+                    "search graph"
+                } else {
+                    "full scans"
+                };
+                let codes = match c.code_bytes {
+                    0 => String::new(),
+                    bytes => format!(" · {bytes} bytes of codes"),
+                };
+                let reindex = match c.reindex_embedder.as_str() {
+                    "" => String::new(),
+                    embedder => format!(" · reindexing with {embedder}: {} embedded", c.reindexed),
+                };
+                println!(
+                    "{}\t{} entries in {} documents · {} bytes of text · {} dims · {} bytes on disk · {saved} · {} pending writes · {search}{codes}{reindex}",
+                    c.name,
+                    c.entries,
+                    c.documents,
+                    c.text_bytes,
+                    c.dimensions,
+                    c.disk_bytes,
+                    c.pending_writes,
+                );
+            }
+            if reply.collections.len() > 1 {
+                println!(
+                    "total\t{} entries in {} documents · {} bytes of text · {} bytes on disk",
+                    reply.entries, reply.documents, reply.text_bytes, reply.disk_bytes
+                );
+            }
+        }
+        Command::Workspaces => {
+            let reply = core.send("workspaces", Value::Null).await?;
+            for w in reply.as_array().into_iter().flatten() {
+                let list = |key: &str| -> String {
+                    let items: Vec<&str> = w[key]
+                        .as_array()
+                        .into_iter()
+                        .flatten()
+                        .filter_map(Value::as_str)
+                        .collect();
+                    items.join(", ")
+                };
+                println!("{}", w["name"].as_str().unwrap_or_default());
+                println!("  collections: {}", list("collections"));
+                if let Some(profile) = w["profile"].as_str() {
+                    println!("  profile: {profile}");
+                }
+                if w["tools"].is_array() {
+                    println!("  tools: {}", list("tools"));
+                }
+                if !list("watch").is_empty() {
+                    println!("  watching: {}", list("watch"));
+                }
+            }
+        }
+        Command::Sources { command } => match command {
+            SourcesCommand::List => {
+                let reply = core.send("sync_status", Value::Null).await?;
+                for s in reply.as_array().into_iter().flatten() {
+                    let every = match s["interval_secs"].as_u64() {
+                        Some(secs) => format!("every {secs}s"),
+                        None => "on request".to_string(),
+                    };
+                    let error = match s["last_error"].as_str() {
+                        Some(e) => format!("\tfailed: {e}"),
+                        None => String::new(),
+                    };
+                    println!(
+                        "{}\t{every} into {}\tlast sync {}: fetched {}, updated {}\t{} syncs, {} failed, {} updated in all{error}",
+                        s["name"].as_str().unwrap_or_default(),
+                        s["collection"].as_str().unwrap_or_default(),
+                        s["last_sync"].as_str().unwrap_or("never"),
+                        s["fetched"],
+                        s["updated"],
+                        s["syncs"],
+                        s["failures"],
+                        s["total_updated"],
+                    );
+                }
+            }
+            SourcesCommand::Sync { name } => {
+                let reply = core.send("sync", json!({ "connector": name })).await?;
+                println!(
+                    "synced {name}: fetched {}, updated {}",
+                    reply["fetched"], reply["updated"]
+                );
+            }
+        },
+        Command::Template { command } => match command {
+            TemplateCommand::List => {
+                let reply = core.send("templates", Value::Null).await?;
+                for t in reply.as_array().into_iter().flatten() {
+                    let vars: Vec<&str> = t["vars"]
+                        .as_array()
+                        .into_iter()
+                        .flatten()
+                        .filter_map(Value::as_str)
+                        .collect();
+                    println!(
+                        "{}\t{}",
+                        t["name"].as_str().unwrap_or_default(),
+                        vars.join(", ")
+                    );
+                }
+            }
+            TemplateCommand::Show { name } => {
+                print!("{}", fetch_template(&mut core, &name).await?);
+            }
+            TemplateCommand::Put { name, file } => {
+                let body = std::fs::read_to_string(&file)?;
+                let payload = json!({ "name": name, "body": body });
+                core.send("template_put", payload).await?;
+            }
+        },
+    }
+    Ok(())
+}
+
+fn parse_vars(raw: &[String]) -> Result<BTreeMap<String, String>, Box<dyn std::error::Error>> {
+    let mut vars = BTreeMap::new();
+    for item in raw {
+        let (name, value) = item
+            .split_once('=')
+            .ok_or_else(|| format!("--var {item}: expected NAME=VALUE"))?;
+        vars.insert(name.to_string(), value.to_string());
+    }
+    if vars.values().filter(|v| *v == "@-").count() > 1 {
+        return Err("only one --var can read stdin (@-)".into());
+    }
+    Ok(vars)
+}
+
+/// Parses `--meta KEY=VALUE` items into document metadata.
+fn parse_metadata(
+    raw: &[String],
+) -> Result<std::collections::HashMap<String, String>, Box<dyn std::error::Error>> {
+    let mut metadata = std::collections::HashMap::new();
+    for item in raw {
+        let (key, value) = item
+            .split_once('=')
+            .ok_or_else(|| format!("--meta {item}: expected KEY=VALUE"))?;
+        metadata.insert(key.to_string(), value.to_string());
+    }
+    Ok(metadata)
+}
+
+/// The text of the file at `path`, by the loader for its media type.
+fn load(path: &str) -> Result<Loaded, Box<dyn std::error::Error>> {
+    match loader::load_file(path.as_ref()) {
+        Ok(loaded) => Ok(loaded),
+        Err(e) if loader::unsupported(&e).is_some() => Err(e.into()),
+        Err(e) => Err(format!("{path}: {e}").into()),
+    }
+}
+
+/// `{"name", "text"}` of a file to attach, named by its file name.
+fn attachment(path: &str) -> Result<Value, Box<dyn std::error::Error>> {
+    let text = load(path)?.text;
+    let name = std::path::Path::new(path)
+        .file_name()
+        .map_or_else(|| path.to_string(), |n| n.to_string_lossy().into_owned());
+    Ok(json!({ "name": name, "text": text }))
+}
+
+/// Bytes of file per `FileChunk` message.
+const UPLOAD_CHUNK: usize = 1024 * 1024;
+
+/// An upload id for `path` as it is now: the same file uploaded again
+/// resumes, and a changed one starts over.
+fn upload_id(path: &str, meta: &std::fs::Metadata) -> std::io::Result<String> {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    std::fs::canonicalize(path)?.hash(&mut hasher);
+    meta.len().hash(&mut hasher);
+    meta.modified()?.hash(&mut hasher);
+    Ok(format!("file-{:016x}", hasher.finish()))
+}
+
+/// Sends `file` from `offset` on in [`UPLOAD_CHUNK`] pieces, the first
+/// being `first` with the data added.
+fn read_upload(
+    mut file: std::fs::File,
+    mut offset: u64,
+    first: FileChunk,
+    tx: tokio::sync::mpsc::Sender<FileChunk>,
+) -> std::io::Result<()> {
+    use std::io::{Seek, SeekFrom};
+    file.seek(SeekFrom::Start(offset))?;
+    let mut chunk = first;
+    loop {
+        let mut data = Vec::with_capacity(UPLOAD_CHUNK);
+        (&mut file)
+            .take(UPLOAD_CHUNK as u64)
+            .read_to_end(&mut data)?;
+        chunk.offset = offset;
+        chunk.last = data.len() < UPLOAD_CHUNK;
+        offset += data.len() as u64;
+        chunk.data = data;
+        let last = chunk.last;
+        if tx.blocking_send(std::mem::take(&mut chunk)).is_err() || last {
+            return Ok(());
+        }
+    }
+}
+
+/// Lines per `EmbedRequest` message.
+const EMBED_MESSAGE: usize = 64;
+
+/// Bytes of lines per `ImportIndexRequest` message.
+const IMPORT_INDEX_MESSAGE: usize = 1024 * 1024;
+
+/// Streams export lines from `input` into `collection` with ImportIndex.
+async fn import_entries(
+    core: &mut Core,
+    input: Box<dyn BufRead + Send>,
+    collection: String,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+    let reader = std::thread::spawn(move || -> std::io::Result<()> {
+        let mut first = Some(collection);
+        let mut jsonl = String::new();
+        let mut send = |jsonl: String| {
+            let collection = first.take().unwrap_or_default();
+            tx.blocking_send(ImportIndexRequest { collection, jsonl })
+                .is_ok()
+        };
+        for line in input.lines() {
+            jsonl.push_str(&line?);
+            jsonl.push('\n');
+            if jsonl.len() >= IMPORT_INDEX_MESSAGE && !send(std::mem::take(&mut jsonl)) {
+                return Ok(());
+            }
+        }
+        if !jsonl.is_empty() {
+            send(jsonl);
+        }
+        Ok(())
+    });
+    let requests = async_stream::stream! {
+        while let Some(request) = rx.recv().await {
+            yield request;
+        }
+    };
+    let reply = core.indexer.import_index(requests).await?.into_inner();
+    reader.join().map_err(|_| "reading the input failed")??;
+    for error in &reply.errors {
+        eprintln!("{}: {}", error.id, error.message);
+    }
+    if reply.failed > reply.errors.len() as u64 {
+        eprintln!("... {} more", reply.failed - reply.errors.len() as u64);
+    }
+    println!(
+        "imported {} of {} entries ({} embedded again, {} failed)",
+        reply.entries, reply.lines, reply.embedded, reply.failed
+    );
+    Ok(())
+}
+
+/// Documents per `ImportRequest` message.
+const IMPORT_MESSAGE: usize = 200;
+
+/// Sends documents as `ImportRequest` messages, the first with the
+/// collection and TTL of `first`. Returns false once the channel is
+/// closed, which means the core ended the import early; its error is
+/// reported by the caller.
+fn import_sender(
+    first: ImportRequest,
+    tx: tokio::sync::mpsc::Sender<ImportRequest>,
+) -> impl FnMut(Vec<Document>) -> bool {
+    let mut first = Some(first);
+    move |documents| {
+        let mut request = first.take().unwrap_or_default();
+        request.documents = documents;
+        tx.blocking_send(request).is_ok()
+    }
+}
+
+/// Parses JSON Lines documents from `input` and sends them with `send`.
+/// Lines that are not documents are reported and skipped; returns how
+/// many were.
+fn read_import(
+    input: Box<dyn BufRead + Send>,
+    mut send: impl FnMut(Vec<Document>) -> bool,
+) -> std::io::Result<u64> {
+    let mut skipped = 0;
+    let mut documents = Vec::with_capacity(IMPORT_MESSAGE);
+    for (n, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<Value>(&line) {
+            Ok(value) if value.is_object() => documents.push(import_document(&value)),
+            Ok(_) => {
+                eprintln!("line {}: not a JSON object; skipped", n + 1);
+                skipped += 1;
+            }
+            Err(e) => {
+                eprintln!("line {}: {e}; skipped", n + 1);
+                skipped += 1;
+            }
+        }
+        if documents.len() == IMPORT_MESSAGE && !send(std::mem::take(&mut documents)) {
+            return Ok(skipped);
+        }
+    }
+    if !documents.is_empty() {
+        send(documents);
+    }
+    Ok(skipped)
+}
+
+/// Reads the archive at `path` as documents `<base>!/<path in archive>`,
+/// sending them with `send` and printing the entries skipped. Returns how
+/// many were skipped.
+fn read_archive(
+    path: &str,
+    kind: archive::Kind,
+    base: &str,
+    metadata: &HashMap<String, String>,
+    mut send: impl FnMut(Vec<Document>) -> bool,
+) -> std::io::Result<u64> {
+    let (mut skipped, mut stopped) = (0, false);
+    let mut documents = Vec::with_capacity(IMPORT_MESSAGE);
+    let read = archive::read(path.as_ref(), path, kind, |entry| {
+        match entry {
+            Ok(entry) => {
+                let id = docid::archive_entry(base, &entry.path);
+                documents.push(Document {
+                    source: id.clone(),
+                    id,
+                    text: entry.loaded.text,
+                    mime_type: entry.loaded.mime_type.to_string(),
+                    metadata: metadata.clone(),
+                    ..Default::default()
+                });
+            }
+            Err(entry) => {
+                eprintln!("{path}!/{}: skipped, {}", entry.path, entry.reason);
+                skipped += 1;
+            }
+        }
+        if documents.len() == IMPORT_MESSAGE && !send(std::mem::take(&mut documents)) {
+            stopped = true;
+            return Err(std::io::Error::other("the import ended early"));
+        }
+        Ok(())
+    });
+    if stopped {
+        return Ok(skipped);
+    }
+    read.map_err(|e| std::io::Error::new(e.kind(), format!("{path}: {e}")))?;
+    if !documents.is_empty() {
+        send(documents);
+    }
+    Ok(skipped)
+}
+
+/// A document from one line of an import; metadata values that are not
+/// strings are kept as JSON.
+fn import_document(value: &Value) -> Document {
+    let string = |key: &str| value[key].as_str().unwrap_or_default().to_string();
+    let metadata = value["metadata"]
+        .as_object()
+        .map(|m| {
+            m.iter()
+                .map(|(k, v)| {
+                    let v = v.as_str().map_or_else(|| v.to_string(), str::to_string);
+                    (k.clone(), v)
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    Document {
+        id: string("id"),
+        text: string("text"),
+        source: string("source"),
+        mime_type: string("mime_type"),
+        chunk: value["chunk"].as_u64().map(|c| c as u32),
+        metadata,
+    }
+}
+
+/// Expands `@-` (stdin) and `@PATH` (file contents) variable values.
+fn resolve_var(value: &str) -> Result<String, Box<dyn std::error::Error>> {
+    match value.strip_prefix('@') {
+        Some("-") => {
+            let mut input = String::new();
+            std::io::stdin().read_to_string(&mut input)?;
+            Ok(input)
+        }
+        Some(path) => std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}").into()),
+        None => Ok(value.to_string()),
+    }
+}
+
+/// Reads a local template file, falling back to the core's template store.
+async fn load_template(
+    core: &mut Core,
+    template: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if std::path::Path::new(template).is_file() {
+        return Ok(std::fs::read_to_string(template)?);
+    }
+    fetch_template(core, template).await
+}
+
+async fn fetch_template(core: &mut Core, name: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let reply = core.send("template_get", json!({ "name": name })).await?;
+    Ok(reply["body"].as_str().unwrap_or_default().to_string())
+}
+
+/// First line of `text`, cut to 60 columns.
+fn preview(text: &str) -> String {
+    text::fit(text.lines().next().unwrap_or_default(), 60)
+}
+
+async fn backup(command: BackupCommand, addr: &str) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        BackupCommand::Push { dest, from } => {
+            let pushed = match from {
+                Some(dir) => push_backup(&std::path::absolute(dir)?, &dest)?,
+                None => {
+                    let mut core = Core::connect(addr, String::new(), None, String::new()).await?;
+                    let staging = Staging::new()?;
+                    let snapshot = staging.0.join("snapshot");
+                    core.indexer
+                        .snapshot(SnapshotRequest {
+                            path: snapshot.display().to_string(),
+                        })
+                        .await?;
+                    push_backup(&snapshot, &dest)?
+                }
+            };
+            println!(
+                "backed up {} as {}: {} files, {} bytes; {} of {} chunks new ({} bytes)",
+                dest,
+                pushed.name,
+                pushed.files,
+                pushed.bytes,
+                pushed.new_chunks,
+                pushed.chunks,
+                pushed.new_bytes
+            );
+        }
+        BackupCommand::List { dest } => {
+            let listed = match Remote::parse(&dest) {
+                Some(remote) => {
+                    let staging = Staging::new()?;
+                    remote.copy_to(
+                        &remote.join("snapshots"),
+                        &staging.0.join("snapshots"),
+                        None,
+                    )?;
+                    backup::list(&staging.0)?
+                }
+                None => backup::list(std::path::Path::new(&dest))?,
+            };
+            for (name, manifest) in listed {
+                println!(
+                    "{name}\t{}\t{} files\t{} bytes",
+                    manifest.created_at,
+                    manifest.files.len(),
+                    manifest.size()
+                );
+            }
+        }
+        BackupCommand::Pull { dest, name, dir } => {
+            let target = std::path::absolute(&dir)?;
+            let manifest = match Remote::parse(&dest) {
+                Some(remote) => {
+                    let staging = Staging::new()?;
+                    let manifest_file = format!("snapshots/{name}.json");
+                    let manifest = staging.0.join(&manifest_file);
+                    remote.copy_to(
+                        &remote.join("snapshots"),
+                        &staging.0.join("snapshots"),
+                        Some(&[format!("{name}.json")]),
+                    )?;
+                    if !manifest.exists() {
+                        return Err(format!("no backup {name}").into());
+                    }
+                    let chunks = backup::manifest(&staging.0, &name)?.chunk_paths();
+                    remote.copy_to(&dest, &staging.0, Some(&chunks))?;
+                    backup::pull(&staging.0, &name, &target)?
+                }
+                None => backup::pull(std::path::Path::new(&dest), &name, &target)?,
+            };
+            println!(
+                "pulled {name} ({} files, {} bytes) to {}; load it with `ondevice restore {}`",
+                manifest.files.len(),
+                manifest.size(),
+                target.display(),
+                target.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Adds `snapshot` to the backup repository `dest`. For an rclone remote
+/// the new chunks and the manifest are staged locally, then copied over.
+fn push_backup(
+    snapshot: &std::path::Path,
+    dest: &str,
+) -> Result<backup::Pushed, Box<dyn std::error::Error>> {
+    let name = backup::name_now();
+    let Some(remote) = Remote::parse(dest) else {
+        return Ok(backup::push(
+            snapshot,
+            std::path::Path::new(dest),
+            &name,
+            &HashSet::new(),
+        )?);
+    };
+    let known = remote
+        .lsf(&remote.join("chunks"))?
+        .lines()
+        .filter_map(|path| path.rsplit('/').next())
+        .map(str::to_string)
+        .collect();
+    if remote
+        .lsf(&remote.join("snapshots"))?
+        .lines()
+        .any(|f| f == format!("{name}.json"))
+    {
+        return Err(format!("backup {name} already exists").into());
+    }
+    let staging = Staging::new()?;
+    let pushed = backup::push(snapshot, &staging.0, &name, &known)?;
+    // Chunks first: a manifest must never arrive before what it lists.
+    remote.copy_from(&staging.0.join("chunks"), &remote.join("chunks"))?;
+    remote.copy_from(&staging.0.join("snapshots"), &remote.join("snapshots"))?;
+    Ok(pushed)
+}
+
+/// A backup destination reached through rclone, such as `b2:bucket/index`.
+struct Remote(String);
+
+impl Remote {
+    /// A destination is a remote if it starts with `name:` and is not a
+    /// local path that exists.
+    fn parse(dest: &str) -> Option<Self> {
+        let (name, _) = dest.split_once(':')?;
+        let local =
+            name.is_empty() || name.contains(['/', '\\']) || std::path::Path::new(dest).exists();
+        (!local).then(|| Remote(dest.to_string()))
+    }
+
+    fn join(&self, path: &str) -> String {
+        if self.0.ends_with([':', '/']) {
+            format!("{}{path}", self.0)
+        } else {
+            format!("{}/{path}", self.0)
+        }
+    }
+
+    /// Files under `path`, one relative path per line; none if it is
+    /// missing.
+    fn lsf(&self, path: &str) -> Result<String, Box<dyn std::error::Error>> {
+        rclone(&["lsf", "-R", "--files-only", path], None)
+    }
+
+    fn copy_from(
+        &self,
+        local: &std::path::Path,
+        path: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if local.exists() {
+            rclone(&["copy", &local.display().to_string(), path], None)?;
+        }
+        Ok(())
+    }
+
+    /// Copies `path` to `local`; only `files`, relative to `path`, if given.
+    fn copy_to(
+        &self,
+        path: &str,
+        local: &std::path::Path,
+        files: Option<&[String]>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        rclone(&["copy", path, &local.display().to_string()], files)?;
+        Ok(())
+    }
+}
+
+/// Runs rclone and returns its output. A missing source directory counts
+/// as empty.
+fn rclone(args: &[&str], files: Option<&[String]>) -> Result<String, Box<dyn std::error::Error>> {
+    let mut command = std::process::Command::new("rclone");
+    command.args(args);
+    let list;
+    if let Some(files) = files {
+        list = Staging::new()?;
+        let path = list.0.join("files");
+        std::fs::write(&path, files.join("\n"))?;
+        command.arg("--files-from").arg(&path);
+    }
+    let output = command.output().map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => {
+            "rclone is not installed; it is needed for remote backups".to_string()
+        }
+        _ => format!("running rclone failed: {e}"),
+    })?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() && !stderr.contains("directory not found") {
+        return Err(format!("rclone {} failed: {}", args[0], stderr.trim()).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Temporary local directory, removed when dropped.
+struct Staging(std::path::PathBuf);
+
+impl Staging {
+    fn new() -> std::io::Result<Self> {
+        let dir = std::env::temp_dir().join(format!("ondevice-staging-{}", request_id()));
+        std::fs::create_dir_all(&dir)?;
+        Ok(Staging(dir))
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Connection to the core plus the settings every request carries.
+struct Core {
+    client: AssistantClient<Channel>,
+    indexer: IndexerClient<InterceptedService<Channel, InWorkspace>>,
+    artifacts: ArtifactsClient<Channel>,
+    profile: String,
+    session: Option<String>,
+    workspace: String,
+}
+
+/// Tells the indexer which workspace requests are made in, through
+/// `workspace` metadata.
+#[derive(Clone)]
+struct InWorkspace(Option<MetadataValue<Ascii>>);
+
+impl Interceptor for InWorkspace {
+    fn call(&mut self, mut req: tonic::Request<()>) -> Result<tonic::Request<()>, tonic::Status> {
+        if let Some(workspace) = &self.0 {
+            req.metadata_mut().insert("workspace", workspace.clone());
+        }
+        Ok(req)
+    }
+}
+
+impl Core {
+    async fn connect(
+        addr: &str,
+        profile: String,
+        session: Option<String>,
+        workspace: String,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let scope = match workspace.as_str() {
+            "" => None,
+            name => Some(
+                name.parse()
+                    .map_err(|_| format!("invalid workspace name: {name:?}"))?,
+            ),
+        };
+        let endpoint = if addr.contains("://") {
+            addr.to_string()
+        } else {
+            format!("http://{addr}")
+        };
+        let channel = Channel::from_shared(endpoint)?
+            .connect()
+            .await
+            .map_err(|e| format!("cannot reach assistant core at {addr}: {e}"))?;
+        Ok(Core {
+            client: AssistantClient::new(channel.clone()),
+            indexer: IndexerClient::with_interceptor(channel.clone(), InWorkspace(scope)),
+            artifacts: ArtifactsClient::new(channel),
+            profile,
+            session,
+            workspace,
+        })
+    }
+
+    fn request(&self, kind: &str, mut payload: Value) -> Request {
+        if let (Some(session), "query") = (&self.session, kind) {
+            payload["session_id"] = json!(session);
+        }
+        Request {
+            id: request_id(),
+            user_id: String::new(),
+            r#type: kind.to_string(),
+            payload: payload.to_string(),
+            profile: self.profile.clone(),
+            workspace: self.workspace.clone(),
+        }
+    }
+
+    /// Sends one request and returns the parsed reply payload, failing on non-200.
+    async fn send(
+        &mut self,
+        kind: &str,
+        payload: Value,
+    ) -> Result<Value, Box<dyn std::error::Error>> {
+        let request = self.request(kind, payload);
+        let reply = self.client.send(request).await?.into_inner();
+        if reply.status != 200 {
+            return Err(format!("assistant returned {}: {}", reply.status, reply.payload).into());
+        }
+        Ok(serde_json::from_str(&reply.payload)?)
+    }
+
+    /// Sends one request over StreamResponses and writes answer deltas to
+    /// stdout (reasoning to stderr) as they arrive, failing on the first
+    /// non-200 reply. Returns the stream's summary.
+    async fn stream(
+        &mut self,
+        kind: &str,
+        payload: Value,
+    ) -> Result<Value, Box<dyn std::error::Error>> {
+        let request = self.request(kind, payload);
+        let mut replies = self
+            .client
+            .stream_responses(futures_util::stream::iter([request]))
+            .await?
+            .into_inner();
+        let mut stdout = std::io::stdout().lock();
+        let mut summary = Value::Null;
+        while let Some(reply) = replies.message().await? {
+            if reply.status != 200 {
+                return Err(
+                    format!("assistant returned {}: {}", reply.status, reply.payload).into(),
+                );
+            }
+            let mut event: Value = serde_json::from_str(&reply.payload)?;
+            if event["done"].as_bool() == Some(true) {
+                break;
+            }
+            if let Some(reasoning) = event["reasoning"].as_str() {
+                eprint!("{reasoning}");
+                continue;
+            }
+            if event.get("summary").is_some() {
+                summary = event["summary"].take();
+                continue;
+            }
+            if let Some(warning) = event.get("warning") {
+                eprintln!(
+                    "warning: possible prompt injection in {} chunk {} ({})",
+                    warning["source"].as_str().unwrap_or_default(),
+                    warning["chunk"],
+                    warning["patterns"]
+                        .as_array()
+                        .map(|p| p
+                            .iter()
+                            .filter_map(Value::as_str)
+                            .collect::<Vec<_>>()
+                            .join(", "))
+                        .unwrap_or_default()
+                );
+                continue;
+            }
+            stdout.write_all(event["delta"].as_str().unwrap_or_default().as_bytes())?;
+            stdout.flush()?;
+        }
+        writeln!(stdout)?;
+        Ok(summary)
+    }
+}
+
+fn request_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    format!("cli-{nanos}")
+}
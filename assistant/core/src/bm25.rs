@@ -0,0 +1,145 @@
+//! Inverted index over entry text, scored with Okapi BM25. It finds exact
+//! keyword matches that the hashed embeddings blur together.
+//!
+//! Like the HNSW graph, it refers to entries by their position in the
+//! index and lives in memory only.
+
+use crate::index::terms;
+use std::collections::HashMap;
+
+/// Term frequency saturation.
+const K1: f32 = 1.2;
+/// How much scores are normalized by entry length.
+const B: f32 = 0.75;
+
+#[derive(Default)]
+pub struct Bm25 {
+    /// Term -> entry -> occurrences.
+    postings: HashMap<String, HashMap<u32, u32>>,
+    /// Terms per entry.
+    lengths: Vec<u32>,
+    total_length: u64,
+}
+
+impl Bm25 {
+    /// An index over entries `0..` with these texts.
+    pub fn build(texts: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        let mut index = Bm25::default();
+        for (node, text) in texts.into_iter().enumerate() {
+            index.insert(node, text.as_ref());
+        }
+        index
+    }
+
+    /// Indexes `text` as entry `node`, which is either new (the next
+    /// position) or was [`remove`](Self::remove)d.
+    pub fn insert(&mut self, node: usize, text: &str) {
+        let terms = terms(text);
+        if node == self.lengths.len() {
+            self.lengths.push(0);
+        }
+        self.lengths[node] = terms.len() as u32;
+        self.total_length += terms.len() as u64;
+        for term in terms {
+            *self
+                .postings
+                .entry(term)
+                .or_default()
+                .entry(node as u32)
+                .or_default() += 1;
+        }
+    }
+
+    /// Forgets entry `node`, which was indexed with `text`.
+    pub fn remove(&mut self, node: usize, text: &str) {
+        for term in terms(text) {
+            if let Some(entries) = self.postings.get_mut(&term) {
+                entries.remove(&(node as u32));
+                if entries.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+        self.total_length -= u64::from(self.lengths[node]);
+        self.lengths[node] = 0;
+    }
+
+    /// Forgets the entries `keep` is false for and numbers the rest in
+    /// order, as removal from the index does, without reading any text.
+    pub fn retain(&mut self, keep: &[bool]) {
+        let mut renumbered = Vec::with_capacity(keep.len());
+        let mut next = 0;
+        for &kept in keep {
+            renumbered.push(kept.then_some(next));
+            next += u32::from(kept);
+        }
+        self.postings.retain(|_, entries| {
+            *entries = entries
+                .drain()
+                .filter_map(|(node, tf)| Some((renumbered.get(node as usize).copied()??, tf)))
+                .collect();
+            !entries.is_empty()
+        });
+        let mut node = 0;
+        self.lengths.retain(|&length| {
+            let kept = keep.get(node).copied().unwrap_or(true);
+            if !kept {
+                self.total_length -= u64::from(length);
+            }
+            node += 1;
+            kept
+        });
+    }
+
+    /// BM25 score of each entry containing at least one of `query_terms`.
+    pub fn scores(&self, query_terms: &[String]) -> HashMap<usize, f32> {
+        let mut scores = HashMap::new();
+        let n = self.lengths.len() as f32;
+        if n == 0.0 {
+            return scores;
+        }
+        let average = (self.total_length as f32 / n).max(1.0);
+        let mut seen = Vec::new();
+        for term in query_terms {
+            if seen.contains(&term) {
+                continue;
+            }
+            seen.push(term);
+            let Some(entries) = self.postings.get(term) else {
+                continue;
+            };
+            let df = entries.len() as f32;
+            let idf = (1.0 + (n - df + 0.5) / (df + 0.5)).ln();
+            for (&node, &tf) in entries {
+                let tf = tf as f32;
+                let length = self.lengths[node as usize] as f32;
+                let norm = K1 * (1.0 - B + B * length / average);
+                *scores.entry(node as usize).or_insert(0.0) += idf * tf * (K1 + 1.0) / (tf + norm);
+            }
+        }
+        scores
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retain_matches_building_from_the_kept_texts() {
+        let texts = ["red fox", "lazy dog", "red dog barks", "fox den", "dog"];
+        let keep = [true, false, true, false, true];
+        let mut index = Bm25::build(texts);
+        index.retain(&keep);
+        let kept = texts.iter().zip(keep).filter(|(_, k)| *k).map(|(t, _)| *t);
+        let built = Bm25::build(kept);
+        for query in [&["red"][..], &["dog"], &["fox"], &["red", "dog"]] {
+            let query: Vec<String> = query.iter().map(|t| t.to_string()).collect();
+            let mut a: Vec<_> = index.scores(&query).into_iter().collect();
+            let mut b: Vec<_> = built.scores(&query).into_iter().collect();
+            a.sort_by_key(|&(node, _)| node);
+            b.sort_by_key(|&(node, _)| node);
+            assert_eq!(a, b);
+        }
+    }
+}
@@ -0,0 +1,518 @@
+//! Chat turns. There is no model backend yet, so answers come from an
+//! echo stand-in; everything around it (profiles, post-processing,
+//! streaming) is real and does not change when a model is plugged in.
+
+use crate::assemble::{self, Inputs};
+use crate::index;
+use crate::injection;
+use crate::postprocess;
+use crate::profile::Profile;
+use crate::session::Turn;
+use crate::text;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::time::Duration;
+
+/// Model id reported for answers from the echo stand-in, unless the
+/// profile names another.
+pub const MODEL_ID: &str = "echo";
+
+pub struct ChatRequest {
+    pub prompt: String,
+    /// Retrieved or caller-supplied context chunks, best first.
+    pub context: Vec<Chunk>,
+    /// `(tool, output)` of tool calls the caller made for this turn.
+    pub tool_results: Vec<(String, String)>,
+    pub memories: Vec<String>,
+    /// Earlier `(prompt, answer)` pairs of the session, oldest first.
+    /// Filled in by the server, not read from the payload.
+    pub history: Vec<(String, String)>,
+    /// Opt-in: also stream the model's reasoning segments.
+    pub include_reasoning: bool,
+    /// Opt-in: report how the prompt was assembled.
+    pub debug: bool,
+    /// When set, the finished turn is saved to this session.
+    pub session_id: String,
+    /// Opt-in: stream at most this many tokens per second.
+    pub tokens_per_second: Option<f64>,
+    /// When set, the answer is also saved as an artifact of this kind.
+    pub save_as: String,
+    /// Plan the turn belongs to, recorded on a saved artifact.
+    pub plan_id: String,
+    /// Overrides the profile's answer policy for this turn.
+    pub answer_policy: Option<AnswerPolicy>,
+    /// `(name, text)` of documents to attach to the session before the
+    /// turn; see [`crate::attach`].
+    pub attachments: Vec<(String, String)>,
+}
+
+/// A context chunk, with where it came from and how well it matched when
+/// the caller passes them on from a query.
+#[derive(Clone, Debug, Default)]
+pub struct Chunk {
+    pub text: String,
+    pub source: String,
+    /// Retrieval score; `None` for text the caller supplied directly,
+    /// such as clipboard contents, which counts as grounding as it is.
+    pub score: Option<f32>,
+}
+
+/// When to answer and when to say that no grounding was found.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AnswerPolicy {
+    /// Turns whose confidence is below this get a reply saying no
+    /// grounding was found, listing the nearest sources, instead of an
+    /// answer. 0 always answers.
+    pub min_confidence: f32,
+}
+
+impl AnswerPolicy {
+    /// Parses `{"min_confidence": <0..1>}`.
+    pub fn from_config(config: &Value) -> Result<Self, String> {
+        if !config.is_object() {
+            return Err("answer_policy must be an object".into());
+        }
+        let min_confidence = match &config["min_confidence"] {
+            Value::Null => 0.0,
+            v => v
+                .as_f64()
+                .filter(|c| (0.0..=1.0).contains(c))
+                .ok_or("answer_policy.min_confidence must be between 0 and 1")?,
+        };
+        Ok(AnswerPolicy {
+            min_confidence: min_confidence as f32,
+        })
+    }
+}
+
+/// How well a turn is grounded, each part from 0 to 1.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Confidence {
+    /// The weakest of the three signals below.
+    pub score: f32,
+    /// Best retrieval score among the context chunks; 1 when any chunk
+    /// was supplied directly, 0 when there are none.
+    pub retrieval: f32,
+    /// Share of the prompt's words found in the context.
+    pub coverage: f32,
+    /// Share of the generated answer's words found in the prompt or
+    /// context.
+    pub support: f32,
+}
+
+impl Confidence {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "score": self.score,
+            "retrieval": self.retrieval,
+            "coverage": self.coverage,
+            "support": self.support,
+        })
+    }
+}
+
+/// One item on a chat stream.
+#[derive(Clone, Debug)]
+pub enum Event {
+    Delta(String),
+    /// Reasoning ("thinking") text, kept off the content channel.
+    Reasoning(String),
+    /// Debug event listing what prompt assembly included and dropped.
+    Assembly(assemble::Report),
+    /// A retrieved chunk or tool result looked like prompt injection.
+    Warning(injection::Warning),
+    /// Sent once per stream, right before `Done`.
+    Summary(Summary),
+    Done,
+}
+
+/// Performance and provenance figures for one streamed turn.
+#[derive(Clone, Debug, Default)]
+pub struct Summary {
+    pub model: String,
+    /// Profile the turn ran under, after routing.
+    pub profile: String,
+    pub wall: Duration,
+    /// Time until the first reasoning or content delta was sent.
+    pub first_token: Option<Duration>,
+    pub tokens: usize,
+    /// Retrieved passages used for the answer.
+    pub retrieved: usize,
+    /// Artifact the answer was saved as, if requested.
+    pub artifact_id: Option<String>,
+    pub confidence: Confidence,
+    /// The answer policy replaced the answer with a no-grounding reply.
+    pub abstained: bool,
+}
+
+impl Summary {
+    pub fn to_json(&self) -> Value {
+        let secs = self.wall.as_secs_f64();
+        let tokens_per_sec = if secs > 0.0 {
+            self.tokens as f64 / secs
+        } else {
+            0.0
+        };
+        let mut out = json!({
+            "model": self.model,
+            "profile": self.profile,
+            "wall_ms": self.wall.as_millis() as u64,
+            "first_token_ms": self.first_token.map(|d| d.as_millis() as u64),
+            "tokens": self.tokens,
+            "tokens_per_sec": tokens_per_sec,
+            "retrieval": { "retrieved": self.retrieved },
+            "confidence": self.confidence.to_json(),
+        });
+        if self.abstained {
+            out["abstained"] = json!(true);
+        }
+        if let Some(id) = &self.artifact_id {
+            out["artifact_id"] = json!(id);
+        }
+        out
+    }
+}
+
+impl Event {
+    pub fn to_json(&self) -> Value {
+        match self {
+            Event::Delta(text) => json!({ "delta": text }),
+            Event::Reasoning(text) => json!({ "reasoning": text }),
+            Event::Assembly(report) => json!({ "assembly": report.to_json() }),
+            Event::Warning(warning) => json!({ "warning": warning.to_json() }),
+            Event::Summary(summary) => json!({ "summary": summary.to_json() }),
+            Event::Done => json!({ "done": true }),
+        }
+    }
+}
+
+impl ChatRequest {
+    /// Reads a `query` payload: `{"prompt" | "question": ..., "context": ...,
+    /// "tool_results": [{"tool", "output"}, ...], "memories": [...]}`.
+    /// `context` is a string or a list of chunks, each a string or
+    /// `{"text", "source" | "id", "score"}`; a tool's `output` is a string
+    /// or any JSON value. An invalid `answer_policy` is ignored.
+    /// `"attachments": [{"name", "text"}, ...]` attach documents to the
+    /// session.
+    pub fn from_payload(payload: &Value) -> Self {
+        let prompt = payload["prompt"]
+            .as_str()
+            .or_else(|| payload["question"].as_str())
+            .unwrap_or_default();
+        ChatRequest {
+            prompt: prompt.to_string(),
+            context: chunks(&payload["context"]),
+            tool_results: tool_results(&payload["tool_results"]),
+            memories: strings(&payload["memories"]),
+            history: Vec::new(),
+            include_reasoning: payload["include_reasoning"].as_bool().unwrap_or(false),
+            debug: payload["debug"].as_bool().unwrap_or(false),
+            session_id: payload["session_id"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            tokens_per_second: payload["tokens_per_second"]
+                .as_f64()
+                .filter(|rate| *rate > 0.0),
+            save_as: payload["save_as"].as_str().unwrap_or_default().to_string(),
+            plan_id: payload["plan_id"].as_str().unwrap_or_default().to_string(),
+            answer_policy: match &payload["answer_policy"] {
+                Value::Null => None,
+                policy => AnswerPolicy::from_config(policy).ok(),
+            },
+            attachments: attachments(&payload["attachments"]),
+        }
+    }
+}
+
+/// Context chunks from a string or a list of strings and
+/// `{"text", "source" | "id", "score"}` objects; entries without text are
+/// skipped.
+fn chunks(value: &Value) -> Vec<Chunk> {
+    let chunk = |item: &Value| match item {
+        Value::String(text) => Some(Chunk {
+            text: text.clone(),
+            ..Chunk::default()
+        }),
+        Value::Object(_) => Some(Chunk {
+            text: item["text"].as_str()?.to_string(),
+            source: item["source"]
+                .as_str()
+                .or_else(|| item["id"].as_str())
+                .unwrap_or_default()
+                .to_string(),
+            score: item["score"].as_f64().map(|s| s as f32),
+        }),
+        _ => None,
+    };
+    match value {
+        Value::String(s) if s.is_empty() => Vec::new(),
+        Value::Array(items) => items.iter().filter_map(chunk).collect(),
+        other => chunk(other).into_iter().collect(),
+    }
+}
+
+/// A string or list of strings; anything else is empty.
+fn strings(value: &Value) -> Vec<String> {
+    match value {
+        Value::String(s) if !s.is_empty() => vec![s.clone()],
+        Value::Array(items) => items
+            .iter()
+            .filter_map(|v| v.as_str().map(str::to_string))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// `(name, text)` pairs from `[{"name", "text"}, ...]`; entries without
+/// text are skipped.
+fn attachments(value: &Value) -> Vec<(String, String)> {
+    let Some(items) = value.as_array() else {
+        return Vec::new();
+    };
+    items
+        .iter()
+        .filter_map(|item| {
+            let text = item["text"].as_str()?;
+            let name = item["name"].as_str().unwrap_or_default();
+            Some((name.to_string(), text.to_string()))
+        })
+        .collect()
+}
+
+/// `(tool, output)` pairs from `[{"tool", "output"}, ...]`; entries
+/// without a tool name are skipped.
+fn tool_results(value: &Value) -> Vec<(String, String)> {
+    let Some(items) = value.as_array() else {
+        return Vec::new();
+    };
+    items
+        .iter()
+        .filter_map(|item| {
+            let tool = item["tool"].as_str()?;
+            let output = match &item["output"] {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            Some((tool.to_string(), output))
+        })
+        .collect()
+}
+
+/// Stand-in generation: answers with the prompt and context it was given.
+pub fn echo_answer(prompt: &str, context: &[String]) -> String {
+    let context = context.join("\n\n");
+    match (prompt.is_empty(), context.is_empty()) {
+        (_, true) => prompt.to_string(),
+        (true, false) => context,
+        (false, false) => format!("{prompt}\n\n{context}"),
+    }
+}
+
+/// A finished turn: content after post-processing, plus any reasoning.
+pub struct Answer {
+    pub content: String,
+    pub reasoning: String,
+    pub model: String,
+    /// How the model input was put together.
+    pub assembly: assemble::Report,
+    /// Retrieved chunks and tool results screened as possible prompt
+    /// injection.
+    pub warnings: Vec<injection::Warning>,
+    pub confidence: Confidence,
+    /// The answer policy replaced the answer with a no-grounding reply.
+    pub abstained: bool,
+}
+
+/// Words of three letters or more, lowercased; shorter ones are mostly
+/// function words that match anything.
+fn words(text: &str) -> HashSet<String> {
+    index::terms(text)
+        .into_iter()
+        .filter(|t| t.chars().count() > 2)
+        .collect()
+}
+
+/// Share of `words` found in `known`; 1 when there are none to check.
+fn share(words: &HashSet<String>, known: &HashSet<String>) -> f32 {
+    if words.is_empty() {
+        return 1.0;
+    }
+    words.iter().filter(|w| known.contains(*w)).count() as f32 / words.len() as f32
+}
+
+/// Scores how well `generated` is grounded in the context for `prompt`.
+pub fn confidence(prompt: &str, context: &[Chunk], generated: &str) -> Confidence {
+    if context.is_empty() {
+        return Confidence::default();
+    }
+    let retrieval = if context.iter().any(|c| c.score.is_none()) {
+        1.0
+    } else {
+        context
+            .iter()
+            .filter_map(|c| c.score)
+            .fold(0.0f32, f32::max)
+            .clamp(0.0, 1.0)
+    };
+    let grounding: HashSet<String> = context.iter().flat_map(|c| words(&c.text)).collect();
+    let asked = words(prompt);
+    let coverage = share(&asked, &grounding);
+    let known = grounding.union(&asked).cloned().collect();
+    let support = share(&words(generated), &known);
+    Confidence {
+        score: retrieval.min(coverage).min(support),
+        retrieval,
+        coverage,
+        support,
+    }
+}
+
+/// Sources listed in a no-grounding reply.
+const NEAREST_SOURCES: usize = 3;
+
+/// What is said instead of guessing: that no grounding was found, and the
+/// nearest sources there were.
+fn no_grounding(context: &[Chunk]) -> String {
+    let mut out =
+        String::from("I couldn't find anything to ground an answer in, so I won't guess.");
+    if context.is_empty() {
+        out.push_str(" No sources were retrieved.");
+        return out;
+    }
+    out.push_str("\n\nThe nearest sources were:");
+    for chunk in context.iter().take(NEAREST_SOURCES) {
+        let name = if chunk.source.is_empty() {
+            let excerpt = text::ellipsize(&chunk.text, 60);
+            format!("\"{}\"", excerpt.replace('\n', " "))
+        } else {
+            chunk.source.clone()
+        };
+        match chunk.score {
+            Some(score) => out.push_str(&format!("\n- {name} ({score:.2})")),
+            None => out.push_str(&format!("\n- {name}")),
+        }
+    }
+    out
+}
+
+/// Runs a turn under `profile`. Reasoning segments never reach the
+/// content channel; post-processing applies to content only.
+pub fn answer(req: &ChatRequest, profile: &Profile) -> Answer {
+    // The assembled prompt is what a model backend will be given; the
+    // echo stand-in only needs the report. Retrieved chunks and tool
+    // results are untrusted, so they are screened on the way in; the
+    // assembler wraps them.
+    let mut retrieved: Vec<String> = req.context.iter().map(|c| c.text.clone()).collect();
+    let mut tool_results = req.tool_results.clone();
+    let mut warnings = injection::screen_tool_results(&mut tool_results, profile.injection);
+    warnings.extend(injection::screen_chunks(&mut retrieved, profile.injection));
+    for warning in &warnings {
+        warning.log();
+    }
+    let inputs = Inputs {
+        system: profile.system.clone(),
+        memories: req.memories.clone(),
+        retrieved,
+        tool_results,
+        history: req.history.clone(),
+        prompt: req.prompt.clone(),
+    };
+    let assembled = assemble::assemble(&inputs, &profile.context);
+    let echoed = echo_answer(&req.prompt, &inputs.retrieved);
+    let (mut content, mut reasoning) = postprocess::split_reasoning(&echoed);
+    let confidence = confidence(&req.prompt, &req.context, &content);
+    let policy = req.answer_policy.unwrap_or(profile.answer_policy);
+    let abstained = confidence.score < policy.min_confidence;
+    if abstained {
+        content = no_grounding(&req.context);
+        reasoning.clear();
+    }
+    Answer {
+        content: profile.postprocess.apply(&content),
+        reasoning,
+        model: profile.model.as_deref().unwrap_or(MODEL_ID).to_string(),
+        assembly: assembled.report,
+        warnings,
+        confidence,
+        abstained,
+    }
+}
+
+/// The session record for a finished turn. It cites the sources of the
+/// retrieved chunks, each once, in the order they were retrieved.
+pub fn turn(
+    req: &ChatRequest,
+    answer: &Answer,
+    started_at: DateTime<Utc>,
+    elapsed: Duration,
+    delivered: bool,
+) -> Turn {
+    Turn {
+        prompt: req.prompt.clone(),
+        answer: answer.content.clone(),
+        reasoning: answer.reasoning.clone(),
+        tokens: deltas(&answer.content).len() + deltas(&answer.reasoning).len(),
+        model: answer.model.clone(),
+        started_at: started_at.to_rfc3339(),
+        duration_ms: elapsed.as_millis() as u64,
+        citations: citations(&req.context),
+        delivered,
+    }
+}
+
+fn citations(context: &[Chunk]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    context
+        .iter()
+        .filter(|chunk| !chunk.source.is_empty() && seen.insert(chunk.source.as_str()))
+        .map(|chunk| chunk.source.clone())
+        .collect()
+}
+
+/// Tokens per second to stream the turn at, if limited: the request's
+/// rate, capped by the profile's ceiling.
+pub fn stream_rate(req: &ChatRequest, profile: &Profile) -> Option<f64> {
+    match (req.tokens_per_second, profile.max_tokens_per_second) {
+        (Some(asked), Some(ceiling)) => Some(asked.min(ceiling)),
+        (asked, ceiling) => asked.or(ceiling),
+    }
+}
+
+/// The events for a turn: prompt-injection warnings, the assembly report
+/// (only when debugging), reasoning deltas (only when requested), then
+/// content. The caller closes the stream with `Summary` and `Done`.
+pub fn events(req: &ChatRequest, answer: &Answer) -> Vec<Event> {
+    let mut events: Vec<Event> = answer
+        .warnings
+        .iter()
+        .cloned()
+        .map(Event::Warning)
+        .collect();
+    if req.debug {
+        events.push(Event::Assembly(answer.assembly.clone()));
+    }
+    if req.include_reasoning {
+        events.extend(deltas(&answer.reasoning).into_iter().map(Event::Reasoning));
+    }
+    events.extend(deltas(&answer.content).into_iter().map(Event::Delta));
+    events
+}
+
+/// Splits an answer into stream deltas, keeping whitespace attached so
+/// concatenating the deltas reproduces the text exactly.
+pub fn deltas(text: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut current = String::new();
+    for c in text.chars() {
+        if !c.is_whitespace() && current.chars().last().is_some_and(char::is_whitespace) {
+            out.push(std::mem::take(&mut current));
+        }
+        current.push(c);
+    }
+    if !current.is_empty() {
+        out.push(current);
+    }
+    out
+}
@@ -0,0 +1,90 @@
+//! Splitting long texts into overlapping chunks before they are embedded.
+//! One embedding of a whole long document matches everything a little and
+//! nothing well; chunks keep hits specific and their text prompt-sized.
+
+use serde::{Deserialize, Serialize};
+
+/// Chunking settings, fixed per collection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChunkParams {
+    /// Most characters per chunk. Texts no longer than this stay whole.
+    pub size: usize,
+    /// Characters each chunk repeats from the end of the one before, so a
+    /// passage cut at a boundary is still found whole in one of them.
+    pub overlap: usize,
+}
+
+impl Default for ChunkParams {
+    fn default() -> Self {
+        ChunkParams {
+            size: 1000,
+            overlap: 100,
+        }
+    }
+}
+
+impl ChunkParams {
+    /// Validates requested settings; a zero size or missing overlap takes
+    /// the default.
+    pub fn new(size: usize, overlap: Option<usize>) -> Result<Self, String> {
+        let default = ChunkParams::default();
+        let params = ChunkParams {
+            size: if size == 0 { default.size } else { size },
+            overlap: overlap.unwrap_or(default.overlap),
+        };
+        if params.overlap >= params.size {
+            return Err(format!(
+                "chunk overlap {} must be less than the chunk size {}",
+                params.overlap, params.size
+            ));
+        }
+        Ok(params)
+    }
+}
+
+/// Byte offset `n` characters into `text`, if it has more than `n`.
+fn char_offset(text: &str, n: usize) -> Option<usize> {
+    text.char_indices().nth(n).map(|(at, _)| at)
+}
+
+/// Splits `text` into chunks of at most `params.size` characters, cut at
+/// whitespace where possible, each starting about `params.overlap`
+/// characters before the previous one ended. A text that fits in one
+/// chunk is returned whole.
+pub fn split(text: &str, params: ChunkParams) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    loop {
+        start += text[start..].len() - text[start..].trim_start().len();
+        let rest = &text[start..];
+        let Some(hard) = char_offset(rest, params.size) else {
+            if !rest.is_empty() || chunks.is_empty() {
+                chunks.push(rest.trim_end());
+            }
+            return chunks;
+        };
+        // Cut before the last word that does not fit, unless that word
+        // is the whole chunk.
+        let end = match rest[..hard].rfind(char::is_whitespace) {
+            Some(at) if at > 0 => at,
+            _ => hard,
+        };
+        chunks.push(rest[..end].trim_end());
+        // Back up by the overlap, then forward to the next word start.
+        let mut next = match params.overlap {
+            0 => end,
+            n => rest[..end]
+                .char_indices()
+                .rev()
+                .nth(n - 1)
+                .map_or(0, |(at, _)| at),
+        };
+        if next > 0 && !rest[..next].ends_with(char::is_whitespace) {
+            next = rest[next..end]
+                .find(char::is_whitespace)
+                .map_or(end, |at| next + at);
+        }
+        start += if next == 0 { end } else { next };
+    }
+}
@@ -0,0 +1,105 @@
+//! System clipboard access, shared by the `ondevice` CLI and the
+//! clipboard connector. Shells out to the platform's paste command so
+//! no windowing libraries are linked into the runtime.
+
+use crate::connector::{Connector, ConnectorError, Health, SyncReport, ToolSpec};
+use serde_json::{json, Value};
+use std::io;
+use std::process::Command;
+
+/// Paste commands to try, in order.
+const PASTE_COMMANDS: &[(&str, &[&str])] = &[
+    ("pbpaste", &[]),
+    ("wl-paste", &["--no-newline"]),
+    ("xclip", &["-selection", "clipboard", "-o"]),
+    ("xsel", &["--clipboard", "--output"]),
+    ("powershell", &["-NoProfile", "-Command", "Get-Clipboard"]),
+];
+
+/// Returns the current clipboard text.
+pub fn read() -> io::Result<String> {
+    for (cmd, args) in PASTE_COMMANDS {
+        let Ok(out) = Command::new(cmd).args(*args).output() else {
+            continue;
+        };
+        if out.status.success() {
+            return Ok(String::from_utf8_lossy(&out.stdout).into_owned());
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "no clipboard command found (tried pbpaste, wl-paste, xclip, xsel, powershell)",
+    ))
+}
+
+/// Exposes `clipboard_read`. Every call must carry `"consent": true`,
+/// which clients set only after asking the user for that specific read.
+pub struct ClipboardConnector;
+
+impl ClipboardConnector {
+    pub fn boxed() -> Box<dyn Connector> {
+        Box::new(ClipboardConnector)
+    }
+}
+
+#[tonic::async_trait]
+impl Connector for ClipboardConnector {
+    fn name(&self) -> &str {
+        "clipboard"
+    }
+
+    fn configure(&mut self, _config: &Value) -> Result<(), ConnectorError> {
+        Ok(())
+    }
+
+    async fn sync(&self) -> Result<SyncReport, ConnectorError> {
+        // Nothing is captured in the background; reads happen per call.
+        Ok(SyncReport::default())
+    }
+
+    fn list_tools(&self) -> Vec<ToolSpec> {
+        vec![ToolSpec {
+            name: "clipboard_read".into(),
+            description: "Read the current clipboard text. Requires consent: true on every call."
+                .into(),
+            parameters: json!({
+                "type": "object",
+                "properties": { "consent": { "type": "boolean" } },
+                "required": ["consent"],
+            }),
+            destructive: false,
+            requires_consent: true,
+            cache_ttl: None,
+        }]
+    }
+
+    async fn call_tool(&self, tool: &str, args: Value) -> Result<Value, ConnectorError> {
+        if tool != "clipboard_read" {
+            return Err(ConnectorError::UnknownTool(tool.to_string()));
+        }
+        if args["consent"].as_bool() != Some(true) {
+            return Err(ConnectorError::ConsentRequired(tool.to_string()));
+        }
+        let text = tokio::task::spawn_blocking(read)
+            .await
+            .map_err(|e| ConnectorError::Failed(e.to_string()))?
+            .map_err(|e| ConnectorError::Failed(e.to_string()))?;
+        Ok(json!({ "text": text }))
+    }
+
+    async fn health(&self) -> Health {
+        if PASTE_COMMANDS.iter().any(|(cmd, _)| on_path(cmd)) {
+            Health::Ok
+        } else {
+            Health::Down("no clipboard command on PATH".into())
+        }
+    }
+}
+
+fn on_path(cmd: &str) -> bool {
+    let Some(paths) = std::env::var_os("PATH") else {
+        return false;
+    };
+    std::env::split_paths(&paths)
+        .any(|dir| dir.join(cmd).is_file() || dir.join(format!("{cmd}.exe")).is_file())
+}
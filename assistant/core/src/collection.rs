@@ -0,0 +1,852 @@
+//! Named collections of documents, each its own [`VectorIndex`] saved as
+//! `<dir>/<name>.idx`, or kept in a [`DocStore`] under `<dir>` if their
+//! settings name other storage, with their settings in
+//! `<dir>/collections.json`.
+//!
+//! Aliases (`<dir>/aliases.json`) point a stable name at a collection, so
+//! a rebuilt `notes_v2` can replace `notes_v1` behind `notes` in one step.
+//!
+//! A snapshot is a directory laid out the same way, with every log folded
+//! into its index file; collections kept in another store are saved there
+//! as index files too.
+//!
+//! A collection snapshot saves one collection as
+//! `<dir>/snapshots/<name>@<millis>.idx`, where it can be queried later
+//! to see what the collection held then.
+
+use crate::chunk::ChunkParams;
+use crate::embed::{Embedder, Embedders, HashEmbedder};
+use crate::hnsw::HnswParams;
+use crate::index::{Reembedded, VectorIndex};
+use crate::indexfile;
+use crate::metric::Metric;
+use crate::normalize::Normalize;
+use crate::quantize::Quantization;
+use crate::store::DocStore;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Collection used when a request does not name one. It always exists.
+pub const DEFAULT: &str = "default";
+
+/// What to do with a document whose text is already stored under another
+/// id; see `CreateCollectionRequest.dedup`.
+const DEDUP_POLICIES: &[&str] = &["off", "skip", "merge", "reject"];
+/// Where a collection's entries are kept: its own index file, or a
+/// RocksDB database with the `rocksdb` feature; see [`crate::store`].
+const STORAGE: &[&str] = &["index", "rocksdb"];
+/// Version of the settings written by this build. Version 0 settings
+/// predate the choice of metric: their `dot` compared normalized
+/// embeddings, so it meant cosine.
+const CONFIG_VERSION: u32 = 1;
+
+/// Settings fixed when a collection is created.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CollectionConfig {
+    pub embedder: String,
+    pub metric: String,
+    pub quantization: String,
+    #[serde(default)]
+    pub hnsw: HnswParams,
+    #[serde(default)]
+    pub chunking: ChunkParams,
+    #[serde(default)]
+    pub version: u32,
+    /// A [`crate::privacy::Privacy`] level name, or empty for the global one.
+    #[serde(default)]
+    pub privacy: String,
+    /// One of [`DEDUP_POLICIES`]; empty, in settings from earlier
+    /// versions, means `off`.
+    #[serde(default)]
+    pub dedup: String,
+    /// How text is normalized before it is chunked and embedded.
+    #[serde(default)]
+    pub normalize: Normalize,
+    /// One of [`STORAGE`]; empty, in settings from earlier versions,
+    /// means `index`.
+    #[serde(default)]
+    pub storage: String,
+}
+
+impl Default for CollectionConfig {
+    fn default() -> Self {
+        CollectionConfig {
+            embedder: HashEmbedder::DEFAULT.into(),
+            metric: Metric::default().name().into(),
+            quantization: Quantization::default().name().into(),
+            hnsw: HnswParams::default(),
+            chunking: ChunkParams::default(),
+            version: CONFIG_VERSION,
+            privacy: String::new(),
+            dedup: DEDUP_POLICIES[0].into(),
+            normalize: Normalize::default(),
+            storage: STORAGE[0].into(),
+        }
+    }
+}
+
+fn pick(kind: &str, value: &str, supported: &[&str]) -> Result<String, String> {
+    match value {
+        "" => Ok(supported[0].to_string()),
+        v if supported.contains(&v) => Ok(v.to_string()),
+        v => Err(format!(
+            "unsupported {kind} {v:?}; supported: {}",
+            supported.join(", ")
+        )),
+    }
+}
+
+impl CollectionConfig {
+    /// Validates requested settings; empty values take the default. The
+    /// embedder is checked, and an empty one replaced by the server's
+    /// default, when the collection is created.
+    pub fn new(embedder: &str, metric: &str, quantization: &str) -> Result<Self, String> {
+        Ok(CollectionConfig {
+            embedder: embedder.to_string(),
+            metric: match metric {
+                "" => Metric::default(),
+                m => Metric::parse(m).ok_or_else(|| {
+                    format!(
+                        "unsupported metric {m:?}; supported: {}",
+                        Metric::NAMES.join(", ")
+                    )
+                })?,
+            }
+            .name()
+            .to_string(),
+            quantization: pick("quantization", quantization, Quantization::NAMES)?,
+            hnsw: HnswParams::default(),
+            chunking: ChunkParams::default(),
+            version: CONFIG_VERSION,
+            privacy: String::new(),
+            dedup: DEDUP_POLICIES[0].into(),
+            normalize: Normalize::default(),
+            storage: STORAGE[0].into(),
+        })
+    }
+}
+
+/// Validates a requested dedup policy; empty takes the default, `off`.
+pub fn dedup_policy(value: &str) -> Result<String, String> {
+    pick("dedup policy", value, DEDUP_POLICIES)
+}
+
+/// Validates requested storage; empty takes the default, `index`.
+/// RocksDB needs a build with the `rocksdb` feature.
+pub fn storage(value: &str) -> Result<String, String> {
+    let storage = pick("storage", value, STORAGE)?;
+    if storage == "rocksdb" && !cfg!(feature = "rocksdb") {
+        return Err("storage \"rocksdb\" needs a server built with the rocksdb feature".into());
+    }
+    Ok(storage)
+}
+
+pub struct Collection {
+    pub config: CollectionConfig,
+    pub index: VectorIndex,
+    /// Identifies this instance of the collection in write tokens; it
+    /// changes when the server restarts or the collection is recreated.
+    epoch: u64,
+}
+
+/// Returned by writes and accepted by reads, so a client can ask for a
+/// query that reflects its own earlier write. Clients treat it as opaque.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WriteToken {
+    epoch: u64,
+    writes: u64,
+}
+
+impl std::fmt::Display for WriteToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:x}.{}", self.epoch, self.writes)
+    }
+}
+
+impl std::str::FromStr for WriteToken {
+    type Err = CollectionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || CollectionError::InvalidConfig(format!("invalid write token {s:?}"));
+        let (epoch, writes) = s.split_once('.').ok_or_else(invalid)?;
+        Ok(WriteToken {
+            epoch: u64::from_str_radix(epoch, 16).map_err(|_| invalid())?,
+            writes: writes.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl Collection {
+    fn new(config: CollectionConfig, index: VectorIndex) -> Self {
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        Collection {
+            config,
+            index,
+            epoch,
+        }
+    }
+
+    /// Token for the writes applied so far.
+    pub fn write_token(&self) -> WriteToken {
+        WriteToken {
+            epoch: self.epoch,
+            writes: self.index.writes(),
+        }
+    }
+
+    /// Whether reads see the write `token` was issued for. Writes are
+    /// saved before they are acknowledged, so a token from an earlier
+    /// epoch is always satisfied.
+    pub fn has_applied(&self, token: WriteToken) -> bool {
+        token.epoch != self.epoch || token.writes <= self.index.writes()
+    }
+
+    /// Whether `token` was issued by this instance of the collection,
+    /// rather than one it replaced.
+    pub fn issued(&self, token: WriteToken) -> bool {
+        token.epoch == self.epoch
+    }
+}
+
+#[derive(Debug)]
+pub enum CollectionError {
+    InvalidName(String),
+    InvalidConfig(String),
+    NotFound(String),
+    AlreadyExists(String),
+    /// The collection is not in a state that allows the request, e.g. an
+    /// alias still points at it.
+    Precondition(String),
+    /// Outside the workspace the request was made in.
+    NotAllowed(String),
+    /// A document was not at the version a write required.
+    Conflict(String),
+    Io(io::Error),
+}
+
+impl std::fmt::Display for CollectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CollectionError::InvalidName(name) => write!(f, "invalid collection name: {name:?}"),
+            CollectionError::InvalidConfig(msg) => write!(f, "{msg}"),
+            CollectionError::NotFound(msg) => write!(f, "{msg}"),
+            CollectionError::AlreadyExists(name) => write!(f, "{name} already exists"),
+            CollectionError::Precondition(msg) => write!(f, "{msg}"),
+            CollectionError::NotAllowed(msg) => write!(f, "{msg}"),
+            CollectionError::Conflict(msg) => write!(f, "{msg}"),
+            CollectionError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for CollectionError {}
+
+impl From<io::Error> for CollectionError {
+    fn from(e: io::Error) -> Self {
+        CollectionError::Io(e)
+    }
+}
+
+/// What a snapshot holds.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SnapshotInfo {
+    pub collections: usize,
+    pub entries: usize,
+}
+
+/// A collection's entries saved as they were at one moment, which stay
+/// queryable while the collection moves on.
+#[derive(Clone, Debug)]
+pub struct CollectionSnapshot {
+    /// `<collection>@<created_at>`, naming the snapshot file.
+    pub id: String,
+    pub collection: String,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: i64,
+    pub entries: usize,
+    pub embedder: String,
+}
+
+impl CollectionSnapshot {
+    fn read(dir: &Path, id: &str) -> Result<Self, CollectionError> {
+        let not_found = || CollectionError::NotFound(format!("no snapshot {id}"));
+        let (collection, created_at) = id.rsplit_once('@').ok_or_else(not_found)?;
+        let created_at: i64 = created_at.parse().map_err(|_| not_found())?;
+        if !valid_name(collection) {
+            return Err(not_found());
+        }
+        let header = match indexfile::read_header(&dir.join(format!("{id}.idx"))) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(not_found()),
+            header => header?,
+        };
+        Ok(CollectionSnapshot {
+            id: id.to_string(),
+            collection: collection.to_string(),
+            created_at,
+            entries: header.count,
+            embedder: header.embedder,
+        })
+    }
+}
+
+/// What opening a collection snapshot takes, gathered under the
+/// collections lock so the slow part can run outside it.
+pub struct SnapshotSource {
+    path: PathBuf,
+    embedder: Arc<dyn Embedder>,
+    hnsw: HnswParams,
+    metric: Metric,
+}
+
+impl SnapshotSource {
+    /// Reads the snapshot into an index of its own.
+    pub fn open(self) -> io::Result<VectorIndex> {
+        VectorIndex::load(&self.path, self.embedder, self.hnsw, self.metric)
+    }
+}
+
+pub struct Collections {
+    dir: PathBuf,
+    embedders: Embedders,
+    collections: BTreeMap<String, Collection>,
+    /// Alias -> collection name.
+    aliases: BTreeMap<String, String>,
+}
+
+/// Names become file names next to the manifests, so theirs are reserved.
+fn valid_name(name: &str) -> bool {
+    !matches!(name, "collections" | "aliases")
+        && !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+}
+
+impl Collections {
+    /// Opens the collections under `dir`, each with its embedder from
+    /// `embedders`. An index saved by earlier versions as a single `legacy`
+    /// file becomes the default collection.
+    pub fn open(dir: impl Into<PathBuf>, legacy: &Path, embedders: Embedders) -> io::Result<Self> {
+        let dir = dir.into();
+        let default_path = dir.join(format!("{DEFAULT}.idx"));
+        let default_json = default_path.with_extension("json");
+        if legacy.is_file() && !default_path.exists() && !default_json.exists() {
+            // Imported like any other JSON index once the default opens.
+            std::fs::create_dir_all(&dir)?;
+            std::fs::rename(legacy, &default_json)?;
+        }
+        Self::load(dir, embedders, false)
+    }
+
+    /// Reads the collections under `dir`. Those of a `snapshot` are read
+    /// from their index files whatever their storage.
+    fn load(dir: PathBuf, embedders: Embedders, snapshot: bool) -> io::Result<Self> {
+        let mut configs: BTreeMap<String, CollectionConfig> =
+            read_json(&dir.join("collections.json"))?;
+        let aliases = read_json(&dir.join("aliases.json"))?;
+        let mut migrated = false;
+        for config in configs.values_mut().filter(|c| c.version == 0) {
+            if config.metric == "dot" {
+                config.metric = Metric::Cosine.name().into();
+            }
+            config.version = CONFIG_VERSION;
+            migrated = true;
+        }
+        if migrated {
+            write_json(&dir.join("collections.json"), &configs)?;
+        }
+        configs
+            .entry(DEFAULT.to_string())
+            .or_insert_with(|| CollectionConfig {
+                embedder: embedders.default_name().to_string(),
+                ..CollectionConfig::default()
+            });
+        let mut collections = BTreeMap::new();
+        for (name, config) in configs {
+            let storage = match snapshot {
+                true => STORAGE[0],
+                false => config.storage.as_str(),
+            };
+            let index = open_index(&dir, &embedders, &name, &config, storage)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+            collections.insert(name, Collection::new(config, index));
+        }
+        Ok(Collections {
+            dir,
+            embedders,
+            collections,
+            aliases,
+        })
+    }
+
+    /// The collection a name refers to: empty means the default one, and
+    /// an alias means its current target.
+    pub fn resolve<'a>(&'a self, name: &'a str) -> &'a str {
+        if name.is_empty() {
+            return DEFAULT;
+        }
+        self.aliases.get(name).map_or(name, String::as_str)
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Collection, CollectionError> {
+        let name = self.resolve(name);
+        self.collections
+            .get(name)
+            .ok_or_else(|| CollectionError::NotFound(format!("no collection named {name}")))
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Result<&mut Collection, CollectionError> {
+        let name = self.resolve(name).to_string();
+        self.collections
+            .get_mut(&name)
+            .ok_or_else(|| CollectionError::NotFound(format!("no collection named {name}")))
+    }
+
+    /// Aliases pointing at `name`.
+    pub fn aliases_of(&self, name: &str) -> Vec<String> {
+        self.aliases
+            .iter()
+            .filter(|(_, target)| *target == name)
+            .map(|(alias, _)| alias.clone())
+            .collect()
+    }
+
+    /// Points `alias` at `target`, creating or moving it. Readers switch
+    /// to the new target with their next request. Returns the previous
+    /// target, if any.
+    pub fn set_alias(
+        &mut self,
+        alias: &str,
+        target: &str,
+    ) -> Result<Option<String>, CollectionError> {
+        if !valid_name(alias) {
+            return Err(CollectionError::InvalidName(alias.to_string()));
+        }
+        if self.collections.contains_key(alias) {
+            return Err(CollectionError::AlreadyExists(format!(
+                "collection {alias}"
+            )));
+        }
+        if !self.collections.contains_key(target) {
+            return Err(CollectionError::NotFound(format!(
+                "no collection named {target}"
+            )));
+        }
+        let previous = self.aliases.insert(alias.to_string(), target.to_string());
+        self.save_aliases()?;
+        Ok(previous)
+    }
+
+    pub fn remove_alias(&mut self, alias: &str) -> Result<(), CollectionError> {
+        if self.aliases.remove(alias).is_none() {
+            return Err(CollectionError::NotFound(format!("no alias named {alias}")));
+        }
+        self.save_aliases()
+    }
+
+    pub fn list(&self) -> impl Iterator<Item = (&String, &Collection)> {
+        self.collections.iter()
+    }
+
+    /// The embedders new collections and reindexes can use.
+    pub fn embedders(&self) -> &Embedders {
+        &self.embedders
+    }
+
+    /// The embedder named `name`, or the default for new collections.
+    pub fn embedder(&self, name: &str) -> Result<Arc<dyn Embedder>, CollectionError> {
+        let name = match name {
+            "" => self.embedders.default_name(),
+            name => name,
+        };
+        self.embedders.get(name).ok_or_else(|| {
+            CollectionError::InvalidConfig(format!(
+                "unknown embedder {name:?}; available: {}",
+                self.embedders.names().join(", ")
+            ))
+        })
+    }
+
+    /// Moves collection `name` to `embedder`, with the embeddings in
+    /// `made` (see [`VectorIndex::replace_embedder`]), and records it in
+    /// the collection's settings. Returns how many entries were embedded
+    /// here rather than ahead.
+    pub fn replace_embedder(
+        &mut self,
+        name: &str,
+        embedder: Arc<dyn Embedder>,
+        made: HashMap<String, (u64, Vec<f32>)>,
+    ) -> Result<usize, CollectionError> {
+        let collection = self.get_mut(name)?;
+        let name = embedder.name().to_string();
+        let embedded = collection.index.replace_embedder(embedder, made)?;
+        collection.config.embedder = name;
+        self.save_manifest()?;
+        Ok(embedded)
+    }
+
+    /// Moves collection `name` to `embedder` with `new` as
+    /// [`VectorIndex::switch_embedder`] does, recording it in the
+    /// collection's settings if it did. Returns whether it did.
+    pub fn switch_embedder(
+        &mut self,
+        name: &str,
+        embedder: Arc<dyn Embedder>,
+        new: Reembedded,
+    ) -> Result<bool, CollectionError> {
+        let collection = self.get_mut(name)?;
+        let name = embedder.name().to_string();
+        if !collection.index.switch_embedder(embedder, new)? {
+            return Ok(false);
+        }
+        collection.config.embedder = name;
+        self.save_manifest()?;
+        Ok(true)
+    }
+
+    pub fn create(
+        &mut self,
+        name: &str,
+        mut config: CollectionConfig,
+    ) -> Result<(), CollectionError> {
+        if !valid_name(name) {
+            return Err(CollectionError::InvalidName(name.to_string()));
+        }
+        if self.collections.contains_key(name) {
+            return Err(CollectionError::AlreadyExists(format!("collection {name}")));
+        }
+        if self.aliases.contains_key(name) {
+            return Err(CollectionError::AlreadyExists(format!("alias {name}")));
+        }
+        if config.embedder.is_empty() {
+            config.embedder = self.embedders.default_name().to_string();
+        }
+        let index = open_index(&self.dir, &self.embedders, name, &config, &config.storage)?;
+        self.collections
+            .insert(name.to_string(), Collection::new(config, index));
+        self.save_manifest()
+    }
+
+    /// Removes a collection and its documents. The default one cannot be dropped.
+    pub fn drop_collection(&mut self, name: &str) -> Result<(), CollectionError> {
+        if name == DEFAULT {
+            return Err(CollectionError::InvalidConfig(
+                "the default collection cannot be dropped".into(),
+            ));
+        }
+        if !self.collections.contains_key(name) {
+            return Err(CollectionError::NotFound(format!(
+                "no collection named {name}"
+            )));
+        }
+        if let Some(alias) = self.aliases_of(name).first() {
+            return Err(CollectionError::Precondition(format!(
+                "collection {name} is the target of alias {alias}"
+            )));
+        }
+        self.collections.remove(name);
+        self.save_manifest()?;
+        self.remove_files(name, "")?;
+        Ok(())
+    }
+
+    /// Writes every collection, its settings and the aliases to `target`,
+    /// a directory that must not exist yet. The caller keeps writes out
+    /// while this runs, so the copy reflects a single point in time. It is
+    /// written under `<target>.partial` and renamed once complete.
+    pub fn snapshot(&self, target: &Path) -> Result<SnapshotInfo, CollectionError> {
+        if target.exists() {
+            return Err(CollectionError::AlreadyExists(target.display().to_string()));
+        }
+        let mut partial = target.as_os_str().to_owned();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+        if partial.exists() {
+            // Left behind by a snapshot that did not finish.
+            std::fs::remove_dir_all(&partial)?;
+        }
+        std::fs::create_dir_all(&partial)?;
+        let mut info = SnapshotInfo::default();
+        for (name, collection) in &self.collections {
+            collection
+                .index
+                .save_as(&partial.join(format!("{name}.idx")))?;
+            info.collections += 1;
+            info.entries += collection.index.len();
+        }
+        write_json(&partial.join("collections.json"), &self.configs())?;
+        write_json(&partial.join("aliases.json"), &self.aliases)?;
+        std::fs::rename(&partial, target)?;
+        Ok(info)
+    }
+
+    /// Replaces every collection and alias with those of the snapshot at
+    /// `source`. The snapshot is read in full before anything is replaced;
+    /// a crash while its files are copied in can leave a mix of old and
+    /// restored collections, so restore again after one.
+    pub fn restore(&mut self, source: &Path) -> Result<SnapshotInfo, CollectionError> {
+        if !source.join("collections.json").is_file() {
+            return Err(CollectionError::NotFound(format!(
+                "no snapshot at {}",
+                source.display()
+            )));
+        }
+        let snapshot = Collections::load(source.to_path_buf(), self.embedders.clone(), true)?;
+        // A store takes one handle at a time: those open here are closed
+        // before theirs is replaced, and everything is reopened from the
+        // restored files, whether or not all of them were written, so
+        // later writes go there.
+        let current: Vec<String> = self.collections.keys().cloned().collect();
+        self.collections.clear();
+        let restored = self.restore_from(&snapshot, &current);
+        *self = Collections::load(self.dir.clone(), self.embedders.clone(), false)?;
+        restored
+    }
+
+    fn restore_from(
+        &self,
+        snapshot: &Collections,
+        current: &[String],
+    ) -> Result<SnapshotInfo, CollectionError> {
+        let mut info = SnapshotInfo::default();
+        for (name, collection) in &snapshot.collections {
+            match collection.config.storage.as_str() {
+                "" | "index" => {
+                    collection
+                        .index
+                        .save_as(&self.dir.join(format!("{name}.idx")))?;
+                    self.remove_files(name, "index")?;
+                }
+                storage => {
+                    self.remove_files(name, "")?;
+                    let mut store = open_store(&self.dir, name, storage)?;
+                    store.put(&collection.index.entries(0, collection.index.len(), true))?;
+                }
+            }
+            info.collections += 1;
+            info.entries += collection.index.len();
+        }
+        write_json(&self.dir.join("collections.json"), &snapshot.configs())?;
+        write_json(&self.dir.join("aliases.json"), &snapshot.aliases)?;
+        for name in current {
+            if !snapshot.collections.contains_key(name) {
+                self.remove_files(name, "")?;
+            }
+        }
+        Ok(info)
+    }
+
+    fn snapshots_dir(&self) -> PathBuf {
+        self.dir.join("snapshots")
+    }
+
+    /// Saves collection `name` as it is now. The caller keeps writes to it
+    /// out while this runs.
+    pub fn create_snapshot(&self, name: &str) -> Result<CollectionSnapshot, CollectionError> {
+        let collection = self.get(name)?;
+        let name = self.resolve(name);
+        let dir = self.snapshots_dir();
+        let mut created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64);
+        // Ids are unique; two snapshots in one millisecond get the next.
+        while dir.join(format!("{name}@{created_at}.idx")).exists() {
+            created_at += 1;
+        }
+        let id = format!("{name}@{created_at}");
+        collection.index.save_as(&dir.join(format!("{id}.idx")))?;
+        Ok(CollectionSnapshot {
+            id,
+            collection: name.to_string(),
+            created_at,
+            entries: collection.index.len(),
+            embedder: collection.index.embedder().name().to_string(),
+        })
+    }
+
+    /// Snapshots of collection `name`, or of every collection if it is
+    /// empty, oldest first. Those of dropped collections are kept.
+    pub fn snapshots(&self, name: &str) -> Result<Vec<CollectionSnapshot>, CollectionError> {
+        let name = match name {
+            "" => None,
+            name => Some(self.resolve(name)),
+        };
+        let entries = match std::fs::read_dir(self.snapshots_dir()) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut snapshots = Vec::new();
+        for entry in entries {
+            let file_name = entry?.file_name();
+            let Some(id) = file_name.to_str().and_then(|f| f.strip_suffix(".idx")) else {
+                continue;
+            };
+            match CollectionSnapshot::read(&self.snapshots_dir(), id) {
+                Ok(snapshot) if name.is_none_or(|n| n == snapshot.collection) => {
+                    snapshots.push(snapshot)
+                }
+                Ok(_) | Err(CollectionError::NotFound(_)) => {}
+                Err(e) => log::warn!("skipping snapshot {id}: {e}"),
+            }
+        }
+        snapshots.sort_by(|a, b| (&a.collection, a.created_at).cmp(&(&b.collection, b.created_at)));
+        Ok(snapshots)
+    }
+
+    /// How to open snapshot `id`. It is embedded with its collection's
+    /// current embedder, or its own if the collection is gone.
+    pub fn snapshot_source(&self, id: &str) -> Result<SnapshotSource, CollectionError> {
+        let dir = self.snapshots_dir();
+        let snapshot = CollectionSnapshot::read(&dir, id)?;
+        let (embedder, hnsw, metric) = match self.collections.get(&snapshot.collection) {
+            Some(c) => (
+                Arc::clone(c.index.embedder()),
+                c.config.hnsw,
+                c.index.metric(),
+            ),
+            None => {
+                let embedder = self.embedders.get(&snapshot.embedder).ok_or_else(|| {
+                    CollectionError::Precondition(format!(
+                        "snapshot {id} needs embedder {:?}, which is not available",
+                        snapshot.embedder
+                    ))
+                })?;
+                (embedder, HnswParams::default(), Metric::default())
+            }
+        };
+        Ok(SnapshotSource {
+            path: dir.join(format!("{id}.idx")),
+            embedder,
+            hnsw,
+            metric,
+        })
+    }
+
+    pub fn delete_snapshot(&self, id: &str) -> Result<(), CollectionError> {
+        let snapshot = CollectionSnapshot::read(&self.snapshots_dir(), id)?;
+        remove_file(&self.snapshots_dir().join(format!("{}.idx", snapshot.id)))?;
+        Ok(())
+    }
+
+    /// Removes collection `name`'s index file and log, and its stores, but
+    /// for the files of `storage`; empty removes them all.
+    fn remove_files(&self, name: &str, storage: &str) -> io::Result<()> {
+        if storage != "index" {
+            for ext in ["idx", "wal"] {
+                remove_file(&self.dir.join(format!("{name}.{ext}")))?;
+            }
+        }
+        for store in STORAGE[1..].iter().filter(|s| **s != storage) {
+            match std::fs::remove_dir_all(self.dir.join(format!("{name}.{store}"))) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn configs(&self) -> BTreeMap<&String, &CollectionConfig> {
+        self.collections
+            .iter()
+            .map(|(name, c)| (name, &c.config))
+            .collect()
+    }
+
+    fn save_manifest(&self) -> Result<(), CollectionError> {
+        write_json(&self.dir.join("collections.json"), &self.configs())?;
+        Ok(())
+    }
+
+    fn save_aliases(&self) -> Result<(), CollectionError> {
+        write_json(&self.dir.join("aliases.json"), &self.aliases)?;
+        Ok(())
+    }
+}
+
+/// Opens collection `name`'s index, kept in `storage`, with the embedder
+/// its config names.
+fn open_index(
+    dir: &Path,
+    embedders: &Embedders,
+    name: &str,
+    config: &CollectionConfig,
+    storage: &str,
+) -> Result<VectorIndex, CollectionError> {
+    let Some(embedder) = embedders.get(&config.embedder) else {
+        return Err(CollectionError::InvalidConfig(format!(
+            "collection {name} needs embedder {:?}, which is not available; available: {}",
+            config.embedder,
+            embedders.names().join(", ")
+        )));
+    };
+    let Some(metric) = Metric::parse(&config.metric) else {
+        return Err(CollectionError::InvalidConfig(format!(
+            "collection {name} has unknown metric {:?}",
+            config.metric
+        )));
+    };
+    let Some(quantization) = Quantization::parse(&config.quantization) else {
+        return Err(CollectionError::InvalidConfig(format!(
+            "collection {name} has unknown quantization {:?}",
+            config.quantization
+        )));
+    };
+    let mut index = match storage {
+        "" | "index" => VectorIndex::open(dir.join(format!("{name}.idx")), embedder)?,
+        storage => VectorIndex::with_store(open_store(dir, name, storage)?, embedder)?,
+    };
+    index.set_graph(config.hnsw, metric);
+    index.set_quantization(quantization)?;
+    Ok(index)
+}
+
+/// Opens collection `name`'s store of kind `storage`, at
+/// `<dir>/<name>.<storage>`.
+#[cfg_attr(not(feature = "rocksdb"), allow(unused_variables))]
+fn open_store(dir: &Path, name: &str, storage: &str) -> Result<Box<dyn DocStore>, CollectionError> {
+    let path = dir.join(format!("{name}.{storage}"));
+    match storage {
+        #[cfg(feature = "rocksdb")]
+        "rocksdb" => Ok(Box::new(crate::store::RocksStore::open(&path)?)),
+        storage => Err(CollectionError::InvalidConfig(format!(
+            "collection {name} is kept in {storage:?} storage, which this server was not built with"
+        ))),
+    }
+}
+
+/// Removes `path` if it exists.
+fn remove_file(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn read_json<T: serde::de::DeserializeOwned + Default>(path: &Path) -> io::Result<T> {
+    match std::fs::read(path) {
+        Ok(data) => {
+            serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes via a temporary file and a rename, so a crash leaves either the
+/// old or the new contents.
+fn write_json(path: &Path, value: &impl Serialize) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(value)?)?;
+    std::fs::rename(tmp, path)
+}
@@ -0,0 +1,232 @@
+//! Compression of stored entry text. A saved index keeps each entry's
+//! text as a zstd frame, compressed with a dictionary trained on the
+//! collection's own texts. Chunks are short and alike, so a shared
+//! dictionary does far better on them than compressing each on its own.
+//! Texts read from a file stay compressed in memory and are decompressed
+//! only where they are used: for hits, exports, keyword indexing and
+//! re-embedding.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use zstd::bulk::{Compressor, Decompressor};
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+/// zstd level texts are compressed at.
+const LEVEL: i32 = 3;
+/// Fewest texts a dictionary is trained on; smaller indexes compress each
+/// text on its own.
+const MIN_TRAINING_TEXTS: usize = 256;
+/// Bytes of text a dictionary is trained on, about: every so many entry
+/// is sampled so the sample spans the whole index.
+const TRAINING_BYTES: usize = 4 << 20;
+/// Bytes of one text a dictionary is trained on.
+const MAX_SAMPLE_BYTES: usize = 64 << 10;
+const MAX_DICTIONARY_BYTES: usize = 64 << 10;
+
+/// A zstd dictionary trained on a collection's texts, ready to compress
+/// and decompress with.
+pub struct Dictionary {
+    raw: Vec<u8>,
+    encoder: EncoderDictionary<'static>,
+    decoder: DecoderDictionary<'static>,
+}
+
+impl Dictionary {
+    pub fn new(raw: Vec<u8>) -> Self {
+        Dictionary {
+            encoder: EncoderDictionary::copy(&raw, LEVEL),
+            decoder: DecoderDictionary::copy(&raw),
+            raw,
+        }
+    }
+
+    /// The dictionary as saved.
+    pub fn bytes(&self) -> &[u8] {
+        &self.raw
+    }
+
+    /// A dictionary trained on a sample of `texts`, or `None` if there are
+    /// too few of them or zstd cannot train one on them.
+    pub fn train(texts: &[&StoredText]) -> Option<Arc<Self>> {
+        if texts.len() < MIN_TRAINING_TEXTS {
+            return None;
+        }
+        let total: usize = texts.iter().map(|t| t.len()).sum();
+        let samples: Vec<Vec<u8>> = texts
+            .iter()
+            .step_by((total / TRAINING_BYTES).max(1))
+            .map(|text| {
+                let mut sample = text.get().into_owned().into_bytes();
+                sample.truncate(MAX_SAMPLE_BYTES);
+                sample
+            })
+            .collect();
+        let sampled: usize = samples.iter().map(Vec::len).sum();
+        let raw = zstd::dict::from_samples(&samples, (sampled / 16).min(MAX_DICTIONARY_BYTES));
+        raw.ok().map(|raw| Arc::new(Dictionary::new(raw)))
+    }
+}
+
+impl fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Dictionary({} bytes)", self.raw.len())
+    }
+}
+
+/// An entry's text: as written, or as a zstd frame read from a saved
+/// index. Serializes as the text itself.
+#[derive(Clone)]
+pub struct StoredText(Repr);
+
+#[derive(Clone)]
+enum Repr {
+    Plain(String),
+    Packed {
+        frame: Arc<[u8]>,
+        /// Bytes of the text.
+        len: usize,
+        dictionary: Option<Arc<Dictionary>>,
+    },
+}
+
+impl StoredText {
+    /// The text compressed as `frame`, `len` bytes long, with
+    /// `dictionary` if it was compressed with one.
+    pub fn packed(frame: &[u8], len: usize, dictionary: Option<&Arc<Dictionary>>) -> Self {
+        if len == 0 {
+            return StoredText::default();
+        }
+        StoredText(Repr::Packed {
+            frame: frame.into(),
+            len,
+            dictionary: dictionary.cloned(),
+        })
+    }
+
+    /// The text, decompressed if need be. A frame that cannot be
+    /// decompressed, which only a damaged file holds, reads as empty.
+    pub fn get(&self) -> Cow<'_, str> {
+        let (frame, len, dictionary) = match &self.0 {
+            Repr::Plain(text) => return Cow::Borrowed(text),
+            Repr::Packed {
+                frame,
+                len,
+                dictionary,
+            } => (frame, len, dictionary),
+        };
+        let decompressor = match dictionary {
+            Some(dictionary) => Decompressor::with_prepared_dictionary(&dictionary.decoder),
+            None => Decompressor::new(),
+        };
+        let text = decompressor
+            .and_then(|mut d| d.decompress(frame, *len))
+            .and_then(|bytes| String::from_utf8(bytes).map_err(io::Error::other));
+        match text {
+            Ok(text) => Cow::Owned(text),
+            Err(e) => {
+                log::error!("decompressing a stored text failed: {e}");
+                Cow::Borrowed("")
+            }
+        }
+    }
+
+    /// Bytes of the text, without decompressing it.
+    pub fn len(&self) -> usize {
+        match &self.0 {
+            Repr::Plain(text) => text.len(),
+            Repr::Packed { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn into_string(self) -> String {
+        match self.0 {
+            Repr::Plain(text) => text,
+            Repr::Packed { .. } => self.get().into_owned(),
+        }
+    }
+
+    /// The dictionary it was compressed with, if any.
+    pub fn dictionary(&self) -> Option<&Arc<Dictionary>> {
+        match &self.0 {
+            Repr::Packed { dictionary, .. } => dictionary.as_ref(),
+            Repr::Plain(_) => None,
+        }
+    }
+}
+
+impl Default for StoredText {
+    fn default() -> Self {
+        StoredText(Repr::Plain(String::new()))
+    }
+}
+
+impl From<String> for StoredText {
+    fn from(text: String) -> Self {
+        StoredText(Repr::Plain(text))
+    }
+}
+
+impl fmt::Debug for StoredText {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.get(), f)
+    }
+}
+
+impl Serialize for StoredText {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.get())
+    }
+}
+
+impl<'de> Deserialize<'de> for StoredText {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(StoredText::from)
+    }
+}
+
+/// Compresses texts for saving, with one dictionary throughout.
+pub struct Packer<'a> {
+    dictionary: Option<&'a Arc<Dictionary>>,
+    compressor: Compressor<'a>,
+}
+
+impl<'a> Packer<'a> {
+    pub fn new(dictionary: Option<&'a Arc<Dictionary>>) -> io::Result<Self> {
+        let compressor = match dictionary {
+            Some(dictionary) => Compressor::with_prepared_dictionary(&dictionary.encoder)?,
+            None => Compressor::new(LEVEL)?,
+        };
+        Ok(Packer {
+            dictionary,
+            compressor,
+        })
+    }
+
+    /// `text` as a zstd frame. A text already compressed with this
+    /// packer's dictionary is passed through as it is.
+    pub fn frame<'t>(&mut self, text: &'t StoredText) -> io::Result<Cow<'t, [u8]>> {
+        if let Repr::Packed {
+            frame, dictionary, ..
+        } = &text.0
+        {
+            let same = match (dictionary, self.dictionary) {
+                (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+                (None, None) => true,
+                _ => false,
+            };
+            if same {
+                return Ok(Cow::Borrowed(frame));
+            }
+        }
+        self.compressor
+            .compress(text.get().as_bytes())
+            .map(Cow::Owned)
+    }
+}
@@ -0,0 +1,552 @@
+//! Connector SDK: data sources plug into the runtime by implementing
+//! [`Connector`] and being registered in a [`ConnectorRegistry`].
+
+use crate::assistant::Document;
+use crate::collection;
+use crate::policy::{self, Breaker, ToolPolicy};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Most cached tool results kept at once, across all tools.
+const MAX_CACHED_RESULTS: usize = 1024;
+
+/// A tool a connector exposes to the assistant.
+#[derive(Clone, Debug)]
+pub struct ToolSpec {
+    pub name: String,
+    pub description: String,
+    /// JSON Schema of the call's `args`.
+    pub parameters: Value,
+    /// True when calling the tool changes state outside the runtime.
+    pub destructive: bool,
+    /// True when every call must pass `"consent": true`, given only after
+    /// asking the user.
+    pub requires_consent: bool,
+    /// How long a result stays valid for the same arguments. `None` for
+    /// tools whose output is not a function of their arguments.
+    pub cache_ttl: Option<Duration>,
+}
+
+impl ToolSpec {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "description": self.description,
+            "parameters": self.parameters,
+            "destructive": self.destructive,
+            "requires_consent": self.requires_consent,
+            "cache_ttl_secs": self.cache_ttl.map(|ttl| ttl.as_secs()),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Health {
+    Ok,
+    Degraded(String),
+    Down(String),
+}
+
+impl Health {
+    pub fn to_json(&self) -> Value {
+        match self {
+            Health::Ok => json!({ "state": "ok" }),
+            Health::Degraded(reason) => json!({ "state": "degraded", "reason": reason }),
+            Health::Down(reason) => json!({ "state": "down", "reason": reason }),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SyncReport {
+    /// Items fetched from the source during this sync.
+    pub fetched: usize,
+    /// Items that were new or changed since the previous sync.
+    pub updated: usize,
+    /// Those items as documents, for connectors whose data the assistant
+    /// should be able to retrieve. The registry passes them to its
+    /// [`DocumentSink`] and leaves this empty.
+    pub documents: Vec<Document>,
+}
+
+/// What a connector's syncs have done since the server started.
+#[derive(Clone, Debug, Default)]
+pub struct SyncStatus {
+    /// When the last sync finished, whether or not it succeeded.
+    pub last_sync: Option<DateTime<Utc>>,
+    /// When the last successful sync finished.
+    pub last_success: Option<DateTime<Utc>>,
+    /// Items fetched and updated by the last successful sync.
+    pub fetched: usize,
+    pub updated: usize,
+    /// Items updated by every successful sync together.
+    pub total_updated: u64,
+    pub syncs: u64,
+    pub failures: u64,
+    /// Why the last sync failed; `None` if it succeeded.
+    pub last_error: Option<String>,
+}
+
+impl SyncStatus {
+    pub fn to_json(&self) -> Value {
+        let time =
+            |t: &Option<DateTime<Utc>>| t.map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true));
+        json!({
+            "last_sync": time(&self.last_sync),
+            "last_success": time(&self.last_success),
+            "fetched": self.fetched,
+            "updated": self.updated,
+            "total_updated": self.total_updated,
+            "syncs": self.syncs,
+            "failures": self.failures,
+            "last_error": self.last_error,
+        })
+    }
+}
+
+/// Where a registry indexes the documents its connectors sync.
+#[tonic::async_trait]
+pub trait DocumentSink: Send + Sync {
+    /// Indexes `docs` from `connector` into `collection`.
+    async fn index(
+        &self,
+        connector: &str,
+        collection: &str,
+        docs: Vec<Document>,
+    ) -> Result<(), String>;
+}
+
+#[derive(Debug)]
+pub enum ConnectorError {
+    InvalidConfig(String),
+    UnknownTool(String),
+    InvalidArgs(String),
+    /// The call needs explicit user consent that was not given.
+    ConsentRequired(String),
+    /// The request's profile does not allow the tool.
+    NotAllowed(String),
+    Failed(String),
+    /// The call took longer than its policy allows.
+    Timeout(String),
+    /// The tool's circuit breaker is open.
+    Unavailable(String),
+}
+
+impl ConnectorError {
+    /// HTTP-style status used in `Response.status`.
+    pub fn status(&self) -> i32 {
+        match self {
+            ConnectorError::InvalidConfig(_) | ConnectorError::InvalidArgs(_) => 400,
+            ConnectorError::ConsentRequired(_) | ConnectorError::NotAllowed(_) => 403,
+            ConnectorError::UnknownTool(_) => 404,
+            ConnectorError::Failed(_) => 502,
+            ConnectorError::Unavailable(_) => 503,
+            ConnectorError::Timeout(_) => 504,
+        }
+    }
+}
+
+impl fmt::Display for ConnectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectorError::InvalidConfig(msg) => write!(f, "invalid config: {msg}"),
+            ConnectorError::UnknownTool(name) => write!(f, "unknown tool: {name}"),
+            ConnectorError::InvalidArgs(msg) => write!(f, "invalid arguments: {msg}"),
+            ConnectorError::ConsentRequired(tool) => write!(f, "{tool} requires user consent"),
+            ConnectorError::NotAllowed(tool) => write!(f, "{tool} is not allowed in this profile"),
+            ConnectorError::Failed(msg) => write!(f, "{msg}"),
+            ConnectorError::Timeout(tool) => write!(f, "{tool} timed out"),
+            ConnectorError::Unavailable(tool) => {
+                write!(f, "{tool} is failing; circuit open, try again later")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConnectorError {}
+
+#[tonic::async_trait]
+pub trait Connector: Send + Sync {
+    /// Stable name used in config files and requests.
+    fn name(&self) -> &str;
+
+    /// Applies the connector's section of the connectors config.
+    /// Called once, before the connector is registered.
+    fn configure(&mut self, config: &Value) -> Result<(), ConnectorError>;
+
+    /// How often the runtime should call [`Connector::sync`] on its own.
+    /// `None` means only on request.
+    fn sync_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Pulls new data from the source.
+    async fn sync(&self) -> Result<SyncReport, ConnectorError>;
+
+    fn list_tools(&self) -> Vec<ToolSpec>;
+
+    async fn call_tool(&self, tool: &str, args: Value) -> Result<Value, ConnectorError>;
+
+    async fn health(&self) -> Health;
+}
+
+/// Builds an unconfigured connector. Each connector crate exports one.
+pub type ConnectorFactory = fn() -> Box<dyn Connector>;
+
+/// Connectors compiled into this binary, by config name.
+pub fn builtin_factories() -> Vec<(&'static str, ConnectorFactory)> {
+    vec![
+        ("clipboard", crate::clipboard::ClipboardConnector::boxed),
+        ("git", crate::git::GitConnector::boxed),
+        ("patch", crate::patch::PatchConnector::boxed),
+        ("rss", crate::rss::RssConnector::boxed),
+    ]
+}
+
+/// Results of cacheable tool calls, keyed by tool name and arguments.
+#[derive(Default)]
+struct ToolCache {
+    entries: Mutex<HashMap<(String, String), (Instant, Value)>>,
+}
+
+impl ToolCache {
+    fn get(&self, key: &(String, String)) -> Option<Value> {
+        let entries = self.entries.lock().unwrap();
+        let (expires, value) = entries.get(key)?;
+        (*expires > Instant::now()).then(|| value.clone())
+    }
+
+    fn put(&self, key: (String, String), value: Value, ttl: Duration) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_CACHED_RESULTS {
+            let now = Instant::now();
+            entries.retain(|_, (expires, _)| *expires > now);
+            if entries.len() >= MAX_CACHED_RESULTS {
+                return;
+            }
+        }
+        entries.insert(key, (Instant::now() + ttl, value));
+    }
+
+    fn invalidate(&self, tools: &[String]) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|(tool, _), _| !tools.contains(tool));
+    }
+}
+
+#[derive(Default)]
+pub struct ConnectorRegistry {
+    connectors: BTreeMap<String, Box<dyn Connector>>,
+    /// Tool name -> owning connector name.
+    tools: BTreeMap<String, String>,
+    /// Tool name -> cache TTL, for tools whose results are cached.
+    cache_ttls: BTreeMap<String, Duration>,
+    cache: ToolCache,
+    policies: BTreeMap<String, ToolPolicy>,
+    breakers: Mutex<HashMap<String, Breaker>>,
+    /// Connector name -> collection its synced documents go to.
+    collections: BTreeMap<String, String>,
+    sink: OnceLock<Arc<dyn DocumentSink>>,
+    /// Connector name -> what its syncs have done.
+    statuses: Mutex<HashMap<String, SyncStatus>>,
+}
+
+impl ConnectorRegistry {
+    /// Builds a registry from a JSON object mapping connector names to
+    /// their config, e.g. `{"rss": {"feeds": [...]}}`.
+    pub fn from_config(
+        config: &Value,
+        factories: &[(&'static str, ConnectorFactory)],
+    ) -> Result<Self, ConnectorError> {
+        let mut registry = Self::default();
+        let Some(entries) = config.as_object() else {
+            return Err(ConnectorError::InvalidConfig(
+                "expected an object of connectors".into(),
+            ));
+        };
+        for (name, section) in entries {
+            let factory = factories
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, f)| f)
+                .ok_or_else(|| {
+                    ConnectorError::InvalidConfig(format!("no connector named {name}"))
+                })?;
+            registry.register(factory(), section)?;
+        }
+        Ok(registry)
+    }
+
+    /// Registers a configured connector. Its section may set
+    /// `"cache_ttl_secs": {"<tool>": secs}` to override a tool's cache
+    /// TTL; 0 turns caching off for that tool,
+    /// `"policies": {"<tool>": {...}}` to set its [`ToolPolicy`], and
+    /// `"collection"` to index its synced documents somewhere other than
+    /// the default collection.
+    pub fn register(
+        &mut self,
+        mut connector: Box<dyn Connector>,
+        config: &Value,
+    ) -> Result<(), ConnectorError> {
+        connector.configure(config)?;
+        let name = connector.name().to_string();
+        if self.connectors.contains_key(&name) {
+            return Err(ConnectorError::InvalidConfig(format!(
+                "connector {name} registered twice"
+            )));
+        }
+        for tool in connector.list_tools() {
+            if let Some(owner) = self.tools.get(&tool.name) {
+                return Err(ConnectorError::InvalidConfig(format!(
+                    "tool {} is provided by both {} and {}",
+                    tool.name, owner, name
+                )));
+            }
+            let ttl = match config["cache_ttl_secs"][&tool.name].as_u64() {
+                Some(secs) => Some(Duration::from_secs(secs)),
+                None => tool.cache_ttl,
+            };
+            if let Some(ttl) = ttl.filter(|ttl| !ttl.is_zero()) {
+                self.cache_ttls.insert(tool.name.clone(), ttl);
+            }
+            let mut policy = match config["policies"].get(&tool.name) {
+                Some(section) => ToolPolicy::from_config(section)?,
+                None => ToolPolicy::default(),
+            };
+            // A timed-out destructive call may still have taken effect.
+            if tool.destructive {
+                policy.retries = 0;
+            }
+            self.policies.insert(tool.name.clone(), policy);
+            self.tools.insert(tool.name, name.clone());
+        }
+        let collection = config["collection"].as_str().unwrap_or(collection::DEFAULT);
+        self.collections
+            .insert(name.clone(), collection.to_string());
+        self.connectors.insert(name, connector);
+        Ok(())
+    }
+
+    /// Has the documents connectors sync from now on indexed by `sink`.
+    /// Without one they are dropped. Only the first sink set is used.
+    pub fn index_into(&self, sink: Arc<dyn DocumentSink>) {
+        let _ = self.sink.set(sink);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Connector> {
+        self.connectors.get(name).map(|c| c.as_ref())
+    }
+
+    /// Specs of all registered tools, by name, with their effective cache
+    /// TTLs.
+    pub fn tools(&self) -> Vec<ToolSpec> {
+        let mut tools: Vec<ToolSpec> = self
+            .connectors
+            .values()
+            .flat_map(|c| c.list_tools())
+            .map(|mut tool| {
+                tool.cache_ttl = self.cache_ttls.get(&tool.name).copied();
+                tool
+            })
+            .collect();
+        tools.sort_by(|a, b| a.name.cmp(&b.name));
+        tools
+    }
+
+    /// The spec of a registered tool.
+    pub fn tool(&self, name: &str) -> Option<ToolSpec> {
+        let owner = self.get(self.tools.get(name)?)?;
+        owner.list_tools().into_iter().find(|t| t.name == name)
+    }
+
+    /// Starts a background task per connector that has a sync interval.
+    pub fn spawn_periodic_syncs(self: &Arc<Self>) {
+        for (name, connector) in &self.connectors {
+            let Some(interval) = connector.sync_interval() else {
+                continue;
+            };
+            let registry = Arc::clone(self);
+            let name = name.clone();
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+                loop {
+                    ticker.tick().await;
+                    if let Err(e) = registry.sync(&name).await {
+                        log::warn!("sync {name} failed: {e}");
+                    }
+                }
+            });
+        }
+    }
+
+    /// Syncs one connector, indexes the documents it returns, and drops
+    /// its cached tool results, which may no longer match the source. The
+    /// outcome is recorded in its [`SyncStatus`].
+    pub async fn sync(&self, name: &str) -> Result<SyncReport, ConnectorError> {
+        let connector = self
+            .get(name)
+            .ok_or_else(|| ConnectorError::InvalidArgs(format!("no connector named {name}")))?;
+        let result = self.sync_connector(name, connector).await;
+        let now = Utc::now();
+        let mut statuses = self.statuses.lock().unwrap();
+        let status = statuses.entry(name.to_string()).or_default();
+        status.last_sync = Some(now);
+        status.syncs += 1;
+        match &result {
+            Ok(report) => {
+                status.last_success = Some(now);
+                status.fetched = report.fetched;
+                status.updated = report.updated;
+                status.total_updated += report.updated as u64;
+                status.last_error = None;
+            }
+            Err(e) => {
+                status.failures += 1;
+                status.last_error = Some(e.to_string());
+            }
+        }
+        result
+    }
+
+    /// What connector `name`'s syncs have done; `None` if there is no
+    /// such connector.
+    pub fn sync_status(&self, name: &str) -> Option<SyncStatus> {
+        self.connectors.get(name)?;
+        let statuses = self.statuses.lock().unwrap();
+        Some(statuses.get(name).cloned().unwrap_or_default())
+    }
+
+    /// Each connector's [`SyncStatus`], with how often it syncs on its
+    /// own and the collection its documents go to.
+    pub fn sync_statuses(&self) -> Value {
+        let statuses = self.statuses.lock().unwrap();
+        let out = self
+            .connectors
+            .iter()
+            .map(|(name, connector)| {
+                let mut out = statuses.get(name).cloned().unwrap_or_default().to_json();
+                out["name"] = json!(name);
+                out["interval_secs"] = json!(connector.sync_interval().map(|i| i.as_secs()));
+                out["collection"] = json!(self.collections.get(name));
+                out
+            })
+            .collect();
+        Value::Array(out)
+    }
+
+    async fn sync_connector(
+        &self,
+        name: &str,
+        connector: &dyn Connector,
+    ) -> Result<SyncReport, ConnectorError> {
+        let report = connector.sync().await;
+        let owned: Vec<String> = self
+            .tools
+            .iter()
+            .filter(|(_, owner)| *owner == name)
+            .map(|(tool, _)| tool.clone())
+            .collect();
+        self.cache.invalidate(&owned);
+        let mut report = report?;
+        let docs = std::mem::take(&mut report.documents);
+        if let (Some(sink), false) = (self.sink.get(), docs.is_empty()) {
+            let collection = self
+                .collections
+                .get(name)
+                .map_or(collection::DEFAULT, String::as_str);
+            sink.index(name, collection, docs).await.map_err(|e| {
+                ConnectorError::Failed(format!("indexing {name} in {collection}: {e}"))
+            })?;
+        }
+        Ok(report)
+    }
+
+    pub async fn describe(&self) -> Value {
+        let mut out = Vec::with_capacity(self.connectors.len());
+        for (name, connector) in &self.connectors {
+            let tools: Vec<Value> = connector
+                .list_tools()
+                .into_iter()
+                .map(|mut tool| {
+                    tool.cache_ttl = self.cache_ttls.get(&tool.name).copied();
+                    let mut out = tool.to_json();
+                    out["circuit_open"] = json!(self.circuit_open(&tool.name));
+                    out
+                })
+                .collect();
+            let sync = self.sync_status(name).unwrap_or_default();
+            out.push(json!({
+                "name": name,
+                "health": connector.health().await.to_json(),
+                "sync": sync.to_json(),
+                "tools": tools,
+            }));
+        }
+        Value::Array(out)
+    }
+
+    pub async fn call_tool(&self, tool: &str, args: Value) -> Result<Value, ConnectorError> {
+        let owner = self
+            .tools
+            .get(tool)
+            .and_then(|name| self.connectors.get(name))
+            .ok_or_else(|| ConnectorError::UnknownTool(tool.to_string()))?;
+        let Some(&ttl) = self.cache_ttls.get(tool) else {
+            return self.call_with_policy(owner.as_ref(), tool, args).await;
+        };
+        // serde_json keeps object keys sorted, so equal arguments
+        // serialize identically.
+        let key = (tool.to_string(), args.to_string());
+        if let Some(hit) = self.cache.get(&key) {
+            return Ok(hit);
+        }
+        let value = self.call_with_policy(owner.as_ref(), tool, args).await?;
+        self.cache.put(key, value.clone(), ttl);
+        Ok(value)
+    }
+
+    fn circuit_open(&self, tool: &str) -> bool {
+        let breakers = self.breakers.lock().unwrap();
+        breakers.get(tool).is_some_and(Breaker::is_open)
+    }
+
+    /// Calls a tool under its policy: each attempt is bounded by the
+    /// timeout, transient failures are retried with backoff, and a call
+    /// that still fails counts once towards the circuit breaker.
+    async fn call_with_policy(
+        &self,
+        owner: &dyn Connector,
+        tool: &str,
+        args: Value,
+    ) -> Result<Value, ConnectorError> {
+        let policy = self.policies.get(tool).cloned().unwrap_or_default();
+        if self.circuit_open(tool) {
+            return Err(ConnectorError::Unavailable(tool.to_string()));
+        }
+        let mut attempt = 0;
+        let result = loop {
+            let result = tokio::time::timeout(policy.timeout, owner.call_tool(tool, args.clone()))
+                .await
+                .unwrap_or_else(|_| Err(ConnectorError::Timeout(tool.to_string())));
+            match result {
+                Err(e) if policy::is_transient(&e) && attempt < policy.retries => {
+                    attempt += 1;
+                    tokio::time::sleep(policy.backoff_for(attempt)).await;
+                }
+                result => break result,
+            }
+        };
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(tool.to_string()).or_default();
+        match &result {
+            Ok(_) => breaker.record_success(),
+            Err(e) if policy::is_transient(e) => breaker.record_failure(&policy),
+            Err(_) => {}
+        }
+        result
+    }
+}
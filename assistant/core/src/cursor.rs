@@ -0,0 +1,97 @@
+//! Query result snapshots behind pagination cursors. The first page of a
+//! paged query ranks once and keeps the results, so later pages neither
+//! skip nor repeat hits when the index changes in between.
+
+use crate::index::Hit;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long a cursor stays valid after its last use.
+pub const CURSOR_TTL: Duration = Duration::from_secs(300);
+/// Most hits kept per snapshot.
+pub const MAX_SNAPSHOT_HITS: usize = 1000;
+/// Most snapshots kept at once; the oldest are evicted first.
+const MAX_SNAPSHOTS: usize = 64;
+
+struct Snapshot {
+    hits: Vec<Hit>,
+    expires: Instant,
+}
+
+#[derive(Default)]
+pub struct Cursors {
+    next_id: u64,
+    snapshots: HashMap<u64, Snapshot>,
+}
+
+/// One page of a snapshot.
+pub struct Page {
+    pub hits: Vec<Hit>,
+    /// Cursor for the following page; empty on the last one.
+    pub next: String,
+    /// Hits in the whole snapshot.
+    pub total: usize,
+}
+
+fn parse(cursor: &str) -> Option<(u64, usize)> {
+    let (id, offset) = cursor.split_once('.')?;
+    Some((u64::from_str_radix(id, 16).ok()?, offset.parse().ok()?))
+}
+
+impl Cursors {
+    fn expire(&mut self, now: Instant) {
+        self.snapshots.retain(|_, s| s.expires > now);
+    }
+
+    fn page(&mut self, id: u64, offset: usize, limit: usize) -> Option<Page> {
+        let snapshot = self.snapshots.get_mut(&id)?;
+        snapshot.expires = Instant::now() + CURSOR_TTL;
+        let total = snapshot.hits.len();
+        let end = offset.saturating_add(limit).min(total);
+        let hits = snapshot.hits.get(offset..end).unwrap_or_default().to_vec();
+        let next = if end < total {
+            format!("{id:x}.{end}")
+        } else {
+            self.snapshots.remove(&id);
+            String::new()
+        };
+        Some(Page { hits, next, total })
+    }
+
+    /// Keeps `hits` and returns the page starting at `offset`.
+    pub fn start(&mut self, mut hits: Vec<Hit>, offset: usize, limit: usize) -> Page {
+        let now = Instant::now();
+        self.expire(now);
+        while self.snapshots.len() >= MAX_SNAPSHOTS {
+            let Some(oldest) = self
+                .snapshots
+                .iter()
+                .min_by_key(|(_, s)| s.expires)
+                .map(|(id, _)| *id)
+            else {
+                break;
+            };
+            self.snapshots.remove(&oldest);
+        }
+        hits.truncate(MAX_SNAPSHOT_HITS);
+        let id = self.next_id;
+        self.next_id += 1;
+        self.snapshots.insert(
+            id,
+            Snapshot {
+                hits,
+                expires: now + CURSOR_TTL,
+            },
+        );
+        self.page(id, offset, limit)
+            .expect("snapshot just inserted")
+    }
+
+    /// The page `cursor` points at, or `None` if it is malformed or has
+    /// expired.
+    pub fn resume(&mut self, cursor: &str, limit: usize) -> Option<Page> {
+        self.expire(Instant::now());
+        let (id, offset) = parse(cursor)?;
+        self.page(id, offset, limit)
+    }
+}
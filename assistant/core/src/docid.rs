@@ -0,0 +1,136 @@
+//! Stable document ids. Loaders derive ids from where the text came from,
+//! so re-ingesting the same source replaces its entries instead of adding
+//! new ones:
+//!
+//! - `file:///abs/path` or `file:///abs/path#chunk=3`
+//! - `email:<message-id>`
+//! - `url:<hash of the URL>`
+//! - `<archive's id>!/<path in the archive>` for an archive's entries
+
+use crate::index::fnv1a;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
+
+/// Where an indexed text came from, kept with the document so citations
+/// can link back to the original.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// Link to the original: a `file://` URL, `mid:` message link or web URL.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub source: String,
+    /// Position of this chunk within the source, when it was split.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk: Option<u32>,
+    /// Media type of the original, e.g. `text/markdown`.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub mime_type: String,
+}
+
+/// Media type of a source, guessed from its scheme and file extension.
+pub fn mime_type(source: &str) -> &'static str {
+    if source.starts_with("mid:") {
+        return "message/rfc822";
+    }
+    let path = source.split(['?', '#']).next().unwrap_or_default();
+    let ext = path
+        .rsplit_once('.')
+        .filter(|(_, ext)| !ext.contains('/'))
+        .map(|(_, ext)| ext.to_ascii_lowercase());
+    match ext.as_deref() {
+        Some("md" | "markdown") => "text/markdown",
+        Some("html" | "htm") => "text/html",
+        Some("pdf") => "application/pdf",
+        Some("json") => "application/json",
+        Some("xml") => "application/xml",
+        Some("csv") => "text/csv",
+        Some("xlsx") => crate::table::XLSX,
+        Some("eml") => "message/rfc822",
+        _ if source.starts_with("http://") || source.starts_with("https://") => "text/html",
+        _ => "text/plain",
+    }
+}
+
+/// The id of chunk `chunk` of document `base`, or `base` itself.
+pub fn with_chunk(base: String, chunk: Option<u32>) -> String {
+    match chunk {
+        Some(n) => format!("{base}#chunk={n}"),
+        None => base,
+    }
+}
+
+/// Id and provenance for (a chunk of) a local file. The path is made
+/// absolute so the id does not depend on the working directory.
+pub fn file(path: &Path, chunk: Option<u32>) -> io::Result<(String, Provenance)> {
+    let path = path.canonicalize()?;
+    let source = format!("file://{}", path.display());
+    let provenance = Provenance {
+        mime_type: mime_type(&source).to_string(),
+        source: source.clone(),
+        chunk,
+    };
+    Ok((with_chunk(source, chunk), provenance))
+}
+
+/// The id of entry `path` of the archive with id `archive`, e.g.
+/// `file:///abs/export.zip!/Notes/todo.md`.
+pub fn archive_entry(archive: &str, path: &str) -> String {
+    format!("{archive}!/{path}")
+}
+
+/// Id and provenance for an email, by its Message-ID header.
+pub fn email(message_id: &str, chunk: Option<u32>) -> (String, Provenance) {
+    let message_id = message_id
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>');
+    let provenance = Provenance {
+        source: format!("mid:{message_id}"),
+        chunk,
+        mime_type: "message/rfc822".to_string(),
+    };
+    (with_chunk(format!("email:{message_id}"), chunk), provenance)
+}
+
+/// Id and provenance for a web page. URLs can be long and contain `#`,
+/// so the id uses a hash and the URL itself is kept as the source.
+pub fn url(url: &str, chunk: Option<u32>) -> (String, Provenance) {
+    let url = url.trim();
+    let provenance = Provenance {
+        source: url.to_string(),
+        chunk,
+        mime_type: mime_type(url).to_string(),
+    };
+    let id = format!("url:{:016x}", fnv1a(url.as_bytes()));
+    (with_chunk(id, chunk), provenance)
+}
+
+/// The id of the document a chunk id belongs to: `file:///a#chunk=3`
+/// gives `file:///a`. Other ids are their own parent.
+pub fn parent(id: &str) -> &str {
+    match id.rsplit_once("#chunk=") {
+        Some((base, n)) if n.parse::<u32>().is_ok() => base,
+        _ => id,
+    }
+}
+
+/// Provenance recoverable from an id alone, for documents indexed without
+/// any. `url:` ids only carry a hash, so they yield none.
+pub fn parse(id: &str) -> Option<Provenance> {
+    let (base, chunk) = match id.rsplit_once("#chunk=") {
+        Some((base, n)) => (base, Some(n.parse().ok()?)),
+        None => (id, None),
+    };
+    let source = if base.starts_with("file://") {
+        base.to_string()
+    } else if let Some(message_id) = base.strip_prefix("email:") {
+        format!("mid:{message_id}")
+    } else {
+        return None;
+    };
+    Some(Provenance {
+        mime_type: mime_type(&source).to_string(),
+        source,
+        chunk,
+    })
+}
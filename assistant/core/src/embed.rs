@@ -0,0 +1,250 @@
+//! Text embedders. Each collection names the embedder it was created
+//! with; the server offers the built-in [`HashEmbedder`]s (`hash-<dim>`)
+//! plus the models it loads at startup.
+
+use crate::index::{fnv1a, terms};
+use crate::simd;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+pub trait Embedder: Send + Sync {
+    /// Name collections refer to it by.
+    fn name(&self) -> &str;
+    /// Floats per embedding.
+    fn dim(&self) -> usize;
+    /// Embedding of `text`, unnormalized: the index's metric decides
+    /// whether its length counts.
+    fn embed(&self, text: &str) -> Vec<f32>;
+    /// Identifies the embeddings it makes, so an index can tell when the
+    /// model behind a name was replaced. By default its name and dim.
+    fn fingerprint(&self) -> String {
+        format!("{}/{}", self.name(), self.dim())
+    }
+    /// Tokens `text` comes to, before any are cut off. By default its
+    /// terms, which is what the hashed embedder embeds.
+    fn tokens(&self, text: &str) -> usize {
+        terms(text).len()
+    }
+    /// Most tokens of a text that are embedded; the rest are cut off.
+    fn max_tokens(&self) -> Option<usize> {
+        None
+    }
+    /// Ways it can pool token states into an embedding, the one
+    /// [`embed`](Self::embed) uses first. None for an embedder without
+    /// token states, such as a hashed one.
+    fn poolings(&self) -> &'static [Pooling] {
+        &[]
+    }
+    /// Embedding of `text` pooled by `pooling`, one of
+    /// [`poolings`](Self::poolings).
+    fn embed_pooled(&self, text: &str, pooling: Pooling) -> Vec<f32> {
+        let _ = pooling;
+        self.embed(text)
+    }
+}
+
+/// How a model's token states become one embedding.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Pooling {
+    /// Their mean, as sentence-transformers pools most models.
+    #[default]
+    Mean,
+    /// The state of the first, `[CLS]`, token.
+    Cls,
+    /// The largest value of each dimension across them.
+    Max,
+}
+
+impl Pooling {
+    pub const NAMES: &'static [&'static str] = &["mean", "cls", "max"];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "mean" => Some(Pooling::Mean),
+            "cls" => Some(Pooling::Cls),
+            "max" => Some(Pooling::Max),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Pooling::Mean => "mean",
+            Pooling::Cls => "cls",
+            Pooling::Max => "max",
+        }
+    }
+}
+
+/// An embedder that pools another's token states a way other than its
+/// own, for requests that ask for it.
+struct Pooled {
+    embedder: Arc<dyn Embedder>,
+    pooling: Pooling,
+}
+
+impl Embedder for Pooled {
+    fn name(&self) -> &str {
+        self.embedder.name()
+    }
+
+    fn dim(&self) -> usize {
+        self.embedder.dim()
+    }
+
+    fn embed(&self, text: &str) -> Vec<f32> {
+        self.embedder.embed_pooled(text, self.pooling)
+    }
+
+    fn fingerprint(&self) -> String {
+        format!("{}+{}", self.embedder.fingerprint(), self.pooling.name())
+    }
+
+    fn tokens(&self, text: &str) -> usize {
+        self.embedder.tokens(text)
+    }
+
+    fn max_tokens(&self) -> Option<usize> {
+        self.embedder.max_tokens()
+    }
+}
+
+/// `embedder`, pooling by the [`Pooling`] named `name`, or its own way if
+/// `name` is empty. Fails for a pooling it cannot do.
+pub fn pooled(embedder: Arc<dyn Embedder>, name: &str) -> Result<Arc<dyn Embedder>, String> {
+    if name.is_empty() {
+        return Ok(embedder);
+    }
+    let supported = embedder.poolings();
+    let Some(pooling) = Pooling::parse(name).filter(|p| supported.contains(p)) else {
+        return Err(match supported {
+            [] => format!("{} has no token states to pool", embedder.name()),
+            _ => format!(
+                "unsupported pooling {name:?} for {}; supported: {}",
+                embedder.name(),
+                supported
+                    .iter()
+                    .map(|p| p.name())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        });
+    };
+    if supported.first() == Some(&pooling) {
+        return Ok(embedder);
+    }
+    Ok(Arc::new(Pooled { embedder, pooling }))
+}
+
+/// Scales `vector` to length 1; a zero vector stays zero.
+pub fn normalize(vector: &mut [f32]) {
+    let norm = simd::dot(vector, vector).sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+/// Hashed bag-of-words vectors: each term is hashed (FNV-1a) into one of
+/// `dim` buckets, which count its occurrences. By cosine, two vectors
+/// compare their term histograms. Needs no model, but only matches shared
+/// words; more buckets mean fewer unrelated terms collide.
+pub struct HashEmbedder {
+    name: String,
+    dim: usize,
+}
+
+impl HashEmbedder {
+    /// Name of the embedder used when none is configured.
+    pub const DEFAULT: &'static str = "hash-256";
+    /// Dims a hash embedder can have.
+    pub const DIMS: std::ops::RangeInclusive<usize> = 16..=4096;
+
+    /// The embedder named `hash-<dim>`, for a dim of 16 to 4096.
+    pub fn parse(name: &str) -> Option<Self> {
+        let dim: usize = name.strip_prefix("hash-")?.parse().ok()?;
+        let canonical = format!("hash-{dim}");
+        (Self::DIMS.contains(&dim) && name == canonical).then_some(HashEmbedder {
+            name: canonical,
+            dim,
+        })
+    }
+}
+
+impl Embedder for HashEmbedder {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn dim(&self) -> usize {
+        self.dim
+    }
+
+    fn embed(&self, text: &str) -> Vec<f32> {
+        let mut v = vec![0.0f32; self.dim];
+        for term in terms(text) {
+            v[(fnv1a(term.as_bytes()) % self.dim as u64) as usize] += 1.0;
+        }
+        v
+    }
+}
+
+/// The embedders a server offers, by name, and the one new collections
+/// use unless they name another.
+#[derive(Clone)]
+pub struct Embedders {
+    /// Loaded models; hash embedders are made on demand.
+    models: BTreeMap<String, Arc<dyn Embedder>>,
+    default: String,
+}
+
+impl Default for Embedders {
+    fn default() -> Self {
+        Embedders {
+            models: BTreeMap::new(),
+            default: HashEmbedder::DEFAULT.to_string(),
+        }
+    }
+}
+
+impl Embedders {
+    /// Adds `embedder`, replacing any other of the same name.
+    pub fn add(&mut self, embedder: Arc<dyn Embedder>) {
+        self.models.insert(embedder.name().to_string(), embedder);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn Embedder>> {
+        match self.models.get(name) {
+            Some(model) => Some(Arc::clone(model)),
+            None => HashEmbedder::parse(name).map(|e| Arc::new(e) as Arc<dyn Embedder>),
+        }
+    }
+
+    /// Makes `name`, which must be available, the default for new
+    /// collections.
+    pub fn set_default(&mut self, name: &str) -> Result<(), String> {
+        if self.get(name).is_none() {
+            return Err(format!(
+                "unknown embedder {name:?}; available: {}",
+                self.names().join(", ")
+            ));
+        }
+        self.default = name.to_string();
+        Ok(())
+    }
+
+    pub fn default_name(&self) -> &str {
+        &self.default
+    }
+
+    /// Loaded models, by name.
+    pub fn models(&self) -> impl Iterator<Item = &Arc<dyn Embedder>> {
+        self.models.values()
+    }
+
+    /// What is available, for messages.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names = vec!["hash-<16..4096>"];
+        names.extend(self.models.keys().map(String::as_str));
+        names
+    }
+}
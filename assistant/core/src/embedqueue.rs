@@ -0,0 +1,261 @@
+//! Shared work queue for embedding and reranking. A pool of worker threads
+//! processes texts in start-time fair queuing order: each client's
+//! interactive and bulk work are separate flows, served in proportion to
+//! their weights. One client's bulk ingest therefore cannot hold up
+//! another's query, and queries overtake ingest in general.
+
+use crate::embed::Embedder;
+use crate::rerank::Reranker;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use tokio::sync::oneshot;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Priority {
+    /// Query embeddings, which someone is waiting on.
+    Interactive,
+    /// Texts being indexed.
+    Bulk,
+}
+
+impl Priority {
+    /// Share of the workers a flow gets while others are busy too.
+    fn weight(self) -> f64 {
+        match self {
+            Priority::Interactive => 16.0,
+            Priority::Bulk => 1.0,
+        }
+    }
+}
+
+/// What a job does with each of its texts.
+enum Work {
+    Embed(Arc<dyn Embedder>),
+    /// Scores each text against the query, as a one-float vector.
+    Rerank(Arc<dyn Reranker>, String),
+}
+
+impl Work {
+    fn run(&self, text: &str) -> Vec<f32> {
+        match self {
+            Work::Embed(embedder) => embedder.embed(text),
+            Work::Rerank(reranker, query) => vec![reranker.score(query, text)],
+        }
+    }
+
+    /// Rough relative cost of running it on `text`.
+    fn cost(&self, text: &str) -> usize {
+        match self {
+            Work::Embed(_) => text.len() + 1,
+            Work::Rerank(_, query) => query.len() + text.len() + 1,
+        }
+    }
+}
+
+/// Texts submitted together, answered once all are processed.
+struct Job {
+    work: Work,
+    texts: Vec<String>,
+    vectors: Mutex<Vec<Vec<f32>>>,
+    remaining: AtomicUsize,
+    reply: Mutex<Option<oneshot::Sender<Vec<Vec<f32>>>>>,
+}
+
+impl Job {
+    /// Whether the submitter stopped waiting, e.g. its client went away.
+    fn abandoned(&self) -> bool {
+        self.reply
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_none_or(|tx| tx.is_closed())
+    }
+
+    fn finish(&self, at: usize, vector: Vec<f32>) {
+        self.vectors.lock().unwrap()[at] = vector;
+        if self.remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+            let vectors = std::mem::take(&mut *self.vectors.lock().unwrap());
+            if let Some(tx) = self.reply.lock().unwrap().take() {
+                let _ = tx.send(vectors);
+            }
+        }
+    }
+}
+
+/// One text of a job.
+struct Item {
+    job: Arc<Job>,
+    at: usize,
+    /// Virtual time at which the item may start; lowest goes first.
+    start: f64,
+}
+
+#[derive(Default)]
+struct Flow {
+    items: VecDeque<Item>,
+    /// Virtual time at which the flow's last queued item finishes.
+    finish: f64,
+}
+
+#[derive(Default)]
+struct State {
+    flows: HashMap<(String, Priority), Flow>,
+    /// Start tag of the item most recently taken.
+    now: f64,
+    closed: bool,
+}
+
+impl State {
+    fn push(&mut self, client: &str, priority: Priority, job: &Arc<Job>) {
+        let now = self.now;
+        let flow = self
+            .flows
+            .entry((client.to_string(), priority))
+            .or_default();
+        for (at, text) in job.texts.iter().enumerate() {
+            // An idle flow starts at the current time rather than
+            // spending credit saved up while it was idle.
+            let start = flow.finish.max(now);
+            flow.finish = start + job.work.cost(text) as f64 / priority.weight();
+            flow.items.push_back(Item {
+                job: Arc::clone(job),
+                at,
+                start,
+            });
+        }
+    }
+
+    /// Takes the queued item with the lowest start tag.
+    fn pop(&mut self) -> Option<Item> {
+        let key = self
+            .flows
+            .iter()
+            .filter_map(|(key, flow)| Some((key, flow.items.front()?.start)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))?
+            .0
+            .clone();
+        let flow = self.flows.get_mut(&key)?;
+        let item = flow.items.pop_front()?;
+        if flow.items.is_empty() {
+            self.flows.remove(&key);
+        }
+        self.now = item.start;
+        Some(item)
+    }
+}
+
+type Shared = (Mutex<State>, Condvar);
+
+pub struct EmbedQueue {
+    shared: Arc<Shared>,
+}
+
+impl EmbedQueue {
+    /// A queue served by `workers` threads.
+    pub fn new(workers: usize) -> Self {
+        let shared: Arc<Shared> = Arc::default();
+        for n in 0..workers.max(1) {
+            let shared = Arc::clone(&shared);
+            std::thread::Builder::new()
+                .name(format!("embed-{n}"))
+                .spawn(move || work(&shared))
+                .expect("spawning an embedding worker failed");
+        }
+        EmbedQueue { shared }
+    }
+
+    /// Embeds `texts` with `embedder` on behalf of `client`, in order.
+    pub async fn embed(
+        &self,
+        client: &str,
+        priority: Priority,
+        embedder: Arc<dyn Embedder>,
+        texts: Vec<String>,
+    ) -> io::Result<Vec<Vec<f32>>> {
+        self.submit(client, priority, Work::Embed(embedder), texts)
+            .await
+    }
+
+    /// Scores each of `texts` against `query` with `reranker` on behalf of
+    /// `client`, in order.
+    pub async fn rerank(
+        &self,
+        client: &str,
+        priority: Priority,
+        reranker: Arc<dyn Reranker>,
+        query: String,
+        texts: Vec<String>,
+    ) -> io::Result<Vec<f32>> {
+        let scores = self
+            .submit(client, priority, Work::Rerank(reranker, query), texts)
+            .await?;
+        Ok(scores.into_iter().map(|s| s[0]).collect())
+    }
+
+    async fn submit(
+        &self,
+        client: &str,
+        priority: Priority,
+        work: Work,
+        texts: Vec<String>,
+    ) -> io::Result<Vec<Vec<f32>>> {
+        if texts.is_empty() {
+            return Ok(Vec::new());
+        }
+        let (tx, rx) = oneshot::channel();
+        let job = Arc::new(Job {
+            work,
+            vectors: Mutex::new(vec![Vec::new(); texts.len()]),
+            remaining: AtomicUsize::new(texts.len()),
+            texts,
+            reply: Mutex::new(Some(tx)),
+        });
+        let (state, ready) = &*self.shared;
+        state.lock().unwrap().push(client, priority, &job);
+        ready.notify_all();
+        drop(job);
+        rx.await
+            .map_err(|_| io::Error::other("embedding failed: a worker stopped"))
+    }
+}
+
+impl Default for EmbedQueue {
+    /// One worker per core.
+    fn default() -> Self {
+        EmbedQueue::new(std::thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+}
+
+impl Drop for EmbedQueue {
+    fn drop(&mut self) {
+        let (state, ready) = &*self.shared;
+        state.lock().unwrap().closed = true;
+        ready.notify_all();
+    }
+}
+
+fn work(shared: &Shared) {
+    let (state, ready) = shared;
+    loop {
+        let item = {
+            let mut state = state.lock().unwrap();
+            loop {
+                if state.closed {
+                    return;
+                }
+                if let Some(item) = state.pop() {
+                    break item;
+                }
+                state = ready.wait(state).unwrap();
+            }
+        };
+        let job = &item.job;
+        let vector = match job.abandoned() {
+            true => Vec::new(),
+            false => job.work.run(&job.texts[item.at]),
+        };
+        job.finish(item.at, vector);
+    }
+}
@@ -0,0 +1,138 @@
+//! Document filters: clauses like `source^=file://`, `chunk>=2` or
+//! `indexed_at>=2024-05-01`, all of which must hold.
+//!
+//! A clause names one of the built-in [`FIELDS`] or else a metadata key;
+//! `metadata.<key>` names a key that a built-in field would shadow.
+//!
+//! Values compare as numbers when both sides parse as one and as strings
+//! otherwise, so ISO dates order correctly: `indexed_at` reads as an
+//! RFC 3339 UTC time. A document without the field never matches a
+//! clause on it.
+
+use crate::docid;
+use crate::index::Doc;
+use chrono::{DateTime, SecondsFormat};
+use std::cmp::Ordering;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// Starts with.
+    Prefix,
+}
+
+/// Longer operators first, so `>=` is not read as `>`.
+const OPS: &[(&str, Op)] = &[
+    ("!=", Op::Ne),
+    ("<=", Op::Le),
+    (">=", Op::Ge),
+    ("^=", Op::Prefix),
+    ("=", Op::Eq),
+    ("<", Op::Lt),
+    (">", Op::Gt),
+];
+
+/// Fields every entry has, besides its metadata.
+pub const FIELDS: &[&str] = &[
+    "id",
+    "document_id",
+    "source",
+    "chunk",
+    "mime_type",
+    "size_bytes",
+    "indexed_at",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+struct Clause {
+    field: String,
+    op: Op,
+    value: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+    clauses: Vec<Clause>,
+}
+
+fn parse_clause(text: &str) -> Result<Clause, String> {
+    let (at, len, op) = OPS
+        .iter()
+        .filter_map(|(sym, op)| text.find(sym).map(|at| (at, sym.len(), *op)))
+        .min_by_key(|(at, len, _)| (*at, usize::MAX - len))
+        .ok_or_else(|| format!("filter {text:?} has no operator"))?;
+    let field = text[..at].trim();
+    if field.is_empty() || field == "metadata." {
+        return Err(format!("filter {text:?} names no field"));
+    }
+    Ok(Clause {
+        field: field.to_string(),
+        op,
+        value: text[at + len..].trim().to_string(),
+    })
+}
+
+fn field(doc: &Doc, name: &str) -> Option<String> {
+    match name {
+        "id" => Some(doc.id.clone()),
+        "document_id" => Some(docid::parent(&doc.id).to_string()),
+        "source" => Some(doc.provenance.source.clone()).filter(|s| !s.is_empty()),
+        "chunk" => doc.provenance.chunk.map(|c| c.to_string()),
+        "mime_type" => Some(doc.provenance.mime_type.clone()).filter(|m| !m.is_empty()),
+        "size_bytes" => Some(doc.text.len().to_string()),
+        "indexed_at" => doc
+            .indexed_at
+            .and_then(DateTime::from_timestamp_millis)
+            .map(|t| t.to_rfc3339_opts(SecondsFormat::Millis, true)),
+        _ => {
+            let key = name.strip_prefix("metadata.").unwrap_or(name);
+            doc.metadata.get(key).cloned()
+        }
+    }
+}
+
+fn compare(a: &str, b: &str) -> Ordering {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(x), Ok(y)) => x.total_cmp(&y),
+        _ => a.cmp(b),
+    }
+}
+
+impl Filter {
+    /// Parses clauses such as `["source^=file://", "chunk<3"]`. No clauses
+    /// match everything.
+    pub fn parse<S: AsRef<str>>(clauses: &[S]) -> Result<Self, String> {
+        let clauses = clauses
+            .iter()
+            .map(|c| parse_clause(c.as_ref()))
+            .collect::<Result<_, _>>()?;
+        Ok(Filter { clauses })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clauses.is_empty()
+    }
+
+    pub fn matches(&self, doc: &Doc) -> bool {
+        self.clauses.iter().all(|c| {
+            let Some(actual) = field(doc, &c.field) else {
+                return false;
+            };
+            let ord = compare(&actual, &c.value);
+            match c.op {
+                Op::Eq => ord.is_eq(),
+                Op::Ne => ord.is_ne(),
+                Op::Lt => ord.is_lt(),
+                Op::Le => ord.is_le(),
+                Op::Gt => ord.is_gt(),
+                Op::Ge => ord.is_ge(),
+                Op::Prefix => actual.starts_with(&c.value),
+            }
+        })
+    }
+}
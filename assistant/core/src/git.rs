@@ -0,0 +1,435 @@
+//! Git connector: read-only tools over an allowlist of local repositories
+//! (`git_status`, `git_log`, `git_diff`, `git_blame`), so answers about
+//! recent changes come from the repository itself, plus `git_commit`,
+//! which is only offered when the config opts in.
+//!
+//! Tools run the `git` binary. Arguments are passed as separate words and
+//! checked not to start with `-`, so a model cannot smuggle in options;
+//! paths are checked to stay inside the repository.
+
+use crate::connector::{Connector, ConnectorError, Health, SyncReport, ToolSpec};
+use crate::text;
+use chrono::DateTime;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+/// Bytes of diff returned before it is cut off.
+const MAX_DIFF_BYTES: usize = 256 * 1024;
+/// Most commits `git_log` returns.
+const MAX_LOG: u64 = 200;
+/// Most lines `git_blame` returns.
+const MAX_BLAME_LINES: usize = 2000;
+
+/// Exposes git tools for the configured repositories.
+#[derive(Default)]
+pub struct GitConnector {
+    /// Repository name to its top-level directory.
+    repos: BTreeMap<String, PathBuf>,
+    allow_commit: bool,
+}
+
+impl GitConnector {
+    pub fn boxed() -> Box<dyn Connector> {
+        Box::new(GitConnector::default())
+    }
+
+    /// The repository `args["repo"]` names; may be left out when only one
+    /// is configured.
+    fn repo(&self, args: &Value) -> Result<PathBuf, ConnectorError> {
+        let name = match args["repo"].as_str() {
+            Some(name) => name,
+            None if self.repos.len() == 1 => self.repos.keys().next().unwrap(),
+            None => return Err(ConnectorError::InvalidArgs("missing \"repo\"".into())),
+        };
+        self.repos.get(name).cloned().ok_or_else(|| {
+            let known: Vec<&str> = self.repos.keys().map(String::as_str).collect();
+            ConnectorError::InvalidArgs(format!(
+                "unknown repo {name:?}; configured: {}",
+                known.join(", ")
+            ))
+        })
+    }
+
+    fn status(&self, args: &Value) -> Result<Value, ConnectorError> {
+        let repo = self.repo(args)?;
+        let out = git(&repo, &["status", "--porcelain=v1", "--branch", "-z"])?;
+        let mut branch = String::new();
+        let mut files = Vec::new();
+        let mut entries = out.split('\0').filter(|e| !e.is_empty());
+        while let Some(entry) = entries.next() {
+            if let Some(rest) = entry.strip_prefix("## ") {
+                branch = rest.to_string();
+                continue;
+            }
+            let (status, path) = entry.split_at(entry.len().min(3));
+            let status = status.trim_end();
+            let mut file = json!({ "status": status, "path": path });
+            // Renames and copies are followed by the original path.
+            if status.contains(['R', 'C']) {
+                file["from"] = json!(entries.next().unwrap_or_default());
+            }
+            files.push(file);
+        }
+        Ok(json!({ "branch": branch, "clean": files.is_empty(), "files": files }))
+    }
+
+    fn log(&self, args: &Value) -> Result<Value, ConnectorError> {
+        let repo = self.repo(args)?;
+        let limit = args["limit"].as_u64().unwrap_or(20).clamp(1, MAX_LOG);
+        let mut argv = vec![
+            "log".to_string(),
+            format!("-n{limit}"),
+            "--name-only".into(),
+            // Commit fields, separated by unit separators; each commit starts
+            // with a record separator so its file names can be told apart.
+            "--format=%x1e%H%x1f%an%x1f%aI%x1f%s".into(),
+        ];
+        for (field, flag) in [
+            ("since", "--since"),
+            ("until", "--until"),
+            ("author", "--author"),
+        ] {
+            if let Some(value) = args[field].as_str() {
+                argv.push(format!("{flag}={value}"));
+            }
+        }
+        if let Some(rev) = word(args, "ref")? {
+            argv.push(rev.to_string());
+        }
+        push_path(&mut argv, &repo, args)?;
+        let out = git(&repo, &argv)?;
+        let commits: Vec<Value> = out
+            .split('\x1e')
+            .filter(|c| !c.trim().is_empty())
+            .map(|commit| {
+                let mut lines = commit.lines();
+                let header: Vec<&str> = lines.next().unwrap_or_default().split('\x1f').collect();
+                let field = |at: usize| header.get(at).copied().unwrap_or_default();
+                let files: Vec<&str> = lines.filter(|l| !l.is_empty()).collect();
+                json!({
+                    "hash": field(0),
+                    "author": field(1),
+                    "date": field(2),
+                    "subject": field(3),
+                    "files": files,
+                })
+            })
+            .collect();
+        Ok(json!({ "commits": commits }))
+    }
+
+    fn diff(&self, args: &Value) -> Result<Value, ConnectorError> {
+        let repo = self.repo(args)?;
+        let mut argv = vec![
+            "diff".to_string(),
+            "--no-color".into(),
+            "--no-ext-diff".into(),
+        ];
+        if args["staged"].as_bool() == Some(true) {
+            argv.push("--cached".into());
+        }
+        if args["stat"].as_bool() == Some(true) {
+            argv.push("--stat".into());
+        }
+        for field in ["from", "to"] {
+            if let Some(rev) = word(args, field)? {
+                argv.push(rev.to_string());
+            }
+        }
+        push_path(&mut argv, &repo, args)?;
+        let mut diff = git(&repo, &argv)?;
+        let truncated = diff.len() > MAX_DIFF_BYTES;
+        if truncated {
+            diff.truncate(text::truncate_bytes(&diff, MAX_DIFF_BYTES).len());
+        }
+        Ok(json!({ "diff": diff, "truncated": truncated }))
+    }
+
+    fn blame(&self, args: &Value) -> Result<Value, ConnectorError> {
+        let repo = self.repo(args)?;
+        if args["path"].as_str().is_none() {
+            return Err(ConnectorError::InvalidArgs("missing \"path\"".into()));
+        }
+        let mut argv = vec!["blame".to_string(), "--line-porcelain".into()];
+        let start = args["start"].as_u64().unwrap_or(1).max(1);
+        match args["end"].as_u64() {
+            Some(end) => argv.push(format!("-L{start},{}", end.max(start))),
+            None => argv.push(format!("-L{start},+{MAX_BLAME_LINES}")),
+        }
+        if let Some(rev) = word(args, "ref")? {
+            argv.push(rev.to_string());
+        }
+        push_path(&mut argv, &repo, args)?;
+        let out = git(&repo, &argv)?;
+        Ok(json!({ "lines": parse_blame(&out) }))
+    }
+
+    fn commit(&self, args: &Value) -> Result<Value, ConnectorError> {
+        if args["consent"].as_bool() != Some(true) {
+            return Err(ConnectorError::ConsentRequired("git_commit".into()));
+        }
+        let repo = self.repo(args)?;
+        let message = args["message"]
+            .as_str()
+            .filter(|m| !m.trim().is_empty())
+            .ok_or_else(|| ConnectorError::InvalidArgs("missing \"message\"".into()))?;
+        let paths: Vec<&str> = args["paths"]
+            .as_array()
+            .map(|paths| paths.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        if !paths.is_empty() {
+            let mut argv = vec!["add".to_string(), "--".into()];
+            for path in paths {
+                argv.push(inside(&repo, path)?);
+            }
+            git(&repo, &argv)?;
+        }
+        git(&repo, &["commit", "--quiet", "-m", message])?;
+        let hash = git(&repo, &["rev-parse", "HEAD"])?;
+        Ok(json!({ "hash": hash.trim(), "summary": message.lines().next().unwrap_or_default() }))
+    }
+}
+
+/// `args[field]` if given, refusing values git would read as an option.
+fn word<'a>(args: &'a Value, field: &str) -> Result<Option<&'a str>, ConnectorError> {
+    match args[field].as_str() {
+        Some(value) if value.starts_with('-') || value.is_empty() => Err(
+            ConnectorError::InvalidArgs(format!("{field}: {value:?} is not a revision")),
+        ),
+        value => Ok(value),
+    }
+}
+
+/// `path`, relative to the repository, if it stays inside it.
+fn inside(repo: &Path, path: &str) -> Result<String, ConnectorError> {
+    let plain = Path::new(path)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if path.is_empty() || !plain {
+        return Err(ConnectorError::InvalidArgs(format!(
+            "{path:?} is not a path inside {}",
+            repo.display()
+        )));
+    }
+    Ok(path.to_string())
+}
+
+/// Appends `-- <path>` when `args["path"]` is given.
+fn push_path(argv: &mut Vec<String>, repo: &Path, args: &Value) -> Result<(), ConnectorError> {
+    if let Some(path) = args["path"].as_str() {
+        argv.push("--".into());
+        argv.push(inside(repo, path)?);
+    }
+    Ok(())
+}
+
+/// Runs git in `repo` and returns its output, or its error message.
+fn git(repo: &Path, argv: &[impl AsRef<std::ffi::OsStr>]) -> Result<String, ConnectorError> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(["-c", "core.pager=cat", "-c", "color.ui=false"])
+        .args(argv)
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .env("GIT_OPTIONAL_LOCKS", "0")
+        .output()
+        .map_err(|e| ConnectorError::Failed(format!("running git: {e}")))?;
+    if !output.status.success() {
+        // Some failures, like nothing to commit, are only reported on stdout.
+        let message = match String::from_utf8_lossy(&output.stderr).trim() {
+            "" => String::from_utf8_lossy(&output.stdout).trim().to_string(),
+            stderr => stderr.to_string(),
+        };
+        return Err(ConnectorError::Failed(format!("git: {message}")));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Lines of `git blame --line-porcelain` output, with the commit that last
+/// changed each.
+fn parse_blame(out: &str) -> Vec<Value> {
+    let mut lines = Vec::new();
+    let mut current = json!({});
+    for line in out.lines() {
+        if let Some(text) = line.strip_prefix('\t') {
+            current["text"] = json!(text);
+            lines.push(std::mem::replace(&mut current, json!({})));
+        } else if current.as_object().is_some_and(|c| c.is_empty()) {
+            let mut words = line.split(' ');
+            let hash = words.next().unwrap_or_default();
+            current["commit"] = json!(&hash[..hash.len().min(12)]);
+            current["line"] = json!(words.nth(1).and_then(|n| n.parse::<u64>().ok()));
+        } else if let Some(author) = line.strip_prefix("author ") {
+            current["author"] = json!(author);
+        } else if let Some(time) = line.strip_prefix("author-time ") {
+            let date = time
+                .parse()
+                .ok()
+                .and_then(|secs| DateTime::from_timestamp(secs, 0));
+            current["date"] = json!(date.map(|d| d.to_rfc3339()));
+        } else if let Some(summary) = line.strip_prefix("summary ") {
+            current["summary"] = json!(summary);
+        }
+    }
+    lines
+}
+
+#[tonic::async_trait]
+impl Connector for GitConnector {
+    fn name(&self) -> &str {
+        "git"
+    }
+
+    fn configure(&mut self, config: &Value) -> Result<(), ConnectorError> {
+        let repos = config["repos"].as_object().ok_or_else(|| {
+            ConnectorError::InvalidConfig("git: \"repos\" must map names to paths".into())
+        })?;
+        for (name, path) in repos {
+            let path = path.as_str().ok_or_else(|| {
+                ConnectorError::InvalidConfig(format!("git: repo {name} must be a path"))
+            })?;
+            let top = git(Path::new(path), &["rev-parse", "--show-toplevel"])
+                .map_err(|e| ConnectorError::InvalidConfig(format!("git: {path}: {e}")))?;
+            self.repos.insert(name.clone(), PathBuf::from(top.trim()));
+        }
+        if self.repos.is_empty() {
+            return Err(ConnectorError::InvalidConfig(
+                "git: \"repos\" is empty".into(),
+            ));
+        }
+        self.allow_commit = config["allow_commit"].as_bool().unwrap_or(false);
+        Ok(())
+    }
+
+    async fn sync(&self) -> Result<SyncReport, ConnectorError> {
+        // Repositories are read on each call.
+        Ok(SyncReport::default())
+    }
+
+    fn list_tools(&self) -> Vec<ToolSpec> {
+        let names: Vec<&str> = self.repos.keys().map(String::as_str).collect();
+        let repo = json!({ "type": "string", "enum": names });
+        let path = json!({ "type": "string", "description": "Limit to this path, relative to the repository." });
+        let read_only = |name: &str, description: String, parameters: Value| ToolSpec {
+            name: name.into(),
+            description,
+            parameters,
+            destructive: false,
+            requires_consent: false,
+            cache_ttl: None,
+        };
+        let mut tools = vec![
+            read_only(
+                "git_status",
+                "Current branch and uncommitted changes of a repository.".into(),
+                json!({ "type": "object", "properties": { "repo": repo } }),
+            ),
+            read_only(
+                "git_log",
+                "Recent commits, newest first, with the files each changed. since and until \
+                 take dates or phrases like \"last friday\"."
+                    .into(),
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "repo": repo,
+                        "since": { "type": "string" },
+                        "until": { "type": "string" },
+                        "author": { "type": "string" },
+                        "ref": { "type": "string", "description": "Branch, tag or commit to start from." },
+                        "path": path,
+                        "limit": { "type": "integer", "minimum": 1, "maximum": MAX_LOG },
+                    },
+                }),
+            ),
+            read_only(
+                "git_diff",
+                "Unified diff of uncommitted changes (staged ones with staged: true), or \
+                 between two revisions. stat: true gives a per-file summary instead."
+                    .into(),
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "repo": repo,
+                        "from": { "type": "string" },
+                        "to": { "type": "string" },
+                        "staged": { "type": "boolean" },
+                        "stat": { "type": "boolean" },
+                        "path": path,
+                    },
+                }),
+            ),
+            read_only(
+                "git_blame",
+                "The commit, author and date that last changed each line of a file.".into(),
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "repo": repo,
+                        "path": path,
+                        "start": { "type": "integer", "minimum": 1 },
+                        "end": { "type": "integer", "minimum": 1 },
+                        "ref": { "type": "string" },
+                    },
+                    "required": ["path"],
+                }),
+            ),
+        ];
+        if self.allow_commit {
+            tools.push(ToolSpec {
+                name: "git_commit".into(),
+                description: "Commit the staged changes, after staging paths if given. \
+                              Requires consent: true on every call."
+                    .into(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "repo": repo,
+                        "message": { "type": "string" },
+                        "paths": { "type": "array", "items": { "type": "string" } },
+                        "consent": { "type": "boolean" },
+                    },
+                    "required": ["message", "consent"],
+                }),
+                destructive: true,
+                requires_consent: true,
+                cache_ttl: None,
+            });
+        }
+        tools
+    }
+
+    async fn call_tool(&self, tool: &str, args: Value) -> Result<Value, ConnectorError> {
+        let call = match tool {
+            "git_status" => GitConnector::status,
+            "git_log" => GitConnector::log,
+            "git_diff" => GitConnector::diff,
+            "git_blame" => GitConnector::blame,
+            "git_commit" if self.allow_commit => GitConnector::commit,
+            _ => return Err(ConnectorError::UnknownTool(tool.to_string())),
+        };
+        let connector = GitConnector {
+            repos: self.repos.clone(),
+            allow_commit: self.allow_commit,
+        };
+        tokio::task::spawn_blocking(move || call(&connector, &args))
+            .await
+            .map_err(|e| ConnectorError::Failed(e.to_string()))?
+    }
+
+    async fn health(&self) -> Health {
+        let missing: Vec<&str> = self
+            .repos
+            .iter()
+            .filter(|(_, path)| !path.join(".git").exists())
+            .map(|(name, _)| name.as_str())
+            .collect();
+        if missing.is_empty() {
+            Health::Ok
+        } else {
+            Health::Down(format!("not a repository any more: {}", missing.join(", ")))
+        }
+    }
+}
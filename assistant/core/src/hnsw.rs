@@ -0,0 +1,411 @@
+//! Hierarchical navigable small world graph for approximate nearest
+//! neighbor search over embeddings, by the [`Metric`] it is built for.
+//!
+//! The graph stores only links; vectors stay with the caller and are
+//! looked up by node number, which is the position of the entry in the
+//! index. Each node sits on levels `0..=level`, with up to `m` links per
+//! level (`2 * m` on level 0). A search descends greedily from the entry
+//! point, then explores level 0 keeping the `ef` best nodes seen.
+
+use crate::metric::Metric;
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+
+/// Graph parameters, fixed per collection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HnswParams {
+    /// Links per node and level; more is slower to build but more accurate.
+    pub m: usize,
+    /// Candidates kept while linking a new node.
+    pub ef_construction: usize,
+    /// Candidates kept while searching; at least `k` are always kept.
+    pub ef_search: usize,
+}
+
+impl Default for HnswParams {
+    fn default() -> Self {
+        HnswParams {
+            m: 16,
+            ef_construction: 100,
+            ef_search: 64,
+        }
+    }
+}
+
+impl HnswParams {
+    /// Validates requested parameters; zero takes the default.
+    pub fn new(m: usize, ef_construction: usize, ef_search: usize) -> Result<Self, String> {
+        let default = HnswParams::default();
+        let pick = |v: usize, d: usize| if v == 0 { d } else { v };
+        let params = HnswParams {
+            m: pick(m, default.m),
+            ef_construction: pick(ef_construction, default.ef_construction),
+            ef_search: pick(ef_search, default.ef_search),
+        };
+        if !(2..=128).contains(&params.m) {
+            return Err(format!(
+                "hnsw m must be between 2 and 128, not {}",
+                params.m
+            ));
+        }
+        Ok(params)
+    }
+}
+
+/// A similarity and the node it belongs to, ordered by similarity.
+#[derive(Clone, Copy, Debug)]
+struct Scored(f32, u32);
+
+impl PartialEq for Scored {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(other.1.cmp(&self.1))
+    }
+}
+
+pub struct Hnsw {
+    params: HnswParams,
+    metric: Metric,
+    /// Per node, per level, the linked nodes.
+    links: Vec<Vec<Vec<u32>>>,
+    entry: Option<u32>,
+    /// xorshift state for drawing levels; fixed seed, so builds repeat.
+    rng: u64,
+}
+
+impl Hnsw {
+    pub fn new(params: HnswParams, metric: Metric) -> Self {
+        Hnsw {
+            params,
+            metric,
+            links: Vec::new(),
+            entry: None,
+            rng: 0x9e37_79b9_7f4a_7c15,
+        }
+    }
+
+    /// A graph over nodes `0..len`.
+    pub fn build<'a>(
+        params: HnswParams,
+        metric: Metric,
+        len: usize,
+        vector: impl Fn(usize) -> &'a [f32],
+    ) -> Self {
+        let mut graph = Hnsw::new(params, metric);
+        for node in 0..len {
+            graph.insert(node, &vector);
+        }
+        graph
+    }
+
+    pub fn len(&self) -> usize {
+        self.links.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.links.is_empty()
+    }
+
+    /// The metric the graph links nodes by; searches only find the nearest
+    /// nodes by this one.
+    pub fn metric(&self) -> Metric {
+        self.metric
+    }
+
+    fn random_level(&mut self) -> usize {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let uniform = (self.rng >> 11) as f64 / (1u64 << 53) as f64;
+        let scale = 1.0 / (self.params.m as f64).ln();
+        (-(1.0 - uniform).ln() * scale) as usize
+    }
+
+    fn max_links(&self, level: usize) -> usize {
+        if level == 0 {
+            2 * self.params.m
+        } else {
+            self.params.m
+        }
+    }
+
+    fn top_level(&self) -> usize {
+        self.entry.map_or(0, |e| self.links[e as usize].len() - 1)
+    }
+
+    /// The `ef` nodes on `level` that `score` rates highest reachable from
+    /// `entries`, best first.
+    fn search_level(
+        &self,
+        entries: &[u32],
+        ef: usize,
+        level: usize,
+        score: &impl Fn(usize) -> f32,
+    ) -> Vec<Scored> {
+        let mut visited: HashSet<u32> = entries.iter().copied().collect();
+        let mut candidates: BinaryHeap<Scored> = BinaryHeap::new();
+        let mut best: BinaryHeap<Reverse<Scored>> = BinaryHeap::new();
+        for &e in entries {
+            let scored = Scored(score(e as usize), e);
+            candidates.push(scored);
+            best.push(Reverse(scored));
+        }
+        while let Some(current) = candidates.pop() {
+            let worst = best.peek().map(|r| r.0);
+            if best.len() >= ef && worst.is_some_and(|w| current < w) {
+                break;
+            }
+            let Some(neighbors) = self.links[current.1 as usize].get(level) else {
+                continue;
+            };
+            for &n in neighbors {
+                if !visited.insert(n) {
+                    continue;
+                }
+                let scored = Scored(score(n as usize), n);
+                if best.len() < ef || best.peek().is_some_and(|w| scored > w.0) {
+                    candidates.push(scored);
+                    best.push(Reverse(scored));
+                    if best.len() > ef {
+                        best.pop();
+                    }
+                }
+            }
+        }
+        let mut found: Vec<Scored> = best.into_iter().map(|r| r.0).collect();
+        found.sort_by(|a, b| b.cmp(a));
+        found
+    }
+
+    /// Links `node` into the graph. A node already in the graph is
+    /// relinked for its current vector; links pointing at it are kept.
+    pub fn insert<'a>(&mut self, node: usize, vector: &impl Fn(usize) -> &'a [f32]) {
+        // Relinking the entry point starts from one of its old links.
+        let mut start = self.entry.filter(|&e| e as usize != node);
+        let level = match self.links.get_mut(node) {
+            Some(levels) => {
+                if start.is_none() {
+                    start = levels.iter().rev().find_map(|l| l.first().copied());
+                }
+                levels.iter_mut().for_each(Vec::clear);
+                levels.len() - 1
+            }
+            None => {
+                assert_eq!(node, self.links.len(), "nodes are inserted in order");
+                let level = self.random_level();
+                self.links.push(vec![Vec::new(); level + 1]);
+                level
+            }
+        };
+        let Some(entry) = start else {
+            self.entry.get_or_insert(node as u32);
+            return;
+        };
+        let query = vector(node);
+        let metric = self.metric;
+        let score = |i: usize| metric.score(query, vector(i));
+        let top = self.top_level();
+        let mut entries = vec![entry];
+        for l in (level + 1..=top).rev() {
+            entries = vec![self.search_level(&entries, 1, l, &score)[0].1];
+        }
+        for l in (0..=level.min(top)).rev() {
+            let found = self.search_level(&entries, self.params.ef_construction, l, &score);
+            let neighbors: Vec<u32> = found
+                .iter()
+                .map(|s| s.1)
+                .filter(|&n| n as usize != node)
+                .take(self.params.m)
+                .collect();
+            for &n in &neighbors {
+                self.link(n as usize, node as u32, l, vector);
+            }
+            self.links[node][l] = neighbors;
+            entries = found.into_iter().map(|s| s.1).collect();
+        }
+        if level > top {
+            self.entry = Some(node as u32);
+        }
+    }
+
+    /// Adds a link from `from` to `to`, dropping `from`'s least similar
+    /// link if it has too many.
+    fn link<'a>(
+        &mut self,
+        from: usize,
+        to: u32,
+        level: usize,
+        vector: &impl Fn(usize) -> &'a [f32],
+    ) {
+        let max = self.max_links(level);
+        let links = &mut self.links[from][level];
+        if links.contains(&to) {
+            return;
+        }
+        links.push(to);
+        if links.len() > max {
+            let base = vector(from);
+            let metric = self.metric;
+            let mut scored: Vec<Scored> = links
+                .iter()
+                .map(|&n| Scored(metric.score(base, vector(n as usize)), n))
+                .collect();
+            scored.sort_by(|a, b| b.cmp(a));
+            *links = scored.into_iter().take(max).map(|s| s.1).collect();
+        }
+    }
+
+    /// Drops the nodes `keep` is false for and numbers the rest in order,
+    /// without building the graph again. A node that loses links is
+    /// linked instead to the best of its dropped neighbors' links, so
+    /// the graph stays connected around the gap; `vector` is by the new
+    /// numbers. Searches degrade as more nodes are dropped, so a graph
+    /// that lost many is better built again.
+    pub fn retain<'a>(&mut self, keep: &[bool], vector: impl Fn(usize) -> &'a [f32]) {
+        let kept = |node: u32| keep.get(node as usize).copied().unwrap_or(true);
+        let mut renumbered = Vec::with_capacity(self.links.len());
+        let mut next = 0;
+        for node in 0..self.links.len() as u32 {
+            renumbered.push(next);
+            next += u32::from(kept(node));
+        }
+        let mut links = Vec::with_capacity(next as usize);
+        for (node, levels) in self.links.iter().enumerate() {
+            if !kept(node as u32) {
+                continue;
+            }
+            let new = renumbered[node];
+            let levels: Vec<Vec<u32>> = levels
+                .iter()
+                .enumerate()
+                .map(|(level, neighbors)| {
+                    let mut linked: Vec<u32> = Vec::new();
+                    for &n in neighbors {
+                        let replacements = match kept(n) {
+                            true => std::slice::from_ref(&n),
+                            false => self.links[n as usize]
+                                .get(level)
+                                .map_or(&[][..], Vec::as_slice),
+                        };
+                        for &r in replacements {
+                            if kept(r)
+                                && r as usize != node
+                                && !linked.contains(&renumbered[r as usize])
+                            {
+                                linked.push(renumbered[r as usize]);
+                            }
+                        }
+                    }
+                    let max = self.max_links(level);
+                    if linked.len() > max {
+                        let base = vector(new as usize);
+                        let metric = self.metric;
+                        let mut scored: Vec<Scored> = linked
+                            .iter()
+                            .map(|&n| Scored(metric.score(base, vector(n as usize)), n))
+                            .collect();
+                        scored.sort_by(|a, b| b.cmp(a));
+                        linked = scored.into_iter().take(max).map(|s| s.1).collect();
+                    }
+                    linked
+                })
+                .collect();
+            links.push(levels);
+        }
+        self.entry = match self.entry.filter(|&e| kept(e)) {
+            Some(e) => Some(renumbered[e as usize]),
+            None => (0..links.len())
+                .max_by_key(|&n| (links[n].len(), std::cmp::Reverse(n)))
+                .map(|n| n as u32),
+        };
+        self.links = links;
+    }
+
+    /// Up to `ef` nodes most similar to a query, best first. `score` rates
+    /// a node's similarity to the query by the graph's metric, exactly or
+    /// from a compressed copy of its vector.
+    pub fn search(&self, ef: usize, score: impl Fn(usize) -> f32) -> Vec<usize> {
+        let Some(entry) = self.entry else {
+            return Vec::new();
+        };
+        let mut entries = vec![entry];
+        for l in (1..=self.top_level()).rev() {
+            entries = vec![self.search_level(&entries, 1, l, &score)[0].1];
+        }
+        self.search_level(&entries, ef.max(1), 0, &score)
+            .into_iter()
+            .map(|s| s.1 as usize)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vectors(n: usize, dim: usize) -> Vec<Vec<f32>> {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        (0..n)
+            .map(|_| {
+                (0..dim)
+                    .map(|_| {
+                        state ^= state << 13;
+                        state ^= state >> 7;
+                        state ^= state << 17;
+                        (state >> 40) as f32 / (1u64 << 24) as f32 - 0.5
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn retained_graph_still_finds_the_nearest_nodes() {
+        let all = vectors(2000, 16);
+        let params = HnswParams::default();
+        let mut graph = Hnsw::build(params, Metric::Cosine, all.len(), |i| &all[i]);
+        // Drop every third node, the entry point among them.
+        let keep: Vec<bool> = (0..all.len())
+            .map(|i| i % 3 != 0 && graph.entry != Some(i as u32))
+            .collect();
+        let left: Vec<Vec<f32>> = all
+            .iter()
+            .zip(&keep)
+            .filter(|(_, kept)| **kept)
+            .map(|(v, _)| v.clone())
+            .collect();
+        graph.retain(&keep, |i| &left[i]);
+        assert_eq!(graph.len(), left.len());
+
+        let queries = vectors(50, 16);
+        let found = queries
+            .iter()
+            .filter(|q| {
+                let exact = (0..left.len())
+                    .max_by(|&a, &b| {
+                        let score = |i: usize| Metric::Cosine.score(q, &left[i]);
+                        score(a).total_cmp(&score(b))
+                    })
+                    .unwrap();
+                let hits = graph.search(params.ef_search, |i| Metric::Cosine.score(q, &left[i]));
+                hits.first() == Some(&exact)
+            })
+            .count();
+        assert!(found >= 45, "{found} of 50 nearest found");
+    }
+}
@@ -0,0 +1,1705 @@
+//! Vector index over documents, persisted as a single file in the
+//! [`indexfile`](crate::indexfile) format. Writes go to a
+//! [write-ahead log](crate::wal) first; the file is rewritten from memory
+//! once [`COMPACT_AFTER`] writes have been logged, and when opened.
+//!
+//! Indexes of [`EXACT_SEARCH_BELOW`] entries or more also keep an HNSW
+//! graph in memory, built on open and updated on every write, so queries
+//! score a few hundred candidates instead of every entry.
+//!
+//! Entries are embedded by the index's [`Embedder`], which is fixed when
+//! it is opened. Embeddings are stored as it makes them and compared by
+//! the index's [`Metric`], or one a query asks for. They are kept apart
+//! from the entries, in [`Vectors`]: those saved in the index file are
+//! mapped rather than read in, so a large index needs little memory for
+//! them. A collection can also keep compact [`Codes`] of them in memory,
+//! which queries rank entries by before reading the vectors of the best
+//! few to score them exactly.
+//!
+//! An index can instead be kept in a [`DocStore`], which it reads in full
+//! when opened and makes each write in rather than logging it.
+
+use crate::bm25::Bm25;
+use crate::compress::StoredText;
+use crate::docid::{self, Provenance};
+use crate::embed::{Embedder, HashEmbedder};
+use crate::filter::Filter;
+use crate::hnsw::{Hnsw, HnswParams};
+use crate::indexfile;
+use crate::metric::Metric;
+use crate::quantize::{Codes, Quantization, MIN_TRAINING};
+use crate::store::DocStore;
+use crate::text;
+use crate::vectors::Vectors;
+use crate::wal::{Record, Wal};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Logged writes after which the index file is rewritten and the log
+/// emptied.
+pub const COMPACT_AFTER: usize = 1000;
+
+/// Rank offset in reciprocal rank fusion; larger values flatten the
+/// difference between the top ranks.
+const RRF_K: f32 = 60.0;
+
+/// Maximal marginal relevance picks `k` hits from this many times `k` of
+/// the best matches.
+const MMR_POOL: usize = 4;
+
+/// Smaller indexes are always searched exactly; a scan is fast enough and
+/// a graph would not pay for itself.
+pub const EXACT_SEARCH_BELOW: usize = 1000;
+
+/// Scans of fewer entries are scored on the calling thread; larger ones
+/// are split across the cores.
+pub const PARALLEL_SCORING_FROM: usize = 10_000;
+
+/// Removed entries are dropped from the graph in place until those removed
+/// since it was built reach one in this many; then it is built again.
+const REBUILD_GRAPH_AFTER_ONE_IN: usize = 4;
+
+/// Entries read from a [`DocStore`] at a time when an index is opened
+/// from one.
+const SCAN_PAGE: usize = 1024;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Doc {
+    pub id: String,
+    /// Compressed once read from a saved index; see [`StoredText`].
+    #[serde(default, skip_serializing_if = "StoredText::is_empty")]
+    pub text: StoredText,
+    /// Saved apart from the rest of the entry; read from JSON indexes.
+    /// Empty on an index's own entries, whose embeddings are in its
+    /// [`Vectors`]; filled in for [`VectorIndex::document`].
+    #[serde(default, skip_serializing)]
+    pub embedding: Vec<f32>,
+    #[serde(default, flatten)]
+    pub provenance: Provenance,
+    /// When the entry was last written, in milliseconds since the Unix
+    /// epoch. Unknown for entries indexed by earlier versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub indexed_at: Option<i64>,
+    /// Caller-supplied key/value pairs, e.g. `{"kind": "email"}`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+    /// When the entry is due to be removed, in milliseconds since the
+    /// Unix epoch; `None` keeps it until deleted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    /// SHA-256 of the whole document's text, on each of its entries; empty
+    /// for caller-supplied chunks and entries from earlier versions.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub content_hash: String,
+    /// Ids indexed with the same text and merged into this document.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub merged_ids: Vec<String>,
+    /// Version of the parent document when the entry was written: 1 for
+    /// its first write, one more for each later one. Entries from earlier
+    /// versions read as 1.
+    #[serde(default = "first_version")]
+    pub version: u64,
+}
+
+fn first_version() -> u64 {
+    1
+}
+
+#[derive(Clone, Debug)]
+pub struct Hit {
+    pub id: String,
+    /// Parent document of the chunk; see [`docid::parent`].
+    pub document_id: String,
+    pub text: String,
+    pub score: f32,
+    pub provenance: Provenance,
+    pub indexed_at: Option<i64>,
+    pub metadata: BTreeMap<String, String>,
+    /// Up to `context_window` chunks right before and after the hit.
+    pub before: Vec<Doc>,
+    pub after: Vec<Doc>,
+}
+
+/// How one hit was scored.
+#[derive(Clone, Debug)]
+pub struct ExplainedHit {
+    pub id: String,
+    pub score: f32,
+    pub vector_score: f32,
+    /// BM25 score of the query terms in the entry.
+    pub keyword_score: f32,
+    pub matched_terms: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Explanation {
+    pub terms: Vec<String>,
+    pub query_vector: Vec<f32>,
+    pub candidates: usize,
+    pub hits: Vec<ExplainedHit>,
+}
+
+/// Order of query hits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Sort {
+    /// Best match first.
+    #[default]
+    Score,
+    /// Most recently indexed first, then by score; entries without a
+    /// time come last.
+    Newest,
+}
+
+impl Sort {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "" | "score" => Some(Sort::Score),
+            "indexed_at" => Some(Sort::Newest),
+            _ => None,
+        }
+    }
+}
+
+/// How query text is matched against entries.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// Embedding similarity.
+    #[default]
+    Vector,
+    /// BM25 over the entries' terms.
+    Keyword,
+    /// Both rankings, fused by reciprocal rank: each entry scores
+    /// `1 / (60 + rank)` summed over the rankings it appears in.
+    Hybrid,
+}
+
+impl Mode {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "" | "vector" => Some(Mode::Vector),
+            "keyword" => Some(Mode::Keyword),
+            "hybrid" => Some(Mode::Hybrid),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct QueryOptions {
+    pub k: usize,
+    /// Keep only the best chunk of each parent document.
+    pub group_by_document: bool,
+    /// Neighboring chunks to return on each side of a hit.
+    pub context_window: u32,
+    /// Only entries matching this are scored.
+    pub filter: Filter,
+    pub sort: Sort,
+    /// Score every entry exactly even when a graph or codes are available.
+    pub exact: bool,
+    pub mode: Mode,
+    /// The query text's embedding, if already computed with this index's
+    /// embedder; otherwise it is embedded on the spot.
+    pub vector: Option<Vec<f32>>,
+    /// Compares embeddings by this instead of the index's metric. The
+    /// graph only serves its own metric, so others score every entry.
+    pub metric: Option<Metric>,
+    /// Picks hits by maximal marginal relevance instead of score alone:
+    /// each next hit maximizes `lambda * relevance - (1 - lambda) *` its
+    /// cosine to the closest hit already picked. 1 ranks by score only;
+    /// lower values favor hits unlike the ones before them. Only applies
+    /// to [`Sort::Score`].
+    pub mmr_lambda: Option<f32>,
+}
+
+impl Default for QueryOptions {
+    fn default() -> Self {
+        QueryOptions {
+            k: 5,
+            group_by_document: false,
+            context_window: 0,
+            filter: Filter::default(),
+            sort: Sort::Score,
+            exact: false,
+            mode: Mode::Vector,
+            vector: None,
+            metric: None,
+            mmr_lambda: None,
+        }
+    }
+}
+
+/// A document put back together from its stored chunks.
+#[derive(Clone, Debug)]
+pub struct Document {
+    pub id: String,
+    pub text: String,
+    pub source: String,
+    pub mime_type: String,
+    /// Latest `indexed_at` of its chunks.
+    pub indexed_at: Option<i64>,
+    /// Metadata of its first chunk.
+    pub metadata: BTreeMap<String, String>,
+    /// Ids merged into it as duplicates.
+    pub merged_ids: Vec<String>,
+    /// Latest `version` of its chunks.
+    pub version: u64,
+    /// Chunks in source order; a document stored whole is its own only chunk.
+    pub chunks: Vec<Doc>,
+}
+
+/// One line of a document listing.
+#[derive(Clone, Debug)]
+pub struct DocumentSummary {
+    pub id: String,
+    /// Start of the first chunk's text.
+    pub preview: String,
+    /// Size of all chunks' text.
+    pub size_bytes: u64,
+    pub chunks: u32,
+    pub source: String,
+    pub indexed_at: Option<i64>,
+    pub version: u64,
+}
+
+/// Sizes and state of an index, as [`VectorIndex::stats`] reports them.
+#[derive(Clone, Debug, Default)]
+pub struct IndexStats {
+    pub entries: usize,
+    /// Distinct parent documents of the entries.
+    pub documents: usize,
+    /// Size of all entries' text.
+    pub text_bytes: u64,
+    /// Floats per embedding.
+    pub dim: usize,
+    /// Size of the index file and its log.
+    pub disk_bytes: u64,
+    /// When the index file was last written, in milliseconds since the
+    /// Unix epoch; `None` if it has not been.
+    pub saved_at: Option<i64>,
+    /// Writes logged since then, which the next rewrite folds in.
+    pub pending_writes: usize,
+    /// Whether queries can use the search graph rather than scan.
+    pub graph: bool,
+    /// Memory taken by the entries' codes; 0 without any.
+    pub code_bytes: usize,
+}
+
+/// Characters of text in a [`DocumentSummary::preview`].
+const PREVIEW_CHARS: usize = 200;
+
+pub struct VectorIndex {
+    /// Where the index is saved; `None` keeps it in memory only.
+    path: Option<PathBuf>,
+    /// Present whenever `path` is.
+    wal: Option<Wal>,
+    /// Where writes go instead of the log, for an index opened from one.
+    store: Option<Box<dyn DocStore>>,
+    embedder: Arc<dyn Embedder>,
+    docs: Vec<Doc>,
+    /// Storage position of each entry by id.
+    positions: HashMap<String, usize>,
+    /// The entries' embeddings, in the same order.
+    vectors: Vectors,
+    /// Writes applied since the index was opened.
+    writes: u64,
+    hnsw_params: HnswParams,
+    metric: Metric,
+    /// Present once the index reaches [`EXACT_SEARCH_BELOW`] entries.
+    graph: Option<Hnsw>,
+    /// Entries removed since the graph was built.
+    unlinked: usize,
+    quantization: Quantization,
+    /// Codes of the entries' embeddings, in the same order, once the
+    /// index has [`MIN_TRAINING`] entries and quantizes them.
+    codes: Option<Codes>,
+    /// Keyword index over the entries' text.
+    keywords: Bm25,
+}
+
+/// Lowercased alphanumeric terms of `text`, in order.
+pub fn terms(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in bytes {
+        hash ^= u64::from(*b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Embeddings of an index's entries by another embedder, in storage
+/// order as of one write, and their search graph once built.
+pub struct Reembedded {
+    writes: u64,
+    vectors: Vectors,
+    graph: Option<Hnsw>,
+    params: HnswParams,
+    metric: Metric,
+}
+
+impl Reembedded {
+    /// Builds the search graph, if the index is large enough to use one.
+    /// This is the slow part of a switch, so it is done before taking the
+    /// index's lock.
+    pub fn build_graph(&mut self) {
+        let vectors = &self.vectors;
+        self.graph = (vectors.len() >= EXACT_SEARCH_BELOW)
+            .then(|| Hnsw::build(self.params, self.metric, vectors.len(), |i| vectors.get(i)));
+    }
+}
+
+/// Whether `made` has an embedding of `doc`'s current text.
+fn made_for(made: &HashMap<String, (u64, Vec<f32>)>, doc: &Doc) -> bool {
+    made.get(&doc.id)
+        .is_some_and(|(hash, _)| *hash == fnv1a(doc.text.get().as_bytes()))
+}
+
+/// An entry to add, before it is embedded.
+#[derive(Clone, Debug)]
+pub struct NewEntry {
+    pub id: String,
+    pub text: String,
+    pub provenance: Provenance,
+    pub metadata: BTreeMap<String, String>,
+    /// How long after being written the entry expires.
+    pub ttl: Option<Duration>,
+    /// See [`Doc::content_hash`].
+    pub content_hash: String,
+}
+
+impl NewEntry {
+    fn into_doc(self, indexed_at: i64, embedding: Vec<f32>) -> Doc {
+        let mut provenance = self.provenance;
+        if provenance.source.is_empty() {
+            if let Some(parsed) = docid::parse(&self.id) {
+                provenance.source = parsed.source;
+                provenance.chunk = parsed.chunk;
+            }
+        }
+        if provenance.mime_type.is_empty() {
+            provenance.mime_type = docid::mime_type(&provenance.source).to_string();
+        }
+        Doc {
+            embedding,
+            id: self.id,
+            text: self.text.into(),
+            provenance,
+            indexed_at: Some(indexed_at),
+            metadata: self.metadata,
+            expires_at: self
+                .ttl
+                .map(|ttl| indexed_at.saturating_add(ttl.as_millis() as i64)),
+            content_hash: self.content_hash,
+            merged_ids: Vec::new(),
+            version: first_version(),
+        }
+    }
+}
+
+/// Fewest items worth handing to a thread of their own.
+const MIN_ITEMS_PER_THREAD: usize = 64;
+
+/// `f` applied to each item, spread over the available cores. Results
+/// keep the items' order.
+pub(crate) fn in_parallel<T: Send, U: Send>(items: Vec<T>, f: impl Fn(T) -> U + Sync) -> Vec<U> {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let per_thread = items.len().div_ceil(threads).max(MIN_ITEMS_PER_THREAD);
+    if items.len() <= per_thread {
+        return items.into_iter().map(f).collect();
+    }
+    let mut batches = Vec::new();
+    let mut rest = items;
+    while rest.len() > per_thread {
+        let tail = rest.split_off(per_thread);
+        batches.push(rest);
+        rest = tail;
+    }
+    batches.push(rest);
+    let f = &f;
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = batches
+            .into_iter()
+            .map(|batch| scope.spawn(move || batch.into_iter().map(f).collect::<Vec<U>>()))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().expect("worker thread panicked"))
+            .collect()
+    })
+}
+
+/// The entries of `saved`, their embeddings and codes, embedded again
+/// with `embedder` if another one made them, and whether they were. Codes
+/// of converted embeddings are dropped. `path` is only for the log message.
+fn convert(
+    saved: indexfile::Contents,
+    embedder: &dyn Embedder,
+    path: &Path,
+) -> (Vec<Doc>, Vectors, Option<Codes>, bool) {
+    let docs = saved.docs;
+    // Files from before fingerprints were saved are taken to match.
+    let replaced = !saved.fingerprint.is_empty() && saved.fingerprint != embedder.fingerprint();
+    let converted = saved.normalized
+        || saved.embedder != embedder.name()
+        || saved.vectors.dim() != embedder.dim()
+        || replaced;
+    if !converted {
+        return (docs, saved.vectors, saved.codes, false);
+    }
+    if saved.embedder == embedder.name() && replaced {
+        log::info!(
+            "re-embedding {}: the model behind {} changed",
+            path.display(),
+            saved.embedder
+        );
+    } else if saved.embedder == embedder.name() {
+        log::info!(
+            "re-embedding {} to keep its vectors unnormalized",
+            path.display()
+        );
+    } else {
+        log::info!(
+            "converting {} from {} to {} embeddings",
+            path.display(),
+            saved.embedder,
+            embedder.name()
+        );
+    }
+    let texts: Vec<String> = docs.iter().map(|d| d.text.get().into_owned()).collect();
+    let mut vectors = Vectors::new(embedder.dim());
+    for embedding in in_parallel(texts, |text| embedder.embed(&text)) {
+        vectors.push(&embedding);
+    }
+    (docs, vectors, None, true)
+}
+
+/// Storage position of each of `docs` by id.
+fn positions(docs: &[Doc]) -> HashMap<String, usize> {
+    docs.iter()
+        .enumerate()
+        .map(|(at, d)| (d.id.clone(), at))
+        .collect()
+}
+
+/// Applies a logged write to `docs`, their `positions`, `vectors` and
+/// `codes`. Both kinds can be applied again without changing the result.
+fn replay(
+    docs: &mut Vec<Doc>,
+    positions: &mut HashMap<String, usize>,
+    vectors: &mut Vectors,
+    codes: &mut Option<Codes>,
+    record: Record,
+    embedder: &dyn Embedder,
+) {
+    match record {
+        Record::Upsert { doc, embedding } => {
+            let doc = *doc;
+            let embedding = match embedding.len() == vectors.dim() {
+                true => embedding,
+                false => embedder.embed(&doc.text.get()),
+            };
+            match positions.get(&doc.id) {
+                Some(&at) => {
+                    docs[at] = doc;
+                    vectors.set(at, &embedding);
+                    if let Some(codes) = codes {
+                        codes.set(at, &embedding);
+                    }
+                }
+                None => {
+                    positions.insert(doc.id.clone(), docs.len());
+                    docs.push(doc);
+                    vectors.push(&embedding);
+                    if let Some(codes) = codes {
+                        codes.push(&embedding);
+                    }
+                }
+            }
+        }
+        Record::Delete { ids } => {
+            let ids: HashSet<String> = ids.into_iter().collect();
+            let keep: Vec<bool> = docs.iter().map(|d| !ids.contains(&d.id)).collect();
+            retain(docs, &keep);
+            *positions = self::positions(docs);
+            vectors.retain(&keep);
+            if let Some(codes) = codes {
+                codes.retain(&keep);
+            }
+        }
+    }
+}
+
+/// Makes the writes in `records` in `store`, in order.
+fn store_records(store: &mut dyn DocStore, records: &[Record]) -> io::Result<()> {
+    let mut docs = Vec::new();
+    for record in records {
+        match record {
+            Record::Upsert { doc, embedding } => docs.push(Doc {
+                embedding: embedding.clone(),
+                ..(**doc).clone()
+            }),
+            Record::Delete { ids } => {
+                if !docs.is_empty() {
+                    store.put(&std::mem::take(&mut docs))?;
+                }
+                store.delete(ids)?;
+            }
+        }
+    }
+    match docs.is_empty() {
+        true => Ok(()),
+        false => store.put(&docs),
+    }
+}
+
+/// Keeps the items for which `keep` is true.
+fn retain<T>(items: &mut Vec<T>, keep: &[bool]) {
+    let mut keep = keep.iter();
+    items.retain(|_| *keep.next().unwrap_or(&true));
+}
+
+impl VectorIndex {
+    /// Opens the index saved at `path`, or an empty one if there is none yet,
+    /// and replays the writes logged next to it in `<name>.wal`. A JSON
+    /// index saved by earlier versions as `<name>.json` is converted and
+    /// then removed. An index saved with another embedder is converted:
+    /// its entries are embedded again with `embedder`.
+    pub fn open(path: impl Into<PathBuf>, embedder: Arc<dyn Embedder>) -> io::Result<Self> {
+        let path = path.into();
+        let legacy = path.with_extension("json");
+        let (saved, imported) = match indexfile::read(&path) {
+            Ok(saved) => (saved, false),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => match std::fs::read(&legacy) {
+                Ok(data) => {
+                    let docs = serde_json::from_slice(&data)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    // JSON indexes predate other embedders, and their
+                    // normalized embeddings are made again.
+                    let embedder = HashEmbedder::DEFAULT.to_string();
+                    let normalized = true;
+                    let vectors = Vectors::default();
+                    (
+                        indexfile::Contents {
+                            embedder,
+                            fingerprint: String::new(),
+                            normalized,
+                            docs,
+                            vectors,
+                            codes: None,
+                            version: 0,
+                        },
+                        true,
+                    )
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    let vectors = Vectors::new(embedder.dim());
+                    let fingerprint = embedder.fingerprint();
+                    let embedder = embedder.name().to_string();
+                    let docs = Vec::new();
+                    let normalized = false;
+                    (
+                        indexfile::Contents {
+                            embedder,
+                            fingerprint,
+                            normalized,
+                            docs,
+                            vectors,
+                            codes: None,
+                            version: indexfile::FORMAT_VERSION,
+                        },
+                        false,
+                    )
+                }
+                Err(e) => return Err(e),
+            },
+            Err(e) => return Err(e),
+        };
+        // Older files are rewritten so their vectors can be mapped.
+        let outdated = saved.version < indexfile::FORMAT_VERSION;
+        let (mut docs, mut vectors, mut codes, converted) =
+            convert(saved, embedder.as_ref(), &path);
+        let (wal, records) = Wal::open(path.with_extension("wal"))?;
+        let logged = !records.is_empty();
+        let mut positions = self::positions(&docs);
+        for record in records {
+            replay(
+                &mut docs,
+                &mut positions,
+                &mut vectors,
+                &mut codes,
+                record,
+                embedder.as_ref(),
+            );
+        }
+        let mut index = VectorIndex {
+            path: Some(path),
+            wal: Some(wal),
+            store: None,
+            embedder,
+            docs,
+            positions,
+            vectors,
+            writes: 0,
+            hnsw_params: HnswParams::default(),
+            metric: Metric::default(),
+            graph: None,
+            unlinked: 0,
+            keywords: Bm25::default(),
+            quantization: codes.as_ref().map_or(Quantization::None, Codes::kind),
+            codes,
+        };
+        if imported || logged || converted || outdated {
+            index.compact()?;
+        }
+        if imported {
+            std::fs::remove_file(legacy)?;
+        }
+        index.keywords = Bm25::build(index.docs.iter().map(|d| d.text.get()));
+        index.rebuild_graph();
+        Ok(index)
+    }
+
+    /// An empty index held in memory only, for entries that are never
+    /// saved.
+    pub fn in_memory(embedder: Arc<dyn Embedder>) -> Self {
+        VectorIndex {
+            path: None,
+            wal: None,
+            store: None,
+            vectors: Vectors::new(embedder.dim()),
+            embedder,
+            docs: Vec::new(),
+            positions: HashMap::new(),
+            writes: 0,
+            hnsw_params: HnswParams::default(),
+            metric: Metric::default(),
+            graph: None,
+            unlinked: 0,
+            quantization: Quantization::None,
+            codes: None,
+            keywords: Bm25::default(),
+        }
+    }
+
+    /// The index kept in `store`, read from it in full; later writes are
+    /// made in the store. Entries stored with an embedding of another size
+    /// are embedded again with `embedder`, and stored so.
+    pub fn with_store(
+        mut store: Box<dyn DocStore>,
+        embedder: Arc<dyn Embedder>,
+    ) -> io::Result<Self> {
+        let mut index = VectorIndex::in_memory(embedder);
+        let mut stale = Vec::new();
+        let mut after = String::new();
+        loop {
+            let docs = store.scan(&after, SCAN_PAGE)?;
+            let Some(last) = docs.last() else {
+                break;
+            };
+            after = last.id.clone();
+            for mut doc in docs {
+                let embedding = std::mem::take(&mut doc.embedding);
+                if embedding.len() != index.vectors.dim() {
+                    stale.push(doc.id.clone());
+                }
+                let record = Record::Upsert {
+                    doc: Box::new(doc),
+                    embedding,
+                };
+                replay(
+                    &mut index.docs,
+                    &mut index.positions,
+                    &mut index.vectors,
+                    &mut index.codes,
+                    record,
+                    index.embedder.as_ref(),
+                );
+            }
+        }
+        if !stale.is_empty() {
+            let docs: Vec<Doc> = stale
+                .iter()
+                .filter_map(|id| index.position(id))
+                .flat_map(|at| index.entries(at, 1, true))
+                .collect();
+            store.put(&docs)?;
+        }
+        index.store = Some(store);
+        index.keywords = Bm25::build(index.docs.iter().map(|d| d.text.get()));
+        index.rebuild_graph();
+        Ok(index)
+    }
+
+    /// The index saved at `path`, held in memory only: it has no log, and
+    /// writes to it are never saved. Entries made by another embedder are
+    /// embedded again with `embedder`.
+    pub fn load(
+        path: &Path,
+        embedder: Arc<dyn Embedder>,
+        hnsw_params: HnswParams,
+        metric: Metric,
+    ) -> io::Result<Self> {
+        let saved = indexfile::read(path)?;
+        let (docs, vectors, codes, _) = convert(saved, embedder.as_ref(), path);
+        let mut index = VectorIndex {
+            path: None,
+            wal: None,
+            store: None,
+            embedder,
+            keywords: Bm25::build(docs.iter().map(|d| d.text.get())),
+            positions: positions(&docs),
+            docs,
+            vectors,
+            writes: 0,
+            hnsw_params,
+            metric,
+            graph: None,
+            unlinked: 0,
+            quantization: codes.as_ref().map_or(Quantization::None, Codes::kind),
+            codes,
+        };
+        index.rebuild_graph();
+        Ok(index)
+    }
+
+    /// Sets the graph parameters and the metric, rebuilding the graph if
+    /// there is one.
+    pub fn set_graph(&mut self, params: HnswParams, metric: Metric) {
+        if params != self.hnsw_params || metric != self.metric {
+            self.hnsw_params = params;
+            self.metric = metric;
+            self.graph = None;
+            self.rebuild_graph();
+        }
+    }
+
+    /// Sets how the entries' embeddings are compressed in memory, training
+    /// codes for them, or dropping them, and saving the index if that
+    /// changes them. An index opened from a file keeps the quantization
+    /// it was saved with until this is called.
+    pub fn set_quantization(&mut self, quantization: Quantization) -> io::Result<()> {
+        self.quantization = quantization;
+        if self.refresh_codes() {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Trains the codes again if they are of another quantization than the
+    /// index's, or the index has doubled since they were trained, and
+    /// drops them if it quantizes nothing. Returns whether they changed.
+    fn refresh_codes(&mut self) -> bool {
+        let stale = match &self.codes {
+            Some(codes) => {
+                codes.kind() != self.quantization || codes.trained_on() * 2 <= self.docs.len()
+            }
+            None => self.quantization != Quantization::None && self.docs.len() >= MIN_TRAINING,
+        };
+        if !stale {
+            return false;
+        }
+        self.codes = Codes::train(self.quantization, &self.vectors);
+        if let Some(codes) = &self.codes {
+            log::info!(
+                "trained {} codes for {} entries: {} KiB in memory for {} KiB of vectors",
+                self.quantization.name(),
+                codes.len(),
+                codes.memory() / 1024,
+                codes.len() * self.vectors.dim() * 4 / 1024
+            );
+        }
+        true
+    }
+
+    /// How the entries' embeddings are compressed in memory.
+    pub fn quantization(&self) -> Quantization {
+        self.quantization
+    }
+
+    /// Builds the graph from scratch if the index is large enough for one,
+    /// and drops it otherwise.
+    fn rebuild_graph(&mut self) {
+        self.unlinked = 0;
+        self.graph = (self.docs.len() >= EXACT_SEARCH_BELOW).then(|| {
+            let vectors = &self.vectors;
+            Hnsw::build(self.hnsw_params, self.metric, vectors.len(), |i| {
+                vectors.get(i)
+            })
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.docs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.docs.is_empty()
+    }
+
+    /// Stored entries matching `filter`, and the distinct documents they
+    /// belong to.
+    pub fn count(&self, filter: &Filter) -> (usize, usize) {
+        let matching: Vec<&Doc> = self.docs.iter().filter(|d| filter.matches(d)).collect();
+        let documents: HashSet<&str> = matching.iter().map(|d| docid::parent(&d.id)).collect();
+        (matching.len(), documents.len())
+    }
+
+    /// Whether an entry has this id, or is a chunk of a document with it.
+    pub fn contains(&self, id: &str) -> bool {
+        self.docs
+            .iter()
+            .any(|d| d.id == id || docid::parent(&d.id) == id)
+    }
+
+    /// Number of writes applied since the index was opened.
+    pub fn writes(&self) -> u64 {
+        self.writes
+    }
+
+    /// Sizes of the index in memory and on disk, and what queries have to
+    /// work with.
+    pub fn stats(&self) -> IndexStats {
+        let file = |path: PathBuf| std::fs::metadata(path).ok();
+        let saved = self.path.clone().and_then(file);
+        let log = self
+            .path
+            .as_ref()
+            .and_then(|path| file(path.with_extension("wal")));
+        let documents: HashSet<&str> = self.docs.iter().map(|d| docid::parent(&d.id)).collect();
+        IndexStats {
+            entries: self.docs.len(),
+            documents: documents.len(),
+            text_bytes: self.docs.iter().map(|d| d.text.len() as u64).sum(),
+            dim: self.embedder.dim(),
+            disk_bytes: [&saved, &log]
+                .iter()
+                .flat_map(|m| m.as_ref())
+                .map(|m| m.len())
+                .sum(),
+            saved_at: saved
+                .and_then(|m| m.modified().ok())
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_millis() as i64),
+            pending_writes: self.wal.as_ref().map_or(0, Wal::len),
+            graph: self.graph.is_some(),
+            code_bytes: self.codes.as_ref().map_or(0, Codes::memory),
+        }
+    }
+
+    /// Adds a document or replaces the one with the same id, then saves.
+    /// Without a source, one is derived from the id where possible; without
+    /// a media type, one is guessed from the source. The entry is stamped
+    /// with the current time and the parent document's next version.
+    pub fn upsert(
+        &mut self,
+        id: &str,
+        text: &str,
+        provenance: Provenance,
+        metadata: BTreeMap<String, String>,
+    ) -> io::Result<()> {
+        self.upsert_many(vec![NewEntry {
+            id: id.to_string(),
+            text: text.to_string(),
+            provenance,
+            metadata,
+            ttl: None,
+            content_hash: String::new(),
+        }])
+    }
+
+    /// [`upsert`](Self::upsert)s each entry in order, embedding them in
+    /// parallel and saving once at the end. Counts as a single write.
+    pub fn upsert_many(&mut self, entries: Vec<NewEntry>) -> io::Result<()> {
+        let embedder = self.embedder.as_ref();
+        let embedded = in_parallel(entries, |entry| {
+            let embedding = embedder.embed(&entry.text);
+            (entry, embedding)
+        });
+        self.upsert_embedded(embedded)
+    }
+
+    /// [`upsert_many`](Self::upsert_many) for entries already embedded
+    /// with this index's embedder.
+    pub fn upsert_embedded(&mut self, entries: Vec<(NewEntry, Vec<f32>)>) -> io::Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let indexed_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64);
+        let mut docs: Vec<Doc> = entries
+            .into_iter()
+            .map(|(entry, embedding)| entry.into_doc(indexed_at, embedding))
+            .collect();
+        // One version per document and write, however many of its
+        // entries the write holds.
+        let next: HashMap<String, u64> = self
+            .versions(docs.iter().map(|d| docid::parent(&d.id)))
+            .into_iter()
+            .map(|(id, version)| (id, version.map_or(first_version(), |v| v + 1)))
+            .collect();
+        for doc in &mut docs {
+            doc.version = next[docid::parent(&doc.id)];
+        }
+        self.insert_entries(docs)
+    }
+
+    /// Stores entries as they are, embeddings included, replacing those
+    /// with the same ids, then saves. For entries moved from another
+    /// index: their times and versions are kept. Counts as a single write.
+    pub fn insert_entries(&mut self, docs: Vec<Doc>) -> io::Result<()> {
+        if docs.is_empty() {
+            return Ok(());
+        }
+        let mut records = Vec::with_capacity(docs.len());
+        for doc in docs {
+            records.push(Record::Upsert {
+                embedding: doc.embedding.clone(),
+                doc: Box::new(doc.clone()),
+            });
+            self.apply(doc);
+        }
+        self.writes += 1;
+        self.log(&records)
+    }
+
+    /// Up to `limit` entries from position `at` on, in storage order, with
+    /// their embeddings when `embeddings` is set.
+    pub fn entries(&self, at: usize, limit: usize, embeddings: bool) -> Vec<Doc> {
+        let end = at.saturating_add(limit).min(self.docs.len());
+        (at.min(end)..end)
+            .map(|at| Doc {
+                embedding: match embeddings {
+                    true => self.vectors.get(at).to_vec(),
+                    false => Vec::new(),
+                },
+                ..self.docs[at].clone()
+            })
+            .collect()
+    }
+
+    /// Storage position of the entry with this id.
+    pub fn position(&self, id: &str) -> Option<usize> {
+        self.positions.get(id).copied()
+    }
+
+    /// Entries `made` has no embedding of their current text for. `made`
+    /// maps entry ids to the hash ([`fnv1a`]) of a text and its embedding
+    /// by another embedder.
+    pub fn unembedded(&self, made: &HashMap<String, (u64, Vec<f32>)>) -> Vec<Doc> {
+        self.docs
+            .iter()
+            .filter(|doc| !made_for(made, doc))
+            .cloned()
+            .collect()
+    }
+
+    /// The entries' embeddings of `dim` floats from `made`, in storage
+    /// order, ready for [`switch_embedder`](Self::switch_embedder); `None`
+    /// if some are missing (see [`unembedded`](Self::unembedded)).
+    pub fn reembedded(
+        &self,
+        made: &HashMap<String, (u64, Vec<f32>)>,
+        dim: usize,
+    ) -> Option<Reembedded> {
+        let mut vectors = Vectors::new(dim);
+        for doc in &self.docs {
+            if !made_for(made, doc) {
+                return None;
+            }
+            vectors.push(&made[&doc.id].1);
+        }
+        Some(Reembedded {
+            writes: self.writes,
+            vectors,
+            graph: None,
+            params: self.hnsw_params,
+            metric: self.metric,
+        })
+    }
+
+    /// Switches the index to `embedder`, with `new` as its embeddings and
+    /// search graph, unless it was written to since `new` was made. Codes
+    /// are trained again and the index saved whole, recording the
+    /// embedder. Returns whether it switched.
+    pub fn switch_embedder(
+        &mut self,
+        embedder: Arc<dyn Embedder>,
+        new: Reembedded,
+    ) -> io::Result<bool> {
+        let current = new.writes == self.writes
+            && new.params == self.hnsw_params
+            && new.metric == self.metric
+            && new.vectors.dim() == embedder.dim();
+        if !current {
+            return Ok(false);
+        }
+        self.embedder = embedder;
+        self.vectors = new.vectors;
+        self.graph = new.graph;
+        self.codes = None;
+        self.writes += 1;
+        if self.path.is_some() {
+            self.compact()?;
+        } else {
+            self.refresh_codes();
+        }
+        if self.store.is_some() {
+            // Every embedding changed; they are stored in one write.
+            let docs = self.entries(0, self.docs.len(), true);
+            if let Some(store) = &mut self.store {
+                store.put(&docs)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Switches the index to `embedder` as [`switch_embedder`] does, with
+    /// the embeddings in `made` and those it lacks made here, and the
+    /// search graph built here. Returns how many entries were embedded
+    /// here.
+    ///
+    /// [`switch_embedder`]: Self::switch_embedder
+    pub fn replace_embedder(
+        &mut self,
+        embedder: Arc<dyn Embedder>,
+        mut made: HashMap<String, (u64, Vec<f32>)>,
+    ) -> io::Result<usize> {
+        let missing = self.unembedded(&made);
+        let texts = missing
+            .iter()
+            .map(|doc| doc.text.get().into_owned())
+            .collect();
+        let late = in_parallel(texts, |text| embedder.embed(&text));
+        for (doc, embedding) in missing.iter().zip(late) {
+            made.insert(
+                doc.id.clone(),
+                (fnv1a(doc.text.get().as_bytes()), embedding),
+            );
+        }
+        let Some(mut new) = self.reembedded(&made, embedder.dim()) else {
+            return Err(io::Error::other("entries were left without an embedding"));
+        };
+        new.build_graph();
+        self.switch_embedder(embedder, new)?;
+        Ok(missing.len())
+    }
+
+    /// Stores `doc` in memory, replacing the entry with its id. Its
+    /// embedding moves to the index's vectors.
+    fn apply(&mut self, mut doc: Doc) {
+        let embedding = std::mem::take(&mut doc.embedding);
+        let at = match self.positions.get(&doc.id).copied() {
+            Some(at) => {
+                self.keywords.remove(at, &self.docs[at].text.get());
+                self.docs[at] = doc;
+                self.vectors.set(at, &embedding);
+                if let Some(codes) = &mut self.codes {
+                    codes.set(at, &embedding);
+                }
+                at
+            }
+            None => {
+                self.positions.insert(doc.id.clone(), self.docs.len());
+                self.docs.push(doc);
+                self.vectors.push(&embedding);
+                if let Some(codes) = &mut self.codes {
+                    codes.push(&embedding);
+                }
+                self.docs.len() - 1
+            }
+        };
+        self.keywords.insert(at, &self.docs[at].text.get());
+        match &mut self.graph {
+            Some(graph) => {
+                let vectors = &self.vectors;
+                graph.insert(at, &|i| vectors.get(i));
+            }
+            None => self.rebuild_graph(),
+        }
+    }
+
+    /// Removes the entries matching `filter` whose id, or parent document
+    /// id, is `id` when given, then saves. Returns how many were removed.
+    pub fn delete(&mut self, id: Option<&str>, filter: &Filter) -> io::Result<usize> {
+        let ids: Vec<String> = self
+            .docs
+            .iter()
+            .filter(|d| id.is_none_or(|id| d.id == id || docid::parent(&d.id) == id))
+            .filter(|d| filter.matches(d))
+            .map(|d| d.id.clone())
+            .collect();
+        self.remove(ids)
+    }
+
+    /// Removes the entries whose id, or parent document id, is one of
+    /// `ids`, in a single write. Returns how many were removed.
+    pub fn delete_all(&mut self, ids: &[String]) -> io::Result<usize> {
+        let wanted: HashSet<&str> = ids.iter().map(String::as_str).collect();
+        let ids: Vec<String> = self
+            .docs
+            .iter()
+            .filter(|d| wanted.contains(d.id.as_str()) || wanted.contains(docid::parent(&d.id)))
+            .map(|d| d.id.clone())
+            .collect();
+        self.remove(ids)
+    }
+
+    /// Ids of the entries making up document `id`: the document itself,
+    /// if stored whole, and its chunks.
+    pub fn entries_of(&self, id: &str) -> Vec<String> {
+        self.docs
+            .iter()
+            .filter(|d| docid::parent(&d.id) == id)
+            .map(|d| d.id.clone())
+            .collect()
+    }
+
+    /// Current versions of documents `ids`, `None` for those not stored.
+    pub fn versions<'a>(
+        &self,
+        ids: impl IntoIterator<Item = &'a str>,
+    ) -> HashMap<String, Option<u64>> {
+        let mut found: HashMap<String, Option<u64>> =
+            ids.into_iter().map(|id| (id.to_string(), None)).collect();
+        for doc in &self.docs {
+            if let Some(version) = found.get_mut(docid::parent(&doc.id)) {
+                *version = (*version).max(Some(doc.version));
+            }
+        }
+        found
+    }
+
+    /// The document other than `id` whose text hashes to `hash`, if any.
+    pub fn duplicate_of(&self, hash: &str, id: &str) -> Option<&str> {
+        self.docs
+            .iter()
+            .filter(|d| d.content_hash == hash)
+            .map(|d| docid::parent(&d.id))
+            .find(|parent| *parent != id)
+    }
+
+    /// Records `merged` as an id of document `id`, on each of its entries,
+    /// then saves. The entries keep their `indexed_at`.
+    pub fn merge_into(&mut self, id: &str, merged: &str) -> io::Result<()> {
+        let mut records = Vec::new();
+        for (at, doc) in self.docs.iter_mut().enumerate() {
+            if docid::parent(&doc.id) == id && !doc.merged_ids.iter().any(|m| m == merged) {
+                doc.merged_ids.push(merged.to_string());
+                records.push(Record::Upsert {
+                    doc: Box::new(doc.clone()),
+                    embedding: self.vectors.get(at).to_vec(),
+                });
+            }
+        }
+        if records.is_empty() {
+            return Ok(());
+        }
+        self.writes += 1;
+        self.log(&records)
+    }
+
+    /// Removes the entries with these ids, then saves. Returns how many
+    /// were removed.
+    pub fn remove(&mut self, ids: Vec<String>) -> io::Result<usize> {
+        let gone: HashSet<&str> = ids.iter().map(String::as_str).collect();
+        let keep: Vec<bool> = self
+            .docs
+            .iter()
+            .map(|d| !gone.contains(d.id.as_str()))
+            .collect();
+        let removed = keep.iter().filter(|k| !**k).count();
+        if removed > 0 {
+            retain(&mut self.docs, &keep);
+            self.positions = positions(&self.docs);
+            self.vectors.retain(&keep);
+            if let Some(codes) = &mut self.codes {
+                codes.retain(&keep);
+            }
+            // Removal renumbers entries; the keywords and the graph follow
+            // without reading the texts or, mostly, the vectors again.
+            self.keywords.retain(&keep);
+            self.unlinked += removed;
+            let vectors = &self.vectors;
+            match &mut self.graph {
+                Some(graph)
+                    if self.docs.len() >= EXACT_SEARCH_BELOW
+                        && self.unlinked * REBUILD_GRAPH_AFTER_ONE_IN < self.docs.len() =>
+                {
+                    graph.retain(&keep, |i| vectors.get(i));
+                }
+                _ => self.rebuild_graph(),
+            }
+            self.writes += 1;
+            self.log(&[Record::Delete { ids }])?;
+        }
+        Ok(removed)
+    }
+
+    /// Whether any entry expired at or before `now`, in milliseconds since
+    /// the Unix epoch.
+    pub fn has_expired(&self, now: i64) -> bool {
+        self.docs
+            .iter()
+            .any(|d| d.expires_at.is_some_and(|at| at <= now))
+    }
+
+    /// Removes the entries that expired at or before `now`, then saves.
+    /// Returns how many were removed.
+    pub fn expire(&mut self, now: i64) -> io::Result<usize> {
+        let ids = self
+            .docs
+            .iter()
+            .filter(|d| d.expires_at.is_some_and(|at| at <= now))
+            .map(|d| d.id.clone())
+            .collect();
+        self.remove(ids)
+    }
+
+    /// Top `k` documents matching the filter by similarity to `text`, in
+    /// `options.sort` order. Documents with nothing in common with it are
+    /// left out.
+    pub fn query(&self, text: &str, options: &QueryOptions) -> Vec<Hit> {
+        let docs = &self.docs;
+        let mut scored = self.scored(text, options);
+        match options.sort {
+            Sort::Score => scored.sort_by(|a, b| b.0.total_cmp(&a.0)),
+            Sort::Newest => scored.sort_by(|a, b| {
+                docs[b.1]
+                    .indexed_at
+                    .cmp(&docs[a.1].indexed_at)
+                    .then(b.0.total_cmp(&a.0))
+            }),
+        }
+        let mut seen = HashSet::new();
+        let ranked = scored.into_iter().filter(|(_, at)| {
+            !options.group_by_document || seen.insert(docid::parent(&docs[*at].id))
+        });
+        let picked = match options.mmr_lambda.filter(|_| options.sort == Sort::Score) {
+            Some(lambda) => mmr(
+                ranked.take(options.k.saturating_mul(MMR_POOL)).collect(),
+                options.k,
+                lambda,
+                &self.vectors,
+            ),
+            None => ranked.take(options.k).collect(),
+        };
+        picked
+            .into_iter()
+            .map(|(score, at)| {
+                let d = &docs[at];
+                let (before, after) = self.neighbors(d, options.context_window);
+                Hit {
+                    id: d.id.clone(),
+                    document_id: docid::parent(&d.id).to_string(),
+                    text: d.text.get().into_owned(),
+                    score,
+                    provenance: d.provenance.clone(),
+                    indexed_at: d.indexed_at,
+                    metadata: d.metadata.clone(),
+                    before,
+                    after,
+                }
+            })
+            .collect()
+    }
+
+    /// Positions of the entries related to `text` in `options.mode`, with
+    /// their scores, unordered.
+    fn scored(&self, text: &str, options: &QueryOptions) -> Vec<(f32, usize)> {
+        let by_vector = || -> Vec<(f32, usize)> {
+            let q = self.query_vector(text, options);
+            let metric = self.metric_for(options);
+            let vectors = &self.vectors;
+            let score = |at: usize| (metric.score(&q, vectors.get(at)), at);
+            let mut candidates = self.candidates(&q, options);
+            // With codes, only the best by code are read and scored
+            // exactly; every entry counts when hits are sorted otherwise.
+            let scorer = self.codes.as_ref().and_then(|c| c.scorer(&q, metric));
+            if let Some(scorer) = scorer.filter(|_| !options.exact && options.sort == Sort::Score) {
+                let pool = self.rescored(options);
+                if candidates.len() > pool {
+                    let rough = |at: usize| (scorer.score(at), at);
+                    let mut ranked = if candidates.len() >= PARALLEL_SCORING_FROM {
+                        in_parallel(candidates, rough)
+                    } else {
+                        candidates.into_iter().map(rough).collect()
+                    };
+                    ranked.select_nth_unstable_by(pool, |a, b| b.0.total_cmp(&a.0));
+                    candidates = ranked[..pool].iter().map(|&(_, at)| at).collect();
+                    candidates.sort_unstable();
+                }
+            }
+            // Scores keep the candidates' order either way, so ties come
+            // out the same.
+            let scored = if candidates.len() >= PARALLEL_SCORING_FROM {
+                in_parallel(candidates, score)
+            } else {
+                candidates.into_iter().map(score).collect()
+            };
+            scored
+                .into_iter()
+                .filter(|(score, _)| metric.related(*score))
+                .collect()
+        };
+        let by_keyword = || -> Vec<(f32, usize)> {
+            self.keywords
+                .scores(&terms(text))
+                .into_iter()
+                .map(|(at, score)| (score, at))
+                .filter(|(score, at)| *score > 0.0 && options.filter.matches(&self.docs[*at]))
+                .collect()
+        };
+        match options.mode {
+            Mode::Vector => by_vector(),
+            Mode::Keyword => by_keyword(),
+            Mode::Hybrid => {
+                let mut fused: Vec<(f32, usize)> = Vec::new();
+                let mut positions: HashMap<usize, usize> = HashMap::new();
+                for mut ranking in [by_vector(), by_keyword()] {
+                    ranking.sort_by(|a, b| b.0.total_cmp(&a.0));
+                    for (rank, (_, at)) in ranking.into_iter().enumerate() {
+                        let score = 1.0 / (RRF_K + rank as f32 + 1.0);
+                        match positions.get(&at) {
+                            Some(&i) => fused[i].0 += score,
+                            None => {
+                                positions.insert(at, fused.len());
+                                fused.push((score, at));
+                            }
+                        }
+                    }
+                }
+                fused
+            }
+        }
+    }
+
+    /// Positions of the entries to score for query vector `q`: the graph's
+    /// nearest ones when the query only wants the best matches, otherwise
+    /// every entry the filter allows.
+    fn candidates(&self, q: &[f32], options: &QueryOptions) -> Vec<usize> {
+        match &self.graph {
+            Some(graph)
+                if !options.exact
+                    && options.filter.is_empty()
+                    && options.sort == Sort::Score
+                    && graph.metric() == self.metric_for(options) =>
+            {
+                let ef = self.hnsw_params.ef_search.max(wanted(options));
+                let metric = graph.metric();
+                match self.codes.as_ref().and_then(|c| c.scorer(q, metric)) {
+                    // Searching by code, keep as many as will be rescored.
+                    Some(scorer) => {
+                        graph.search(ef.max(self.rescored(options)), |i| scorer.score(i))
+                    }
+                    None => graph.search(ef, |i| metric.score(q, self.vectors.get(i))),
+                }
+            }
+            _ => (0..self.docs.len())
+                .filter(|&at| options.filter.matches(&self.docs[at]))
+                .collect(),
+        }
+    }
+
+    /// How many of the best entries by code a query scores exactly.
+    fn rescored(&self, options: &QueryOptions) -> usize {
+        wanted(options).saturating_mul(self.quantization.rescore())
+    }
+
+    /// Chunks of the same document within `window` positions of `doc`,
+    /// split into those before and after it, in order.
+    fn neighbors(&self, doc: &Doc, window: u32) -> (Vec<Doc>, Vec<Doc>) {
+        let Some(at) = doc.provenance.chunk.filter(|_| window > 0) else {
+            return (Vec::new(), Vec::new());
+        };
+        let parent = docid::parent(&doc.id);
+        let mut near: Vec<&Doc> = self
+            .docs
+            .iter()
+            .filter(|d| docid::parent(&d.id) == parent)
+            .filter(|d| {
+                d.provenance
+                    .chunk
+                    .is_some_and(|c| c != at && c.abs_diff(at) <= window)
+            })
+            .collect();
+        near.sort_by_key(|d| d.provenance.chunk);
+        let (before, after): (Vec<&Doc>, Vec<&Doc>) = near
+            .into_iter()
+            .partition(|d| d.provenance.chunk < Some(at));
+        (
+            before.into_iter().cloned().collect(),
+            after.into_iter().cloned().collect(),
+        )
+    }
+
+    /// Runs `query` and reports how each hit was scored.
+    pub fn explain(&self, text: &str, options: &QueryOptions) -> Explanation {
+        let query_terms = terms(text);
+        let q = self.query_vector(text, options);
+        let metric = self.metric_for(options);
+        let keyword_scores = self.keywords.scores(&query_terms);
+        let hits = self
+            .query(text, options)
+            .into_iter()
+            .map(|hit| {
+                let doc_terms = terms(&hit.text);
+                let mut matched: Vec<String> = Vec::new();
+                for term in &query_terms {
+                    if doc_terms.contains(term) && !matched.contains(term) {
+                        matched.push(term.clone());
+                    }
+                }
+                let at = self.position(&hit.id);
+                ExplainedHit {
+                    vector_score: at.map_or(0.0, |at| metric.score(&q, self.vectors.get(at))),
+                    keyword_score: at
+                        .and_then(|at| keyword_scores.get(&at))
+                        .copied()
+                        .unwrap_or(0.0),
+                    id: hit.id,
+                    score: hit.score,
+                    matched_terms: matched,
+                }
+            })
+            .collect();
+        let candidates = match options.mode {
+            Mode::Vector => self.candidates(&q, options).len(),
+            Mode::Keyword | Mode::Hybrid => self.scored(text, options).len(),
+        };
+        Explanation {
+            query_vector: q,
+            terms: query_terms,
+            candidates,
+            hits,
+        }
+    }
+
+    /// Up to `limit` documents in id order, starting after the id `after`
+    /// (empty to start at the beginning), and whether more follow.
+    pub fn list_documents(&self, after: &str, limit: usize) -> (Vec<DocumentSummary>, bool) {
+        // Each summary with the position of the chunk its preview came from.
+        let mut documents: BTreeMap<&str, (DocumentSummary, u32)> = BTreeMap::new();
+        for d in &self.docs {
+            let id = docid::parent(&d.id);
+            if !after.is_empty() && id <= after {
+                continue;
+            }
+            let position = d.provenance.chunk.unwrap_or(0);
+            let (summary, first) = documents.entry(id).or_insert_with(|| {
+                let summary = DocumentSummary {
+                    id: id.to_string(),
+                    preview: String::new(),
+                    size_bytes: 0,
+                    chunks: 0,
+                    source: d.provenance.source.clone(),
+                    indexed_at: None,
+                    version: 0,
+                };
+                (summary, u32::MAX)
+            });
+            summary.size_bytes += d.text.len() as u64;
+            summary.chunks += 1;
+            summary.indexed_at = summary.indexed_at.max(d.indexed_at);
+            summary.version = summary.version.max(d.version);
+            if position < *first {
+                *first = position;
+                summary.preview = text::truncate(&d.text.get(), PREVIEW_CHARS).to_string();
+            }
+        }
+        let more = documents.len() > limit;
+        let page = documents.into_values().take(limit).map(|(s, _)| s);
+        (page.collect(), more)
+    }
+
+    /// The document with this id, or the chunks whose parent it is.
+    pub fn document(&self, id: &str) -> Option<Document> {
+        let mut chunks: Vec<Doc> = (0..self.docs.len())
+            .filter(|&at| self.docs[at].id == id || docid::parent(&self.docs[at].id) == id)
+            .map(|at| Doc {
+                embedding: self.vectors.get(at).to_vec(),
+                ..self.docs[at].clone()
+            })
+            .collect();
+        if chunks.is_empty() {
+            return None;
+        }
+        chunks.sort_by_key(|d| d.provenance.chunk);
+        let text: Vec<_> = chunks.iter().map(|d| d.text.get()).collect();
+        Some(Document {
+            id: id.to_string(),
+            text: text.join("\n"),
+            source: chunks[0].provenance.source.clone(),
+            mime_type: chunks[0].provenance.mime_type.clone(),
+            indexed_at: chunks.iter().filter_map(|d| d.indexed_at).max(),
+            metadata: chunks[0].metadata.clone(),
+            merged_ids: chunks[0].merged_ids.clone(),
+            version: chunks.iter().map(|d| d.version).max().unwrap_or_default(),
+            chunks,
+        })
+    }
+
+    /// Makes a write durable by logging it, compacting once the log is
+    /// long, or by making it in the store the index was opened from.
+    fn log(&mut self, records: &[Record]) -> io::Result<()> {
+        if let Some(store) = &mut self.store {
+            return store_records(store.as_mut(), records);
+        }
+        let Some(wal) = &mut self.wal else {
+            return Ok(());
+        };
+        wal.append(records)?;
+        if wal.len() >= COMPACT_AFTER {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the entries as they are now to a new index file at `path`,
+    /// leaving this index's own files alone.
+    pub fn save_as(&self, path: &Path) -> io::Result<()> {
+        let codes = self.codes.as_ref();
+        indexfile::write(
+            path,
+            &self.docs,
+            &self.vectors,
+            codes,
+            self.embedder.as_ref(),
+        )
+    }
+
+    /// The embedder entries and queries are embedded with.
+    pub fn embedder(&self) -> &Arc<dyn Embedder> {
+        &self.embedder
+    }
+
+    fn metric_for(&self, options: &QueryOptions) -> Metric {
+        options.metric.unwrap_or(self.metric)
+    }
+
+    /// The metric entries are compared by unless a query asks for another.
+    pub fn metric(&self) -> Metric {
+        self.metric
+    }
+
+    fn query_vector(&self, text: &str, options: &QueryOptions) -> Vec<f32> {
+        match &options.vector {
+            Some(vector) => vector.clone(),
+            None => self.embedder.embed(text),
+        }
+    }
+
+    /// Rewrites the index file from memory, with codes trained again if
+    /// they are due, maps its vectors and takes its compressed texts in
+    /// place of those held so far, and empties the log. The log is only emptied once
+    /// the new file is in place, so a crash in between replays writes that
+    /// are already saved, which changes nothing.
+    fn compact(&mut self) -> io::Result<()> {
+        if self.path.is_none() {
+            return Ok(());
+        }
+        self.refresh_codes();
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let codes = self.codes.as_ref();
+        indexfile::write(
+            path,
+            &self.docs,
+            &self.vectors,
+            codes,
+            self.embedder.as_ref(),
+        )?;
+        self.vectors = indexfile::read_vectors(path)?;
+        for (doc, text) in self.docs.iter_mut().zip(indexfile::read_texts(path)?) {
+            doc.text = text;
+        }
+        match &mut self.wal {
+            Some(wal) => wal.clear(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// How many of the best matches a query needs to find its hits. Grouping
+/// keeps one chunk per document and MMR skips near-duplicates, so they
+/// need more.
+fn wanted(options: &QueryOptions) -> usize {
+    if options.group_by_document || options.mmr_lambda.is_some() {
+        options.k.saturating_mul(MMR_POOL)
+    } else {
+        options.k
+    }
+}
+
+/// Picks up to `k` of `pool`, entry positions ranked best first, by
+/// maximal marginal relevance. Relevance is each score scaled to 0..=1
+/// within the pool, so it weighs the same whatever the mode or metric;
+/// similarity between entries is always the cosine of their `vectors`.
+fn mmr(mut pool: Vec<(f32, usize)>, k: usize, lambda: f32, vectors: &Vectors) -> Vec<(f32, usize)> {
+    let (low, high) = pool
+        .iter()
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(low, high), (s, _)| {
+            (low.min(*s), high.max(*s))
+        });
+    let relevance = |score: f32| {
+        if high > low {
+            (score - low) / (high - low)
+        } else {
+            1.0
+        }
+    };
+    // Highest similarity of each pool entry to the hits picked so far.
+    let mut closest = vec![0.0f32; pool.len()];
+    let mut picked = Vec::with_capacity(k.min(pool.len()));
+    while picked.len() < k && !pool.is_empty() {
+        let value = |at: usize| lambda * relevance(pool[at].0) - (1.0 - lambda) * closest[at];
+        let best = (0..pool.len())
+            .max_by(|&a, &b| value(a).total_cmp(&value(b)).then(b.cmp(&a)))
+            .unwrap_or_default();
+        let (score, at) = pool.remove(best);
+        closest.remove(best);
+        for ((_, other), close) in pool.iter().zip(&mut closest) {
+            let similarity = Metric::Cosine.score(vectors.get(at), vectors.get(*other));
+            if picked.is_empty() || similarity > *close {
+                *close = similarity;
+            }
+        }
+        picked.push((score, at));
+    }
+    picked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logged_embeddings_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("index-replay-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notes.idx");
+        let embedder: Arc<dyn Embedder> = Arc::new(HashEmbedder::parse("hash-16").unwrap());
+        let mut doc: Doc = serde_json::from_str(r#"{"id": "a", "text": "hello"}"#).unwrap();
+        doc.embedding = (0..16).map(|i| i as f32).collect();
+
+        let mut index = VectorIndex::open(&path, Arc::clone(&embedder)).unwrap();
+        index.insert_entries(vec![doc.clone()]).unwrap();
+        drop(index);
+
+        // Reopening replays the log rather than reading a saved file.
+        let index = VectorIndex::open(&path, embedder).unwrap();
+        assert_eq!(index.entries(0, 1, true)[0].embedding, doc.embedding);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
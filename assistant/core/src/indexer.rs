@@ -0,0 +1,2667 @@
+//! gRPC `Indexer` service over the index [`Collections`].
+
+use crate::archive;
+use crate::assistant::indexer_server::Indexer;
+use crate::assistant::{
+    BatchIndexRequest, BatchIndexResponse, Chunk, CollectionInfo, CollectionSnapshot,
+    CollectionStats, CountRequest, CountResponse, CreateCollectionRequest, CreateSnapshotRequest,
+    DeleteAliasRequest, DeleteAliasResponse, DeleteRequest, DeleteResponse, DeleteSnapshotRequest,
+    DeleteSnapshotResponse, Document, DocumentSummary, DropCollectionRequest,
+    DropCollectionResponse, EmbedRequest, EmbedResponse, EmbedderInfo, Embedding, ExistsRequest,
+    ExistsResponse, ExplainResponse, ExplainedHit, ExportIndexRequest, FileChunk,
+    GetDocumentRequest, GetDocumentResponse, Hit, ImportError, ImportIndexRequest,
+    ImportIndexResponse, ImportRequest, ImportResponse, IndexLines, IndexRequest, IndexResponse,
+    ListCollectionsRequest, ListCollectionsResponse, ListDocumentsRequest, ListDocumentsResponse,
+    ListEmbeddingModelsRequest, ListEmbeddingModelsResponse, ListSnapshotsRequest,
+    ListSnapshotsResponse, QueryAtRequest, QueryRequest, QueryResponse, ReindexRequest,
+    ReindexResponse, RestoreRequest, RestoreResponse, SetAliasRequest, SetAliasResponse,
+    SnapshotRequest, SnapshotResponse, StatsRequest, StatsResponse, UploadResult,
+    UploadStatusRequest,
+};
+use crate::chunk::{self, ChunkParams};
+use crate::collection::{
+    self, Collection, CollectionConfig, CollectionError, Collections, WriteToken,
+};
+use crate::connector::DocumentSink;
+use crate::cursor::{Cursors, Page, MAX_SNAPSHOT_HITS};
+use crate::docid::{self, Provenance};
+use crate::embed::{self, Embedder, HashEmbedder};
+use crate::embedqueue::{EmbedQueue, Priority};
+use crate::filter::Filter;
+use crate::hnsw::HnswParams;
+use crate::index::{self, Doc, Mode, NewEntry, QueryOptions, Sort, VectorIndex};
+use crate::loader;
+use crate::metric::Metric;
+use crate::normalize::Normalize;
+use crate::privacy::Privacy;
+use crate::rerank::Reranker;
+use crate::table;
+use crate::text;
+use crate::upload::{self, UploadStore};
+use crate::watch::Watcher;
+use crate::workspace::{Workspace, Workspaces};
+use futures_util::stream::FuturesOrdered;
+use futures_util::{Stream, StreamExt};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tonic::{Request, Response, Status};
+
+const DEFAULT_K: usize = 5;
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 1000;
+const DEFAULT_RERANK_TOP_N: usize = 20;
+const MAX_RERANK_TOP_N: usize = 200;
+/// Hits an adaptive query returns when they stand out.
+const DEFAULT_ADAPTIVE_MIN_K: usize = 2;
+/// Relative score gap at which an adaptive query's best hits stand out.
+const DEFAULT_ADAPTIVE_MARGIN: f32 = 0.1;
+/// How often expired entries are looked for.
+const EXPIRY_SWEEP: Duration = Duration::from_secs(10);
+/// How often watched folders are walked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(30);
+/// Changed files read and indexed per write.
+const WATCH_BATCH: usize = 100;
+/// Documents of an import written at a time.
+const IMPORT_BATCH: usize = 500;
+/// Failed documents of an import listed in its response; the rest are
+/// only counted.
+const MAX_IMPORT_ERRORS: usize = 100;
+/// An import's progress is logged each time it stores this many more
+/// documents.
+const IMPORT_PROGRESS: u64 = 10_000;
+/// Entries sent per message of an export.
+const EXPORT_PAGE: usize = 500;
+/// Entries of an uploaded archive indexed at a time.
+const ARCHIVE_BATCH: usize = 64;
+/// Characters of a query the query log keeps, so a pasted document
+/// does not flood it.
+const LOGGED_QUERY_CHARS: usize = 200;
+/// Texts of an `Embed` stream embedded at a time.
+const EMBED_BATCH: usize = 64;
+/// Texts of an `Embed` stream received but not yet answered, past which
+/// the server stops reading the stream.
+const EMBED_WINDOW: usize = 1024;
+/// Entries of a reindex embedded at a time.
+const REINDEX_BATCH: usize = 256;
+/// Times a reindex tries to switch without holding the lock throughout,
+/// before it stops letting writes in.
+const REINDEX_ATTEMPTS: usize = 3;
+
+pub struct IndexerService {
+    /// Shared with export streams, which outlive the request handler.
+    collections: Arc<RwLock<Collections>>,
+    cursors: Mutex<Cursors>,
+    /// Texts are embedded here, outside the collections lock. Shared with
+    /// reindexes running in the background.
+    embeds: Arc<EmbedQueue>,
+    /// Reindexes running, by collection.
+    reindexing: Arc<Mutex<HashMap<String, Reindexing>>>,
+    snapshots: Mutex<OpenSnapshots>,
+    /// Rescores hits of queries that ask for it, if the server has one.
+    reranker: Option<Arc<dyn Reranker>>,
+    /// Requests made in a workspace only reach its collections.
+    workspaces: Arc<Workspaces>,
+    /// Shared with the assistant, which attaches uploads to sessions.
+    uploads: Arc<UploadStore>,
+}
+
+impl IndexerService {
+    pub fn new(
+        collections: Collections,
+        reranker: Option<Arc<dyn Reranker>>,
+        workspaces: Arc<Workspaces>,
+        uploads: Arc<UploadStore>,
+    ) -> Self {
+        IndexerService {
+            collections: Arc::new(RwLock::new(collections)),
+            cursors: Mutex::default(),
+            embeds: Arc::default(),
+            reindexing: Arc::default(),
+            snapshots: Mutex::default(),
+            reranker,
+            workspaces,
+            uploads,
+        }
+    }
+
+    /// Keeps the files under each workspace's watched folders indexed in
+    /// its first collection, walking them every [`WATCH_INTERVAL`] for as
+    /// long as the service is in use. Files are stored under their
+    /// `file://` URIs, so changed files replace their entries and removed
+    /// files are deleted. Every file is read again on startup.
+    pub fn spawn_watchers(self: &Arc<Self>) {
+        for workspace in self.workspaces.iter() {
+            if workspace.watch.is_empty() {
+                continue;
+            }
+            let service = Arc::downgrade(self);
+            let workspace = workspace.clone();
+            let mut watcher = Watcher::new(workspace.watch.clone());
+            tokio::spawn(async move {
+                let mut ticks = tokio::time::interval(WATCH_INTERVAL);
+                loop {
+                    ticks.tick().await;
+                    let scanned = tokio::task::spawn_blocking(move || {
+                        let changes = watcher.scan();
+                        (watcher, changes)
+                    })
+                    .await;
+                    let Ok((scanned, changes)) = scanned else {
+                        log::error!("walking the folders of workspace {} failed", workspace.name);
+                        return;
+                    };
+                    watcher = scanned;
+                    let Some(service) = service.upgrade() else {
+                        return;
+                    };
+                    let failed = service.apply_changes(&workspace, changes).await;
+                    // Files that could not be indexed are tried again next time.
+                    watcher.forget(&failed);
+                }
+            });
+        }
+    }
+
+    /// Indexes the files `changes` found changed and deletes the entries
+    /// of those removed, in `workspace`'s first collection. Returns the
+    /// changed files that could not be indexed.
+    async fn apply_changes(
+        &self,
+        workspace: &Workspace,
+        changes: crate::watch::Changes,
+    ) -> Vec<std::path::PathBuf> {
+        let mut failed = Vec::new();
+        let collection = workspace.default_collection();
+        let client = format!("workspace:{}", workspace.name);
+        for paths in changes.changed.chunks(WATCH_BATCH) {
+            let (batch, name) = (paths.to_vec(), workspace.name.clone());
+            let docs = tokio::task::spawn_blocking(move || watched_documents(&name, &batch))
+                .await
+                .unwrap_or_default();
+            if docs.is_empty() {
+                continue;
+            }
+            let count = docs.len();
+            match self
+                .write(&client, collection, docs, None, &BTreeMap::new())
+                .await
+            {
+                Ok(written) => log::info!(
+                    "indexed {count} watched files of workspace {} in {} entries",
+                    workspace.name,
+                    written.chunks
+                ),
+                Err(e) => {
+                    log::error!(
+                        "indexing watched files of workspace {} failed: {e}",
+                        workspace.name
+                    );
+                    failed.extend_from_slice(paths);
+                }
+            }
+        }
+        if changes.removed.is_empty() {
+            return failed;
+        }
+        let mut collections = self.collections.write().unwrap();
+        let Ok(collection) = collections.get_mut(collection) else {
+            return failed;
+        };
+        let ids: Vec<String> = changes
+            .removed
+            .iter()
+            .map(|path| format!("file://{}", path.display()))
+            .collect();
+        if let Err(e) = collection.index.delete_all(&ids) {
+            let privacy = Privacy::resolve(&collection.config.privacy);
+            log::error!(
+                "removing {} deleted files failed: {e}: {}",
+                ids.len(),
+                privacy.redact(ids.join(", "))
+            );
+        }
+        failed
+    }
+
+    /// The workspace `req` was made in, from its `workspace` metadata.
+    fn workspace<T>(&self, req: &Request<T>) -> Result<Option<&Workspace>, CollectionError> {
+        let name = req
+            .metadata()
+            .get("workspace")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        self.workspaces
+            .get(name)
+            .map_err(|e| CollectionError::NotFound(e.to_string()))
+    }
+
+    /// Limits `collection` to `workspace`, if the request was made in one:
+    /// an empty name becomes its first collection, and collections outside
+    /// it are refused.
+    fn scope(
+        &self,
+        workspace: Option<&Workspace>,
+        collection: &mut String,
+    ) -> Result<(), CollectionError> {
+        let Some(workspace) = workspace else {
+            return Ok(());
+        };
+        if collection.is_empty() {
+            *collection = workspace.default_collection().to_string();
+        }
+        self.allow(Some(workspace), collection)
+    }
+
+    /// Refuses `collection` if it is not in `workspace`.
+    fn allow(
+        &self,
+        workspace: Option<&Workspace>,
+        collection: &str,
+    ) -> Result<(), CollectionError> {
+        match workspace {
+            Some(w) if !w.covers(collection, &self.collections.read().unwrap()) => {
+                Err(CollectionError::NotAllowed(format!(
+                    "collection {collection} is not in workspace {}",
+                    w.name
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes expired entries from every collection every
+    /// [`EXPIRY_SWEEP`], for as long as the service is in use.
+    pub fn spawn_expiry_sweeper(self: &Arc<Self>) {
+        let service = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(EXPIRY_SWEEP);
+            loop {
+                ticks.tick().await;
+                let Some(service) = service.upgrade() else {
+                    return;
+                };
+                service.expire();
+            }
+        });
+    }
+
+    /// Removes the entries that have expired by now. Collections are
+    /// checked under the read lock, so the write lock is only taken when
+    /// there is something to remove.
+    fn expire(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64);
+        let due: Vec<String> = {
+            let collections = self.collections.read().unwrap();
+            collections
+                .list()
+                .filter(|(_, c)| c.index.has_expired(now))
+                .map(|(name, _)| name.to_string())
+                .collect()
+        };
+        if due.is_empty() {
+            return;
+        }
+        let mut collections = self.collections.write().unwrap();
+        for name in due {
+            // The collection may have been dropped since.
+            let Ok(collection) = collections.get_mut(&name) else {
+                continue;
+            };
+            match collection.index.expire(now) {
+                Ok(0) => {}
+                Ok(n) => log::info!("{n} expired entries removed from {name}"),
+                Err(e) => log::error!("removing expired entries from {name} failed: {e}"),
+            }
+        }
+    }
+
+    /// Indexes the entries of an uploaded archive as they are read, a
+    /// batch at a time, recording the outcome in `result`.
+    async fn index_archive(
+        &self,
+        client: &str,
+        collection: &str,
+        archived: ArchiveUpload,
+        result: &mut UploadResult,
+    ) -> Result<(), Status> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(ARCHIVE_BATCH);
+        let ArchiveUpload {
+            path,
+            name,
+            kind,
+            id,
+            source,
+        } = archived;
+        let reading = tokio::task::spawn_blocking(move || {
+            archive::read(&path, &name, kind, |entry| {
+                tx.blocking_send(entry)
+                    .map_err(|_| std::io::Error::other("indexing stopped"))
+            })
+        });
+        let mut batch = Vec::with_capacity(ARCHIVE_BATCH);
+        while let Some(entry) = rx.recv().await {
+            match entry {
+                Ok(entry) => batch.push(Document {
+                    id: docid::archive_entry(&id, &entry.path),
+                    text: entry.loaded.text,
+                    mime_type: entry.loaded.mime_type.to_string(),
+                    source: match source.as_str() {
+                        "" => String::new(),
+                        source => docid::archive_entry(source, &entry.path),
+                    },
+                    ..Default::default()
+                }),
+                Err(skipped) => {
+                    result.skipped += 1;
+                    if result.skipped_entries.len() < MAX_IMPORT_ERRORS {
+                        result.skipped_entries.push(ImportError {
+                            id: skipped.path,
+                            message: skipped.reason,
+                        });
+                    }
+                }
+            }
+            if batch.len() == ARCHIVE_BATCH {
+                let docs = std::mem::take(&mut batch);
+                self.write_archived(client, collection, docs, result)
+                    .await?;
+            }
+        }
+        if !batch.is_empty() {
+            self.write_archived(client, collection, batch, result)
+                .await?;
+        }
+        reading
+            .await
+            .map_err(std::io::Error::other)
+            .and_then(|read| read)
+            .map_err(upload::status)
+    }
+
+    async fn write_archived(
+        &self,
+        client: &str,
+        collection: &str,
+        docs: Vec<Document>,
+        result: &mut UploadResult,
+    ) -> Result<(), CollectionError> {
+        let documents = docs.len() as u64;
+        let written = self
+            .write(client, collection, docs, None, &BTreeMap::new())
+            .await?;
+        result.documents += documents - written.duplicates.len() as u64;
+        result.chunks += written.chunks as u32;
+        result.write_token = written.token.to_string();
+        Ok(())
+    }
+
+    /// Splits `docs` per the collection's chunk settings, embeds them as
+    /// `client`'s bulk work, then stores them, expiring after `ttl` if
+    /// given, and deduplicating them per the collection's policy.
+    async fn write(
+        &self,
+        client: &str,
+        name: &str,
+        docs: Vec<Document>,
+        ttl: Option<Duration>,
+        expected: &BTreeMap<String, u64>,
+    ) -> Result<Written, CollectionError> {
+        let (chunking, normalize, embedder) = {
+            let collections = self.collections.read().unwrap();
+            let collection = collections.get(name)?;
+            let embedder = Arc::clone(collection.index.embedder());
+            let config = &collection.config;
+            (config.chunking, config.normalize, embedder)
+        };
+        let prepared = prepare(docs, chunking, normalize, ttl);
+        let texts = prepared.entries.iter().map(|e| e.text.clone()).collect();
+        let vectors = self
+            .embeds
+            .embed(client, Priority::Bulk, Arc::clone(&embedder), texts)
+            .await?;
+        let mut collections = self.collections.write().unwrap();
+        let collection = collections.get_mut(name)?;
+        if !Arc::ptr_eq(collection.index.embedder(), &embedder)
+            || collection.config.chunking != chunking
+        {
+            return Err(CollectionError::Precondition(format!(
+                "collection {name} was replaced while indexing; retry"
+            )));
+        }
+        // Checked under the write lock, so no other write can come
+        // between the check and the store.
+        check_versions(&collection.index, expected)?;
+        let duplicates = match collection.config.dedup.as_str() {
+            "skip" | "merge" | "reject" => duplicates(&collection.index, &prepared.hashes),
+            _ => BTreeMap::new(),
+        };
+        if collection.config.dedup == "reject" {
+            if let Some((id, of)) = duplicates.iter().next() {
+                return Err(CollectionError::AlreadyExists(format!(
+                    "text of document {id} (stored as {of})"
+                )));
+            }
+        }
+        let (prepared, vectors) = prepared.without(vectors, &duplicates);
+        let parents: BTreeSet<String> = prepared
+            .entries
+            .iter()
+            .map(|e| docid::parent(&e.id).to_string())
+            .collect();
+        let chunks = store(collection, prepared, vectors)?;
+        let versions = collection
+            .index
+            .versions(parents.iter().map(String::as_str))
+            .into_iter()
+            .filter_map(|(id, version)| Some((id, version?)))
+            .collect();
+        if collection.config.dedup == "merge" {
+            for (id, of) in &duplicates {
+                collection.index.merge_into(of, id)?;
+            }
+        }
+        Ok(Written {
+            chunks,
+            token: collection.write_token(),
+            duplicates,
+            versions,
+        })
+    }
+
+    /// Writes one batch of an import, adding what it stored or why it
+    /// failed to `progress`. A failed write fails only its documents.
+    async fn import_batch(
+        &self,
+        client: &str,
+        collection: &str,
+        batch: Vec<Document>,
+        ttl: Option<Duration>,
+        progress: &mut ImportResponse,
+    ) {
+        let ids: Vec<String> = batch.iter().map(|d| d.id.clone()).collect();
+        match self
+            .write(client, collection, batch, ttl, &BTreeMap::new())
+            .await
+        {
+            Ok(written) => {
+                progress.documents += (ids.len() - written.duplicates.len()) as u64;
+                progress.duplicates += written.duplicates.len() as u64;
+                progress.chunks += written.chunks as u64;
+                progress.write_token = written.token.to_string();
+            }
+            Err(e) => {
+                let message = e.to_string();
+                for id in ids {
+                    import_failed(progress, id, message.clone());
+                }
+            }
+        }
+    }
+
+    /// Stores imported entries in collection `name` as they are. Those
+    /// without an embedding from the collection's embedder are embedded
+    /// again, as `client`'s bulk work. Returns how many were.
+    async fn insert_imported(
+        &self,
+        client: &str,
+        name: &str,
+        entries: Vec<(Doc, String)>,
+    ) -> Result<(usize, WriteToken), CollectionError> {
+        let embedder = {
+            let collections = self.collections.read().unwrap();
+            Arc::clone(collections.get(name)?.index.embedder())
+        };
+        // Lines from before fingerprints were exported only name the
+        // embedder.
+        let fingerprint = embedder.fingerprint();
+        let usable = |(doc, by): &(Doc, String)| {
+            (*by == fingerprint || by == embedder.name()) && doc.embedding.len() == embedder.dim()
+        };
+        let texts: Vec<String> = entries
+            .iter()
+            .filter(|e| !usable(e))
+            .map(|(doc, _)| doc.text.get().into_owned())
+            .collect();
+        let embedded = texts.len();
+        let mut vectors = self
+            .embeds
+            .embed(client, Priority::Bulk, Arc::clone(&embedder), texts)
+            .await?
+            .into_iter();
+        let mut docs = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let keep = usable(&entry);
+            let mut doc = entry.0;
+            if !keep {
+                doc.embedding = vectors.next().unwrap_or_default();
+            }
+            docs.push(doc);
+        }
+        let mut collections = self.collections.write().unwrap();
+        let collection = collections.get_mut(name)?;
+        if !Arc::ptr_eq(collection.index.embedder(), &embedder) {
+            return Err(CollectionError::Precondition(format!(
+                "collection {name} was replaced while importing; retry"
+            )));
+        }
+        collection.index.insert_entries(docs)?;
+        Ok((embedded, collection.write_token()))
+    }
+
+    /// Stores a batch of an index import, adding what it stored or why it
+    /// failed to `progress`. A failed batch fails only its entries.
+    async fn import_index_batch(
+        &self,
+        client: &str,
+        name: &str,
+        batch: Vec<(Doc, String)>,
+        progress: &mut ImportIndexResponse,
+    ) {
+        let ids: Vec<String> = batch.iter().map(|(d, _)| d.id.clone()).collect();
+        match self.insert_imported(client, name, batch).await {
+            Ok((embedded, token)) => {
+                progress.entries += ids.len() as u64;
+                progress.embedded += embedded as u64;
+                progress.write_token = token.to_string();
+            }
+            Err(e) => {
+                let message = e.to_string();
+                for id in ids {
+                    index_import_failed(progress, id, message.clone());
+                }
+            }
+        }
+    }
+
+    /// Sets `options.vector` to the embedding of `req.query` for the
+    /// collection `req` reads, computed as `client`'s interactive work.
+    /// Returns the embedder used.
+    async fn embed_query(
+        &self,
+        client: &str,
+        req: &QueryRequest,
+        options: &mut QueryOptions,
+    ) -> Result<Arc<dyn Embedder>, CollectionError> {
+        let embedder = {
+            let collections = self.collections.read().unwrap();
+            Arc::clone(collections.get(&req.collection)?.index.embedder())
+        };
+        let texts = vec![req.query.clone()];
+        let vectors = self
+            .embeds
+            .embed(client, Priority::Interactive, Arc::clone(&embedder), texts)
+            .await?;
+        options.vector = vectors.into_iter().next();
+        Ok(embedder)
+    }
+
+    /// `query` normalized as collection `name` normalizes text. Left as it
+    /// is when there is no such collection, which the query then reports.
+    fn normalized(&self, name: &str, query: String) -> String {
+        match self.collections.read().unwrap().get(name) {
+            Ok(collection) => collection.config.normalize.query(query),
+            Err(_) => query,
+        }
+    }
+
+    /// The next page of `req.cursor`'s results.
+    fn resume(&self, req: &QueryRequest) -> Result<QueryResponse, CollectionError> {
+        let page = self
+            .cursors
+            .lock()
+            .unwrap()
+            .resume(&req.cursor, (req.limit as usize).max(1))
+            .ok_or_else(|| {
+                CollectionError::InvalidConfig("cursor is invalid or expired; query again".into())
+            })?;
+        Ok(page_response(page))
+    }
+
+    /// Rescores `hits` with the reranker as `client`'s interactive work,
+    /// best first, and keeps the `k` best unless `req` pages through them.
+    async fn rerank(
+        &self,
+        client: &str,
+        req: &QueryRequest,
+        mut hits: Vec<index::Hit>,
+    ) -> Result<Vec<index::Hit>, CollectionError> {
+        let Some(reranker) = &self.reranker else {
+            return Err(no_reranker());
+        };
+        let texts = hits.iter().map(|h| h.text.clone()).collect();
+        let scores = self
+            .embeds
+            .rerank(
+                client,
+                Priority::Interactive,
+                Arc::clone(reranker),
+                req.query.clone(),
+                texts,
+            )
+            .await?;
+        for (hit, score) in hits.iter_mut().zip(scores) {
+            hit.score = score;
+        }
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+        if req.limit == 0 {
+            hits.truncate(requested_k(req));
+        }
+        Ok(hits)
+    }
+
+    /// Reranks `hits` if `req` asks for it. An adaptive query, given as
+    /// its smallest k and margin, keeps only its best hits if they stand
+    /// out and is reranked only if they do not; it also returns whether
+    /// they did not and the margin.
+    async fn finish(
+        &self,
+        client: &str,
+        req: &QueryRequest,
+        adaptive: Option<(usize, f32)>,
+        mut hits: Vec<index::Hit>,
+    ) -> Result<(Vec<index::Hit>, Option<(bool, f32)>), CollectionError> {
+        let Some((min_k, threshold)) = adaptive else {
+            if req.rerank {
+                hits = self.rerank(client, req, hits).await?;
+            }
+            return Ok((hits, None));
+        };
+        let margin = margin(&hits, min_k);
+        let expanded = margin < threshold;
+        if !expanded {
+            hits.truncate(min_k);
+        } else if req.rerank {
+            hits = self.rerank(client, req, hits).await?;
+        }
+        log::debug!(
+            target: "query",
+            "adaptive: margin {margin:.3}, {} hits",
+            hits.len()
+        );
+        Ok((hits, Some((expanded, margin))))
+    }
+
+    /// `hits` as `req` asked for them: all at once, or the first page with
+    /// a cursor for the rest when it sets a limit. `adaptive` is what
+    /// [`finish`](Self::finish) decided for an adaptive query.
+    fn respond(
+        &self,
+        hits: Vec<index::Hit>,
+        req: &QueryRequest,
+        adaptive: Option<(bool, f32)>,
+    ) -> QueryResponse {
+        let limit = req.limit as usize;
+        if limit == 0 {
+            let hits = hits.into_iter().map(hit).collect();
+            let (expanded, margin) = adaptive.unwrap_or_default();
+            return QueryResponse {
+                hits,
+                expanded,
+                margin,
+                ..Default::default()
+            };
+        }
+        let page = self
+            .cursors
+            .lock()
+            .unwrap()
+            .start(hits, req.offset as usize, limit);
+        page_response(page)
+    }
+
+    /// Collection snapshot `id`, read from disk unless it was queried
+    /// recently.
+    async fn open_snapshot(&self, id: &str) -> Result<Arc<VectorIndex>, CollectionError> {
+        if let Some(index) = self.snapshots.lock().unwrap().get(id) {
+            return Ok(index);
+        }
+        let source = self.collections.read().unwrap().snapshot_source(id)?;
+        let index = tokio::task::spawn_blocking(move || source.open())
+            .await
+            .map_err(std::io::Error::other)??;
+        let index = Arc::new(index);
+        self.snapshots
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), Arc::clone(&index));
+        Ok(index)
+    }
+}
+
+/// Collection snapshots opened for QueryAt, most recently used last.
+#[derive(Default)]
+struct OpenSnapshots(Vec<(String, Arc<VectorIndex>)>);
+
+impl OpenSnapshots {
+    /// How many stay open; each holds a whole collection in memory.
+    const MAX: usize = 4;
+
+    fn get(&mut self, id: &str) -> Option<Arc<VectorIndex>> {
+        let at = self.0.iter().position(|(open, _)| open == id)?;
+        let entry = self.0.remove(at);
+        let index = Arc::clone(&entry.1);
+        self.0.push(entry);
+        Some(index)
+    }
+
+    fn insert(&mut self, id: String, index: Arc<VectorIndex>) {
+        self.remove(&id);
+        if self.0.len() >= Self::MAX {
+            self.0.remove(0);
+        }
+        self.0.push((id, index));
+    }
+
+    fn remove(&mut self, id: &str) {
+        self.0.retain(|(open, _)| open != id);
+    }
+}
+
+/// Whether `index` still embeds with `embedder`. A query is embedded
+/// before the collections are locked, and the collection may have been
+/// replaced in between.
+fn embedded_with(index: &VectorIndex, embedder: Option<&Arc<dyn Embedder>>) -> bool {
+    embedder.is_some_and(|e| Arc::ptr_eq(index.embedder(), e))
+}
+
+/// Refuses requests made in a workspace that act on every collection.
+fn whole_index(workspace: Option<&Workspace>) -> Result<(), CollectionError> {
+    match workspace {
+        Some(workspace) => Err(CollectionError::NotAllowed(format!(
+            "whole-index snapshots cannot be taken or restored from workspace {}",
+            workspace.name
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// The collection a snapshot id, `<collection>@<time>`, was taken of.
+fn snapshot_collection(id: &str) -> String {
+    id.split('@').next().unwrap_or_default().to_string()
+}
+
+/// Watched files as documents, each by the loader for its media type.
+/// Files of other types are skipped.
+fn watched_documents(workspace: &str, paths: &[std::path::PathBuf]) -> Vec<Document> {
+    paths
+        .iter()
+        .filter_map(|path| {
+            let loaded = match loader::load_file(path) {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    if loader::unsupported(&e).is_some() {
+                        let path = Privacy::global().redact(path.display());
+                        log::info!("not indexing {path}: {e}");
+                    }
+                    return None;
+                }
+            };
+            let (id, provenance) = docid::file(path, None).ok()?;
+            Some(Document {
+                id,
+                text: loaded.text,
+                source: provenance.source,
+                mime_type: loaded.mime_type.to_string(),
+                metadata: [("workspace".to_string(), workspace.to_string())].into(),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+/// Synced connector items are indexed like any other write, embedded at
+/// bulk priority under the connector's name.
+#[tonic::async_trait]
+impl DocumentSink for IndexerService {
+    async fn index(
+        &self,
+        connector: &str,
+        collection: &str,
+        docs: Vec<Document>,
+    ) -> Result<(), String> {
+        let count = docs.len();
+        let client = format!("connector:{connector}");
+        let written = self
+            .write(&client, collection, docs, None, &BTreeMap::new())
+            .await
+            .map_err(|e| e.to_string())?;
+        log::info!(
+            "indexed {count} items of {connector} in {collection} in {} entries",
+            written.chunks
+        );
+        Ok(())
+    }
+}
+
+/// Who a request is from, for fair embedding: its `client-id` metadata
+/// when set, otherwise the address it came from.
+fn client<T>(req: &Request<T>) -> String {
+    if let Some(id) = req
+        .metadata()
+        .get("client-id")
+        .and_then(|v| v.to_str().ok())
+    {
+        return id.to_string();
+    }
+    req.remote_addr()
+        .map_or_else(String::new, |addr| addr.to_string())
+}
+
+impl From<CollectionError> for Status {
+    fn from(e: CollectionError) -> Self {
+        match e {
+            CollectionError::InvalidName(_) | CollectionError::InvalidConfig(_) => {
+                Status::invalid_argument(e.to_string())
+            }
+            CollectionError::NotFound(_) => Status::not_found(e.to_string()),
+            CollectionError::AlreadyExists(_) => Status::already_exists(e.to_string()),
+            CollectionError::Precondition(_) => Status::failed_precondition(e.to_string()),
+            CollectionError::NotAllowed(_) => Status::permission_denied(e.to_string()),
+            CollectionError::Conflict(_) => Status::aborted(e.to_string()),
+            CollectionError::Io(e) => io_status(e),
+        }
+    }
+}
+
+fn collection_info(
+    collections: &Collections,
+    name: &str,
+    collection: &Collection,
+) -> CollectionInfo {
+    CollectionInfo {
+        aliases: collections.aliases_of(name),
+        name: name.to_string(),
+        embedder: collection.config.embedder.clone(),
+        metric: collection.config.metric.clone(),
+        quantization: collection.config.quantization.clone(),
+        documents: collection.index.len() as u64,
+        hnsw_m: collection.config.hnsw.m as u32,
+        hnsw_ef_construction: collection.config.hnsw.ef_construction as u32,
+        hnsw_ef_search: collection.config.hnsw.ef_search as u32,
+        chunk_size: collection.config.chunking.size as u32,
+        chunk_overlap: collection.config.chunking.overlap as u32,
+        dimensions: collection.index.embedder().dim() as u32,
+        privacy: collection.config.privacy.clone(),
+        dedup: collection.config.dedup.clone(),
+        normalize: collection.config.normalize.names(),
+        storage: match collection.config.storage.as_str() {
+            "" => "index".into(),
+            storage => storage.into(),
+        },
+    }
+}
+
+/// Records a finished query in the query log, as far as `privacy` allows:
+/// text and hit ids only at [`Privacy::Full`].
+fn log_query(
+    privacy: Privacy,
+    searched: &str,
+    req: &QueryRequest,
+    hits: &[index::Hit],
+    started: Instant,
+) {
+    let took = started.elapsed().as_millis();
+    match privacy {
+        Privacy::Nothing => {}
+        Privacy::Metadata => log::info!(
+            target: "query",
+            "{searched}: {} hits in {took} ms",
+            hits.len()
+        ),
+        Privacy::Full => {
+            let ids: Vec<&str> = hits.iter().map(|h| h.id.as_str()).collect();
+            log::info!(
+                target: "query",
+                "{searched}: {:?} -> {} hits in {took} ms: {}",
+                text::ellipsize(&req.query, LOGGED_QUERY_CHARS),
+                hits.len(),
+                ids.join(", ")
+            );
+        }
+    }
+}
+
+fn no_reranker() -> CollectionError {
+    CollectionError::Precondition(
+        "no reranker is loaded; start the server with ASSISTANT_RERANK_MODEL".into(),
+    )
+}
+
+fn ttl(seconds: u64) -> Option<Duration> {
+    (seconds > 0).then(|| Duration::from_secs(seconds))
+}
+
+fn requested_k(req: &QueryRequest) -> usize {
+    if req.k == 0 {
+        DEFAULT_K
+    } else {
+        req.k as usize
+    }
+}
+
+/// How many hits `req` ranks before paging or reranking picks from them:
+/// its `k`, unless it pages through up to [`MAX_SNAPSHOT_HITS`] or reranks
+/// the best `rerank_top_n`.
+fn ranked(req: &QueryRequest) -> Result<usize, String> {
+    if req.rerank {
+        if !matches!(req.sort.as_str(), "" | "score") {
+            return Err("rerank needs sort score".into());
+        }
+        return match req.rerank_top_n as usize {
+            0 => Ok(DEFAULT_RERANK_TOP_N),
+            n if n > MAX_RERANK_TOP_N => {
+                Err(format!("rerank_top_n must be at most {MAX_RERANK_TOP_N}"))
+            }
+            n => Ok(n),
+        };
+    }
+    Ok(match req.limit {
+        0 => requested_k(req),
+        _ => MAX_SNAPSHOT_HITS,
+    })
+}
+
+/// The smallest k and the margin of an adaptive query, or `None` if `req`
+/// is not one.
+fn adaptive(req: &QueryRequest) -> Result<Option<(usize, f32)>, String> {
+    if !req.adaptive {
+        return Ok(None);
+    }
+    if req.limit > 0 {
+        return Err("adaptive queries cannot be paged; leave limit 0".into());
+    }
+    if !matches!(req.sort.as_str(), "" | "score") {
+        return Err("adaptive needs sort score".into());
+    }
+    let min_k = match req.adaptive_min_k {
+        0 => DEFAULT_ADAPTIVE_MIN_K,
+        n => n as usize,
+    };
+    let margin = match req.adaptive_margin {
+        0.0 => DEFAULT_ADAPTIVE_MARGIN,
+        m if m > 0.0 && m.is_finite() => m,
+        m => return Err(format!("adaptive_margin must be positive, not {m}")),
+    };
+    Ok(Some((min_k.min(requested_k(req)), margin)))
+}
+
+/// How clearly the best `keep` hits stand out: the score gap between the
+/// last of them and the next hit, over the best hit's score. Infinite when
+/// no hit follows them, and 0 when the best score is.
+fn margin(hits: &[index::Hit], keep: usize) -> f32 {
+    let Some(next) = hits.get(keep) else {
+        return f32::INFINITY;
+    };
+    let best = hits[0].score.abs();
+    if best == 0.0 {
+        return 0.0;
+    }
+    (hits[keep.saturating_sub(1)].score - next.score) / best
+}
+
+fn query_options(req: &QueryRequest) -> Result<QueryOptions, String> {
+    Ok(QueryOptions {
+        k: requested_k(req),
+        group_by_document: req.group_by_document,
+        context_window: req.context_window,
+        filter: Filter::parse(&req.filter)?,
+        sort: Sort::parse(&req.sort)
+            .ok_or_else(|| format!("unknown sort {:?}; use score or indexed_at", req.sort))?,
+        exact: req.exact,
+        mode: Mode::parse(&req.mode)
+            .ok_or_else(|| format!("unknown mode {:?}; use vector, keyword or hybrid", req.mode))?,
+        vector: None,
+        metric: match req.metric.as_str() {
+            "" => None,
+            m => Some(Metric::parse(m).ok_or_else(|| {
+                format!("unknown metric {m:?}; use {}", Metric::NAMES.join(", "))
+            })?),
+        },
+        mmr_lambda: match req.mmr_lambda {
+            Some(_) if req.sort == "indexed_at" => {
+                return Err("mmr_lambda needs sort \"score\"".into())
+            }
+            Some(lambda) if !(0.0..=1.0).contains(&lambda) => {
+                return Err(format!("mmr_lambda must be between 0 and 1, not {lambda}"))
+            }
+            lambda => lambda,
+        },
+    })
+}
+
+pub(crate) fn timestamp(millis: Option<i64>) -> Option<prost_types::Timestamp> {
+    millis.map(|ms| prost_types::Timestamp {
+        seconds: ms.div_euclid(1000),
+        nanos: (ms.rem_euclid(1000) * 1_000_000) as i32,
+    })
+}
+
+/// The collection a query reads, once it reflects `req.after_write`, if
+/// it is embedded with `req.embedder`.
+fn readable<'a>(
+    collections: &'a Collections,
+    req: &QueryRequest,
+) -> Result<&'a Collection, CollectionError> {
+    let collection = collections.get(&req.collection)?;
+    let embedder = collection.index.embedder().name();
+    if !req.embedder.is_empty() && req.embedder != embedder {
+        return Err(CollectionError::Precondition(format!(
+            "collection {} is embedded with {embedder}, not {}",
+            collections.resolve(&req.collection),
+            req.embedder
+        )));
+    }
+    if !req.after_write.is_empty() {
+        let token: WriteToken = req.after_write.parse()?;
+        if !collection.has_applied(token) {
+            return Err(CollectionError::Precondition(
+                "the write is not visible yet; retry".into(),
+            ));
+        }
+    }
+    Ok(collection)
+}
+
+fn hit(h: index::Hit) -> Hit {
+    Hit {
+        id: h.id,
+        size_bytes: h.text.len() as u64,
+        text: h.text,
+        score: h.score,
+        source: h.provenance.source,
+        chunk: h.provenance.chunk,
+        document_id: h.document_id,
+        before: h.before.into_iter().map(chunk).collect(),
+        after: h.after.into_iter().map(chunk).collect(),
+        indexed_at: timestamp(h.indexed_at),
+        mime_type: h.provenance.mime_type,
+        metadata: h.metadata.into_iter().collect(),
+    }
+}
+
+fn page_response(page: Page) -> QueryResponse {
+    QueryResponse {
+        hits: page.hits.into_iter().map(hit).collect(),
+        next_cursor: page.next,
+        total: page.total as u32,
+        ..Default::default()
+    }
+}
+
+fn chunk(doc: Doc) -> Chunk {
+    Chunk {
+        id: doc.id,
+        text: doc.text.into_string(),
+        chunk: doc.provenance.chunk,
+        embedding: Vec::new(),
+    }
+}
+
+fn collection_snapshot(s: crate::collection::CollectionSnapshot) -> CollectionSnapshot {
+    CollectionSnapshot {
+        id: s.id,
+        collection: s.collection,
+        created_at: timestamp(Some(s.created_at)),
+        entries: s.entries as u64,
+        embedder: s.embedder,
+    }
+}
+
+/// Snapshot paths are resolved on the core's machine, whose working
+/// directory the client does not know, so they must be absolute.
+fn snapshot_path(path: &str) -> Result<&Path, CollectionError> {
+    let path = Path::new(path);
+    if !path.is_absolute() {
+        return Err(CollectionError::InvalidConfig(format!(
+            "snapshot path {} must be absolute",
+            path.display()
+        )));
+    }
+    Ok(path)
+}
+
+fn io_status(e: std::io::Error) -> Status {
+    Status::internal(format!("saving index failed: {e}"))
+}
+
+/// The entries `doc` is stored as: its chunks when it is longer than the
+/// chunk size, otherwise the document itself. A table is stored in
+/// chunks of rows (see [`table::split`]), which record their `sheet`,
+/// `first_row` and `last_row` in their metadata. Chunks the caller split
+/// itself are stored as they are. Also returns whether `doc` is a whole
+/// document, whose entries replace all of its earlier ones. Text is
+/// normalized first, or a table's rows once they are written out.
+fn entries(
+    mut doc: Document,
+    chunking: ChunkParams,
+    normalize: Normalize,
+    ttl: Option<Duration>,
+) -> (Vec<NewEntry>, bool) {
+    let provenance = Provenance {
+        source: doc.source,
+        chunk: doc.chunk,
+        mime_type: doc.mime_type,
+    };
+    let metadata: BTreeMap<String, String> = doc.metadata.into_iter().collect();
+    let whole = doc.chunk.is_none() && docid::parent(&doc.id) == doc.id;
+    let tables = whole
+        .then(|| table::split(&doc.text, &provenance.mime_type, chunking))
+        .flatten();
+    if tables.is_none() {
+        doc.text = normalize.document(doc.text);
+    }
+    let content_hash = match whole {
+        true => content_hash(&doc.text),
+        false => String::new(),
+    };
+    if let Some(tables) = tables {
+        let chunked = tables.len() > 1;
+        let entries = (0u32..)
+            .zip(tables)
+            .map(|(n, rows)| {
+                let chunk = chunked.then_some(n);
+                let mut metadata = metadata.clone();
+                metadata.insert("first_row".into(), rows.first.to_string());
+                metadata.insert("last_row".into(), rows.last.to_string());
+                if let Some(sheet) = rows.sheet {
+                    metadata.insert("sheet".into(), sheet);
+                }
+                NewEntry {
+                    id: docid::with_chunk(doc.id.clone(), chunk),
+                    text: normalize.document(rows.text),
+                    provenance: Provenance {
+                        chunk,
+                        ..provenance.clone()
+                    },
+                    metadata,
+                    ttl,
+                    content_hash: content_hash.clone(),
+                }
+            })
+            .collect();
+        return (entries, whole);
+    }
+    let parts = match whole {
+        true => chunk::split(&doc.text, chunking),
+        false => Vec::new(),
+    };
+    if parts.len() <= 1 {
+        let entry = NewEntry {
+            id: doc.id,
+            text: doc.text,
+            provenance,
+            metadata,
+            ttl,
+            content_hash,
+        };
+        return (vec![entry], whole);
+    }
+    let entries = (0u32..)
+        .zip(parts)
+        .map(|(n, text)| NewEntry {
+            id: docid::with_chunk(doc.id.clone(), Some(n)),
+            text: text.to_string(),
+            provenance: Provenance {
+                chunk: Some(n),
+                ..provenance.clone()
+            },
+            metadata: metadata.clone(),
+            ttl,
+            content_hash: content_hash.clone(),
+        })
+        .collect();
+    (entries, whole)
+}
+
+/// Hex SHA-256 of a document's text, which finds documents indexed twice.
+fn content_hash(text: &str) -> String {
+    Sha256::digest(text.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Documents split into the entries they are stored as.
+struct Prepared {
+    entries: Vec<NewEntry>,
+    /// Whole documents written, with the entries they now consist of.
+    replaced: BTreeMap<String, Vec<String>>,
+    /// Whole documents written and their content hashes, in order.
+    hashes: Vec<(String, String)>,
+}
+
+impl Prepared {
+    /// Leaves out the documents in `ids`, along with their entries' vectors.
+    fn without(
+        self,
+        vectors: Vec<Vec<f32>>,
+        ids: &BTreeMap<String, String>,
+    ) -> (Prepared, Vec<Vec<f32>>) {
+        if ids.is_empty() {
+            return (self, vectors);
+        }
+        let (entries, vectors) = self
+            .entries
+            .into_iter()
+            .zip(vectors)
+            .filter(|(e, _)| !ids.contains_key(docid::parent(&e.id)))
+            .unzip();
+        let mut replaced = self.replaced;
+        replaced.retain(|id, _| !ids.contains_key(id));
+        let mut hashes = self.hashes;
+        hashes.retain(|(id, _)| !ids.contains_key(id));
+        let prepared = Prepared {
+            entries,
+            replaced,
+            hashes,
+        };
+        (prepared, vectors)
+    }
+}
+
+/// What a write stored.
+struct Written {
+    /// Entries written.
+    chunks: usize,
+    token: WriteToken,
+    /// Documents left out as duplicates, mapped to the document with the
+    /// same text.
+    duplicates: BTreeMap<String, String>,
+    /// Documents stored, mapped to their versions after the write.
+    versions: BTreeMap<String, u64>,
+}
+
+/// Counts a failed document of an import, listing it among the first
+/// [`MAX_IMPORT_ERRORS`].
+fn import_failed(progress: &mut ImportResponse, id: String, message: String) {
+    progress.failed += 1;
+    if progress.errors.len() < MAX_IMPORT_ERRORS {
+        progress.errors.push(ImportError { id, message });
+    }
+}
+
+/// Counts a failed entry of an index import, listing it among the first
+/// [`MAX_IMPORT_ERRORS`].
+fn index_import_failed(progress: &mut ImportIndexResponse, id: String, message: String) {
+    progress.failed += 1;
+    if progress.errors.len() < MAX_IMPORT_ERRORS {
+        progress.errors.push(ImportError { id, message });
+    }
+}
+
+/// An uploaded archive to index, and the ids to index its entries under.
+struct ArchiveUpload {
+    path: std::path::PathBuf,
+    name: String,
+    kind: archive::Kind,
+    /// Id of the archive; its entries' ids extend it.
+    id: String,
+    source: String,
+}
+
+/// What an `Embed` stream waited for.
+enum EmbedEvent {
+    Received(Result<Option<EmbedRequest>, Status>),
+    Embedded(std::io::Result<EmbedBatch>),
+}
+
+/// A batch of an `Embed` stream, embedded.
+struct EmbedBatch {
+    vectors: Vec<Vec<f32>>,
+    tokens: u64,
+    truncated: u32,
+    wall: Duration,
+}
+
+/// Tokens `texts` come to once cut to what `embedder` takes, and how many
+/// were cut.
+fn embed_usage(embedder: &dyn Embedder, texts: &[String]) -> (u64, u32) {
+    let max = embedder.max_tokens().unwrap_or(usize::MAX);
+    texts.iter().fold((0, 0), |(tokens, truncated), text| {
+        let n = embedder.tokens(text);
+        (tokens + n.min(max) as u64, truncated + u32::from(n > max))
+    })
+}
+
+/// Progress of a reindex, for `Stats`.
+#[derive(Clone, Debug)]
+struct Reindexing {
+    embedder: String,
+    /// Entries embedded so far.
+    done: u64,
+}
+
+/// A collection being moved to another embedder. Its entries are read a
+/// batch at a time and embedded outside the lock, so queries and writes go
+/// on against the old embeddings meanwhile; entries written after their
+/// batch was read are embedded again when the new embeddings are put in
+/// place. Dropping it, finished or not, lets the collection be reindexed
+/// again.
+struct Reindex {
+    collections: Arc<RwLock<Collections>>,
+    embeds: Arc<EmbedQueue>,
+    reindexing: Arc<Mutex<HashMap<String, Reindexing>>>,
+    client: String,
+    /// Resolved collection name.
+    name: String,
+    embedder: Arc<dyn Embedder>,
+    /// The collection's embedder when the reindex started; another one
+    /// means the collection was replaced or reindexed meanwhile.
+    previous: Arc<dyn Embedder>,
+    /// Whether [`claim`](Self::claim) registered it.
+    claimed: bool,
+}
+
+impl Reindex {
+    /// Registers the reindex, failing if the collection has one running.
+    fn claim(&mut self) -> Result<(), CollectionError> {
+        let mut reindexing = self.reindexing.lock().unwrap();
+        if reindexing.contains_key(&self.name) {
+            return Err(CollectionError::Conflict(format!(
+                "collection {} is already being reindexed",
+                self.name
+            )));
+        }
+        let progress = Reindexing {
+            embedder: self.embedder.name().to_string(),
+            done: 0,
+        };
+        reindexing.insert(self.name.clone(), progress);
+        self.claimed = true;
+        Ok(())
+    }
+
+    fn check(&self, collection: &Collection) -> Result<(), CollectionError> {
+        match Arc::ptr_eq(collection.index.embedder(), &self.previous) {
+            true => Ok(()),
+            false => Err(CollectionError::Conflict(format!(
+                "collection {} was replaced while being reindexed",
+                self.name
+            ))),
+        }
+    }
+
+    /// Embeds every entry and switches the collection over. Returns how
+    /// many entries it holds then, and the write token of the switch.
+    async fn run(&self) -> Result<(usize, WriteToken), CollectionError> {
+        let mut made = HashMap::new();
+        let mut at = 0;
+        loop {
+            let batch = {
+                let collections = self.collections.read().unwrap();
+                let collection = collections.get(&self.name)?;
+                self.check(collection)?;
+                collection.index.entries(at, REINDEX_BATCH, false)
+            };
+            if batch.is_empty() {
+                break;
+            }
+            at += batch.len();
+            self.embed_into(&mut made, batch).await?;
+        }
+        // Entries written meanwhile are embedded, and the new search graph
+        // built, outside the lock, so queries go on. Only if writes keep
+        // landing before the switch is it all done under the lock.
+        let mut late = 0;
+        for _ in 0..REINDEX_ATTEMPTS {
+            let prepared = {
+                let collections = self.collections.read().unwrap();
+                let collection = collections.get(&self.name)?;
+                self.check(collection)?;
+                let index = &collection.index;
+                index
+                    .reembedded(&made, self.embedder.dim())
+                    .ok_or_else(|| index.unembedded(&made))
+            };
+            match prepared {
+                Ok(mut new) => {
+                    let new = tokio::task::spawn_blocking(move || {
+                        new.build_graph();
+                        new
+                    })
+                    .await
+                    .map_err(std::io::Error::other)?;
+                    let mut collections = self.collections.write().unwrap();
+                    self.check(collections.get(&self.name)?)?;
+                    let embedder = Arc::clone(&self.embedder);
+                    if collections.switch_embedder(&self.name, embedder, new)? {
+                        return self.finished(&collections, late);
+                    }
+                }
+                Err(missing) => {
+                    late += missing.len();
+                    self.embed_into(&mut made, missing).await?;
+                }
+            }
+        }
+        let mut collections = self.collections.write().unwrap();
+        self.check(collections.get(&self.name)?)?;
+        late += collections.replace_embedder(&self.name, Arc::clone(&self.embedder), made)?;
+        self.finished(&collections, late)
+    }
+
+    /// Embeds `docs` with the new embedder into `made`, keyed by id with
+    /// the hash of the text embedded.
+    async fn embed_into(
+        &self,
+        made: &mut HashMap<String, (u64, Vec<f32>)>,
+        docs: Vec<Doc>,
+    ) -> Result<(), CollectionError> {
+        let texts = docs.iter().map(|doc| doc.text.get().into_owned()).collect();
+        let embedder = Arc::clone(&self.embedder);
+        let vectors = self
+            .embeds
+            .embed(&self.client, Priority::Bulk, embedder, texts)
+            .await?;
+        for (doc, vector) in docs.into_iter().zip(vectors) {
+            made.insert(doc.id, (index::fnv1a(doc.text.get().as_bytes()), vector));
+        }
+        if let Some(progress) = self.reindexing.lock().unwrap().get_mut(&self.name) {
+            progress.done = made.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn finished(
+        &self,
+        collections: &Collections,
+        late: usize,
+    ) -> Result<(usize, WriteToken), CollectionError> {
+        let collection = collections.get(&self.name)?;
+        log::info!(
+            "reindexed {} with {}: {} entries, {late} of them written meanwhile",
+            self.name,
+            self.embedder.name(),
+            collection.index.len()
+        );
+        Ok((collection.index.len(), collection.write_token()))
+    }
+}
+
+impl Drop for Reindex {
+    fn drop(&mut self) {
+        if self.claimed {
+            self.reindexing.lock().unwrap().remove(&self.name);
+        }
+    }
+}
+
+/// Where an export has got to. Pages are read under the lock one at a
+/// time, so writes go on during an export; after one, the export resumes
+/// after the last entry sent, wherever it has moved to.
+struct ExportCursor {
+    /// The collection's state when the last page was read.
+    token: WriteToken,
+    at: usize,
+    /// Id of the last entry sent.
+    last: Option<String>,
+}
+
+impl ExportCursor {
+    /// The next page of collection `name`'s entries as export lines;
+    /// empty once all have been sent.
+    fn next_page(
+        &mut self,
+        collections: &RwLock<Collections>,
+        name: &str,
+        embeddings: bool,
+    ) -> Result<String, CollectionError> {
+        let collections = collections.read().unwrap();
+        let collection = collections.get(name)?;
+        if !collection.issued(self.token) {
+            return Err(CollectionError::Conflict(format!(
+                "collection {name} was replaced during the export"
+            )));
+        }
+        let index = &collection.index;
+        if collection.write_token() != self.token {
+            self.token = collection.write_token();
+            self.at = match self.last.as_deref().and_then(|id| index.position(id)) {
+                Some(found) => found + 1,
+                None => self.at.min(index.len()),
+            };
+        }
+        let docs = index.entries(self.at, EXPORT_PAGE, embeddings);
+        self.at += docs.len();
+        if let Some(doc) = docs.last() {
+            self.last = Some(doc.id.clone());
+        }
+        let embedder = embeddings.then(|| index.embedder().as_ref());
+        Ok(export_lines(docs, embedder))
+    }
+}
+
+/// `docs` as export lines; with an `embedder`, each line carries its
+/// embedding and that embedder's name and fingerprint.
+fn export_lines(docs: Vec<Doc>, embedder: Option<&dyn Embedder>) -> String {
+    let mut out = String::new();
+    for doc in docs {
+        let embedding = doc.embedding.clone();
+        let mut line = serde_json::to_value(doc).unwrap_or_default();
+        if let Some(embedder) = embedder {
+            line["embedding"] = serde_json::json!(embedding);
+            line["embedder"] = serde_json::json!(embedder.name());
+            line["fingerprint"] = serde_json::json!(embedder.fingerprint());
+        }
+        out.push_str(&line.to_string());
+        out.push('\n');
+    }
+    out
+}
+
+/// The entry on one line of an import, and the fingerprint of the
+/// embedder its embedding came from, or its name on lines without one;
+/// empty without an embedding. Entries without a time are stamped with
+/// `now`.
+fn import_line(line: &str, now: i64) -> Result<(Doc, String), String> {
+    let value: serde_json::Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    let embedder = match value["fingerprint"].as_str() {
+        Some(fingerprint) => fingerprint.to_string(),
+        None => value["embedder"].as_str().unwrap_or_default().to_string(),
+    };
+    let mut doc: Doc = serde_json::from_value(value).map_err(|e| e.to_string())?;
+    if doc.id.is_empty() {
+        return Err("empty id".into());
+    }
+    doc.indexed_at.get_or_insert(now);
+    Ok((doc, embedder))
+}
+
+/// Fails unless each document in `expected` is at the version it maps
+/// to, 0 meaning not stored.
+fn check_versions(
+    index: &VectorIndex,
+    expected: &BTreeMap<String, u64>,
+) -> Result<(), CollectionError> {
+    let current = index.versions(expected.keys().map(String::as_str));
+    for (id, &wanted) in expected {
+        let found = current[id.as_str()].unwrap_or(0);
+        if found != wanted {
+            return Err(CollectionError::Conflict(match (found, wanted) {
+                (0, _) => format!("document {id} does not exist, expected version {wanted}"),
+                (_, 0) => format!("document {id} already exists at version {found}"),
+                _ => format!("document {id} is at version {found}, expected {wanted}"),
+            }));
+        }
+    }
+    Ok(())
+}
+
+/// Whole documents in `hashes` whose text `index` already holds under
+/// another id, or an earlier document of the same write has, mapped to
+/// that id.
+fn duplicates(index: &VectorIndex, hashes: &[(String, String)]) -> BTreeMap<String, String> {
+    let mut found = BTreeMap::new();
+    let mut first: HashMap<&str, &str> = HashMap::new();
+    for (id, hash) in hashes {
+        let of = index
+            .duplicate_of(hash, id)
+            .or_else(|| first.get(hash.as_str()).copied().filter(|of| of != id));
+        match of {
+            Some(of) => {
+                found.insert(id.clone(), of.to_string());
+            }
+            None => {
+                first.entry(hash).or_insert(id);
+            }
+        }
+    }
+    found
+}
+
+fn prepare(
+    docs: Vec<Document>,
+    chunking: ChunkParams,
+    normalize: Normalize,
+    ttl: Option<Duration>,
+) -> Prepared {
+    let mut all = Vec::new();
+    let mut replaced: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut hashes = Vec::new();
+    for doc in docs {
+        let (entries, whole) = entries(doc, chunking, normalize, ttl);
+        let parent = docid::parent(&entries[0].id).to_string();
+        let ids = entries.iter().map(|e| e.id.clone());
+        if whole {
+            hashes.push((parent.clone(), entries[0].content_hash.clone()));
+            replaced.insert(parent, ids.collect());
+        } else if let Some(kept) = replaced.get_mut(&parent) {
+            kept.extend(ids);
+        }
+        all.extend(entries);
+    }
+    Prepared {
+        entries: all,
+        replaced,
+        hashes,
+    }
+}
+
+/// Stores prepared entries in `collection` with their `vectors`, and
+/// returns how many were written.
+fn store(
+    collection: &mut Collection,
+    prepared: Prepared,
+    vectors: Vec<Vec<f32>>,
+) -> std::io::Result<usize> {
+    let Prepared {
+        entries, replaced, ..
+    } = prepared;
+    let count = entries.len();
+    collection
+        .index
+        .upsert_embedded(entries.into_iter().zip(vectors).collect())?;
+    // Entries the new texts no longer produce, such as trailing chunks
+    // of a longer earlier version, go.
+    let stale = replaced
+        .iter()
+        .flat_map(|(id, kept)| {
+            collection
+                .index
+                .entries_of(id)
+                .into_iter()
+                .filter(|e| !kept.contains(e))
+        })
+        .collect();
+    collection.index.remove(stale)?;
+    Ok(count)
+}
+
+#[tonic::async_trait]
+impl Indexer for IndexerService {
+    type ExportIndexStream =
+        Pin<Box<dyn Stream<Item = Result<IndexLines, Status>> + Send + 'static>>;
+    type EmbedStream = Pin<Box<dyn Stream<Item = Result<EmbedResponse, Status>> + Send + 'static>>;
+
+    async fn index(&self, req: Request<IndexRequest>) -> Result<Response<IndexResponse>, Status> {
+        let client = client(&req);
+        let workspace = self.workspace(&req)?;
+        let mut req = req.into_inner();
+        self.scope(workspace, &mut req.collection)?;
+        let doc = req
+            .document
+            .ok_or_else(|| Status::invalid_argument("missing document"))?;
+        if doc.id.is_empty() {
+            return Err(Status::invalid_argument("document id is empty"));
+        }
+        let id = doc.id.clone();
+        let expected = req
+            .if_version_matches
+            .map(|v| (docid::parent(&id).to_string(), v))
+            .into_iter()
+            .collect();
+        let ttl = ttl(req.ttl_seconds);
+        let written = self
+            .write(&client, &req.collection, vec![doc], ttl, &expected)
+            .await?;
+        Ok(Response::new(IndexResponse {
+            duplicate_of: written.duplicates.get(&id).cloned().unwrap_or_default(),
+            version: written
+                .versions
+                .get(docid::parent(&id))
+                .copied()
+                .unwrap_or_default(),
+            id,
+            write_token: written.token.to_string(),
+            chunks: written.chunks as u32,
+        }))
+    }
+
+    async fn batch_index(
+        &self,
+        req: Request<BatchIndexRequest>,
+    ) -> Result<Response<BatchIndexResponse>, Status> {
+        let client = client(&req);
+        let workspace = self.workspace(&req)?;
+        let mut req = req.into_inner();
+        self.scope(workspace, &mut req.collection)?;
+        if let Some(n) = req.documents.iter().position(|d| d.id.is_empty()) {
+            return Err(Status::invalid_argument(format!(
+                "document {n} has an empty id"
+            )));
+        }
+        let documents = req.documents.len() as u32;
+        let ttl = ttl(req.ttl_seconds);
+        let expected = req.if_version_matches.into_iter().collect();
+        let written = self
+            .write(&client, &req.collection, req.documents, ttl, &expected)
+            .await?;
+        Ok(Response::new(BatchIndexResponse {
+            documents,
+            chunks: written.chunks as u32,
+            write_token: written.token.to_string(),
+            duplicates: written.duplicates.into_iter().collect(),
+            versions: written.versions.into_iter().collect(),
+        }))
+    }
+
+    async fn import_documents(
+        &self,
+        req: Request<tonic::Streaming<ImportRequest>>,
+    ) -> Result<Response<ImportResponse>, Status> {
+        let client = client(&req);
+        let workspace = self.workspace(&req)?;
+        let mut inbound = req.into_inner();
+        let mut progress = ImportResponse::default();
+        let Some(first) = inbound.message().await? else {
+            return Ok(Response::new(progress));
+        };
+        let mut collection = first.collection;
+        self.scope(workspace, &mut collection)?;
+        // Fails the import up front rather than every batch of it.
+        self.collections.read().unwrap().get(&collection)?;
+        let ttl = ttl(first.ttl_seconds);
+        let started = Instant::now();
+        let mut logged = 0;
+        let mut batch = Vec::with_capacity(IMPORT_BATCH);
+        let mut pending = Some(first.documents);
+        loop {
+            let documents = match pending.take() {
+                Some(documents) => documents,
+                None => match inbound.message().await? {
+                    Some(next) => next.documents,
+                    None => break,
+                },
+            };
+            for doc in documents {
+                progress.received += 1;
+                if doc.id.is_empty() {
+                    let message = format!("document {} has an empty id", progress.received - 1);
+                    import_failed(&mut progress, String::new(), message);
+                    continue;
+                }
+                batch.push(doc);
+                if batch.len() == IMPORT_BATCH {
+                    let full = std::mem::take(&mut batch);
+                    self.import_batch(&client, &collection, full, ttl, &mut progress)
+                        .await;
+                }
+            }
+            if progress.documents >= logged + IMPORT_PROGRESS {
+                logged = progress.documents;
+                log::info!(
+                    "import into {collection}: {} documents stored, {} failed, {:.0?} so far",
+                    progress.documents,
+                    progress.failed,
+                    started.elapsed()
+                );
+            }
+        }
+        if !batch.is_empty() {
+            self.import_batch(&client, &collection, batch, ttl, &mut progress)
+                .await;
+        }
+        log::info!(
+            "import into {collection} done: {} of {} documents stored in {} entries, {} failed, in {:.0?}",
+            progress.documents,
+            progress.received,
+            progress.chunks,
+            progress.failed,
+            started.elapsed()
+        );
+        Ok(Response::new(progress))
+    }
+
+    async fn export_index(
+        &self,
+        req: Request<ExportIndexRequest>,
+    ) -> Result<Response<Self::ExportIndexStream>, Status> {
+        let workspace = self.workspace(&req)?;
+        let mut req = req.into_inner();
+        self.scope(workspace, &mut req.collection)?;
+        let name = req.collection;
+        let token = self.collections.read().unwrap().get(&name)?.write_token();
+        let collections = Arc::clone(&self.collections);
+        let output = async_stream::try_stream! {
+            let mut cursor = ExportCursor { token, at: 0, last: None };
+            loop {
+                let jsonl = cursor.next_page(&collections, &name, req.include_embeddings)?;
+                if jsonl.is_empty() {
+                    break;
+                }
+                yield IndexLines { jsonl };
+            }
+        };
+        Ok(Response::new(Box::pin(output)))
+    }
+
+    async fn import_index(
+        &self,
+        req: Request<tonic::Streaming<ImportIndexRequest>>,
+    ) -> Result<Response<ImportIndexResponse>, Status> {
+        let client = client(&req);
+        let workspace = self.workspace(&req)?;
+        let mut inbound = req.into_inner();
+        let mut progress = ImportIndexResponse::default();
+        let Some(first) = inbound.message().await? else {
+            return Ok(Response::new(progress));
+        };
+        let mut name = first.collection;
+        self.scope(workspace, &mut name)?;
+        self.collections.read().unwrap().get(&name)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64);
+        let (mut buffer, mut line_no) = (String::new(), 0);
+        let mut batch = Vec::with_capacity(IMPORT_BATCH);
+        let mut pending = Some(first.jsonl);
+        let mut done = false;
+        while !done {
+            match pending.take() {
+                Some(jsonl) => buffer.push_str(&jsonl),
+                None => match inbound.message().await? {
+                    Some(next) => buffer.push_str(&next.jsonl),
+                    // What is left is the last line, without a newline.
+                    None => {
+                        buffer.push('\n');
+                        done = true;
+                    }
+                },
+            }
+            let Some(end) = buffer.rfind('\n') else {
+                continue;
+            };
+            let rest = buffer.split_off(end + 1);
+            let lines = std::mem::replace(&mut buffer, rest);
+            for line in lines.lines() {
+                line_no += 1;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                progress.lines += 1;
+                match import_line(line, now) {
+                    Ok(entry) => batch.push(entry),
+                    Err(e) => {
+                        index_import_failed(&mut progress, format!("line {line_no}"), e);
+                        continue;
+                    }
+                }
+                if batch.len() == IMPORT_BATCH {
+                    let full = std::mem::take(&mut batch);
+                    self.import_index_batch(&client, &name, full, &mut progress)
+                        .await;
+                }
+            }
+        }
+        if !batch.is_empty() {
+            self.import_index_batch(&client, &name, batch, &mut progress)
+                .await;
+        }
+        log::info!(
+            "index import into {name} done: {} of {} lines stored, {} embedded again, {} failed",
+            progress.entries,
+            progress.lines,
+            progress.embedded,
+            progress.failed
+        );
+        Ok(Response::new(progress))
+    }
+
+    async fn upload_file(
+        &self,
+        req: Request<tonic::Streaming<FileChunk>>,
+    ) -> Result<Response<UploadResult>, Status> {
+        let client = client(&req);
+        let workspace = self.workspace(&req)?;
+        let mut inbound = req.into_inner();
+        let first = inbound
+            .message()
+            .await?
+            .ok_or_else(|| Status::invalid_argument("upload stream is empty"))?;
+        let mut collection = first.collection.clone();
+        if first.index {
+            // Fails before any bytes are stored rather than after all are.
+            self.scope(workspace, &mut collection)?;
+            self.collections.read().unwrap().get(&collection)?;
+        }
+        let uploaded_in = workspace.map_or("", |w| w.name.as_str());
+        let mut writer = self
+            .uploads
+            .open(&first.upload_id, &first.name, first.total_size, uploaded_in)
+            .map_err(upload::status)?;
+        let (index, document_id, source) =
+            (first.index, first.document_id.clone(), first.source.clone());
+        let mut pending = Some(first);
+        let mut last = false;
+        while !last {
+            let chunk = match pending.take() {
+                Some(chunk) => chunk,
+                None => match inbound.message().await? {
+                    Some(chunk) => chunk,
+                    None => break,
+                },
+            };
+            writer
+                .append(chunk.offset, &chunk.data)
+                .map_err(upload::status)?;
+            last = chunk.last;
+        }
+        if !last && !writer.upload().complete {
+            // The client stopped early and can resume from `received`.
+            return Ok(Response::new(writer.upload().to_message()));
+        }
+        let upload = writer.finish().map_err(upload::status)?;
+        let mut result = upload.to_message();
+        if index {
+            let (upload, path) = self
+                .uploads
+                .file(&upload.id, uploaded_in)
+                .map_err(upload::status)?;
+            let id = match document_id.as_str() {
+                "" => format!("upload:{}", upload.id),
+                id => id.to_string(),
+            };
+            if let Some(kind) = archive::kind_of(&path, &upload.name).map_err(upload::status)? {
+                let archived = ArchiveUpload {
+                    path,
+                    name: upload.name,
+                    kind,
+                    id: id.clone(),
+                    source,
+                };
+                self.index_archive(&client, &collection, archived, &mut result)
+                    .await?;
+                result.document_id = id;
+                return Ok(Response::new(result));
+            }
+            let (_, loaded) = self
+                .uploads
+                .load(&upload.id, uploaded_in)
+                .map_err(upload::status)?;
+            let doc = Document {
+                id: id.clone(),
+                text: loaded.text,
+                mime_type: loaded.mime_type.to_string(),
+                source,
+                ..Default::default()
+            };
+            let written = self
+                .write(&client, &collection, vec![doc], None, &BTreeMap::new())
+                .await?;
+            result.document_id = id;
+            result.chunks = written.chunks as u32;
+            result.write_token = written.token.to_string();
+        }
+        Ok(Response::new(result))
+    }
+
+    async fn upload_status(
+        &self,
+        req: Request<UploadStatusRequest>,
+    ) -> Result<Response<UploadResult>, Status> {
+        let uploaded_in = self.workspace(&req)?.map_or("", |w| w.name.as_str());
+        let upload = self
+            .uploads
+            .get(&req.get_ref().upload_id, uploaded_in)
+            .map_err(upload::status)?;
+        Ok(Response::new(upload.to_message()))
+    }
+
+    async fn query(&self, req: Request<QueryRequest>) -> Result<Response<QueryResponse>, Status> {
+        let client = client(&req);
+        let workspace = self.workspace(&req)?;
+        let mut req = req.into_inner();
+        self.scope(workspace, &mut req.collection)?;
+        if !req.cursor.is_empty() {
+            return Ok(Response::new(self.resume(&req)?));
+        }
+        req.query = self.normalized(&req.collection, req.query);
+        let mut options = query_options(&req).map_err(Status::invalid_argument)?;
+        options.k = ranked(&req).map_err(Status::invalid_argument)?;
+        let adaptive = adaptive(&req).map_err(Status::invalid_argument)?;
+        if req.rerank && self.reranker.is_none() {
+            return Err(no_reranker().into());
+        }
+        let started = Instant::now();
+        let embedder = match options.mode {
+            Mode::Keyword => None,
+            _ => Some(self.embed_query(&client, &req, &mut options).await?),
+        };
+        let (name, privacy, hits) = {
+            let collections = self.collections.read().unwrap();
+            let collection = readable(&collections, &req)?;
+            let index = &collection.index;
+            if !embedded_with(index, embedder.as_ref()) {
+                options.vector = None;
+            }
+            (
+                collections.resolve(&req.collection).to_string(),
+                Privacy::resolve(&collection.config.privacy),
+                index.query(&req.query, &options),
+            )
+        };
+        let (hits, adaptive) = self.finish(&client, &req, adaptive, hits).await?;
+        log_query(privacy, &name, &req, &hits, started);
+        Ok(Response::new(self.respond(hits, &req, adaptive)))
+    }
+
+    async fn get_document(
+        &self,
+        req: Request<GetDocumentRequest>,
+    ) -> Result<Response<GetDocumentResponse>, Status> {
+        let workspace = self.workspace(&req)?;
+        let GetDocumentRequest {
+            id,
+            mut collection,
+            include_embedding,
+        } = req.into_inner();
+        self.scope(workspace, &mut collection)?;
+        let collections = self.collections.read().unwrap();
+        let doc = collections
+            .get(&collection)?
+            .index
+            .document(&id)
+            .ok_or_else(|| Status::not_found(format!("no document {id}")))?;
+        let size_bytes = doc.chunks.iter().map(|c| c.text.len() as u64).sum();
+        let chunks = doc
+            .chunks
+            .into_iter()
+            .map(|d| {
+                let embedding = if include_embedding {
+                    d.embedding.clone()
+                } else {
+                    Vec::new()
+                };
+                Chunk {
+                    embedding,
+                    ..chunk(d)
+                }
+            })
+            .collect();
+        Ok(Response::new(GetDocumentResponse {
+            id: doc.id,
+            text: doc.text,
+            source: doc.source,
+            chunks,
+            indexed_at: timestamp(doc.indexed_at),
+            mime_type: doc.mime_type,
+            size_bytes,
+            metadata: doc.metadata.into_iter().collect(),
+            merged_ids: doc.merged_ids,
+            version: doc.version,
+        }))
+    }
+
+    async fn delete(
+        &self,
+        req: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        let workspace = self.workspace(&req)?;
+        let mut req = req.into_inner();
+        self.scope(workspace, &mut req.collection)?;
+        if req.id.is_empty() && req.filter.is_empty() {
+            return Err(Status::invalid_argument("give an id, a filter or both"));
+        }
+        let filter = Filter::parse(&req.filter).map_err(Status::invalid_argument)?;
+        let id = Some(req.id.as_str()).filter(|id| !id.is_empty());
+        let mut collections = self.collections.write().unwrap();
+        let collection = collections.get_mut(&req.collection)?;
+        let deleted = collection.index.delete(id, &filter).map_err(io_status)?;
+        Ok(Response::new(DeleteResponse {
+            deleted: deleted as u64,
+            write_token: collection.write_token().to_string(),
+        }))
+    }
+
+    async fn list_documents(
+        &self,
+        req: Request<ListDocumentsRequest>,
+    ) -> Result<Response<ListDocumentsResponse>, Status> {
+        let workspace = self.workspace(&req)?;
+        let mut req = req.into_inner();
+        self.scope(workspace, &mut req.collection)?;
+        let page_size = match req.page_size as usize {
+            0 => DEFAULT_PAGE_SIZE,
+            n => n.min(MAX_PAGE_SIZE),
+        };
+        let collections = self.collections.read().unwrap();
+        let (documents, more) = collections
+            .get(&req.collection)?
+            .index
+            .list_documents(&req.page_token, page_size);
+        let next_page_token = match documents.last() {
+            Some(last) if more => last.id.clone(),
+            _ => String::new(),
+        };
+        let documents = documents
+            .into_iter()
+            .map(|d| DocumentSummary {
+                id: d.id,
+                preview: d.preview,
+                size_bytes: d.size_bytes,
+                chunks: d.chunks,
+                source: d.source,
+                indexed_at: timestamp(d.indexed_at),
+                version: d.version,
+            })
+            .collect();
+        Ok(Response::new(ListDocumentsResponse {
+            documents,
+            next_page_token,
+        }))
+    }
+
+    async fn count(&self, req: Request<CountRequest>) -> Result<Response<CountResponse>, Status> {
+        let workspace = self.workspace(&req)?;
+        let mut req = req.into_inner();
+        self.scope(workspace, &mut req.collection)?;
+        let filter = Filter::parse(&req.filter).map_err(Status::invalid_argument)?;
+        let collections = self.collections.read().unwrap();
+        let (count, documents) = collections.get(&req.collection)?.index.count(&filter);
+        Ok(Response::new(CountResponse {
+            count: count as u64,
+            documents: documents as u64,
+        }))
+    }
+
+    async fn exists(
+        &self,
+        req: Request<ExistsRequest>,
+    ) -> Result<Response<ExistsResponse>, Status> {
+        let workspace = self.workspace(&req)?;
+        let ExistsRequest { id, mut collection } = req.into_inner();
+        self.scope(workspace, &mut collection)?;
+        let collections = self.collections.read().unwrap();
+        let exists = collections.get(&collection)?.index.contains(&id);
+        Ok(Response::new(ExistsResponse { exists }))
+    }
+
+    async fn stats(&self, req: Request<StatsRequest>) -> Result<Response<StatsResponse>, Status> {
+        let workspace = self.workspace(&req)?;
+        let named = req.into_inner().collection;
+        if !named.is_empty() {
+            self.allow(workspace, &named)?;
+        }
+        let collections = self.collections.read().unwrap();
+        let chosen: Vec<(&str, &Collection)> = if named.is_empty() {
+            collections
+                .list()
+                .filter(|(name, _)| workspace.is_none_or(|w| w.covers(name, &collections)))
+                .map(|(name, c)| (name.as_str(), c))
+                .collect()
+        } else {
+            vec![(collections.resolve(&named), collections.get(&named)?)]
+        };
+        let reindexing = self.reindexing.lock().unwrap().clone();
+        let mut reply = StatsResponse::default();
+        for (name, collection) in chosen {
+            let stats = collection.index.stats();
+            reply.entries += stats.entries as u64;
+            reply.documents += stats.documents as u64;
+            reply.text_bytes += stats.text_bytes;
+            reply.disk_bytes += stats.disk_bytes;
+            reply.collections.push(CollectionStats {
+                name: name.to_string(),
+                entries: stats.entries as u64,
+                documents: stats.documents as u64,
+                text_bytes: stats.text_bytes,
+                dimensions: stats.dim as u32,
+                disk_bytes: stats.disk_bytes,
+                saved_at: timestamp(stats.saved_at),
+                pending_writes: stats.pending_writes as u64,
+                search_graph: stats.graph,
+                code_bytes: stats.code_bytes as u64,
+                reindex_embedder: reindexing
+                    .get(name)
+                    .map(|r| r.embedder.clone())
+                    .unwrap_or_default(),
+                reindexed: reindexing.get(name).map_or(0, |r| r.done),
+            });
+        }
+        Ok(Response::new(reply))
+    }
+
+    async fn explain_query(
+        &self,
+        req: Request<QueryRequest>,
+    ) -> Result<Response<ExplainResponse>, Status> {
+        let client = client(&req);
+        let workspace = self.workspace(&req)?;
+        let mut req = req.into_inner();
+        self.scope(workspace, &mut req.collection)?;
+        req.query = self.normalized(&req.collection, req.query);
+        let mut options = query_options(&req).map_err(Status::invalid_argument)?;
+        let embedder = self.embed_query(&client, &req, &mut options).await?;
+        let collections = self.collections.read().unwrap();
+        let index = &readable(&collections, &req)?.index;
+        if !embedded_with(index, Some(&embedder)) {
+            options.vector = None;
+        }
+        let explanation = index.explain(&req.query, &options);
+        let hits = explanation
+            .hits
+            .into_iter()
+            .map(|h| ExplainedHit {
+                id: h.id,
+                score: h.score,
+                vector_score: h.vector_score,
+                keyword_score: h.keyword_score,
+                matched_terms: h.matched_terms,
+            })
+            .collect();
+        Ok(Response::new(ExplainResponse {
+            terms: explanation.terms,
+            query_vector: explanation.query_vector,
+            candidates: explanation.candidates as u32,
+            hits,
+        }))
+    }
+
+    async fn create_collection(
+        &self,
+        req: Request<CreateCollectionRequest>,
+    ) -> Result<Response<CollectionInfo>, Status> {
+        let workspace = self.workspace(&req)?;
+        let req = req.into_inner();
+        self.allow(workspace, &req.name)?;
+        let mut config = CollectionConfig::new(&req.embedder, &req.metric, &req.quantization)
+            .map_err(CollectionError::InvalidConfig)?;
+        config.chunking = ChunkParams::new(
+            req.chunk_size as usize,
+            req.chunk_overlap.map(|n| n as usize),
+        )
+        .map_err(CollectionError::InvalidConfig)?;
+        config.hnsw = HnswParams::new(
+            req.hnsw_m as usize,
+            req.hnsw_ef_construction as usize,
+            req.hnsw_ef_search as usize,
+        )
+        .map_err(CollectionError::InvalidConfig)?;
+        config.privacy = Privacy::pick(&req.privacy).map_err(CollectionError::InvalidConfig)?;
+        config.dedup =
+            collection::dedup_policy(&req.dedup).map_err(CollectionError::InvalidConfig)?;
+        config.normalize =
+            Normalize::parse(&req.normalize).map_err(CollectionError::InvalidConfig)?;
+        config.storage =
+            collection::storage(&req.storage).map_err(CollectionError::InvalidConfig)?;
+        let mut collections = self.collections.write().unwrap();
+        collections.create(&req.name, config)?;
+        let info = collection_info(&collections, &req.name, collections.get(&req.name)?);
+        Ok(Response::new(info))
+    }
+
+    async fn drop_collection(
+        &self,
+        req: Request<DropCollectionRequest>,
+    ) -> Result<Response<DropCollectionResponse>, Status> {
+        let workspace = self.workspace(&req)?;
+        let name = req.into_inner().name;
+        self.allow(workspace, &name)?;
+        self.collections.write().unwrap().drop_collection(&name)?;
+        Ok(Response::new(DropCollectionResponse {}))
+    }
+
+    async fn reindex(
+        &self,
+        req: Request<ReindexRequest>,
+    ) -> Result<Response<ReindexResponse>, Status> {
+        let client = client(&req);
+        let workspace = self.workspace(&req)?;
+        let mut req = req.into_inner();
+        self.scope(workspace, &mut req.collection)?;
+        let (mut job, entries) = {
+            let collections = self.collections.read().unwrap();
+            let collection = collections.get(&req.collection)?;
+            let job = Reindex {
+                collections: Arc::clone(&self.collections),
+                embeds: Arc::clone(&self.embeds),
+                reindexing: Arc::clone(&self.reindexing),
+                client,
+                name: collections.resolve(&req.collection).to_string(),
+                embedder: collections.embedder(&req.embedder)?,
+                previous: Arc::clone(collection.index.embedder()),
+                claimed: false,
+            };
+            (job, collection.index.len())
+        };
+        job.claim()?;
+        let mut reply = ReindexResponse {
+            collection: job.name.clone(),
+            embedder: job.embedder.name().to_string(),
+            entries: entries as u64,
+            ..Default::default()
+        };
+        if req.background {
+            tokio::spawn(async move {
+                if let Err(e) = job.run().await {
+                    log::error!("reindexing {} failed: {e}", job.name);
+                }
+            });
+            return Ok(Response::new(reply));
+        }
+        let (entries, token) = job.run().await?;
+        reply.entries = entries as u64;
+        reply.done = true;
+        reply.write_token = token.to_string();
+        Ok(Response::new(reply))
+    }
+
+    async fn embed(
+        &self,
+        req: Request<tonic::Streaming<EmbedRequest>>,
+    ) -> Result<Response<Self::EmbedStream>, Status> {
+        let client = client(&req);
+        let mut inbound = req.into_inner();
+        let Some(first) = inbound.message().await? else {
+            return Ok(Response::new(Box::pin(futures_util::stream::empty())));
+        };
+        let embedder = self.collections.read().unwrap().embedder(&first.embedder)?;
+        let pooling = match first.pooling.as_str() {
+            "" => embedder.poolings().first().map_or("", |p| p.name()),
+            name => name,
+        }
+        .to_string();
+        let embedder = embed::pooled(embedder, &first.pooling).map_err(Status::invalid_argument)?;
+        let normalize = first.normalize;
+        let embeds = Arc::clone(&self.embeds);
+        let output = async_stream::try_stream! {
+            // Texts are submitted in batches as each message arrives, and
+            // answered in order. Messages stop being read while
+            // EMBED_WINDOW texts are unanswered; answers stop being made
+            // while the client is not reading them.
+            let mut in_flight = FuturesOrdered::new();
+            let (mut unanswered, mut offset, mut closed) = (0, 0u64, false);
+            let mut total_tokens = 0;
+            let mut received = Some(first.texts);
+            loop {
+                if let Some(texts) = received.take() {
+                    unanswered += texts.len();
+                    let mut texts = texts.into_iter().peekable();
+                    while texts.peek().is_some() {
+                        let batch: Vec<String> = texts.by_ref().take(EMBED_BATCH).collect();
+                        let (embeds, embedder, client) =
+                            (Arc::clone(&embeds), Arc::clone(&embedder), client.clone());
+                        in_flight.push_back(async move {
+                            let (tokens, truncated) = embed_usage(embedder.as_ref(), &batch);
+                            let started = Instant::now();
+                            let mut vectors =
+                                embeds.embed(&client, Priority::Bulk, embedder, batch).await?;
+                            if normalize {
+                                vectors.iter_mut().for_each(|v| embed::normalize(v));
+                            }
+                            Ok(EmbedBatch {
+                                vectors,
+                                tokens,
+                                truncated,
+                                wall: started.elapsed(),
+                            })
+                        });
+                    }
+                }
+                let event = tokio::select! {
+                    message = inbound.message(), if !closed && unanswered < EMBED_WINDOW => {
+                        EmbedEvent::Received(message)
+                    }
+                    Some(vectors) = in_flight.next(), if !in_flight.is_empty() => {
+                        EmbedEvent::Embedded(vectors)
+                    }
+                    else => break,
+                };
+                match event {
+                    EmbedEvent::Received(message) => match message? {
+                        Some(message) => received = Some(message.texts),
+                        None => closed = true,
+                    },
+                    EmbedEvent::Embedded(batch) => {
+                        let batch = batch?;
+                        unanswered -= batch.vectors.len();
+                        let answered = batch.vectors.len() as u64;
+                        total_tokens += batch.tokens;
+                        yield EmbedResponse {
+                            offset,
+                            embeddings: batch
+                                .vectors
+                                .into_iter()
+                                .map(|values| Embedding { values })
+                                .collect(),
+                            embedder: embedder.name().to_string(),
+                            dimensions: embedder.dim() as u32,
+                            tokens: batch.tokens,
+                            truncated: batch.truncated,
+                            wall_ms: batch.wall.as_millis() as u64,
+                            total_tokens,
+                            normalized: normalize,
+                            pooling: pooling.clone(),
+                        };
+                        offset += answered;
+                    }
+                }
+            }
+        };
+        Ok(Response::new(Box::pin(output)))
+    }
+
+    async fn list_embedding_models(
+        &self,
+        req: Request<ListEmbeddingModelsRequest>,
+    ) -> Result<Response<ListEmbeddingModelsResponse>, Status> {
+        let workspace = self.workspace(&req)?;
+        let collections = self.collections.read().unwrap();
+        let embedders = collections.embedders();
+        let default = embedders.default_name();
+        let info = |embedder: &Arc<dyn Embedder>| EmbedderInfo {
+            name: embedder.name().to_string(),
+            dimensions: embedder.dim() as u32,
+            fingerprint: embedder.fingerprint(),
+            model: HashEmbedder::parse(embedder.name()).is_none(),
+            default: embedder.name() == default,
+            collections: Vec::new(),
+            poolings: embedder
+                .poolings()
+                .iter()
+                .map(|p| p.name().to_string())
+                .collect(),
+        };
+        let mut listed: BTreeMap<String, EmbedderInfo> = embedders
+            .models()
+            .chain(embedders.get(default).as_ref())
+            .map(|embedder| (embedder.name().to_string(), info(embedder)))
+            .collect();
+        for (name, c) in collections.list() {
+            if workspace.is_some_and(|w| !w.covers(name, &collections)) {
+                continue;
+            }
+            let embedder = c.index.embedder();
+            listed
+                .entry(embedder.name().to_string())
+                .or_insert_with(|| info(embedder))
+                .collections
+                .push(name.clone());
+        }
+        Ok(Response::new(ListEmbeddingModelsResponse {
+            embedders: listed.into_values().collect(),
+            default_embedder: default.to_string(),
+            hash_min_dimensions: *HashEmbedder::DIMS.start() as u32,
+            hash_max_dimensions: *HashEmbedder::DIMS.end() as u32,
+        }))
+    }
+
+    async fn list_collections(
+        &self,
+        req: Request<ListCollectionsRequest>,
+    ) -> Result<Response<ListCollectionsResponse>, Status> {
+        let workspace = self.workspace(&req)?;
+        let collections = self.collections.read().unwrap();
+        let collections = collections
+            .list()
+            .filter(|(name, _)| workspace.is_none_or(|w| w.covers(name, &collections)))
+            .map(|(name, c)| collection_info(&collections, name, c))
+            .collect();
+        Ok(Response::new(ListCollectionsResponse { collections }))
+    }
+
+    async fn set_alias(
+        &self,
+        req: Request<SetAliasRequest>,
+    ) -> Result<Response<SetAliasResponse>, Status> {
+        let workspace = self.workspace(&req)?;
+        let mut req = req.into_inner();
+        self.scope(workspace, &mut req.collection)?;
+        self.allow(workspace, &req.alias)?;
+        let previous = self
+            .collections
+            .write()
+            .unwrap()
+            .set_alias(&req.alias, &req.collection)?;
+        Ok(Response::new(SetAliasResponse {
+            previous: previous.unwrap_or_default(),
+        }))
+    }
+
+    async fn delete_alias(
+        &self,
+        req: Request<DeleteAliasRequest>,
+    ) -> Result<Response<DeleteAliasResponse>, Status> {
+        let workspace = self.workspace(&req)?;
+        let alias = req.into_inner().alias;
+        self.allow(workspace, &alias)?;
+        self.collections.write().unwrap().remove_alias(&alias)?;
+        Ok(Response::new(DeleteAliasResponse {}))
+    }
+
+    async fn snapshot(
+        &self,
+        req: Request<SnapshotRequest>,
+    ) -> Result<Response<SnapshotResponse>, Status> {
+        whole_index(self.workspace(&req)?)?;
+        let path = req.into_inner().path;
+        let path = snapshot_path(&path)?;
+        let info = self.collections.read().unwrap().snapshot(path)?;
+        log::info!(
+            "snapshot of {} collections ({} entries) written to {}",
+            info.collections,
+            info.entries,
+            path.display()
+        );
+        Ok(Response::new(SnapshotResponse {
+            collections: info.collections as u32,
+            entries: info.entries as u64,
+        }))
+    }
+
+    async fn restore(
+        &self,
+        req: Request<RestoreRequest>,
+    ) -> Result<Response<RestoreResponse>, Status> {
+        whole_index(self.workspace(&req)?)?;
+        let path = req.into_inner().path;
+        let path = snapshot_path(&path)?;
+        let info = self.collections.write().unwrap().restore(path)?;
+        log::info!(
+            "restored {} collections ({} entries) from {}",
+            info.collections,
+            info.entries,
+            path.display()
+        );
+        Ok(Response::new(RestoreResponse {
+            collections: info.collections as u32,
+            entries: info.entries as u64,
+        }))
+    }
+
+    async fn create_snapshot(
+        &self,
+        req: Request<CreateSnapshotRequest>,
+    ) -> Result<Response<CollectionSnapshot>, Status> {
+        let workspace = self.workspace(&req)?;
+        let mut name = req.into_inner().collection;
+        self.scope(workspace, &mut name)?;
+        let snapshot = self.collections.read().unwrap().create_snapshot(&name)?;
+        log::info!(
+            "snapshot {} of {} entries created",
+            snapshot.id,
+            snapshot.entries
+        );
+        Ok(Response::new(collection_snapshot(snapshot)))
+    }
+
+    async fn list_snapshots(
+        &self,
+        req: Request<ListSnapshotsRequest>,
+    ) -> Result<Response<ListSnapshotsResponse>, Status> {
+        let workspace = self.workspace(&req)?;
+        let mut name = req.into_inner().collection;
+        self.scope(workspace, &mut name)?;
+        let snapshots = self.collections.read().unwrap().snapshots(&name)?;
+        let snapshots = snapshots.into_iter().map(collection_snapshot).collect();
+        Ok(Response::new(ListSnapshotsResponse { snapshots }))
+    }
+
+    async fn delete_snapshot(
+        &self,
+        req: Request<DeleteSnapshotRequest>,
+    ) -> Result<Response<DeleteSnapshotResponse>, Status> {
+        let workspace = self.workspace(&req)?;
+        let id = req.into_inner().id;
+        self.allow(workspace, &snapshot_collection(&id))?;
+        self.collections.read().unwrap().delete_snapshot(&id)?;
+        self.snapshots.lock().unwrap().remove(&id);
+        Ok(Response::new(DeleteSnapshotResponse {}))
+    }
+
+    async fn query_at(
+        &self,
+        req: Request<QueryAtRequest>,
+    ) -> Result<Response<QueryResponse>, Status> {
+        let client = client(&req);
+        let workspace = self.workspace(&req)?;
+        let QueryAtRequest { snapshot_id, query } = req.into_inner();
+        self.allow(workspace, &snapshot_collection(&snapshot_id))?;
+        let mut req = query.unwrap_or_default();
+        if !req.cursor.is_empty() {
+            return Ok(Response::new(self.resume(&req)?));
+        }
+        // A snapshot is normalized like its collection, if it still exists.
+        req.query = self.normalized(&snapshot_collection(&snapshot_id), req.query);
+        let mut options = query_options(&req).map_err(Status::invalid_argument)?;
+        options.k = ranked(&req).map_err(Status::invalid_argument)?;
+        let adaptive = adaptive(&req).map_err(Status::invalid_argument)?;
+        if req.rerank && self.reranker.is_none() {
+            return Err(no_reranker().into());
+        }
+        let started = Instant::now();
+        let index = self
+            .open_snapshot(&snapshot_id)
+            .await
+            .map_err(|e| match e {
+                CollectionError::Io(e) => {
+                    Status::internal(format!("reading snapshot {snapshot_id} failed: {e}"))
+                }
+                e => e.into(),
+            })?;
+        let embedder = index.embedder();
+        if !req.embedder.is_empty() && req.embedder != embedder.name() {
+            return Err(Status::failed_precondition(format!(
+                "snapshot {snapshot_id} is embedded with {}, not {}",
+                embedder.name(),
+                req.embedder
+            )));
+        }
+        if options.mode != Mode::Keyword {
+            let texts = vec![req.query.clone()];
+            let vectors = self
+                .embeds
+                .embed(&client, Priority::Interactive, Arc::clone(embedder), texts)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+            options.vector = vectors.into_iter().next();
+        }
+        let hits = index.query(&req.query, &options);
+        let (hits, adaptive) = self.finish(&client, &req, adaptive, hits).await?;
+        // A snapshot follows its collection's level, or the global one once
+        // the collection is dropped.
+        let collection = snapshot_collection(&snapshot_id);
+        let privacy = match self.collections.read().unwrap().get(&collection) {
+            Ok(collection) => Privacy::resolve(&collection.config.privacy),
+            Err(_) => Privacy::global(),
+        };
+        log_query(privacy, &snapshot_id, &req, &hits, started);
+        Ok(Response::new(self.respond(hits, &req, adaptive)))
+    }
+}
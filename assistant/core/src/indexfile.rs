@@ -0,0 +1,449 @@
+//! On-disk format of a [`VectorIndex`](crate::index::VectorIndex):
+//!
+//! ```text
+//! magic    8 bytes   "MAHIIDX\0"
+//! version  u32       FORMAT_VERSION
+//! dim      u32       floats per embedding
+//! count    u64       entries
+//! len      u64       bytes of the records that follow
+//! name_len u32       bytes of the embedder name (version 2 on)
+//! embedder name_len  UTF-8 name of the embedder that made the vectors
+//! fp_len   u32       bytes of the embedder's fingerprint (version 6 on)
+//! fp       fp_len    UTF-8 fingerprint of the embedder
+//! texts_len u64      bytes of the texts section (version 7 on)
+//! records  len bytes JSON array of the entries without their embeddings
+//!                    (and, from version 7, without their texts)
+//! texts    texts_len bytes (version 7 on):
+//!   dict_len u32     bytes of the zstd dictionary; 0 for none
+//!   dict     dict_len the dictionary the texts were compressed with
+//!   then per entry, in entry order:
+//!   text_len u64     bytes of the text
+//!   frame_len u32    bytes of its zstd frame
+//!   frame    frame_len
+//! padding  0-3 zero bytes, up to a multiple of 4 (version 4 on)
+//! vectors  count * dim little-endian f32, in entry order
+//! codes    compact copies of the vectors, if the collection quantizes
+//!          them (version 5 on; see `Codes::write`)
+//! ```
+//!
+//! Integers are little-endian. Embeddings make up most of an index, so
+//! they are stored raw rather than as JSON numbers, and [`read`] maps
+//! them rather than reading them in (see [`Vectors`]). Version 1 files
+//! have no embedder name; they were all made by `hash-256`. Versions 1 and
+//! 2 hold L2-normalized embeddings, which lost their lengths; version 3
+//! has the same layout with embeddings as the embedder made them. Version
+//! 4 aligns the vectors so they can be used in place. Version 5 adds the
+//! codes, so they need not be trained again on every open. Version 6 adds
+//! the embedder's fingerprint, which tells a model replaced under the same
+//! name; earlier files have none. Version 7 stores the entries' texts
+//! compressed, apart from the records (see [`compress`](crate::compress)),
+//! and [`read`] leaves them compressed.
+
+use crate::compress::{Dictionary, Packer, StoredText};
+use crate::embed::{Embedder, HashEmbedder};
+use crate::index::Doc;
+use crate::quantize::Codes;
+use crate::vectors::Vectors;
+use memmap2::Mmap;
+use serde::{Serialize, Serializer};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+const MAGIC: &[u8; 8] = b"MAHIIDX\0";
+/// Bumped whenever the layout changes; older versions stay readable.
+pub const FORMAT_VERSION: u32 = 7;
+const HEADER_LEN: usize = 8 + 4 + 4 + 8 + 8;
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// Entries as saved, with the embedder their vectors came from.
+pub struct Contents {
+    pub embedder: String,
+    /// See [`Embedder::fingerprint`]; empty before version 6.
+    pub fingerprint: String,
+    /// Whether the embeddings were L2-normalized when saved, as before
+    /// version 3.
+    pub normalized: bool,
+    /// The entries, with empty `embedding`s and, from version 7, their
+    /// texts compressed.
+    pub docs: Vec<Doc>,
+    /// Their embeddings, in the same order.
+    pub vectors: Vectors,
+    /// Compact copies of the embeddings, if saved with any.
+    pub codes: Option<Codes>,
+    /// Format version of the file; 0 for JSON indexes.
+    pub version: u32,
+}
+
+/// Entries as saved in the records: without their texts.
+struct Records<'a>(&'a [Doc]);
+
+impl Serialize for Records<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().map(|doc| Doc {
+            text: StoredText::default(),
+            ..doc.clone()
+        }))
+    }
+}
+
+/// The texts section for `docs`. Texts are compressed with the dictionary
+/// some of them already were, so saving again only compresses new texts;
+/// an index without one gets one trained once it is large enough.
+fn texts(docs: &[Doc]) -> io::Result<Vec<u8>> {
+    let dictionary = docs
+        .iter()
+        .find_map(|doc| doc.text.dictionary())
+        .cloned()
+        .or_else(|| Dictionary::train(&docs.iter().map(|doc| &doc.text).collect::<Vec<_>>()));
+    let mut out = Vec::new();
+    let raw = dictionary.as_ref().map_or(&[][..], |d| d.bytes());
+    out.extend_from_slice(&(raw.len() as u32).to_le_bytes());
+    out.extend_from_slice(raw);
+    let mut packer = Packer::new(dictionary.as_ref())?;
+    for doc in docs {
+        let frame = packer.frame(&doc.text)?;
+        out.extend_from_slice(&(doc.text.len() as u64).to_le_bytes());
+        out.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        out.extend_from_slice(&frame);
+    }
+    Ok(out)
+}
+
+/// Writes `docs`, whose embeddings are `vectors` and their `codes`, in
+/// the current format.
+pub fn encode(
+    out: &mut impl Write,
+    docs: &[Doc],
+    vectors: &Vectors,
+    codes: Option<&Codes>,
+    embedder: &dyn Embedder,
+) -> io::Result<()> {
+    if vectors.len() != docs.len() {
+        return Err(invalid(format!(
+            "{} entries but {} embeddings",
+            docs.len(),
+            vectors.len()
+        )));
+    }
+    let records = serde_json::to_vec(&Records(docs))?;
+    let texts = texts(docs)?;
+    let (name, fingerprint) = (embedder.name(), embedder.fingerprint());
+    out.write_all(MAGIC)?;
+    out.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    out.write_all(&(vectors.dim() as u32).to_le_bytes())?;
+    out.write_all(&(docs.len() as u64).to_le_bytes())?;
+    out.write_all(&(records.len() as u64).to_le_bytes())?;
+    for field in [name, &fingerprint] {
+        out.write_all(&(field.len() as u32).to_le_bytes())?;
+        out.write_all(field.as_bytes())?;
+    }
+    out.write_all(&(texts.len() as u64).to_le_bytes())?;
+    out.write_all(&records)?;
+    out.write_all(&texts)?;
+    let written = HEADER_LEN + 8 + name.len() + fingerprint.len() + 8 + records.len() + texts.len();
+    out.write_all(&[0; 3][..written.next_multiple_of(4) - written])?;
+    for at in 0..vectors.len() {
+        for x in vectors.get(at) {
+            out.write_all(&x.to_le_bytes())?;
+        }
+    }
+    match codes {
+        Some(codes) => codes.write(out),
+        None => Codes::write_none(out),
+    }
+}
+
+/// What the start of an index file says about the rest.
+pub struct Header {
+    /// Name of the embedder that made the vectors.
+    pub embedder: String,
+    /// See [`Contents::fingerprint`].
+    pub fingerprint: String,
+    /// See [`Contents::normalized`].
+    pub normalized: bool,
+    pub dim: usize,
+    /// Entries in the file.
+    pub count: usize,
+    /// Format version of the file.
+    pub version: u32,
+    records_len: usize,
+    /// Offset of the records.
+    records_at: usize,
+    /// Bytes of the texts after the records; 0 before version 7.
+    texts_len: usize,
+    /// Whether the vectors are aligned after the records.
+    padded: bool,
+}
+
+impl Header {
+    /// Offset of the vectors.
+    fn vectors_at(&self) -> usize {
+        let end = self
+            .records_at
+            .saturating_add(self.records_len)
+            .saturating_add(self.texts_len);
+        if self.padded {
+            end.next_multiple_of(4)
+        } else {
+            end
+        }
+    }
+
+    /// Offset of the end of the vectors.
+    fn vectors_end(&self) -> io::Result<usize> {
+        self.count
+            .checked_mul(self.dim * 4)
+            .and_then(|len| len.checked_add(self.vectors_at()))
+            .ok_or_else(|| invalid("index file is truncated"))
+    }
+}
+
+/// Parses the header at the start of `data`, which may stop after it.
+fn parse_header(data: &[u8]) -> io::Result<Header> {
+    if !data.starts_with(MAGIC) || data.len() < HEADER_LEN {
+        return Err(invalid("not an index file"));
+    }
+    let u32_at = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+    let u64_at = |at: usize| u64::from_le_bytes(data[at..at + 8].try_into().unwrap());
+    let version = u32_at(8);
+    // A length-prefixed string at `at`, and the offset after it.
+    let string_at = |at: usize| -> io::Result<(String, usize)> {
+        let truncated = || invalid("index file is truncated");
+        let len = data.get(at..at + 4).ok_or_else(truncated)?;
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        let bytes = data.get(at + 4..at + 4 + len).ok_or_else(truncated)?;
+        let string = std::str::from_utf8(bytes).map_err(|e| invalid(e.to_string()))?;
+        Ok((string.to_string(), at + 4 + len))
+    };
+    let (embedder, fingerprint, texts_len, records_at) = match version {
+        1 => (
+            HashEmbedder::DEFAULT.to_string(),
+            String::new(),
+            0,
+            HEADER_LEN,
+        ),
+        2..=5 => {
+            let (name, end) = string_at(HEADER_LEN)?;
+            (name, String::new(), 0, end)
+        }
+        6 => {
+            let (name, end) = string_at(HEADER_LEN)?;
+            let (fingerprint, end) = string_at(end)?;
+            (name, fingerprint, 0, end)
+        }
+        FORMAT_VERSION => {
+            let (name, end) = string_at(HEADER_LEN)?;
+            let (fingerprint, end) = string_at(end)?;
+            let texts_len = data
+                .get(end..end + 8)
+                .ok_or_else(|| invalid("index file is truncated"))?;
+            let texts_len = u64::from_le_bytes(texts_len.try_into().unwrap()) as usize;
+            (name, fingerprint, texts_len, end + 8)
+        }
+        _ => {
+            return Err(invalid(format!(
+                "unsupported index format version {version}"
+            )))
+        }
+    };
+    Ok(Header {
+        embedder,
+        fingerprint,
+        normalized: version < 3,
+        dim: u32_at(12) as usize,
+        count: u64_at(16) as usize,
+        records_len: u64_at(24) as usize,
+        records_at,
+        texts_len,
+        padded: version >= 4,
+        version,
+    })
+}
+
+/// Reads just the header of the index file at `path`, for listings that
+/// do not need the entries.
+pub fn read_header(path: &Path) -> io::Result<Header> {
+    let mut file = File::open(path)?;
+    let mut data = vec![0; HEADER_LEN + 4];
+    let read = file.read(&mut data)?;
+    data.truncate(read);
+    let version = data
+        .get(8..12)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()));
+    let name_len = data.get(HEADER_LEN..).unwrap_or_default().try_into();
+    if let (Some(2..=FORMAT_VERSION), Ok(name_len)) = (version, name_len) {
+        let mut name = vec![0; u32::from_le_bytes(name_len) as usize];
+        file.read_exact(&mut name)
+            .map_err(|_| invalid("index file is truncated"))?;
+        data.extend_from_slice(&name);
+        if version >= Some(6) {
+            let mut len = [0; 4];
+            file.read_exact(&mut len)
+                .map_err(|_| invalid("index file is truncated"))?;
+            let mut fingerprint = vec![0; u32::from_le_bytes(len) as usize];
+            file.read_exact(&mut fingerprint)
+                .map_err(|_| invalid("index file is truncated"))?;
+            data.extend_from_slice(&len);
+            data.extend_from_slice(&fingerprint);
+        }
+        if version == Some(FORMAT_VERSION) {
+            let mut texts_len = [0; 8];
+            file.read_exact(&mut texts_len)
+                .map_err(|_| invalid("index file is truncated"))?;
+            data.extend_from_slice(&texts_len);
+        }
+    }
+    parse_header(&data)
+}
+
+/// The entries of the index file at `path`, with its embeddings mapped
+/// rather than read in.
+pub fn read(path: &Path) -> io::Result<Contents> {
+    let map = map(path)?;
+    let header = parse_header(&map)?;
+    // The records are only parsed from the map; once they are, their pages
+    // can be dropped. Texts are copied out still compressed.
+    let mut docs = records(&map, &header)?;
+    if header.version >= 7 {
+        for (doc, text) in docs.iter_mut().zip(texts_of(&map, &header)?) {
+            doc.text = text;
+        }
+    }
+    let codes = codes(&map, &header)?;
+    let vectors = Vectors::mapped(map, header.vectors_at(), header.count, header.dim)?;
+    Ok(Contents {
+        embedder: header.embedder,
+        fingerprint: header.fingerprint,
+        normalized: header.normalized,
+        docs,
+        vectors,
+        codes,
+        version: header.version,
+    })
+}
+
+/// Just the texts of the version 7 index file at `path`, compressed.
+pub fn read_texts(path: &Path) -> io::Result<Vec<StoredText>> {
+    let map = map(path)?;
+    let header = parse_header(&map)?;
+    texts_of(&map, &header)
+}
+
+/// The texts section of `data`, one text per entry.
+fn texts_of(data: &[u8], header: &Header) -> io::Result<Vec<StoredText>> {
+    let start = header.records_at.saturating_add(header.records_len);
+    let mut rest = start
+        .checked_add(header.texts_len)
+        .and_then(|end| data.get(start..end))
+        .ok_or_else(|| invalid("index file is truncated"))?;
+    let dictionary = match u32_le(take(&mut rest, 4)?) {
+        0 => None,
+        len => Some(Arc::new(Dictionary::new(
+            take(&mut rest, len as usize)?.to_vec(),
+        ))),
+    };
+    let mut texts = Vec::with_capacity(header.count);
+    for _ in 0..header.count {
+        let len = u64::from_le_bytes(take(&mut rest, 8)?.try_into().unwrap()) as usize;
+        let frame_len = u32_le(take(&mut rest, 4)?) as usize;
+        let frame = take(&mut rest, frame_len)?;
+        texts.push(StoredText::packed(frame, len, dictionary.as_ref()));
+    }
+    if !rest.is_empty() {
+        return Err(invalid("index file has more texts than entries"));
+    }
+    Ok(texts)
+}
+
+/// The first `n` bytes of `rest`, which is left with the others.
+fn take<'a>(rest: &mut &'a [u8], n: usize) -> io::Result<&'a [u8]> {
+    if rest.len() < n {
+        return Err(invalid("index file is truncated"));
+    }
+    let (head, tail) = rest.split_at(n);
+    *rest = tail;
+    Ok(head)
+}
+
+fn u32_le(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes.try_into().unwrap())
+}
+
+/// Just the embeddings of the index file at `path`, mapped.
+pub fn read_vectors(path: &Path) -> io::Result<Vectors> {
+    let map = map(path)?;
+    let header = parse_header(&map)?;
+    Vectors::mapped(map, header.vectors_at(), header.count, header.dim)
+}
+
+/// The codes after the vectors in `data`, which must end with them;
+/// files before version 5 end with the vectors.
+fn codes(data: &[u8], header: &Header) -> io::Result<Option<Codes>> {
+    let end = header.vectors_end()?;
+    if header.version < 5 {
+        return match data.len() == end {
+            true => Ok(None),
+            false => Err(invalid("index file is truncated")),
+        };
+    }
+    let section = data
+        .get(end..)
+        .ok_or_else(|| invalid("index file is truncated"))?;
+    Codes::read(section, header.count, header.dim)
+}
+
+fn map(path: &Path) -> io::Result<Mmap> {
+    let file = File::open(path)?;
+    // SAFETY: index files are never changed in place, only replaced by
+    // renaming a new file over them (see `write`), which leaves a map of
+    // the old one as it was.
+    unsafe { Mmap::map(&file) }
+}
+
+/// The entries listed in `data`, checked against the header's count.
+fn records(data: &[u8], header: &Header) -> io::Result<Vec<Doc>> {
+    let records = header
+        .records_at
+        .checked_add(header.records_len)
+        .and_then(|end| data.get(header.records_at..end))
+        .ok_or_else(|| invalid("index file is truncated"))?;
+    let docs: Vec<Doc> = serde_json::from_slice(records).map_err(|e| invalid(e.to_string()))?;
+    if docs.len() != header.count {
+        return Err(invalid(format!(
+            "index file lists {} entries, not {}",
+            docs.len(),
+            header.count
+        )));
+    }
+    Ok(docs)
+}
+
+/// Writes via a synced temporary file and a rename, so a crash leaves
+/// either the old or the new index. The folder is synced too, so the
+/// rename has reached the disk once this returns and the log of writes it
+/// saves may be cleared.
+pub fn write(
+    path: &Path,
+    docs: &[Doc],
+    vectors: &Vectors,
+    codes: Option<&Codes>,
+    embedder: &dyn Embedder,
+) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    std::fs::create_dir_all(dir)?;
+    let tmp = path.with_extension("idx.tmp");
+    let mut out = BufWriter::new(File::create(&tmp)?);
+    encode(&mut out, docs, vectors, codes, embedder)?;
+    let file = out.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    std::fs::rename(tmp, path)?;
+    File::open(dir)?.sync_all()
+}
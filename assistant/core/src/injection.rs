@@ -0,0 +1,240 @@
+//! Prompt-injection screening. Retrieved chunks and tool output come from
+//! web pages, email and the like, so they may carry instructions aimed at
+//! the model rather than the user ("ignore previous instructions", chat
+//! template markup, requests to send credentials). Text matching such a
+//! pattern is flagged, or has the match replaced, before it goes into the
+//! prompt, and each finding becomes a [`Warning`].
+//!
+//! The patterns catch the common phrasings only; a finding is a reason to
+//! distrust the text, and a clean scan is no guarantee.
+
+use crate::privacy::Privacy;
+use regex::Regex;
+use serde_json::{json, Value};
+use std::sync::LazyLock;
+
+/// Stands in for a neutralized match.
+pub const NEUTRALIZED: &str = "[possible prompt injection removed]";
+
+const PATTERNS: &[(&str, &str)] = &[
+    (
+        "ignore_instructions",
+        r"(?i)\b(ignore|disregard|forget|override|bypass)(\s+\w+){0,3}?\s+(previous|prior|above|earlier|preceding|original|system)\s+(instructions?|prompts?|rules|directions|guidelines)",
+    ),
+    (
+        "role_override",
+        r"(?i)\b(you are now|from now on,? you (are|will|must)|pretend (to be|you are)|act as an? (unrestricted|unfiltered|jailbroken)|developer mode|DAN mode)\b",
+    ),
+    (
+        "new_instructions",
+        r"(?i)\b(new|updated|real|actual|additional)\s+(system\s+)?instructions?\s*:",
+    ),
+    (
+        "prompt_leak",
+        r"(?i)\b(reveal|print|show|repeat|output|leak)(\s+\w+){0,2}?\s+(system prompt|hidden (prompt|instructions)|initial instructions)",
+    ),
+    (
+        "addresses_model",
+        r"(?i)\b((note|message|attention)\s+(to|for)\s+(the\s+)?(ai|assistant|llm|language model|chatbot)|if you are an? (ai|llm|language model|assistant))\b",
+    ),
+    (
+        "exfiltration",
+        r"(?i)\b(send|email|forward|post|upload)\b[^.\n]{0,60}\b(passwords?|api keys?|credentials|private keys?)\b",
+    ),
+    (
+        "chat_markup",
+        r"<\|im_start\|>|<\|im_end\|>|<\|system\|>|<\|start_header_id\|>|<\|eot_id\|>|\[/?INST\]|<</?SYS>>",
+    ),
+];
+
+static COMPILED: LazyLock<Vec<(&str, Regex)>> = LazyLock::new(|| {
+    PATTERNS
+        .iter()
+        .map(|(name, pattern)| (*name, Regex::new(pattern).expect("valid regex")))
+        .collect()
+});
+
+/// What happens to untrusted text matching a pattern.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Action {
+    /// No screening.
+    Off,
+    /// Warn, but leave the text as it is.
+    Flag,
+    /// Warn, and replace each match with [`NEUTRALIZED`].
+    #[default]
+    Neutralize,
+}
+
+impl Action {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(Action::Off),
+            "flag" => Some(Action::Flag),
+            "neutralize" => Some(Action::Neutralize),
+            _ => None,
+        }
+    }
+}
+
+/// Where a finding came from.
+#[derive(Clone, Debug)]
+pub enum Source {
+    /// A retrieved chunk, by position in the request's context.
+    Retrieved { chunk: usize },
+    /// A tool call's result, by step and position within it.
+    Tool {
+        tool: String,
+        step: usize,
+        call: usize,
+    },
+}
+
+/// One piece of untrusted text that matched.
+#[derive(Clone, Debug)]
+pub struct Warning {
+    pub source: Source,
+    /// Names of the patterns it matched.
+    pub patterns: Vec<&'static str>,
+    pub neutralized: bool,
+}
+
+impl Warning {
+    pub fn to_json(&self) -> Value {
+        let mut out = json!({
+            "event": "prompt_injection",
+            "patterns": self.patterns,
+            "action": if self.neutralized { "neutralized" } else { "flagged" },
+        });
+        match &self.source {
+            Source::Retrieved { chunk } => {
+                out["source"] = json!("retrieved");
+                out["chunk"] = json!(chunk);
+            }
+            Source::Tool { tool, step, call } => {
+                out["source"] = json!("tool");
+                out["tool"] = json!(tool);
+                out["step"] = json!(step);
+                out["call"] = json!(call);
+            }
+        }
+        out
+    }
+
+    /// Logs the finding, without the text itself. The chunk's id is left
+    /// out below `log_full`.
+    pub fn log(&self) {
+        let source = match &self.source {
+            Source::Retrieved { chunk } => {
+                format!("retrieved chunk {}", Privacy::global().redact(chunk))
+            }
+            Source::Tool { tool, step, call } => {
+                format!("{tool} output (step {step}, call {call})")
+            }
+        };
+        log::warn!(
+            "possible prompt injection in {source}: {}",
+            self.patterns.join(", ")
+        );
+    }
+}
+
+/// Screens `text`, appending the patterns it matches to `found`. Returns
+/// the text to use instead when `action` neutralizes a match.
+fn screen(text: &str, action: Action, found: &mut Vec<&'static str>) -> Option<String> {
+    let mut replaced: Option<String> = None;
+    for (name, regex) in COMPILED.iter() {
+        let current = replaced.as_deref().unwrap_or(text);
+        if !regex.is_match(current) {
+            continue;
+        }
+        if !found.contains(name) {
+            found.push(name);
+        }
+        if action == Action::Neutralize {
+            replaced = Some(regex.replace_all(current, NEUTRALIZED).into_owned());
+        }
+    }
+    replaced
+}
+
+/// Screens each text, neutralizing matches in place if `action` says so.
+/// `source` names the text at a position.
+fn screen_texts<'a>(
+    texts: impl Iterator<Item = &'a mut String>,
+    action: Action,
+    source: impl Fn(usize) -> Source,
+) -> Vec<Warning> {
+    if action == Action::Off {
+        return Vec::new();
+    }
+    let mut warnings = Vec::new();
+    for (at, text) in texts.enumerate() {
+        let mut patterns = Vec::new();
+        if let Some(replaced) = screen(text, action, &mut patterns) {
+            *text = replaced;
+        }
+        if !patterns.is_empty() {
+            warnings.push(Warning {
+                source: source(at),
+                patterns,
+                neutralized: action == Action::Neutralize,
+            });
+        }
+    }
+    warnings
+}
+
+/// Screens each chunk, neutralizing matches in place if `action` says so.
+pub fn screen_chunks(chunks: &mut [String], action: Action) -> Vec<Warning> {
+    screen_texts(chunks.iter_mut(), action, |chunk| Source::Retrieved {
+        chunk,
+    })
+}
+
+/// Screens the output of each `(tool, output)` pair given with a chat
+/// turn, neutralizing matches in place if `action` says so. They count as
+/// the calls of step 0.
+pub fn screen_tool_results(results: &mut [(String, String)], action: Action) -> Vec<Warning> {
+    let tools: Vec<String> = results.iter().map(|(tool, _)| tool.clone()).collect();
+    screen_texts(
+        results.iter_mut().map(|(_, output)| output),
+        action,
+        |call| Source::Tool {
+            tool: tools[call].clone(),
+            step: 0,
+            call,
+        },
+    )
+}
+
+/// Screens every string in a JSON value, neutralizing matches in place if
+/// `action` says so. Returns the patterns matched.
+pub fn screen_value(value: &mut Value, action: Action) -> Vec<&'static str> {
+    let mut patterns = Vec::new();
+    if action != Action::Off {
+        walk(value, action, &mut patterns);
+    }
+    patterns
+}
+
+fn walk(value: &mut Value, action: Action, found: &mut Vec<&'static str>) {
+    match value {
+        Value::String(text) => {
+            if let Some(replaced) = screen(text, action, found) {
+                *text = replaced;
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                walk(item, action, found);
+            }
+        }
+        Value::Object(fields) => {
+            for item in fields.values_mut() {
+                walk(item, action, found);
+            }
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,57 @@
+//! Shared runtime pieces for the assistant core. Third-party connectors
+//! depend on this crate and implement [`connector::Connector`].
+
+pub mod assistant {
+    tonic::include_proto!("assistant");
+}
+
+pub mod archive;
+pub mod artifact;
+pub mod assemble;
+pub mod attach;
+pub mod backup;
+#[cfg(feature = "bert")]
+pub mod bert;
+pub mod bm25;
+pub mod chat;
+pub mod chunk;
+pub mod clipboard;
+pub mod collection;
+pub mod compress;
+pub mod connector;
+pub mod cursor;
+pub mod docid;
+pub mod embed;
+pub mod embedqueue;
+pub mod filter;
+pub mod git;
+pub mod hnsw;
+pub mod index;
+pub mod indexer;
+pub mod indexfile;
+pub mod injection;
+pub mod loader;
+pub mod logging;
+pub mod metric;
+pub mod normalize;
+pub mod patch;
+pub mod policy;
+pub mod postprocess;
+pub mod privacy;
+pub mod profile;
+pub mod quantize;
+pub mod rerank;
+pub mod route;
+pub mod rss;
+pub mod run;
+pub mod session;
+pub mod simd;
+pub mod store;
+pub mod table;
+pub mod template;
+pub mod text;
+pub mod upload;
+pub mod vectors;
+pub mod wal;
+pub mod watch;
+pub mod workspace;
@@ -0,0 +1,508 @@
+//! Turns files into text to index or attach. A file's media type is
+//! detected from its first bytes, so a PDF named `notes.txt` is still
+//! refused as a PDF. Text formats have no such signature; they are told
+//! apart by extension, and a file without a telling one by how its text
+//! starts. Each supported type has a loader, e.g. HTML loses its markup,
+//! an email keeps its main headers and readable body, and an Excel
+//! workbook becomes CSV text (see [`table`]). Other types are refused with an [`Unsupported`] error listing the supported ones.
+
+use crate::assistant::UnsupportedFormat;
+use crate::{docid, table};
+use prost::Message;
+use regex::{Captures, Regex};
+use std::io;
+use std::sync::LazyLock;
+use tonic::{Code, Status};
+
+/// Media types there is a loader for.
+pub const SUPPORTED: &[&str] = &[
+    "text/plain",
+    "text/markdown",
+    "text/csv",
+    table::XLSX,
+    "application/json",
+    "text/html",
+    "application/xml",
+    "message/rfc822",
+];
+
+/// Leading bytes of binary formats, none of which can be loaded.
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"%PDF-", "application/pdf"),
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"II*\0", "image/tiff"),
+    (b"MM\0*", "image/tiff"),
+    (b"OggS", "audio/ogg"),
+    (b"fLaC", "audio/flac"),
+    (b"ID3", "audio/mpeg"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (
+        b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1",
+        "application/x-ole-storage",
+    ),
+    (b"{\\rtf", "application/rtf"),
+    (b"SQLite format 3\0", "application/vnd.sqlite3"),
+    (b"\x7fELF", "application/x-executable"),
+];
+
+/// A file's text and the media type it was loaded as.
+#[derive(Clone, Debug)]
+pub struct Loaded {
+    pub mime_type: &'static str,
+    pub text: String,
+}
+
+/// A file of a type there is no loader for.
+#[derive(Clone, Debug)]
+pub struct Unsupported {
+    pub name: String,
+    /// What the file was detected as.
+    pub mime_type: &'static str,
+    /// Why a file of a supported type could not be read, such as a
+    /// damaged workbook.
+    pub reason: Option<String>,
+}
+
+impl std::fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(reason) = &self.reason {
+            return write!(
+                f,
+                "{} is {} but cannot be read: {reason}",
+                self.name, self.mime_type
+            );
+        }
+        write!(
+            f,
+            "{} is {}, which cannot be loaded; supported formats: {}",
+            self.name,
+            self.mime_type,
+            SUPPORTED.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for Unsupported {}
+
+impl From<Unsupported> for io::Error {
+    fn from(e: Unsupported) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
+impl Unsupported {
+    pub fn to_message(&self) -> UnsupportedFormat {
+        UnsupportedFormat {
+            name: self.name.clone(),
+            mime_type: self.mime_type.to_string(),
+            supported: SUPPORTED.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    /// `INVALID_ARGUMENT`, with an [`UnsupportedFormat`] as its details.
+    pub fn to_status(&self) -> Status {
+        Status::with_details(
+            Code::InvalidArgument,
+            self.to_string(),
+            self.to_message().encode_to_vec().into(),
+        )
+    }
+}
+
+/// The [`Unsupported`] error `e` wraps, if it wraps one.
+pub fn unsupported(e: &io::Error) -> Option<&Unsupported> {
+    e.get_ref()?.downcast_ref()
+}
+
+/// The media type of file `name` holding `bytes`: a binary format's by its
+/// signature, a text format's by the extension of `name` or else by how
+/// the text starts, and `application/octet-stream` for bytes that are
+/// neither.
+pub fn sniff(name: &str, bytes: &[u8]) -> &'static str {
+    detect(name, bytes).0
+}
+
+/// The text of file `name` holding `bytes`, by the loader for its media
+/// type (see [`sniff`]).
+pub fn load(name: &str, bytes: &[u8]) -> Result<Loaded, Unsupported> {
+    let (mime_type, text) = detect(name, bytes);
+    let text = match text {
+        None if mime_type == table::XLSX => {
+            table::xlsx_text(bytes).map_err(|reason| Unsupported {
+                name: name.to_string(),
+                mime_type,
+                reason: Some(reason),
+            })?
+        }
+        Some(text) if matches!(mime_type, "text/html" | "application/xml") => markup_text(&text),
+        Some(text) if mime_type == "message/rfc822" => email_text(&text),
+        Some(text) if SUPPORTED.contains(&mime_type) => text,
+        _ => {
+            return Err(Unsupported {
+                name: name.to_string(),
+                mime_type,
+                reason: None,
+            })
+        }
+    };
+    Ok(Loaded { mime_type, text })
+}
+
+/// Reads and loads the file at `path`.
+pub fn load_file(path: &std::path::Path) -> io::Result<Loaded> {
+    let bytes = std::fs::read(path)?;
+    Ok(load(&path.display().to_string(), &bytes)?)
+}
+
+/// The media type and, for text, the decoded text.
+fn detect(name: &str, bytes: &[u8]) -> (&'static str, Option<String>) {
+    if let Some(binary) = signature(name, bytes) {
+        return (binary, None);
+    }
+    let Some(text) = decode(bytes) else {
+        return ("application/octet-stream", None);
+    };
+    let mime_type = match docid::mime_type(name) {
+        by_name if by_name != "text/plain" && SUPPORTED.contains(&by_name) => by_name,
+        _ => sniff_text(&text),
+    };
+    (mime_type, Some(text))
+}
+
+fn signature(name: &str, bytes: &[u8]) -> Option<&'static str> {
+    if let Some((_, found)) = SIGNATURES
+        .iter()
+        .find(|(magic, _)| bytes.starts_with(magic))
+    {
+        return Some(found);
+    }
+    if bytes.starts_with(b"RIFF") {
+        match bytes.get(8..12) {
+            Some(b"WEBP") => return Some("image/webp"),
+            Some(b"WAVE") => return Some("audio/wav"),
+            Some(b"AVI ") => return Some("video/x-msvideo"),
+            _ => {}
+        }
+    }
+    if bytes.get(257..262) == Some(b"ustar") {
+        return Some("application/x-tar");
+    }
+    if bytes.get(4..8) == Some(b"ftyp") {
+        return Some("video/mp4");
+    }
+    if bytes.starts_with(b"PK\x03\x04") {
+        // Office documents and e-books are zip archives; their extension
+        // says which.
+        let ext = name
+            .rsplit_once('.')
+            .map(|(_, ext)| ext.to_ascii_lowercase());
+        return Some(match ext.as_deref() {
+            Some("docx") => {
+                "application/vnd.openxmlformats-officedocument.wordprocessingml.document"
+            }
+            Some("xlsx") => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+            Some("pptx") => {
+                "application/vnd.openxmlformats-officedocument.presentationml.presentation"
+            }
+            Some("odt") => "application/vnd.oasis.opendocument.text",
+            Some("epub") => "application/epub+zip",
+            _ => "application/zip",
+        });
+    }
+    None
+}
+
+/// `bytes` as text: UTF-8, or UTF-16 with a byte order mark. `None` for
+/// anything else, including UTF-8 holding NUL bytes, which text files do
+/// not have.
+fn decode(bytes: &[u8]) -> Option<String> {
+    let utf16 = |bytes: &[u8], unit: fn([u8; 2]) -> u16| {
+        let units: Vec<u16> = bytes
+            .chunks(2)
+            .map(|pair| pair.try_into().map(unit))
+            .collect::<Result<_, _>>()
+            .ok()?;
+        String::from_utf16(&units).ok()
+    };
+    if let Some(rest) = bytes.strip_prefix(b"\xff\xfe") {
+        return utf16(rest, u16::from_le_bytes);
+    }
+    if let Some(rest) = bytes.strip_prefix(b"\xfe\xff") {
+        return utf16(rest, u16::from_be_bytes);
+    }
+    let bytes = bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(bytes);
+    if bytes.contains(&0) {
+        return None;
+    }
+    String::from_utf8(bytes.to_vec()).ok()
+}
+
+/// The type of text that gave no type by name.
+fn sniff_text(text: &str) -> &'static str {
+    let start = text.trim_start();
+    let opening: String = start
+        .chars()
+        .take(16)
+        .collect::<String>()
+        .to_ascii_lowercase();
+    if opening.starts_with("<!doctype html") || opening.starts_with("<html") {
+        "text/html"
+    } else if opening.starts_with("<?xml") {
+        "application/xml"
+    } else if start.starts_with(['{', '['])
+        && serde_json::from_str::<serde::de::IgnoredAny>(start).is_ok()
+    {
+        "application/json"
+    } else if looks_like_email(text) {
+        "message/rfc822"
+    } else {
+        "text/plain"
+    }
+}
+
+fn looks_like_email(text: &str) -> bool {
+    let Some((headers, _)) = split_headers(text) else {
+        return false;
+    };
+    let has = |name: &str| header(&headers, name).is_some();
+    has("from") && (has("date") || has("message-id") || has("received"))
+}
+
+static SKIPPED: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?is)<script\b.*?</script\s*>|<style\b.*?</style\s*>|<!--.*?-->").unwrap()
+});
+/// Tags that end a line of text.
+static BREAKS: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)<br\s*/?>|</?(p|div|li|tr|h[1-6]|title|section|article|header|footer|blockquote|pre|table|ul|ol)\b[^>]*>",
+    )
+    .unwrap()
+});
+static TAGS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[!?/]?[a-zA-Z][^<>]*>").unwrap());
+static ENTITIES: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"&(#[0-9]{1,7}|#[xX][0-9a-fA-F]{1,6}|[a-zA-Z]+);").unwrap());
+
+/// The text of HTML or XML: scripts, styles, comments and tags dropped,
+/// block elements on lines of their own and entities decoded.
+fn markup_text(markup: &str) -> String {
+    let text = SKIPPED.replace_all(markup, "");
+    let text = BREAKS.replace_all(&text, "\n");
+    let text = TAGS.replace_all(&text, "");
+    let text = ENTITIES.replace_all(&text, |caps: &Captures| {
+        let entity = &caps[1];
+        let decoded = match entity {
+            "nbsp" => Some(' '),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "amp" => Some('&'),
+            _ => match entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+            {
+                Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                None => entity
+                    .strip_prefix('#')
+                    .and_then(|n| n.parse().ok())
+                    .and_then(char::from_u32),
+            },
+        };
+        decoded.map_or_else(|| caps[0].to_string(), String::from)
+    });
+    let mut out = String::with_capacity(text.len());
+    let mut blank = true;
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.is_empty() {
+            if !blank {
+                out.push('\n');
+            }
+            blank = true;
+            continue;
+        }
+        out.push_str(&line);
+        out.push('\n');
+        blank = false;
+    }
+    out.trim_end().to_string()
+}
+
+/// An email as its subject, sender, recipients and date, then the body:
+/// its plain text part, or failing that its HTML one as text.
+/// Attachments are left out.
+fn email_text(message: &str) -> String {
+    let Some((headers, body)) = split_headers(message) else {
+        return message.to_string();
+    };
+    let mut out = String::new();
+    for name in ["Subject", "From", "To", "Date"] {
+        if let Some(value) = header(&headers, name) {
+            out.push_str(&format!("{name}: {value}\n"));
+        }
+    }
+    out.push('\n');
+    out.push_str(
+        part_text(&headers, body)
+            .as_deref()
+            .unwrap_or_default()
+            .trim(),
+    );
+    out
+}
+
+/// The readable text of a message part, or `None` for a part that is not
+/// text, e.g. an attachment.
+fn part_text(headers: &[(String, String)], body: &str) -> Option<String> {
+    let content_type = header(headers, "content-type").unwrap_or("text/plain");
+    let media = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if media.starts_with("multipart/") {
+        let boundary = parameter(content_type, "boundary")?;
+        let delimiter = format!("--{boundary}");
+        let mut html = None;
+        for part in body.split(delimiter.as_str()).skip(1) {
+            if part.starts_with("--") {
+                break;
+            }
+            let part = part
+                .strip_prefix("\r\n")
+                .or_else(|| part.strip_prefix('\n'));
+            let Some((headers, body)) = part.and_then(split_headers) else {
+                continue;
+            };
+            let Some(text) = part_text(&headers, body) else {
+                continue;
+            };
+            let is_html = header(&headers, "content-type")
+                .is_some_and(|t| t.to_ascii_lowercase().starts_with("text/html"));
+            if !is_html {
+                return Some(text);
+            }
+            html.get_or_insert(text);
+        }
+        return html;
+    }
+    if header(headers, "content-disposition")
+        .is_some_and(|d| d.to_ascii_lowercase().starts_with("attachment"))
+        || !media.starts_with("text/")
+    {
+        return None;
+    }
+    let encoding = header(headers, "content-transfer-encoding").map(str::to_ascii_lowercase);
+    let text = match encoding.as_deref() {
+        Some("quoted-printable") => String::from_utf8_lossy(&quoted_printable(body)).into_owned(),
+        Some("base64") => String::from_utf8_lossy(&base64(body)?).into_owned(),
+        _ => body.to_string(),
+    };
+    Some(if media == "text/html" {
+        markup_text(&text)
+    } else {
+        text
+    })
+}
+
+/// A message's headers, continuation lines unfolded, and the body after
+/// the blank line ending them; `None` if it does not start with headers.
+fn split_headers(message: &str) -> Option<(Vec<(String, String)>, &str)> {
+    let mut headers: Vec<(String, String)> = Vec::new();
+    let mut at = 0;
+    for line in message.split_inclusive('\n') {
+        at += line.len();
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            return (!headers.is_empty()).then_some((headers, &message[at..]));
+        }
+        if line.starts_with([' ', '\t']) {
+            let (_, value) = headers.last_mut()?;
+            value.push(' ');
+            value.push_str(line.trim());
+            continue;
+        }
+        let (name, value) = line.split_once(':')?;
+        if name.is_empty() || !name.bytes().all(|b| b.is_ascii_graphic()) {
+            return None;
+        }
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+    (!headers.is_empty()).then_some((headers, ""))
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// Parameter `name` of a header value such as `multipart/mixed;
+/// boundary="abc"`.
+fn parameter<'a>(value: &'a str, name: &str) -> Option<&'a str> {
+    value.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| value.trim().trim_matches('"'))
+    })
+}
+
+fn quoted_printable(body: &str) -> Vec<u8> {
+    let bytes = body.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'=' {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let rest = &bytes[i + 1..];
+        if rest.starts_with(b"\r\n") {
+            i += 3;
+        } else if rest.starts_with(b"\n") {
+            i += 2;
+        } else if let Some(byte) = rest
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            out.push(byte);
+            i += 3;
+        } else {
+            out.push(b'=');
+            i += 1;
+        }
+    }
+    out
+}
+
+fn base64(body: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(body.len() * 3 / 4);
+    let (mut bits, mut count) = (0u32, 0);
+    for c in body.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return None,
+        };
+        bits = (bits << 6 | value as u32) & 0xffff;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
@@ -0,0 +1,318 @@
+//! Server logging behind the `log` facade. Records go to any number of
+//! sinks, configured from JSON:
+//!
+//! ```json
+//! {"level": "info", "sinks": [
+//!   {"type": "stderr", "format": "pretty"},
+//!   {"type": "file", "path": "logs/core.log", "format": "json",
+//!    "max_bytes": 10485760, "keep": 5},
+//!   {"type": "syslog", "path": "/dev/log", "ident": "assistant-core"}
+//! ]}
+//! ```
+//!
+//! Without a config, records at `info` and above go to stderr, pretty.
+//! A `"privacy"` key sets the global [`Privacy`] level, which decides what
+//! the query log and panic reports include.
+
+use crate::privacy::Privacy;
+use chrono::{SecondsFormat, Utc};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::{json, Value};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_KEEP: u32 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    /// `2024-05-01T12:00:00.000Z INFO  target: message`
+    Pretty,
+    /// One object per line: `{"ts", "level", "target", "message"}`.
+    Json,
+}
+
+impl Format {
+    fn parse(config: &Value) -> Result<Self, String> {
+        match config["format"].as_str().unwrap_or("pretty") {
+            "pretty" => Ok(Format::Pretty),
+            "json" => Ok(Format::Json),
+            other => Err(format!("unknown log format {other:?}; use pretty or json")),
+        }
+    }
+
+    fn line(self, record: &Record) -> String {
+        let ts = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        match self {
+            Format::Pretty => format!(
+                "{ts} {:<5} {}: {}\n",
+                record.level(),
+                record.target(),
+                record.args()
+            ),
+            Format::Json => {
+                let line = json!({
+                    "ts": ts,
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                });
+                format!("{line}\n")
+            }
+        }
+    }
+}
+
+/// A file that is moved aside once it would grow past `max_bytes`:
+/// `core.log` becomes `core.log.1`, `core.log.1` becomes `core.log.2`,
+/// and so on up to `keep` old files.
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: u32,
+    file: File,
+    size: u64,
+}
+
+fn open_append(path: &PathBuf) -> io::Result<File> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, keep: u32) -> io::Result<Self> {
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            max_bytes,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    fn rotated(&self, n: u32) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{n}"));
+        name.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    std::fs::rename(from, self.rotated(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Datagrams to the local syslog socket, which journald also reads.
+#[cfg(unix)]
+struct Syslog {
+    socket: std::os::unix::net::UnixDatagram,
+    ident: String,
+}
+
+#[cfg(unix)]
+impl Syslog {
+    fn connect(path: &str, ident: String) -> io::Result<Self> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Syslog { socket, ident })
+    }
+
+    fn send(&self, record: &Record) -> io::Result<()> {
+        // Facility "daemon" (3) plus the record's severity.
+        let severity = match record.level() {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        };
+        let message = format!(
+            "<{}>{}[{}]: {}: {}",
+            3 * 8 + severity,
+            self.ident,
+            std::process::id(),
+            record.target(),
+            record.args()
+        );
+        self.socket.send(message.as_bytes()).map(|_| ())
+    }
+}
+
+enum Sink {
+    Stdout(Format),
+    Stderr(Format),
+    File(Format, Mutex<RotatingFile>),
+    #[cfg(unix)]
+    Syslog(Syslog),
+}
+
+impl Sink {
+    fn from_config(config: &Value) -> Result<Self, String> {
+        let kind = config["type"].as_str().unwrap_or_default();
+        match kind {
+            "stdout" => Ok(Sink::Stdout(Format::parse(config)?)),
+            "stderr" => Ok(Sink::Stderr(Format::parse(config)?)),
+            "file" => {
+                let path = config["path"]
+                    .as_str()
+                    .ok_or("file log sink needs a path")?;
+                let max_bytes = config["max_bytes"].as_u64().unwrap_or(DEFAULT_MAX_BYTES);
+                let keep = config["keep"].as_u64().map_or(DEFAULT_KEEP, |n| n as u32);
+                let file = RotatingFile::open(path.into(), max_bytes, keep)
+                    .map_err(|e| format!("opening log file {path}: {e}"))?;
+                Ok(Sink::File(Format::parse(config)?, Mutex::new(file)))
+            }
+            #[cfg(unix)]
+            "syslog" => {
+                let path = config["path"].as_str().unwrap_or("/dev/log");
+                let ident = config["ident"].as_str().unwrap_or("assistant-core");
+                let syslog = Syslog::connect(path, ident.to_string())
+                    .map_err(|e| format!("connecting to syslog at {path}: {e}"))?;
+                Ok(Sink::Syslog(syslog))
+            }
+            other => Err(format!("unknown log sink type {other:?}")),
+        }
+    }
+
+    /// Sinks have nowhere to report their own failures, so they are dropped.
+    fn write(&self, record: &Record) {
+        let _ = match self {
+            Sink::Stdout(format) => io::stdout().write_all(format.line(record).as_bytes()),
+            Sink::Stderr(format) => io::stderr().write_all(format.line(record).as_bytes()),
+            Sink::File(format, file) => file.lock().unwrap().write(&format.line(record)),
+            #[cfg(unix)]
+            Sink::Syslog(syslog) => syslog.send(record),
+        };
+    }
+
+    fn flush(&self) {
+        let _ = match self {
+            Sink::Stdout(_) => io::stdout().flush(),
+            Sink::Stderr(_) => io::stderr().flush(),
+            Sink::File(_, file) => file.lock().unwrap().file.flush(),
+            #[cfg(unix)]
+            Sink::Syslog(_) => Ok(()),
+        };
+    }
+}
+
+pub struct Logger {
+    level: LevelFilter,
+    sinks: Vec<Sink>,
+    privacy: Privacy,
+}
+
+impl Default for Logger {
+    fn default() -> Self {
+        Logger {
+            level: LevelFilter::Info,
+            sinks: vec![Sink::Stderr(Format::Pretty)],
+            privacy: Privacy::default(),
+        }
+    }
+}
+
+impl Logger {
+    pub fn from_config(config: &Value) -> Result<Self, String> {
+        let mut logger = Logger::default();
+        if let Some(level) = config["level"].as_str() {
+            logger.level = level
+                .parse()
+                .map_err(|_| format!("unknown log level {level:?}"))?;
+        }
+        if let Some(privacy) = config["privacy"].as_str() {
+            logger.privacy = Privacy::parse(privacy).ok_or_else(|| {
+                format!(
+                    "unknown privacy level {privacy:?}; use {}",
+                    Privacy::NAMES.join(", ")
+                )
+            })?;
+        }
+        if let Some(sinks) = config["sinks"].as_array() {
+            logger.sinks = sinks
+                .iter()
+                .map(Sink::from_config)
+                .collect::<Result<_, _>>()?;
+        }
+        Ok(logger)
+    }
+
+    /// Installs this as the process-wide logger, and reports panics
+    /// through it.
+    pub fn install(self) -> Result<(), log::SetLoggerError> {
+        self.privacy.set_global();
+        log::set_max_level(self.level);
+        log::set_boxed_logger(Box::new(self))?;
+        std::panic::set_hook(Box::new(report_panic));
+        Ok(())
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            for sink in &self.sinks {
+                sink.write(record);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        for sink in &self.sinks {
+            sink.flush();
+        }
+    }
+}
+
+/// Logs a panic. Its message can quote whatever was being handled, such
+/// as indexed text, so only `log_full` includes it.
+fn report_panic(info: &std::panic::PanicHookInfo) {
+    let location = info
+        .location()
+        .map(|l| format!(" at {}:{}", l.file(), l.line()))
+        .unwrap_or_default();
+    let thread = std::thread::current();
+    let thread = thread.name().unwrap_or("<unnamed>");
+    if Privacy::global() < Privacy::Full {
+        log::error!(target: "panic", "thread {thread} panicked{location}");
+        return;
+    }
+    let message = match info.payload().downcast_ref::<&str>() {
+        Some(message) => message,
+        None => info
+            .payload()
+            .downcast_ref::<String>()
+            .map_or("Box<dyn Any>", String::as_str),
+    };
+    log::error!(target: "panic", "thread {thread} panicked{location}: {message}");
+}
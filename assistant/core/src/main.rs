@@ -1,40 +1,805 @@
 use futures_util::Stream;
-use std::{pin::Pin};
+use serde_json::{json, Value};
+use std::{pin::Pin, sync::Arc};
 use tonic::{transport::Server, Request as TRequest, Response as TResponse, Status};
 
-pub mod assistant {
-    tonic::include_proto!("assistant");
+use assistant_core::artifact::{ArtifactService, ArtifactStore, NewArtifact, Retention};
+use assistant_core::assistant::artifacts_server::ArtifactsServer;
+use assistant_core::assistant::assistant_server::{Assistant, AssistantServer};
+use assistant_core::assistant::indexer_server::IndexerServer;
+use assistant_core::assistant::{Request, Response};
+use assistant_core::attach::Attachments;
+use assistant_core::chat::{self, ChatRequest};
+use assistant_core::collection::Collections;
+use assistant_core::connector::{self, ConnectorError, ConnectorRegistry};
+use assistant_core::embed::{Embedder, Embedders};
+use assistant_core::indexer::IndexerService;
+use assistant_core::loader::{self, Unsupported};
+use assistant_core::logging::Logger;
+use assistant_core::profile::Profiles;
+use assistant_core::rerank::Reranker;
+use assistant_core::route::{self, Router};
+use assistant_core::run::{self, Run};
+use assistant_core::session::{self, SessionStore};
+use assistant_core::template::{TemplateError, TemplateStore};
+use assistant_core::upload::UploadStore;
+use assistant_core::workspace::{UnknownWorkspace, Workspace, Workspaces};
+use std::time::{Duration, Instant};
+
+struct AssistantSvc {
+    connectors: Arc<ConnectorRegistry>,
+    templates: TemplateStore,
+    profiles: Arc<Profiles>,
+    router: Arc<Router>,
+    sessions: Arc<SessionStore>,
+    artifacts: Arc<ArtifactStore>,
+    workspaces: Arc<Workspaces>,
+    attachments: Arc<Attachments>,
+    uploads: Arc<UploadStore>,
+}
+
+/// An error reply: HTTP-style status plus message for `Response.payload`.
+struct Failure {
+    status: i32,
+    message: String,
+    /// Set for a file no loader handles, whose detected `mime_type` and
+    /// the `supported` ones go in the payload too.
+    unsupported: Option<Unsupported>,
 }
 
-use assistant::assistant_server::{Assistant, AssistantServer};
-use assistant::{Request, Response};
+impl Failure {
+    fn payload(&self) -> Value {
+        let mut payload = json!({ "error": self.message });
+        if let Some(unsupported) = &self.unsupported {
+            payload["mime_type"] = json!(unsupported.mime_type);
+            payload["supported"] = json!(loader::SUPPORTED);
+        }
+        payload
+    }
+}
+
+impl From<ConnectorError> for Failure {
+    fn from(e: ConnectorError) -> Self {
+        Failure {
+            status: e.status(),
+            message: e.to_string(),
+            unsupported: None,
+        }
+    }
+}
 
-#[derive(Default)]
-struct AssistantSvc;
+impl From<TemplateError> for Failure {
+    fn from(e: TemplateError) -> Self {
+        let status = match e {
+            TemplateError::NotFound(_) => 404,
+            TemplateError::Io(_) => 500,
+            _ => 400,
+        };
+        Failure {
+            status,
+            message: e.to_string(),
+            unsupported: None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Failure {
+    fn from(e: std::io::Error) -> Self {
+        let status = match e.kind() {
+            std::io::ErrorKind::NotFound => 404,
+            std::io::ErrorKind::InvalidInput => 400,
+            std::io::ErrorKind::PermissionDenied => 403,
+            _ => 500,
+        };
+        Failure {
+            status,
+            unsupported: loader::unsupported(&e).cloned(),
+            message: e.to_string(),
+        }
+    }
+}
+
+impl From<UnknownWorkspace> for Failure {
+    fn from(e: UnknownWorkspace) -> Self {
+        Failure {
+            status: 404,
+            message: e.to_string(),
+            unsupported: None,
+        }
+    }
+}
+
+impl AssistantSvc {
+    /// The workspace `req` was made in, if any.
+    fn workspace(&self, req: &Request) -> Result<Option<&Workspace>, Failure> {
+        Ok(self.workspaces.get(&req.workspace)?)
+    }
+
+    /// Checks a tool call against the permissions of the request's
+    /// profile and workspace. Unknown tools are left to fail when called,
+    /// as they always have.
+    fn check_tool_call(&self, req: &Request, tool: &str, args: &Value) -> Result<(), Failure> {
+        let workspace = self.workspace(req)?;
+        if let Some(spec) = self.connectors.tool(tool) {
+            let profile = requested_profile(&req.profile, workspace);
+            self.profiles.get(&profile).tools.check(&spec, args)?;
+            if let Some(workspace) = workspace {
+                workspace.tools.check(&spec, args)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn dispatch(&self, req: &Request) -> Result<Value, Failure> {
+        let payload = req.payload.as_str();
+        match req.r#type.as_str() {
+            "query" => {
+                let mut chat = ChatRequest::from_payload(&parse_payload(payload)?);
+                let workspace = chat_workspace(
+                    &self.workspaces,
+                    &self.sessions,
+                    &req.workspace,
+                    &chat.session_id,
+                )?;
+                load_history(&mut chat, &self.sessions)?;
+                let name = workspace
+                    .as_ref()
+                    .map(|w| w.name.as_str())
+                    .unwrap_or_default();
+                with_attachments(&self.attachments, &mut chat, name).await?;
+                let (started_at, clock) = (chrono::Utc::now(), Instant::now());
+                let requested = requested_profile(&req.profile, workspace.as_ref());
+                let (profile, category) = choose_profile(&self.router, &requested, &chat);
+                let profile = self.profiles.get(&profile);
+                let answer = chat::answer(&chat, profile);
+                let artifact_id = save_artifact(&chat, &answer, &self.artifacts)?;
+                let workspace = workspace.map(|w| w.name).unwrap_or_default();
+                if !chat.session_id.is_empty() {
+                    let turn = chat::turn(&chat, &answer, started_at, clock.elapsed(), true);
+                    self.sessions.append(&chat.session_id, &workspace, turn)?;
+                }
+                let mut reply = json!({
+                    "answer": answer.content,
+                    "profile": profile.name,
+                    "confidence": answer.confidence.to_json(),
+                });
+                if answer.abstained {
+                    reply["abstained"] = json!(true);
+                }
+                if !workspace.is_empty() {
+                    reply["workspace"] = json!(workspace);
+                }
+                if let Some(id) = artifact_id {
+                    reply["artifact_id"] = json!(id);
+                }
+                if let Some(category) = category {
+                    reply["category"] = json!(category.as_str());
+                }
+                if chat.include_reasoning {
+                    reply["reasoning"] = json!(answer.reasoning);
+                }
+                if chat.debug {
+                    reply["assembly"] = answer.assembly.to_json();
+                }
+                if !answer.warnings.is_empty() {
+                    let warnings = answer.warnings.iter().map(|w| w.to_json()).collect();
+                    reply["warnings"] = Value::Array(warnings);
+                }
+                Ok(reply)
+            }
+            "connectors" => Ok(self.connectors.describe().await),
+            "sync_status" => Ok(self.connectors.sync_statuses()),
+            "tools" => {
+                let workspace = self.workspace(req)?;
+                let profile = requested_profile(&req.profile, workspace);
+                let permissions = &self.profiles.get(&profile).tools;
+                let scoped = workspace.map(|w| &w.tools);
+                let tools: Vec<Value> = self
+                    .connectors
+                    .tools()
+                    .into_iter()
+                    .filter(|tool| permissions.allows(&tool.name))
+                    .filter(|tool| scoped.is_none_or(|s| s.allows(&tool.name)))
+                    .map(|tool| {
+                        let confirm = permissions.requires_confirmation(&tool)
+                            || scoped.is_some_and(|s| s.requires_confirmation(&tool));
+                        let mut out = tool.to_json();
+                        out["requires_confirmation"] = json!(confirm);
+                        out
+                    })
+                    .collect();
+                Ok(Value::Array(tools))
+            }
+            "sync" => {
+                let args = parse_payload(payload)?;
+                let name = args["connector"].as_str().unwrap_or_default();
+                let report = self.connectors.sync(name).await?;
+                let status = self.connectors.sync_status(name).unwrap_or_default();
+                Ok(json!({
+                    "connector": name,
+                    "fetched": report.fetched,
+                    "updated": report.updated,
+                    "status": status.to_json(),
+                }))
+            }
+            "action" => {
+                let args = parse_payload(payload)?;
+                let tool = args["tool"]
+                    .as_str()
+                    .ok_or_else(|| ConnectorError::InvalidArgs("missing \"tool\"".into()))?;
+                self.check_tool_call(req, tool, &args["args"])?;
+                let mut result = self
+                    .connectors
+                    .call_tool(tool, args["args"].clone())
+                    .await?;
+                keep_tool_artifact(&mut result, &args, &self.artifacts)?;
+                Ok(result)
+            }
+            "run" => {
+                let args = parse_payload(payload)?;
+                let mut run = Run::from_payload(&args).map_err(|message| Failure {
+                    status: 400,
+                    message,
+                    unsupported: None,
+                })?;
+                let profile = requested_profile(&req.profile, self.workspace(req)?);
+                run.options.injection = self.profiles.get(&profile).injection;
+                for call in run.steps.iter().flatten() {
+                    self.check_tool_call(req, &call.tool, &call.args)?;
+                }
+                let mut outcome = run::execute(&self.connectors, &run).await;
+                for observation in outcome.steps.iter_mut().flatten() {
+                    if let Ok(result) = &mut observation.result {
+                        if let Err(e) = keep_tool_artifact(result, &args, &self.artifacts) {
+                            log::error!("saving {} artifact: {e}", observation.tool);
+                        }
+                    }
+                }
+                let steps: Vec<Value> = outcome
+                    .steps
+                    .iter()
+                    .map(|step| step.iter().map(run::Observation::to_json).collect())
+                    .collect();
+                let mut reply = json!({ "steps": steps, "usage": outcome.usage.to_json() });
+                if run.options.dry_run {
+                    let actions: Vec<Value> =
+                        outcome.actions.iter().map(run::ToolCall::to_json).collect();
+                    if !actions.is_empty() {
+                        match keep_actions(&actions, &args, &req.id, &self.artifacts) {
+                            Ok(id) => reply["actions_artifact_id"] = json!(id),
+                            Err(e) => log::error!("saving dry run actions: {e}"),
+                        }
+                    }
+                    reply["actions"] = Value::Array(actions);
+                }
+                if let Some(exceeded) = &outcome.exceeded {
+                    reply["terminal"] = exceeded.to_json();
+                }
+                if !outcome.warnings.is_empty() {
+                    let warnings = outcome.warnings.iter().map(|w| w.to_json()).collect();
+                    reply["warnings"] = Value::Array(warnings);
+                }
+                Ok(reply)
+            }
+            "session_get" => {
+                let args = parse_payload(payload)?;
+                let id = args["session_id"].as_str().unwrap_or_default();
+                let session = self.sessions.get_in(id, &req.workspace)?;
+                Ok(serde_json::to_value(session).unwrap_or_default())
+            }
+            "sessions" => {
+                self.workspace(req)?;
+                let sessions = self.sessions.list(&req.workspace)?;
+                Ok(serde_json::to_value(sessions).unwrap_or_default())
+            }
+            "attach" => {
+                let args = parse_payload(payload)?;
+                let session_id = args["session_id"].as_str().unwrap_or_default().to_string();
+                let workspace = chat_workspace(
+                    &self.workspaces,
+                    &self.sessions,
+                    &req.workspace,
+                    &session_id,
+                )?;
+                let workspace = workspace.map(|w| w.name).unwrap_or_default();
+                let name = args["name"].as_str().unwrap_or_default().to_string();
+                let text = args["text"].as_str().unwrap_or_default().to_string();
+                // `upload_id` attaches a file pushed with UploadFile in
+                // place of `text`, named after it unless `name` is given.
+                let upload_id = args["upload_id"].as_str().unwrap_or_default().to_string();
+                let (attachments, uploads) =
+                    (Arc::clone(&self.attachments), Arc::clone(&self.uploads));
+                let session = session_id.clone();
+                let (name, chunks) = tokio::task::spawn_blocking(move || {
+                    let (name, text) = match upload_id.as_str() {
+                        "" => (name, text),
+                        id => {
+                            let (upload, loaded) = uploads.load(id, &workspace)?;
+                            (
+                                if name.is_empty() { upload.name } else { name },
+                                loaded.text,
+                            )
+                        }
+                    };
+                    let chunks = attachments.attach(&session, &workspace, &name, &text)?;
+                    Ok::<_, std::io::Error>((name, chunks))
+                })
+                .await
+                .map_err(|e| std::io::Error::other(e.to_string()))??;
+                Ok(json!({ "session_id": session_id, "name": name, "chunks": chunks }))
+            }
+            "detach" => {
+                let args = parse_payload(payload)?;
+                let session_id = args["session_id"].as_str().unwrap_or_default();
+                let workspace =
+                    chat_workspace(&self.workspaces, &self.sessions, &req.workspace, session_id)?;
+                let workspace = workspace.map(|w| w.name).unwrap_or_default();
+                let name = args["name"].as_str().unwrap_or_default();
+                let detached = self.attachments.detach(session_id, &workspace, name)?;
+                Ok(json!({ "session_id": session_id, "name": name, "detached": detached }))
+            }
+            "attachments" => {
+                let args = parse_payload(payload)?;
+                let session_id = args["session_id"].as_str().unwrap_or_default();
+                let workspace =
+                    chat_workspace(&self.workspaces, &self.sessions, &req.workspace, session_id)?;
+                let workspace = workspace.map(|w| w.name).unwrap_or_default();
+                Ok(self.attachments.list(session_id, &workspace)?)
+            }
+            "session_rename" => {
+                let args = parse_payload(payload)?;
+                let id = args["session_id"].as_str().unwrap_or_default();
+                let title = args["title"].as_str().unwrap_or_default();
+                self.sessions.rename(id, &req.workspace, title)?;
+                Ok(json!({ "session_id": id, "title": title.trim() }))
+            }
+            "workspaces" => Ok(self.workspaces.iter().map(Workspace::to_json).collect()),
+            "templates" => {
+                let list: Vec<Value> = self
+                    .templates
+                    .list()?
+                    .into_iter()
+                    .map(|(name, vars)| json!({ "name": name, "vars": vars }))
+                    .collect();
+                Ok(Value::Array(list))
+            }
+            "template_get" => {
+                let args = parse_payload(payload)?;
+                let name = args["name"].as_str().unwrap_or_default();
+                Ok(json!({ "name": name, "body": self.templates.get(name)? }))
+            }
+            "template_put" => {
+                let args = parse_payload(payload)?;
+                let name = args["name"].as_str().unwrap_or_default();
+                let body = args["body"].as_str().unwrap_or_default();
+                Ok(json!({ "name": name, "vars": self.templates.put(name, body)? }))
+            }
+            kind => Ok(json!({ "echo": payload, "type": kind })),
+        }
+    }
+}
+
+/// Saves the answer as an artifact if the request asks for one, linked to
+/// its session and plan. Returns the artifact's id.
+fn save_artifact(
+    chat: &ChatRequest,
+    answer: &chat::Answer,
+    artifacts: &ArtifactStore,
+) -> std::io::Result<Option<String>> {
+    if chat.save_as.is_empty() {
+        return Ok(None);
+    }
+    let artifact = artifacts.save(NewArtifact {
+        kind: chat.save_as.clone(),
+        title: session::title_for(&chat.prompt),
+        content: answer.content.clone(),
+        mime_type: "text/markdown".into(),
+        session_id: chat.session_id.clone(),
+        plan_id: chat.plan_id.clone(),
+        ..NewArtifact::default()
+    })?;
+    Ok(Some(artifact.id))
+}
+
+/// Saves an artifact a tool handed back as `"artifact": {"kind", "title",
+/// "content", "mime_type"}`, such as `apply_patch`'s preview, linked to
+/// the request's session and plan, and leaves its id in its place.
+fn keep_tool_artifact(
+    result: &mut Value,
+    request: &Value,
+    artifacts: &ArtifactStore,
+) -> std::io::Result<()> {
+    let Some(artifact) = result.get("artifact").filter(|a| a.is_object()) else {
+        return Ok(());
+    };
+    let field = |name: &str| artifact[name].as_str().unwrap_or_default().to_string();
+    let link = |name: &str| request[name].as_str().unwrap_or_default().to_string();
+    let saved = artifacts.save(NewArtifact {
+        kind: field("kind"),
+        title: field("title"),
+        content: field("content"),
+        mime_type: field("mime_type"),
+        session_id: link("session_id"),
+        plan_id: link("plan_id"),
+        ..NewArtifact::default()
+    })?;
+    let fields = result.as_object_mut().expect("checked above");
+    fields.remove("artifact");
+    fields.insert("artifact_id".into(), json!(saved.id));
+    Ok(())
+}
+
+/// Saves the calls a dry run would have made as an `actions` artifact,
+/// linked to the payload's `session_id` and to its `plan_id`, or else to
+/// the request's id. Returns the artifact's id.
+fn keep_actions(
+    actions: &[Value],
+    request: &Value,
+    request_id: &str,
+    artifacts: &ArtifactStore,
+) -> std::io::Result<String> {
+    let link = |name: &str| request[name].as_str().unwrap_or_default().to_string();
+    let plan_id = match link("plan_id") {
+        id if id.is_empty() => request_id.to_string(),
+        id => id,
+    };
+    let title = match actions.len() {
+        1 => "1 action".to_string(),
+        n => format!("{n} actions"),
+    };
+    let saved = artifacts.save(NewArtifact {
+        kind: "actions".into(),
+        title: format!("Dry run: {title}"),
+        content: serde_json::to_string_pretty(actions)?,
+        mime_type: "application/json".into(),
+        session_id: link("session_id"),
+        plan_id,
+        ..NewArtifact::default()
+    })?;
+    Ok(saved.id)
+}
+
+/// Fills in the request's conversation history from its session.
+fn load_history(chat: &mut ChatRequest, sessions: &SessionStore) -> std::io::Result<()> {
+    if chat.session_id.is_empty() {
+        return Ok(());
+    }
+    match sessions.get(&chat.session_id) {
+        Ok(session) => {
+            chat.history = session
+                .turns
+                .into_iter()
+                .map(|turn| (turn.prompt, turn.answer))
+                .collect();
+            Ok(())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// The workspace a chat turn runs in: the one the request names, else the
+/// one its session was started in. A session can only be continued from
+/// the workspace it was started in.
+fn chat_workspace(
+    workspaces: &Workspaces,
+    sessions: &SessionStore,
+    requested: &str,
+    session_id: &str,
+) -> Result<Option<Workspace>, Failure> {
+    let mut name = requested.to_string();
+    if !session_id.is_empty() {
+        match sessions.get(session_id) {
+            Ok(session) if requested.is_empty() => name = session.workspace,
+            Ok(session) => session.check_workspace(requested)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(workspaces.get(&name)?.cloned())
+}
+
+/// Attaches the turn's documents to its session, then adds the session's
+/// attached chunks that best match the prompt to the turn's context.
+async fn with_attachments(
+    attachments: &Arc<Attachments>,
+    chat: &mut ChatRequest,
+    workspace: &str,
+) -> Result<(), Failure> {
+    if !chat.attachments.is_empty() {
+        let (attachments, documents) = (
+            Arc::clone(attachments),
+            std::mem::take(&mut chat.attachments),
+        );
+        let (session, workspace) = (chat.session_id.clone(), workspace.to_string());
+        tokio::task::spawn_blocking(move || {
+            documents.iter().try_for_each(|(name, text)| {
+                attachments
+                    .attach(&session, &workspace, name, text)
+                    .map(drop)
+            })
+        })
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))??;
+    }
+    if !chat.session_id.is_empty() {
+        let attached = attachments.context(&chat.session_id, workspace, &chat.prompt);
+        chat.context.extend(attached);
+    }
+    Ok(())
+}
+
+/// The profile a request names, else the one its workspace runs under.
+fn requested_profile(requested: &str, workspace: Option<&Workspace>) -> String {
+    match workspace.and_then(|w| w.profile.as_deref()) {
+        Some(profile) if requested.is_empty() => profile.to_string(),
+        _ => requested.to_string(),
+    }
+}
+
+/// The profile a chat request runs under: the one it names, else the one
+/// its routing rules pick. Also returns the category when routing ran.
+fn choose_profile(
+    router: &Router,
+    requested: &str,
+    chat: &ChatRequest,
+) -> (String, Option<route::Category>) {
+    if !requested.is_empty() {
+        return (requested.to_string(), None);
+    }
+    let route = router.route(&chat.prompt);
+    (route.profile.unwrap_or_default(), Some(route.category))
+}
+
+/// Runs a chat turn in its own task so it completes, and is saved to
+/// its session, even if the client disconnects mid-stream.
+fn spawn_turn(
+    mut chat: ChatRequest,
+    profiles: Arc<Profiles>,
+    profile: String,
+    workspace: String,
+    sessions: Arc<SessionStore>,
+    artifacts: Arc<ArtifactStore>,
+) -> tokio::sync::mpsc::Receiver<chat::Event> {
+    let (tx, rx) = tokio::sync::mpsc::channel(64);
+    tokio::spawn(async move {
+        let (started_at, clock) = (chrono::Utc::now(), Instant::now());
+        if let Err(e) = load_history(&mut chat, &sessions) {
+            log::warn!("loading session {} failed: {e}", chat.session_id);
+        }
+        // Post-processing needs the whole answer, so deltas are cut
+        // from the processed text rather than the raw generation.
+        let profile = profiles.get(&profile);
+        let answer = chat::answer(&chat, profile);
+        let mut summary = chat::Summary {
+            model: answer.model.clone(),
+            profile: profile.name.clone(),
+            retrieved: chat.context.len(),
+            confidence: answer.confidence,
+            abstained: answer.abstained,
+            ..Default::default()
+        };
+        let mut delivered = true;
+        // Tokens are sent on a fixed schedule when the rate is limited.
+        let pace =
+            chat::stream_rate(&chat, profile).map(|rate| Duration::from_secs_f64(1.0 / rate));
+        let mut next_token = tokio::time::Instant::now();
+        for event in chat::events(&chat, &answer) {
+            if matches!(event, chat::Event::Delta(_) | chat::Event::Reasoning(_)) {
+                if let Some(pace) = pace {
+                    tokio::time::sleep_until(next_token).await;
+                    next_token += pace;
+                }
+                summary.first_token.get_or_insert(clock.elapsed());
+                summary.tokens += 1;
+            }
+            if tx.send(event).await.is_err() {
+                delivered = false;
+                break;
+            }
+        }
+        match save_artifact(&chat, &answer, &artifacts) {
+            Ok(id) => summary.artifact_id = id,
+            Err(e) => log::error!("saving the answer as a {} failed: {e}", chat.save_as),
+        }
+        if delivered {
+            summary.wall = clock.elapsed();
+            let _ = tx.send(chat::Event::Summary(summary)).await;
+            let _ = tx.send(chat::Event::Done).await;
+        }
+        if !chat.session_id.is_empty() {
+            let turn = chat::turn(&chat, &answer, started_at, clock.elapsed(), delivered);
+            if let Err(e) = sessions.append(&chat.session_id, &workspace, turn) {
+                log::error!("saving session {} failed: {e}", chat.session_id);
+            }
+        }
+    });
+    rx
+}
+
+fn parse_payload(payload: &str) -> Result<Value, Failure> {
+    serde_json::from_str(payload).map_err(|e| Failure {
+        status: 400,
+        message: e.to_string(),
+        unsupported: None,
+    })
+}
+
+fn load_logger() -> Result<Logger, Box<dyn std::error::Error>> {
+    // Log sinks come from a JSON file: {"level": ..., "sinks": [...]}
+    let Ok(path) = std::env::var("ASSISTANT_LOG") else {
+        return Ok(Logger::default());
+    };
+    let config: Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    Ok(Logger::from_config(&config)?)
+}
+
+fn load_router() -> Result<Router, Box<dyn std::error::Error>> {
+    // Routing rules come from a JSON file: {"rules": [...]}
+    let Ok(path) = std::env::var("ASSISTANT_ROUTES") else {
+        return Ok(Router::default());
+    };
+    let config: Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    Ok(Router::from_config(&config)?)
+}
+
+fn load_profiles() -> Result<Profiles, Box<dyn std::error::Error>> {
+    // Profiles are configured from a JSON file: {"<name>": {"postprocess": [...]}, ...}
+    let Ok(path) = std::env::var("ASSISTANT_PROFILES") else {
+        return Ok(Profiles::default());
+    };
+    let config: Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    Ok(Profiles::from_config(&config)?)
+}
+
+fn load_workspaces() -> Result<Workspaces, Box<dyn std::error::Error>> {
+    // Workspaces come from a JSON file: {"<name>": {"collections": [...], ...}, ...}
+    let Ok(path) = std::env::var("ASSISTANT_WORKSPACES") else {
+        return Ok(Workspaces::default());
+    };
+    let config: Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    Ok(Workspaces::from_config(&config)?)
+}
+
+fn load_retention() -> Result<Retention, Box<dyn std::error::Error>> {
+    // Artifact retention comes from a JSON file: {"retention_days": {"<kind>": days}}
+    let Ok(path) = std::env::var("ASSISTANT_ARTIFACTS") else {
+        return Ok(Retention::default());
+    };
+    let config: Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    Ok(Retention::from_config(&config)?)
+}
+
+fn load_embedders() -> Result<Embedders, Box<dyn std::error::Error>> {
+    // Sentence-transformer model directories, separated like PATH, each
+    // offered under its name.
+    let mut embedders = Embedders::default();
+    if let Some(paths) = std::env::var_os("ASSISTANT_EMBEDDING_MODEL") {
+        for path in std::env::split_paths(&paths) {
+            let path = path.to_string_lossy();
+            if path.is_empty() {
+                continue;
+            }
+            let model = load_model(&path)?;
+            if embedders.models().any(|m| m.name() == model.name()) {
+                return Err(format!("two embedding models are named {:?}", model.name()).into());
+            }
+            embedders.add(model);
+            log::info!("loaded embedding model {path}");
+        }
+    }
+    // The embedder of new collections, e.g. "hash-512" or the model's name.
+    if let Ok(name) = std::env::var("ASSISTANT_EMBEDDER") {
+        embedders.set_default(&name)?;
+    }
+    Ok(embedders)
+}
+
+#[cfg(feature = "bert")]
+fn load_model(path: &str) -> Result<Arc<dyn Embedder>, Box<dyn std::error::Error>> {
+    let model = assistant_core::bert::BertEmbedder::load(std::path::Path::new(path))?;
+    Ok(Arc::new(model))
+}
+
+#[cfg(not(feature = "bert"))]
+fn load_model(path: &str) -> Result<Arc<dyn Embedder>, Box<dyn std::error::Error>> {
+    Err(format!("ASSISTANT_EMBEDDING_MODEL={path} needs a build with --features bert").into())
+}
+
+fn load_reranker() -> Result<Option<Arc<dyn Reranker>>, Box<dyn std::error::Error>> {
+    // A cross-encoder model directory, for queries with `rerank` set.
+    let Ok(path) = std::env::var("ASSISTANT_RERANK_MODEL") else {
+        return Ok(None);
+    };
+    let reranker = load_cross_encoder(&path)?;
+    log::info!("loaded reranking model {path}");
+    Ok(Some(reranker))
+}
+
+#[cfg(feature = "bert")]
+fn load_cross_encoder(path: &str) -> Result<Arc<dyn Reranker>, Box<dyn std::error::Error>> {
+    let model = assistant_core::bert::BertCrossEncoder::load(std::path::Path::new(path))?;
+    Ok(Arc::new(model))
+}
+
+#[cfg(not(feature = "bert"))]
+fn load_cross_encoder(path: &str) -> Result<Arc<dyn Reranker>, Box<dyn std::error::Error>> {
+    Err(format!("ASSISTANT_RERANK_MODEL={path} needs a build with --features bert").into())
+}
+
+fn load_connectors() -> Result<ConnectorRegistry, Box<dyn std::error::Error>> {
+    // Connectors are configured from a JSON file: {"<name>": {<config>}, ...}
+    let Ok(path) = std::env::var("ASSISTANT_CONNECTORS") else {
+        return Ok(ConnectorRegistry::default());
+    };
+    let config: Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    Ok(ConnectorRegistry::from_config(
+        &config,
+        &connector::builtin_factories(),
+    )?)
+}
 
 #[tonic::async_trait]
 impl Assistant for AssistantSvc {
     async fn send(&self, req: TRequest<Request>) -> Result<TResponse<Response>, Status> {
         let inner = req.into_inner();
+        let (status, payload) = match self.dispatch(&inner).await {
+            Ok(value) => (200, value),
+            Err(e) => (e.status, e.payload()),
+        };
         let reply = Response {
             id: inner.id,
-            status: 200,
-            payload: format!("{{\"echo\":{},\"type\":\"{}\"}}", serde_json::to_string(&inner.payload).unwrap_or("\"\"".into()), inner.r#type),
+            status,
+            payload: payload.to_string(),
         };
         Ok(TResponse::new(reply))
     }
 
-    type StreamResponsesStream = Pin<Box<dyn Stream<Item = Result<Response, Status>> + Send + 'static>>;
+    type StreamResponsesStream =
+        Pin<Box<dyn Stream<Item = Result<Response, Status>> + Send + 'static>>;
 
     async fn stream_responses(
         &self,
         req: TRequest<tonic::Streaming<Request>>,
     ) -> Result<TResponse<Self::StreamResponsesStream>, Status> {
         let mut inbound = req.into_inner();
+        let profiles = Arc::clone(&self.profiles);
+        let router = Arc::clone(&self.router);
+        let sessions = Arc::clone(&self.sessions);
+        let artifacts = Arc::clone(&self.artifacts);
+        let workspaces = Arc::clone(&self.workspaces);
+        let attachments = Arc::clone(&self.attachments);
         let output = async_stream::try_stream! {
             while let Some(next) = inbound.message().await? {
-                let resp = Response { id: next.id, status: 200, payload: next.payload };
-                yield resp;
+                if next.r#type != "query" {
+                    yield Response { id: next.id, status: 200, payload: next.payload };
+                    continue;
+                }
+                let Ok(payload) = serde_json::from_str::<Value>(&next.payload) else {
+                    let error = json!({ "error": "payload is not valid JSON" });
+                    yield Response { id: next.id, status: 400, payload: error.to_string() };
+                    continue;
+                };
+                let mut chat = ChatRequest::from_payload(&payload);
+                let workspace = match chat_workspace(&workspaces, &sessions, &next.workspace, &chat.session_id) {
+                    Ok(workspace) => workspace,
+                    Err(e) => {
+                        yield Response { id: next.id, status: e.status, payload: e.payload().to_string() };
+                        continue;
+                    }
+                };
+                let name = workspace.as_ref().map(|w| w.name.as_str()).unwrap_or_default();
+                if let Err(e) = with_attachments(&attachments, &mut chat, name).await {
+                    yield Response { id: next.id, status: e.status, payload: e.payload().to_string() };
+                    continue;
+                }
+                let requested = requested_profile(&next.profile, workspace.as_ref());
+                let (profile, _) = choose_profile(&router, &requested, &chat);
+                let workspace = workspace.map(|w| w.name).unwrap_or_default();
+                let mut events = spawn_turn(chat, Arc::clone(&profiles), profile, workspace, Arc::clone(&sessions), Arc::clone(&artifacts));
+                while let Some(event) = events.recv().await {
+                    yield Response { id: next.id.clone(), status: 200, payload: event.to_json().to_string() };
+                }
             }
         };
         Ok(TResponse::new(Box::pin(output)))
@@ -43,13 +808,57 @@ impl Assistant for AssistantSvc {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    load_logger()?.install()?;
     // Simple TCP address (UDS can be added later)
     let addr = std::env::var("ASSISTANT_ADDR").unwrap_or_else(|_| "127.0.0.1:50051".to_string());
     let addr = addr.parse()?;
 
-    println!("assistant-core listening on {}", addr);
+    let connectors = Arc::new(load_connectors()?);
+    let data_dir = std::env::var("ASSISTANT_DATA_DIR").unwrap_or_else(|_| "data".to_string());
+    let data_dir = std::path::PathBuf::from(data_dir);
+    let embedders = load_embedders()?;
+    let attachments = embedders
+        .get(embedders.default_name())
+        .ok_or("the default embedder is not loaded")?;
+    let svc = AssistantSvc {
+        connectors: Arc::clone(&connectors),
+        templates: TemplateStore::new(data_dir.join("templates")),
+        profiles: Arc::new(load_profiles()?),
+        router: Arc::new(load_router()?),
+        workspaces: Arc::new(load_workspaces()?),
+        sessions: Arc::new(SessionStore::new(data_dir.join("sessions"))),
+        artifacts: Arc::new(ArtifactStore::new(
+            data_dir.join("artifacts"),
+            load_retention()?,
+        )),
+        attachments: Arc::new(Attachments::new(attachments)),
+        uploads: Arc::new(UploadStore::new(data_dir.join("uploads"))),
+    };
+    svc.artifacts.spawn_retention_sweeper();
+    svc.uploads.spawn_sweeper();
+    let artifacts = ArtifactService::new(Arc::clone(&svc.artifacts));
+
+    let collections = Collections::open(
+        data_dir.join("index"),
+        &data_dir.join("index.json"),
+        embedders,
+    )?;
+    let indexer = Arc::new(IndexerService::new(
+        collections,
+        load_reranker()?,
+        Arc::clone(&svc.workspaces),
+        Arc::clone(&svc.uploads),
+    ));
+    indexer.spawn_expiry_sweeper();
+    indexer.spawn_watchers();
+    connectors.index_into(indexer.clone());
+    connectors.spawn_periodic_syncs();
+
+    log::info!("assistant-core listening on {}", addr);
     Server::builder()
-        .add_service(AssistantServer::new(AssistantSvc::default()))
+        .add_service(AssistantServer::new(svc))
+        .add_service(IndexerServer::from_arc(indexer))
+        .add_service(ArtifactsServer::new(artifacts))
         .serve(addr)
         .await?;
 
@@ -0,0 +1,85 @@
+//! How embeddings are compared. Indexes store embeddings as their
+//! embedder made them, so any metric can be applied to them at query time;
+//! cosine divides out their lengths as it scores.
+//!
+//! Every metric scores higher for more similar vectors: euclidean
+//! distance is negated.
+
+use crate::simd;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Metric {
+    /// Angle between the vectors, ignoring their lengths.
+    #[default]
+    Cosine,
+    /// Raw dot product, so longer vectors score higher.
+    Dot,
+    /// Negated L2 distance.
+    Euclidean,
+}
+
+impl Metric {
+    pub const NAMES: &'static [&'static str] = &["cosine", "dot", "euclidean"];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "cosine" => Some(Metric::Cosine),
+            "dot" => Some(Metric::Dot),
+            "euclidean" | "l2" => Some(Metric::Euclidean),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Metric::Cosine => "cosine",
+            Metric::Dot => "dot",
+            Metric::Euclidean => "euclidean",
+        }
+    }
+
+    /// Similarity of `a` and `b`, over the length of the shorter. Cosine is
+    /// zero if either is all zeros.
+    pub fn score(self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            Metric::Cosine => {
+                let (ab, aa, bb) = simd::dot3(a, b);
+                let norms = (aa * bb).sqrt();
+                if norms > 0.0 {
+                    ab / norms
+                } else {
+                    0.0
+                }
+            }
+            Metric::Dot => simd::dot(a, b),
+            Metric::Euclidean => -simd::distance2(a, b).sqrt(),
+        }
+    }
+
+    /// The score of two vectors from their dot product and squared
+    /// lengths, for when the vectors themselves are not at hand.
+    pub fn from_dot(self, ab: f32, aa: f32, bb: f32) -> f32 {
+        match self {
+            Metric::Cosine => {
+                let norms = (aa * bb).sqrt();
+                if norms > 0.0 {
+                    ab / norms
+                } else {
+                    0.0
+                }
+            }
+            Metric::Dot => ab,
+            Metric::Euclidean => -(aa - 2.0 * ab + bb).max(0.0).sqrt(),
+        }
+    }
+
+    /// Whether a score means the vectors have anything in common. Cosine
+    /// and dot products of unrelated vectors are zero or below; every
+    /// distance counts, as nearer is always better.
+    pub fn related(self, score: f32) -> bool {
+        match self {
+            Metric::Cosine | Metric::Dot => score > 0.0,
+            Metric::Euclidean => true,
+        }
+    }
+}
@@ -0,0 +1,193 @@
+//! Text normalization, set per collection, applied to its documents before
+//! they are chunked and embedded and to its queries before they are
+//! embedded. Loaders leave different formatting noise behind, such as
+//! decomposed accents from one, runs of spaces from another and page
+//! footers from a third; normalizing makes the same words embed alike
+//! wherever they came from.
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::LazyLock;
+use unicode_normalization::UnicodeNormalization;
+
+/// Lines with words at most this long that occur this often in one text
+/// are taken for running headers and footers.
+const REPEATED_LINE_CHARS: usize = 80;
+const REPEATED_LINE_COUNT: usize = 3;
+/// Lines after a `--` line that are taken for an email signature.
+const SIGNATURE_LINES: usize = 10;
+
+/// Whole lines of boilerplate: page numbers, separators, mail client
+/// taglines, unsubscribe and copyright notices.
+static BOILERPLATE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?ix)^(?:
+            page\s+\d+(?:\s+of\s+\d+)?
+            | -\s*\d+\s*-
+            | [-_=*~\#.·•]{3,}
+            | sent\s+from\s+my\s+\S+.{0,40}
+            | get\s+outlook\s+for\s+\S+
+            | .{0,80}\bunsubscribe\b.{0,80}
+            | .{0,60}\bview\s+(?:this\s+(?:email|message)\s+)?in\s+(?:your\s+)?browser\b.{0,60}
+            | (?:©|\(c\)|copyright\b).{0,100}
+            | .{0,100}\ball\s+rights\s+reserved\b.{0,20}
+        )$",
+    )
+    .unwrap()
+});
+
+/// Steps a collection normalizes text with. All are off unless set.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Normalize {
+    /// Unicode NFC, so a precomposed é and an e with a combining accent
+    /// are the same text.
+    pub nfc: bool,
+    /// Runs of spaces, tabs and other blanks become one space, lines are
+    /// trimmed, zero-width characters dropped, and runs of blank lines
+    /// become one.
+    pub whitespace: bool,
+    /// Lines of boilerplate are dropped: page numbers, separators, mail
+    /// taglines, unsubscribe and copyright notices, an email signature
+    /// after a `--` line, and short lines repeated three or more times,
+    /// such as running headers, after their first occurrence. Not applied
+    /// to queries.
+    pub boilerplate: bool,
+    /// Lowercased, for embedders that tell case apart. The stored text is
+    /// lowercased too.
+    pub lowercase: bool,
+}
+
+impl Normalize {
+    /// Step names, in the order they run.
+    pub const STEPS: &'static [&'static str] = &["nfc", "whitespace", "boilerplate", "lowercase"];
+
+    /// The steps named in `names`; none for an empty list.
+    pub fn parse(names: &[String]) -> Result<Self, String> {
+        let mut normalize = Normalize::default();
+        for name in names {
+            let step = match name.trim() {
+                "nfc" => &mut normalize.nfc,
+                "whitespace" => &mut normalize.whitespace,
+                "boilerplate" => &mut normalize.boilerplate,
+                "lowercase" => &mut normalize.lowercase,
+                name => {
+                    return Err(format!(
+                        "unsupported normalization {name:?}; supported: {}",
+                        Self::STEPS.join(", ")
+                    ))
+                }
+            };
+            *step = true;
+        }
+        Ok(normalize)
+    }
+
+    /// The names of the steps set, in the order they run.
+    pub fn names(&self) -> Vec<String> {
+        [self.nfc, self.whitespace, self.boilerplate, self.lowercase]
+            .into_iter()
+            .zip(Self::STEPS)
+            .filter(|(on, _)| *on)
+            .map(|(_, name)| name.to_string())
+            .collect()
+    }
+
+    /// `text` of a document, normalized.
+    pub fn document(&self, text: String) -> String {
+        let text = self.common(text, self.boilerplate);
+        self.lowercased(text)
+    }
+
+    /// `text` of a query, normalized like documents but for boilerplate.
+    pub fn query(&self, text: String) -> String {
+        let text = self.common(text, false);
+        self.lowercased(text)
+    }
+
+    fn common(&self, mut text: String, boilerplate: bool) -> String {
+        if self.nfc {
+            text = text.nfc().collect();
+        }
+        if boilerplate {
+            text = strip_boilerplate(&text);
+        }
+        if self.whitespace {
+            text = collapse_whitespace(&text);
+        }
+        text
+    }
+
+    fn lowercased(&self, text: String) -> String {
+        match self.lowercase {
+            true => text.to_lowercase(),
+            false => text,
+        }
+    }
+}
+
+/// A line with its runs of whitespace made single spaces, for comparing.
+fn squeezed(line: &str) -> String {
+    line.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn strip_boilerplate(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    let end = lines
+        .iter()
+        .rposition(|line| line.trim_end() == "--")
+        .filter(|at| lines.len() - at - 1 <= SIGNATURE_LINES)
+        .unwrap_or(lines.len());
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for line in &lines[..end] {
+        let line = squeezed(line);
+        if line.chars().any(char::is_alphanumeric) && line.chars().count() <= REPEATED_LINE_CHARS {
+            *counts.entry(line).or_default() += 1;
+        }
+    }
+    let mut seen = HashSet::new();
+    let mut kept = String::with_capacity(text.len());
+    for line in &lines[..end] {
+        let key = squeezed(line);
+        if BOILERPLATE.is_match(&key) {
+            continue;
+        }
+        if counts.get(&key).is_some_and(|&n| n >= REPEATED_LINE_COUNT) && !seen.insert(key) {
+            continue;
+        }
+        kept.push_str(line);
+        kept.push('\n');
+    }
+    kept.truncate(kept.trim_end().len());
+    kept
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut blank = false;
+    for line in text.lines() {
+        let line: String = line
+            .chars()
+            .filter(|c| {
+                !matches!(
+                    c,
+                    '\u{200b}' | '\u{200c}' | '\u{200d}' | '\u{2060}' | '\u{feff}'
+                )
+            })
+            .collect();
+        let line = squeezed(&line);
+        if line.is_empty() {
+            blank = !out.is_empty();
+            continue;
+        }
+        if blank {
+            out.push('\n');
+            blank = false;
+        }
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out.truncate(out.trim_end().len());
+    out
+}
@@ -0,0 +1,546 @@
+//! Patch connector: lets the assistant edit files under a configured
+//! project root by unified diff. `apply_patch` without consent only checks
+//! the diff against the files and returns a preview; the same call with
+//! `"consent": true`, made after the user approved the preview, writes it.
+//!
+//! Hunks must match the files exactly, apart from their position: a hunk
+//! whose context moved is applied where it now is, nearest to the line the
+//! diff names. Nothing is written unless every hunk of every file applies,
+//! and the files are only replaced once all their new content is written.
+
+use crate::connector::{Connector, ConnectorError, Health, SyncReport, ToolSpec};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::path::{Component, Path, PathBuf};
+
+/// Largest diff accepted, in bytes.
+const MAX_PATCH_BYTES: usize = 1 << 20;
+/// Largest file a diff may touch, in bytes.
+const MAX_FILE_BYTES: u64 = 8 << 20;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Line {
+    Context(String),
+    Remove(String),
+    Add(String),
+}
+
+#[derive(Debug, Default)]
+struct Hunk {
+    /// First line of the old text it covers, from 1; 0 for an empty file.
+    old_start: usize,
+    lines: Vec<Line>,
+    /// The new text ends inside this hunk without a newline.
+    new_no_newline: bool,
+}
+
+impl Hunk {
+    /// Takes in a "\ No newline at end of file" marker, which applies to
+    /// the line before it. One after a removed line only says the old text
+    /// ended without a newline, which the file itself shows.
+    fn no_newline(&mut self) {
+        if matches!(
+            self.lines.last(),
+            Some(Line::Add(_) | Line::Context(_)) | None
+        ) {
+            self.new_no_newline = true;
+        }
+    }
+
+    fn old_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|l| match l {
+                Line::Context(t) | Line::Remove(t) => Some(t.as_str()),
+                Line::Add(_) => None,
+            })
+            .collect()
+    }
+
+    fn new_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|l| match l {
+                Line::Context(t) | Line::Add(t) => Some(t.as_str()),
+                Line::Remove(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// The changes to one file; a path is `None` for `/dev/null`.
+#[derive(Debug, Default)]
+struct FilePatch {
+    old: Option<String>,
+    new: Option<String>,
+    hunks: Vec<Hunk>,
+}
+
+impl FilePatch {
+    fn path(&self) -> &str {
+        self.new
+            .as_deref()
+            .or(self.old.as_deref())
+            .unwrap_or_default()
+    }
+
+    fn change(&self) -> &'static str {
+        match (&self.old, &self.new) {
+            (None, _) => "create",
+            (_, None) => "delete",
+            _ => "modify",
+        }
+    }
+
+    fn counts(&self) -> (usize, usize) {
+        let lines = self.hunks.iter().flat_map(|h| &h.lines);
+        lines.fold((0, 0), |(added, removed), line| match line {
+            Line::Add(_) => (added + 1, removed),
+            Line::Remove(_) => (added, removed + 1),
+            Line::Context(_) => (added, removed),
+        })
+    }
+}
+
+/// A `---`/`+++` path with its `a/` or `b/` prefix and any trailing
+/// timestamp removed; `None` for `/dev/null`.
+fn header_path(rest: &str, prefix: &str) -> Option<String> {
+    let path = rest.split('\t').next().unwrap_or_default().trim_end();
+    if path == "/dev/null" {
+        return None;
+    }
+    Some(path.strip_prefix(prefix).unwrap_or(path).to_string())
+}
+
+/// `(start, count)` of one side of a `@@ -a,b +c,d @@` header.
+fn range(spec: &str) -> Option<(usize, usize)> {
+    let (start, count) = spec.split_once(',').unwrap_or((spec, "1"));
+    Some((start.parse().ok()?, count.parse().ok()?))
+}
+
+fn parse(diff: &str) -> Result<Vec<FilePatch>, String> {
+    let lines: Vec<&str> = diff.lines().collect();
+    let mut files = Vec::new();
+    let mut at = 0;
+    while at < lines.len() {
+        let line = lines[at];
+        if line.starts_with("GIT binary patch") || line.starts_with("Binary files ") {
+            return Err("binary patches are not supported".into());
+        }
+        let Some(old) = line.strip_prefix("--- ") else {
+            at += 1;
+            continue;
+        };
+        let new = lines
+            .get(at + 1)
+            .and_then(|l| l.strip_prefix("+++ "))
+            .ok_or_else(|| format!("line {}: \"---\" without \"+++\"", at + 1))?;
+        let mut file = FilePatch {
+            old: header_path(old, "a/"),
+            new: header_path(new, "b/"),
+            hunks: Vec::new(),
+        };
+        at += 2;
+        while let Some(header) = lines.get(at).and_then(|l| l.strip_prefix("@@ -")) {
+            let bad = || format!("line {}: malformed hunk header", at + 1);
+            let (old_spec, rest) = header.split_once(" +").ok_or_else(bad)?;
+            let new_spec = rest.split(" @@").next().ok_or_else(bad)?;
+            let (old_start, mut old_left) = range(old_spec).ok_or_else(bad)?;
+            let (_, mut new_left) = range(new_spec).ok_or_else(bad)?;
+            let mut hunk = Hunk {
+                old_start,
+                ..Hunk::default()
+            };
+            at += 1;
+            while old_left > 0 || new_left > 0 {
+                let Some(line) = lines.get(at) else {
+                    return Err(format!("{}: hunk ends early", file.path()));
+                };
+                let (kind, text) = line.split_at(line.chars().next().map_or(0, char::len_utf8));
+                let text = text.to_string();
+                match kind {
+                    // Some tools drop the space of empty context lines.
+                    " " | "" if old_left > 0 && new_left > 0 => {
+                        hunk.lines.push(Line::Context(text));
+                        old_left -= 1;
+                        new_left -= 1;
+                    }
+                    "-" if old_left > 0 => {
+                        hunk.lines.push(Line::Remove(text));
+                        old_left -= 1;
+                    }
+                    "+" if new_left > 0 => {
+                        hunk.lines.push(Line::Add(text));
+                        new_left -= 1;
+                    }
+                    "\\" => hunk.no_newline(),
+                    _ => {
+                        return Err(format!(
+                            "line {}: unexpected {line:?} in a hunk of {}",
+                            at + 1,
+                            file.path()
+                        ))
+                    }
+                }
+                at += 1;
+            }
+            while lines.get(at).is_some_and(|l| l.starts_with('\\')) {
+                hunk.no_newline();
+                at += 1;
+            }
+            file.hunks.push(hunk);
+        }
+        if file.hunks.is_empty() {
+            return Err(format!("{}: no hunks", file.path()));
+        }
+        files.push(file);
+    }
+    if files.is_empty() {
+        return Err("no file changes found; expected a unified diff".into());
+    }
+    Ok(files)
+}
+
+/// `content` with `file`'s hunks applied.
+fn apply(content: &str, file: &FilePatch) -> Result<String, String> {
+    let mut ends_with_newline = content.ends_with('\n');
+    let mut lines: Vec<&str> = content.split('\n').collect();
+    if ends_with_newline || content.is_empty() {
+        lines.pop();
+    }
+    let mut out: Vec<&str> = Vec::with_capacity(lines.len());
+    let mut cursor = 0;
+    // How far hunks so far were found from where the diff put them.
+    let mut drift: isize = 0;
+    for (n, hunk) in file.hunks.iter().enumerate() {
+        let old = hunk.old_lines();
+        // A hunk with no old lines goes after line `old_start`; any other
+        // starts at it.
+        let start = match old.len() {
+            0 => hunk.old_start,
+            _ => hunk.old_start.saturating_sub(1),
+        };
+        let expected = (start as isize + drift).max(cursor as isize);
+        let fits =
+            |at: usize| at + old.len() <= lines.len() && lines[at..at + old.len()] == old[..];
+        let found = (cursor..=lines.len().saturating_sub(old.len()))
+            .filter(|&at| fits(at))
+            .min_by_key(|&at| (at as isize - expected).unsigned_abs());
+        let Some(at) = found else {
+            return Err(format!(
+                "hunk {} of {} does not match the file near line {}",
+                n + 1,
+                file.path(),
+                hunk.old_start
+            ));
+        };
+        drift += at as isize - expected;
+        out.extend_from_slice(&lines[cursor..at]);
+        out.extend(hunk.new_lines());
+        cursor = at + old.len();
+        if cursor == lines.len() {
+            ends_with_newline = !hunk.new_no_newline;
+        }
+    }
+    out.extend_from_slice(&lines[cursor..]);
+    let mut text = out.join("\n");
+    if ends_with_newline && !out.is_empty() {
+        text.push('\n');
+    }
+    Ok(text)
+}
+
+/// A file's state after the diff: new content, or `None` when deleted.
+struct Change {
+    path: PathBuf,
+    content: Option<String>,
+}
+
+/// Exposes `apply_patch` for the files under `root`.
+pub struct PatchConnector {
+    root: PathBuf,
+}
+
+impl PatchConnector {
+    pub fn boxed() -> Box<dyn Connector> {
+        Box::new(PatchConnector {
+            root: PathBuf::new(),
+        })
+    }
+
+    /// Where `path` from a diff lives, refusing anything outside the root,
+    /// including through symlinks.
+    fn resolve(&self, path: &str) -> Result<PathBuf, String> {
+        let relative = Path::new(path);
+        let plain = relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+        if path.is_empty() || !plain {
+            return Err(format!(
+                "{path:?} is not a relative path inside the project"
+            ));
+        }
+        let full = self.root.join(relative);
+        let existing = full
+            .ancestors()
+            .find(|p| p.exists())
+            .unwrap_or(&self.root)
+            .canonicalize()
+            .map_err(|e| format!("{path}: {e}"))?;
+        if !existing.starts_with(&self.root) {
+            return Err(format!("{path} is outside the project"));
+        }
+        Ok(full)
+    }
+
+    fn read(&self, path: &Path, name: &str) -> Result<String, String> {
+        let size = std::fs::metadata(path)
+            .map_err(|e| format!("{name}: {e}"))?
+            .len();
+        if size > MAX_FILE_BYTES {
+            return Err(format!("{name} is larger than {MAX_FILE_BYTES} bytes"));
+        }
+        std::fs::read_to_string(path).map_err(|e| format!("{name}: {e}"))
+    }
+
+    /// Checks the diff against the files and works out their new content.
+    fn plan(&self, files: &[FilePatch]) -> Result<Vec<Change>, String> {
+        let mut changes: Vec<Change> = Vec::with_capacity(files.len());
+        for file in files {
+            if let (Some(old), Some(new)) = (&file.old, &file.new) {
+                if old != new {
+                    return Err(format!("{old} -> {new}: renames are not supported"));
+                }
+            }
+            let name = file.path();
+            let path = self.resolve(name)?;
+            if changes.iter().any(|c| c.path == path) {
+                return Err(format!("{name} appears twice in the diff"));
+            }
+            let current = match file.old {
+                None if path.exists() => return Err(format!("{name} already exists")),
+                None => String::new(),
+                Some(_) => self.read(&path, name)?,
+            };
+            let content = apply(&current, file)?;
+            let content = match file.new {
+                None if !content.is_empty() => {
+                    return Err(format!("{name}: deleting it leaves lines behind"))
+                }
+                None => None,
+                Some(_) => Some(content),
+            };
+            changes.push(Change { path, content });
+        }
+        Ok(changes)
+    }
+
+    /// Writes every file's new content to a temporary file beside it, then
+    /// renames them all into place and removes deleted files. A failure
+    /// while writing leaves every file as it was; only a rename or removal
+    /// failing, after all content is written, can leave some files changed.
+    fn write(changes: &[Change]) -> Result<(), String> {
+        let mut staged: Vec<(PathBuf, &Path)> = Vec::new();
+        for change in changes {
+            let Some(content) = &change.content else {
+                continue;
+            };
+            let mut tmp = change.path.as_os_str().to_owned();
+            tmp.push(".apply_patch.tmp");
+            let tmp = PathBuf::from(tmp);
+            let written = match change.path.parent() {
+                Some(dir) => std::fs::create_dir_all(dir),
+                None => Ok(()),
+            }
+            .and_then(|()| std::fs::write(&tmp, content));
+            if let Err(e) = written {
+                let _ = std::fs::remove_file(&tmp);
+                for (tmp, _) in &staged {
+                    let _ = std::fs::remove_file(tmp);
+                }
+                return Err(format!("{}: {e}", change.path.display()));
+            }
+            staged.push((tmp, &change.path));
+        }
+        for (tmp, path) in staged {
+            std::fs::rename(&tmp, path).map_err(|e| format!("{}: {e}", path.display()))?;
+        }
+        for change in changes.iter().filter(|c| c.content.is_none()) {
+            std::fs::remove_file(&change.path)
+                .map_err(|e| format!("{}: {e}", change.path.display()))?;
+        }
+        Ok(())
+    }
+
+    fn apply_patch(&self, args: &Value) -> Result<Value, ConnectorError> {
+        let diff = args["patch"]
+            .as_str()
+            .ok_or_else(|| ConnectorError::InvalidArgs("missing \"patch\"".into()))?;
+        if diff.len() > MAX_PATCH_BYTES {
+            return Err(ConnectorError::InvalidArgs(format!(
+                "patch is larger than {MAX_PATCH_BYTES} bytes"
+            )));
+        }
+        let files = parse(diff).map_err(ConnectorError::InvalidArgs)?;
+        let changes = self.plan(&files).map_err(ConnectorError::InvalidArgs)?;
+        let preview_id: String = Sha256::digest(diff.as_bytes())
+            .iter()
+            .take(8)
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        if let Some(expected) = args["preview_id"].as_str() {
+            if expected != preview_id {
+                return Err(ConnectorError::InvalidArgs(
+                    "patch differs from the previewed one".into(),
+                ));
+            }
+        }
+        let summary: Vec<Value> = files
+            .iter()
+            .map(|f| {
+                let (added, removed) = f.counts();
+                json!({ "path": f.path(), "change": f.change(), "added": added, "removed": removed })
+            })
+            .collect();
+        if args["consent"].as_bool() != Some(true) {
+            let paths: Vec<&str> = files.iter().map(FilePatch::path).collect();
+            return Ok(json!({
+                "applied": false,
+                "preview_id": preview_id,
+                "files": summary,
+                "artifact": {
+                    "kind": "patch",
+                    "title": format!("Patch to {}", paths.join(", ")),
+                    "content": diff,
+                    "mime_type": "text/x-diff",
+                },
+            }));
+        }
+        PatchConnector::write(&changes).map_err(ConnectorError::Failed)?;
+        Ok(json!({ "applied": true, "preview_id": preview_id, "files": summary }))
+    }
+}
+
+#[tonic::async_trait]
+impl Connector for PatchConnector {
+    fn name(&self) -> &str {
+        "patch"
+    }
+
+    fn configure(&mut self, config: &Value) -> Result<(), ConnectorError> {
+        let root = config["root"].as_str().ok_or_else(|| {
+            ConnectorError::InvalidConfig("patch: \"root\" must name the project directory".into())
+        })?;
+        self.root = Path::new(root)
+            .canonicalize()
+            .map_err(|e| ConnectorError::InvalidConfig(format!("patch: {root}: {e}")))?;
+        Ok(())
+    }
+
+    async fn sync(&self) -> Result<SyncReport, ConnectorError> {
+        // The files are read on each call.
+        Ok(SyncReport::default())
+    }
+
+    fn list_tools(&self) -> Vec<ToolSpec> {
+        vec![ToolSpec {
+            name: "apply_patch".into(),
+            description: format!(
+                "Edit files under {} with a unified diff. Without consent: true the diff is only \
+                 checked and previewed; apply it with consent: true once the user approves.",
+                self.root.display()
+            ),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "patch": { "type": "string", "description": "Unified diff; paths relative to the project root." },
+                    "preview_id": { "type": "string", "description": "From the preview; the call fails if the patch changed since." },
+                    "consent": { "type": "boolean" },
+                },
+                "required": ["patch"],
+            }),
+            destructive: true,
+            requires_consent: true,
+            cache_ttl: None,
+        }]
+    }
+
+    async fn call_tool(&self, tool: &str, args: Value) -> Result<Value, ConnectorError> {
+        if tool != "apply_patch" {
+            return Err(ConnectorError::UnknownTool(tool.to_string()));
+        }
+        self.apply_patch(&args)
+    }
+
+    async fn health(&self) -> Health {
+        if self.root.is_dir() {
+            Health::Ok
+        } else {
+            Health::Down(format!("{} is not a directory", self.root.display()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patched(content: &str, diff: &str) -> Result<String, String> {
+        let files = parse(diff)?;
+        apply(content, &files[0])
+    }
+
+    #[test]
+    fn zero_context_insert_goes_after_the_named_line() {
+        let diff = "--- a/f\n+++ b/f\n@@ -2,0 +3 @@\n+X\n";
+        assert_eq!(patched("a\nb\nc\n", diff).unwrap(), "a\nb\nX\nc\n");
+        let diff = "--- a/f\n+++ b/f\n@@ -0,0 +1 @@\n+X\n";
+        assert_eq!(patched("a\n", diff).unwrap(), "X\na\n");
+    }
+
+    #[test]
+    fn moved_context_is_found_nearest_the_named_line() {
+        let diff = "--- a/f\n+++ b/f\n@@ -1,2 +1,2 @@\n x\n-y\n+Y\n";
+        // The diff names line 1, but its context now starts at line 3.
+        assert_eq!(
+            patched("new\nnew\nx\ny\n", diff).unwrap(),
+            "new\nnew\nx\nY\n"
+        );
+        // Of two matches, the one nearer line 4 is taken.
+        let diff = "--- a/f\n+++ b/f\n@@ -4,1 +4,1 @@\n-x\n+X\n";
+        assert_eq!(patched("x\na\nb\nc\nx\n", diff).unwrap(), "x\na\nb\nc\nX\n");
+    }
+
+    #[test]
+    fn later_hunks_carry_the_drift_of_earlier_ones() {
+        let diff = "--- a/f\n+++ b/f\n@@ -1 +1 @@\n-a\n+A\n@@ -3 +3 @@\n-c\n+C\n";
+        assert_eq!(patched("z\na\nb\nc\n", diff).unwrap(), "z\nA\nb\nC\n");
+    }
+
+    #[test]
+    fn hunks_apply_in_order() {
+        // The second hunk's line only exists before the first one's, so
+        // it cannot match once the first has been applied.
+        let diff = "--- a/f\n+++ b/f\n@@ -2 +2 @@\n-b\n+B\n@@ -1 +1 @@\n-a\n+A\n";
+        assert!(patched("a\nb\n", diff).is_err());
+    }
+
+    #[test]
+    fn mismatched_context_is_rejected() {
+        let diff = "--- a/f\n+++ b/f\n@@ -1 +1 @@\n-q\n+Q\n";
+        assert!(patched("a\nb\n", diff).is_err());
+    }
+
+    #[test]
+    fn multi_byte_line_kinds_are_rejected() {
+        let diff = "--- a/f\n+++ b/f\n@@ -1 +1 @@\n\u{e9}a\n+A\n";
+        let err = parse(diff).unwrap_err();
+        assert!(err.contains("unexpected"), "{err}");
+    }
+
+    #[test]
+    fn no_newline_markers_apply_to_the_new_text() {
+        let diff = "--- a/f\n+++ b/f\n@@ -1 +1 @@\n-a\n+b\n\\ No newline at end of file\n";
+        assert_eq!(patched("a\n", diff).unwrap(), "b");
+    }
+}
@@ -0,0 +1,97 @@
+//! Per-tool execution policies: timeout, retries with backoff and a
+//! circuit breaker, so one flaky connector cannot stall a whole run.
+
+use crate::connector::ConnectorError;
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug)]
+pub struct ToolPolicy {
+    /// Longest a single attempt may take.
+    pub timeout: Duration,
+    /// Extra attempts after a failed or timed-out call.
+    pub retries: u32,
+    /// Delay before the first retry; doubles for each one after.
+    pub backoff: Duration,
+    /// Consecutive failed calls that open the circuit.
+    pub breaker_failures: u32,
+    /// How long an open circuit rejects calls before letting one through.
+    pub breaker_cooldown: Duration,
+}
+
+impl Default for ToolPolicy {
+    fn default() -> Self {
+        ToolPolicy {
+            timeout: Duration::from_secs(30),
+            retries: 0,
+            backoff: Duration::from_millis(200),
+            breaker_failures: 5,
+            breaker_cooldown: Duration::from_secs(60),
+        }
+    }
+}
+
+impl ToolPolicy {
+    /// Parses `{"timeout_ms", "retries", "backoff_ms", "breaker_failures",
+    /// "breaker_cooldown_secs"}`; missing fields keep their defaults.
+    pub fn from_config(config: &Value) -> Result<Self, ConnectorError> {
+        if !config.is_object() {
+            return Err(ConnectorError::InvalidConfig(
+                "a tool policy must be an object".into(),
+            ));
+        }
+        let mut policy = ToolPolicy::default();
+        if let Some(ms) = config["timeout_ms"].as_u64() {
+            policy.timeout = Duration::from_millis(ms.max(1));
+        }
+        if let Some(n) = config["retries"].as_u64() {
+            policy.retries = n as u32;
+        }
+        if let Some(ms) = config["backoff_ms"].as_u64() {
+            policy.backoff = Duration::from_millis(ms);
+        }
+        if let Some(n) = config["breaker_failures"].as_u64() {
+            policy.breaker_failures = (n as u32).max(1);
+        }
+        if let Some(secs) = config["breaker_cooldown_secs"].as_u64() {
+            policy.breaker_cooldown = Duration::from_secs(secs);
+        }
+        Ok(policy)
+    }
+
+    /// Delay before retry number `attempt` (1-based).
+    pub fn backoff_for(&self, attempt: u32) -> Duration {
+        self.backoff.saturating_mul(1 << (attempt - 1).min(16))
+    }
+}
+
+/// Failures that say something about the connector rather than the caller,
+/// and so are worth retrying and count towards the breaker.
+pub fn is_transient(e: &ConnectorError) -> bool {
+    matches!(e, ConnectorError::Failed(_) | ConnectorError::Timeout(_))
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl Breaker {
+    pub fn is_open(&self) -> bool {
+        self.open_until.is_some_and(|until| until > Instant::now())
+    }
+
+    pub fn record_success(&mut self) {
+        *self = Breaker::default();
+    }
+
+    /// Once open, the first call after the cooldown is a probe: another
+    /// failure reopens the circuit straight away.
+    pub fn record_failure(&mut self, policy: &ToolPolicy) {
+        self.failures += 1;
+        if self.failures >= policy.breaker_failures {
+            self.open_until = Some(Instant::now() + policy.breaker_cooldown);
+        }
+    }
+}
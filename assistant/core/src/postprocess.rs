@@ -0,0 +1,126 @@
+//! Post-processing chain applied to chat output before it reaches the
+//! client, so automation gets clean text without client-side munging.
+
+use crate::text;
+use regex::Regex;
+use serde_json::Value;
+use std::sync::LazyLock;
+
+#[derive(Clone, Debug)]
+pub enum Step {
+    /// Removes `<think>…</think>` / `<reasoning>…</reasoning>` blocks.
+    StripReasoning,
+    /// Drops markdown syntax, keeping the text.
+    PlainText,
+    /// Truncates to at most this many grapheme clusters.
+    MaxLength(usize),
+    /// Regex find/replace; `with` may use `$1`-style captures.
+    Replace { pattern: Regex, with: String },
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Chain {
+    steps: Vec<Step>,
+}
+
+impl Chain {
+    /// Parses a list like
+    /// `[{"op": "strip_reasoning"}, {"op": "max_length", "chars": 2000},
+    ///   {"op": "replace", "pattern": "\\s+$", "with": ""}]`.
+    pub fn from_config(config: &Value) -> Result<Self, String> {
+        let Some(items) = config.as_array() else {
+            return Err("postprocess must be a list of steps".into());
+        };
+        let mut steps = Vec::with_capacity(items.len());
+        for item in items {
+            let step = match item["op"].as_str() {
+                Some("strip_reasoning") => Step::StripReasoning,
+                Some("plain_text") => Step::PlainText,
+                Some("max_length") => {
+                    // `chars` counts grapheme clusters, not `char`s.
+                    let max = item["chars"].as_u64().ok_or("max_length needs \"chars\"")?;
+                    Step::MaxLength(max as usize)
+                }
+                Some("replace") => {
+                    let pattern = item["pattern"]
+                        .as_str()
+                        .ok_or("replace needs \"pattern\"")?;
+                    Step::Replace {
+                        pattern: Regex::new(pattern).map_err(|e| e.to_string())?,
+                        with: item["with"].as_str().unwrap_or_default().to_string(),
+                    }
+                }
+                other => return Err(format!("unknown postprocess op {other:?}")),
+            };
+            steps.push(step);
+        }
+        Ok(Chain { steps })
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        let mut out = text.to_string();
+        for step in &self.steps {
+            out = match step {
+                Step::StripReasoning => strip_reasoning(&out),
+                Step::PlainText => plain_text(&out),
+                Step::MaxLength(max) => text::truncate(&out, *max).to_string(),
+                Step::Replace { pattern, with } => {
+                    pattern.replace_all(&out, with.as_str()).into_owned()
+                }
+            };
+        }
+        out
+    }
+}
+
+const REASONING_TAGS: &[(&str, &str)] = &[("<think>", "</think>"), ("<reasoning>", "</reasoning>")];
+
+pub fn strip_reasoning(text: &str) -> String {
+    split_reasoning(text).0
+}
+
+/// Separates reasoning blocks from the answer: returns `(content, reasoning)`.
+pub fn split_reasoning(text: &str) -> (String, String) {
+    let mut content = text.to_string();
+    let mut reasoning = Vec::new();
+    for (open, close) in REASONING_TAGS {
+        while let Some(start) = content.find(open) {
+            let body = start + open.len();
+            match content[body..].find(close) {
+                Some(len) => {
+                    reasoning.push(content[body..body + len].trim().to_string());
+                    content.replace_range(start..body + len + close.len(), "");
+                }
+                // An unclosed block runs to the end of the output.
+                None => {
+                    reasoning.push(content[body..].trim().to_string());
+                    content.truncate(start);
+                }
+            }
+        }
+    }
+    (content.trim().to_string(), reasoning.join("\n\n"))
+}
+
+/// Markdown links and images, keeping their text.
+static LINKS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"!?\[([^\]]*)\]\([^)]*\)").unwrap());
+static EMPHASIS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(\*\*|__|\*|`)").unwrap());
+
+fn plain_text(text: &str) -> String {
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") {
+            continue;
+        }
+        let line = trimmed.trim_start_matches('#').trim_start();
+        let line = line
+            .strip_prefix("> ")
+            .or_else(|| line.strip_prefix("* "))
+            .unwrap_or(line);
+        lines.push(line.to_string());
+    }
+    let text = lines.join("\n");
+    let text = LINKS.replace_all(&text, "$1");
+    EMPHASIS.replace_all(&text, "").into_owned()
+}
@@ -0,0 +1,85 @@
+//! How much the core writes down about what is searched. The level is set
+//! globally by the `privacy` key of the logging config, and a collection
+//! may set its own, which wins for queries against it.
+//!
+//! - `log_nothing`: queries leave no log record.
+//! - `log_metadata` (the default): the query log records the collection,
+//!   mode, hit count and timing, never query text or document ids, and a
+//!   panic is reported by its location only.
+//! - `log_full`: query text and hit ids are logged too, as are panic
+//!   messages, which can quote indexed text.
+//!
+//! Other log lines that name a document or file, such as a failed removal
+//! or a skipped file, name it only at `log_full`; below it they go through
+//! [`Privacy::redact`].
+
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privacy {
+    Nothing,
+    #[default]
+    Metadata,
+    Full,
+}
+
+static GLOBAL: AtomicU8 = AtomicU8::new(Privacy::Metadata as u8);
+
+impl Privacy {
+    pub const NAMES: &'static [&'static str] = &["log_nothing", "log_metadata", "log_full"];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "log_nothing" => Some(Privacy::Nothing),
+            "log_metadata" => Some(Privacy::Metadata),
+            "log_full" => Some(Privacy::Full),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        Privacy::NAMES[self as usize]
+    }
+
+    /// Validates a collection's setting; empty means the global level.
+    pub fn pick(value: &str) -> Result<String, String> {
+        match value {
+            "" => Ok(String::new()),
+            v => Privacy::parse(v)
+                .map(|p| p.name().to_string())
+                .ok_or_else(|| {
+                    format!(
+                        "unsupported privacy level {v:?}; supported: {}",
+                        Privacy::NAMES.join(", ")
+                    )
+                }),
+        }
+    }
+
+    /// The level in force for a collection set to `configured`.
+    pub fn resolve(configured: &str) -> Self {
+        Privacy::parse(configured).unwrap_or_else(Privacy::global)
+    }
+
+    pub fn global() -> Self {
+        match GLOBAL.load(Ordering::Relaxed) {
+            0 => Privacy::Nothing,
+            1 => Privacy::Metadata,
+            _ => Privacy::Full,
+        }
+    }
+
+    /// `what`, a document id, file path or other content, if this level
+    /// logs it; a placeholder otherwise.
+    pub fn redact(self, what: impl fmt::Display) -> String {
+        match self {
+            Privacy::Full => what.to_string(),
+            _ => "<redacted>".to_string(),
+        }
+    }
+
+    pub fn set_global(self) {
+        GLOBAL.store(self as u8, Ordering::Relaxed);
+    }
+}
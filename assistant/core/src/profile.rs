@@ -0,0 +1,183 @@
+//! Named profiles: per-use-case settings selected by `Request.profile`.
+
+use crate::assemble::Allocation;
+use crate::chat::AnswerPolicy;
+use crate::connector::{ConnectorError, ToolSpec};
+use crate::injection;
+use crate::postprocess::Chain;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Which tools a profile may call, and which need the user's consent.
+#[derive(Clone, Debug, Default)]
+pub struct ToolPermissions {
+    /// Tools callable under the profile; `None` allows all of them.
+    pub allow: Option<BTreeSet<String>>,
+    /// Tools that need `"consent": true` under this profile, on top of
+    /// those that always do.
+    pub confirm: BTreeSet<String>,
+}
+
+impl ToolPermissions {
+    /// Parses `{"allow": ["<tool>", ...], "confirm": ["<tool>", ...]}`.
+    pub(crate) fn from_config(config: &Value) -> Result<Self, String> {
+        let names = |key: &str| -> Result<Option<BTreeSet<String>>, String> {
+            match &config[key] {
+                Value::Null => Ok(None),
+                Value::Array(items) => items
+                    .iter()
+                    .map(|item| {
+                        item.as_str()
+                            .map(str::to_string)
+                            .ok_or_else(|| format!("tools.{key} must list tool names"))
+                    })
+                    .collect::<Result<_, _>>()
+                    .map(Some),
+                _ => Err(format!("tools.{key} must be a list")),
+            }
+        };
+        Ok(ToolPermissions {
+            allow: names("allow")?,
+            confirm: names("confirm")?.unwrap_or_default(),
+        })
+    }
+
+    pub fn allows(&self, tool: &str) -> bool {
+        self.allow.as_ref().is_none_or(|allow| allow.contains(tool))
+    }
+
+    /// Whether calls to `tool` must carry `"consent": true`.
+    pub fn requires_confirmation(&self, tool: &ToolSpec) -> bool {
+        tool.requires_consent || self.confirm.contains(&tool.name)
+    }
+
+    /// Checks a call to `tool` with `args` before it is made.
+    pub fn check(&self, tool: &ToolSpec, args: &Value) -> Result<(), ConnectorError> {
+        if !self.allows(&tool.name) {
+            return Err(ConnectorError::NotAllowed(tool.name.clone()));
+        }
+        if self.confirm.contains(&tool.name) && args["consent"].as_bool() != Some(true) {
+            return Err(ConnectorError::ConsentRequired(tool.name.clone()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    pub name: String,
+    /// Applied to every chat answer produced under this profile.
+    pub postprocess: Chain,
+    /// Model answering under this profile; `None` uses the default.
+    pub model: Option<String>,
+    /// System prompt placed first in the model input.
+    pub system: String,
+    /// How the context window is shared out.
+    pub context: Allocation,
+    pub tools: ToolPermissions,
+    /// Ceiling on streamed tokens per second; requests may ask for less.
+    pub max_tokens_per_second: Option<f64>,
+    /// What to do with retrieved chunks and tool output that look like
+    /// prompt injection.
+    pub injection: injection::Action,
+    /// When to say no grounding was found instead of answering.
+    pub answer_policy: AnswerPolicy,
+}
+
+impl Profile {
+    fn from_config(name: &str, config: &Value) -> Result<Self, String> {
+        let postprocess = match config.get("postprocess") {
+            Some(steps) => Chain::from_config(steps).map_err(|e| format!("{name}: {e}"))?,
+            None => Chain::default(),
+        };
+        let context = match config.get("context") {
+            Some(section) => {
+                Allocation::from_config(section).map_err(|e| format!("{name}: {e}"))?
+            }
+            None => Allocation::default(),
+        };
+        let tools = match config.get("tools") {
+            Some(section) => {
+                ToolPermissions::from_config(section).map_err(|e| format!("{name}: {e}"))?
+            }
+            None => ToolPermissions::default(),
+        };
+        let max_tokens_per_second = match &config["max_tokens_per_second"] {
+            Value::Null => None,
+            v => match v.as_f64() {
+                Some(rate) if rate > 0.0 => Some(rate),
+                _ => {
+                    return Err(format!(
+                        "{name}: max_tokens_per_second must be a positive number"
+                    ))
+                }
+            },
+        };
+        let injection = match &config["injection"] {
+            Value::Null => injection::Action::default(),
+            v => v
+                .as_str()
+                .and_then(injection::Action::parse)
+                .ok_or_else(|| {
+                    format!("{name}: injection must be \"neutralize\", \"flag\" or \"off\"")
+                })?,
+        };
+        let answer_policy = match &config["answer_policy"] {
+            Value::Null => AnswerPolicy::default(),
+            section => AnswerPolicy::from_config(section).map_err(|e| format!("{name}: {e}"))?,
+        };
+        Ok(Profile {
+            name: name.to_string(),
+            postprocess,
+            model: config["model"].as_str().map(str::to_string),
+            system: config["system"].as_str().unwrap_or_default().to_string(),
+            context,
+            tools,
+            max_tokens_per_second,
+            injection,
+            answer_policy,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Profiles {
+    profiles: BTreeMap<String, Profile>,
+    fallback: Profile,
+}
+
+impl Default for Profiles {
+    fn default() -> Self {
+        Profiles {
+            profiles: BTreeMap::new(),
+            fallback: Profile {
+                name: "default".into(),
+                ..Profile::default()
+            },
+        }
+    }
+}
+
+impl Profiles {
+    /// Parses `{"<name>": {"model", "system", "context", "postprocess", "tools", "injection", "answer_policy"}, ...}`. A profile named
+    /// `default` is used for requests that do not name one.
+    pub fn from_config(config: &Value) -> Result<Self, String> {
+        let Some(entries) = config.as_object() else {
+            return Err("profiles config must be an object".into());
+        };
+        let mut profiles = Profiles::default();
+        for (name, section) in entries {
+            let profile = Profile::from_config(name, section)?;
+            if name == "default" {
+                profiles.fallback = profile.clone();
+            }
+            profiles.profiles.insert(name.clone(), profile);
+        }
+        Ok(profiles)
+    }
+
+    /// Looks up a profile; empty or unknown names get the default.
+    pub fn get(&self, name: &str) -> &Profile {
+        self.profiles.get(name).unwrap_or(&self.fallback)
+    }
+}
@@ -0,0 +1,439 @@
+//! Compact codes for an index's vectors, set per collection, so queries
+//! can score entries without reading their full vectors. The full vectors
+//! stay in the index file, memory-mapped, for rescoring the best matches
+//! and for training the codes again; a query only pages in the few it
+//! rescores.
+//!
+//! Codes are scored by asymmetric distance computation: the query keeps
+//! its full precision and is compared with each code as it stands, so
+//! only the stored side loses precision. What is approximated is the dot
+//! product; each entry's exact length is stored next to its code, which
+//! is enough to turn that into any [`Metric`].
+
+use crate::index::in_parallel;
+use crate::metric::Metric;
+use crate::simd;
+use crate::vectors::Vectors;
+use std::io::{self, Write};
+use std::ops::Range;
+
+/// How a collection's vectors are compressed in memory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Quantization {
+    /// Full vectors only.
+    #[default]
+    None,
+    /// One byte per float: each dimension is mapped linearly onto 0..=255
+    /// between the lowest and highest value it takes, about 4x smaller.
+    Int8,
+    /// Product quantization: one byte per [`PQ_WIDTH`] floats, naming the
+    /// nearest of 256 centroids learned for those floats, about 30x
+    /// smaller per entry and coarser.
+    Pq,
+}
+
+impl Quantization {
+    pub const NAMES: &'static [&'static str] = &["none", "int8", "pq"];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "" | "none" => Some(Quantization::None),
+            "int8" => Some(Quantization::Int8),
+            "pq" => Some(Quantization::Pq),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Quantization::None => "none",
+            Quantization::Int8 => "int8",
+            Quantization::Pq => "pq",
+        }
+    }
+
+    /// How many times as many entries as a query wants to score exactly,
+    /// from the best by code. Coarser codes need more to find the same
+    /// hits.
+    pub fn rescore(self) -> usize {
+        match self {
+            Quantization::None => 1,
+            Quantization::Int8 => 4,
+            Quantization::Pq => 16,
+        }
+    }
+
+    fn tag(self) -> u32 {
+        match self {
+            Quantization::None => 0,
+            Quantization::Int8 => 1,
+            Quantization::Pq => 2,
+        }
+    }
+}
+
+/// Fewest vectors codes are trained on; smaller indexes keep using their
+/// full vectors, which take little memory anyway.
+pub const MIN_TRAINING: usize = 256;
+/// Floats per product-quantization subvector.
+pub const PQ_WIDTH: usize = 8;
+/// Centroids per subvector, so that a code fits in a byte.
+const CENTROIDS: usize = 256;
+/// Most vectors product-quantization centroids are learned from.
+const PQ_SAMPLE: usize = 4096;
+/// k-means rounds per subvector.
+const PQ_ROUNDS: usize = 8;
+
+enum Codec {
+    /// Per dimension, the value code 0 stands for and the step per code.
+    Int8 { low: Vec<f32>, step: Vec<f32> },
+    /// Per subvector, [`CENTROIDS`] centroids of [`PQ_WIDTH`] floats; the
+    /// last subvector's are zero-padded when `dim` is not a multiple.
+    Pq { centroids: Vec<f32> },
+}
+
+/// The codes of an index's entries, in entry order.
+pub struct Codes {
+    dim: usize,
+    codec: Codec,
+    /// Entries in the index when the codec was trained.
+    trained_on: usize,
+    /// `code_len` bytes per entry.
+    codes: Vec<u8>,
+    /// Squared length of each entry's full vector.
+    norms: Vec<f32>,
+}
+
+/// Floats `m * PQ_WIDTH..` of a `dim`-float vector.
+fn subvector(m: usize, dim: usize) -> Range<usize> {
+    m * PQ_WIDTH..((m + 1) * PQ_WIDTH).min(dim)
+}
+
+impl Codes {
+    /// Codes of `kind` for every vector in `vectors`, trained on them, or
+    /// `None` if there are fewer than [`MIN_TRAINING`].
+    pub fn train(kind: Quantization, vectors: &Vectors) -> Option<Codes> {
+        if vectors.len() < MIN_TRAINING {
+            return None;
+        }
+        let dim = vectors.dim();
+        let codec = match kind {
+            Quantization::None => return None,
+            Quantization::Int8 => {
+                let mut low = vec![f32::INFINITY; dim];
+                let mut high = vec![f32::NEG_INFINITY; dim];
+                for at in 0..vectors.len() {
+                    for (i, &x) in vectors.get(at).iter().enumerate() {
+                        low[i] = low[i].min(x);
+                        high[i] = high[i].max(x);
+                    }
+                }
+                let step = low
+                    .iter()
+                    .zip(&high)
+                    .map(|(l, h)| (h - l) / 255.0)
+                    .collect();
+                Codec::Int8 { low, step }
+            }
+            Quantization::Pq => {
+                // An even spread of the vectors, so the same index always
+                // learns the same centroids.
+                let taken = vectors.len().min(PQ_SAMPLE);
+                let sample: Vec<&[f32]> = (0..taken)
+                    .map(|i| vectors.get(i * vectors.len() / taken))
+                    .collect();
+                let subvectors: Vec<usize> = (0..dim.div_ceil(PQ_WIDTH)).collect();
+                let learned = in_parallel(subvectors, |m| kmeans(&sample, subvector(m, dim)));
+                Codec::Pq {
+                    centroids: learned.concat(),
+                }
+            }
+        };
+        let mut codes = Codes {
+            dim,
+            codec,
+            trained_on: vectors.len(),
+            codes: Vec::new(),
+            norms: Vec::new(),
+        };
+        let encoded = in_parallel((0..vectors.len()).collect(), |at| {
+            codes.encode(vectors.get(at))
+        });
+        for (code, norm) in encoded {
+            codes.codes.extend_from_slice(&code);
+            codes.norms.push(norm);
+        }
+        Some(codes)
+    }
+
+    pub fn kind(&self) -> Quantization {
+        match self.codec {
+            Codec::Int8 { .. } => Quantization::Int8,
+            Codec::Pq { .. } => Quantization::Pq,
+        }
+    }
+
+    /// Entries in the index when the codes were trained.
+    pub fn trained_on(&self) -> usize {
+        self.trained_on
+    }
+
+    pub fn len(&self) -> usize {
+        self.norms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.norms.is_empty()
+    }
+
+    /// Bytes the codes and codec take in memory.
+    pub fn memory(&self) -> usize {
+        let codec = match &self.codec {
+            Codec::Int8 { low, step } => low.len() + step.len(),
+            Codec::Pq { centroids } => centroids.len(),
+        };
+        self.codes.len() + 4 * (self.norms.len() + codec)
+    }
+
+    fn code_len(&self) -> usize {
+        match self.codec {
+            Codec::Int8 { .. } => self.dim,
+            Codec::Pq { .. } => self.dim.div_ceil(PQ_WIDTH),
+        }
+    }
+
+    /// The code of `vector` and its squared length.
+    fn encode(&self, vector: &[f32]) -> (Vec<u8>, f32) {
+        let norm = simd::dot(vector, vector);
+        let code = match &self.codec {
+            Codec::Int8 { low, step } => vector
+                .iter()
+                .zip(low.iter().zip(step))
+                .map(|(x, (l, s))| {
+                    if *s > 0.0 {
+                        ((x - l) / s).round().clamp(0.0, 255.0) as u8
+                    } else {
+                        0
+                    }
+                })
+                .collect(),
+            Codec::Pq { centroids } => (0..self.code_len())
+                .map(|m| {
+                    let range = subvector(m, self.dim);
+                    let table = &centroids[m * CENTROIDS * PQ_WIDTH..][..CENTROIDS * PQ_WIDTH];
+                    nearest(table, &vector[range]) as u8
+                })
+                .collect(),
+        };
+        (code, norm)
+    }
+
+    /// Adds the code of a new last entry.
+    pub fn push(&mut self, vector: &[f32]) {
+        let (code, norm) = self.encode(vector);
+        self.codes.extend_from_slice(&code);
+        self.norms.push(norm);
+    }
+
+    /// Replaces the code of entry `at`.
+    pub fn set(&mut self, at: usize, vector: &[f32]) {
+        let (code, norm) = self.encode(vector);
+        let len = self.code_len();
+        self.codes[at * len..(at + 1) * len].copy_from_slice(&code);
+        self.norms[at] = norm;
+    }
+
+    /// Keeps the codes of the entries for which `keep` is true.
+    pub fn retain(&mut self, keep: &[bool]) {
+        let len = self.code_len();
+        let mut codes = Vec::with_capacity(self.codes.len());
+        for (at, code) in self.codes.chunks_exact(len).enumerate() {
+            if keep.get(at).copied().unwrap_or(true) {
+                codes.extend_from_slice(code);
+            }
+        }
+        self.codes = codes;
+        let mut keep = keep.iter();
+        self.norms.retain(|_| *keep.next().unwrap_or(&true));
+    }
+
+    /// Scores entries against `query` by `metric`, or `None` if the query
+    /// does not have the codes' dimensions.
+    pub fn scorer(&self, query: &[f32], metric: Metric) -> Option<Scorer<'_>> {
+        if query.len() != self.dim {
+            return None;
+        }
+        let lookup = match &self.codec {
+            Codec::Int8 { low, step } => Lookup::Int8 {
+                scaled: query.iter().zip(step).map(|(q, s)| q * s).collect(),
+                offset: simd::dot(query, low),
+            },
+            Codec::Pq { centroids } => {
+                let mut table = Vec::with_capacity(self.code_len() * CENTROIDS);
+                for m in 0..self.code_len() {
+                    let range = subvector(m, self.dim);
+                    let width = range.len();
+                    let sub = &query[range];
+                    let centroids = &centroids[m * CENTROIDS * PQ_WIDTH..];
+                    table.extend(
+                        (0..CENTROIDS).map(|k| simd::dot(sub, &centroids[k * PQ_WIDTH..][..width])),
+                    );
+                }
+                Lookup::Pq { table }
+            }
+        };
+        Some(Scorer {
+            codes: self,
+            metric,
+            query_norm: simd::dot(query, query),
+            lookup,
+        })
+    }
+
+    /// Writes the codes as [`read`](Self::read) takes them: codec kind
+    /// (u32), entries trained on (u64), then the norms, the codec's floats
+    /// and the codes, all little-endian.
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(&self.kind().tag().to_le_bytes())?;
+        out.write_all(&(self.trained_on as u64).to_le_bytes())?;
+        let floats: Box<dyn Iterator<Item = &f32>> = match &self.codec {
+            Codec::Int8 { low, step } => Box::new(self.norms.iter().chain(low).chain(step)),
+            Codec::Pq { centroids } => Box::new(self.norms.iter().chain(centroids)),
+        };
+        for x in floats {
+            out.write_all(&x.to_le_bytes())?;
+        }
+        out.write_all(&self.codes)
+    }
+
+    /// Writes that there are no codes, in place of [`write`](Self::write).
+    pub fn write_none(out: &mut impl Write) -> io::Result<()> {
+        out.write_all(&Quantization::None.tag().to_le_bytes())
+    }
+
+    /// Reads codes for `count` vectors of `dim` floats written by
+    /// [`write`](Self::write) or [`write_none`](Self::write_none), which
+    /// must fill `data`.
+    pub fn read(data: &[u8], count: usize, dim: usize) -> io::Result<Option<Codes>> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let truncated = || invalid("index file is truncated");
+        let tag = data.get(..4).ok_or_else(truncated)?;
+        let kind = match u32::from_le_bytes(tag.try_into().unwrap()) {
+            0 if data.len() == 4 => return Ok(None),
+            1 => Quantization::Int8,
+            2 => Quantization::Pq,
+            _ => return Err(invalid("index file has unknown vector codes")),
+        };
+        let trained_on = data.get(4..12).ok_or_else(truncated)?;
+        let trained_on = u64::from_le_bytes(trained_on.try_into().unwrap()) as usize;
+        let (codec_floats, code_len) = match kind {
+            Quantization::Pq => {
+                let subvectors = dim.div_ceil(PQ_WIDTH);
+                (subvectors * CENTROIDS * PQ_WIDTH, subvectors)
+            }
+            _ => (2 * dim, dim),
+        };
+        let floats_end = 12 + 4 * (count + codec_floats);
+        if data.len() != floats_end + count * code_len {
+            return Err(truncated());
+        }
+        let mut floats = data[12..floats_end]
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()));
+        let norms: Vec<f32> = floats.by_ref().take(count).collect();
+        let codec = match kind {
+            Quantization::Pq => Codec::Pq {
+                centroids: floats.collect(),
+            },
+            _ => Codec::Int8 {
+                low: floats.by_ref().take(dim).collect(),
+                step: floats.collect(),
+            },
+        };
+        Ok(Some(Codes {
+            dim,
+            codec,
+            trained_on,
+            codes: data[floats_end..].to_vec(),
+            norms,
+        }))
+    }
+}
+
+/// What a query precomputes to score codes.
+enum Lookup {
+    /// The query scaled by each dimension's step, and its dot product with
+    /// the values code 0 stands for.
+    Int8 { scaled: Vec<f32>, offset: f32 },
+    /// Per subvector and centroid, the dot product of the query's floats
+    /// with the centroid.
+    Pq { table: Vec<f32> },
+}
+
+/// Scores entries' codes against one query.
+pub struct Scorer<'a> {
+    codes: &'a Codes,
+    metric: Metric,
+    /// Squared length of the query.
+    query_norm: f32,
+    lookup: Lookup,
+}
+
+impl Scorer<'_> {
+    /// The approximate score of entry `at`.
+    pub fn score(&self, at: usize) -> f32 {
+        let len = self.codes.code_len();
+        let code = &self.codes.codes[at * len..(at + 1) * len];
+        let dot = match &self.lookup {
+            Lookup::Int8 { scaled, offset } => offset + simd::dot_u8(scaled, code),
+            Lookup::Pq { table } => code
+                .iter()
+                .enumerate()
+                .map(|(m, &c)| table[m * CENTROIDS + c as usize])
+                .sum(),
+        };
+        self.metric
+            .from_dot(dot, self.query_norm, self.codes.norms[at])
+    }
+}
+
+/// Learns [`CENTROIDS`] centroids for floats `range` of `sample` by
+/// k-means, each padded to [`PQ_WIDTH`] floats. `sample` has at least as
+/// many vectors as there are centroids.
+fn kmeans(sample: &[&[f32]], range: Range<usize>) -> Vec<f32> {
+    let width = range.len();
+    let points: Vec<&[f32]> = sample.iter().map(|v| &v[range.clone()]).collect();
+    let mut centroids = vec![0.0f32; CENTROIDS * PQ_WIDTH];
+    for k in 0..CENTROIDS {
+        let point = points[k * points.len() / CENTROIDS];
+        centroids[k * PQ_WIDTH..][..width].copy_from_slice(point);
+    }
+    for _ in 0..PQ_ROUNDS {
+        let mut sums = vec![0.0f32; CENTROIDS * width];
+        let mut counts = [0usize; CENTROIDS];
+        for point in &points {
+            let k = nearest(&centroids, point);
+            counts[k] += 1;
+            for (sum, x) in sums[k * width..][..width].iter_mut().zip(*point) {
+                *sum += x;
+            }
+        }
+        // A centroid nothing is nearest to stays where it is.
+        for k in (0..CENTROIDS).filter(|&k| counts[k] > 0) {
+            for i in 0..width {
+                centroids[k * PQ_WIDTH + i] = sums[k * width + i] / counts[k] as f32;
+            }
+        }
+    }
+    centroids
+}
+
+/// The centroid in `centroids` nearest to `point`.
+fn nearest(centroids: &[f32], point: &[f32]) -> usize {
+    let width = point.len();
+    (0..CENTROIDS)
+        .map(|k| simd::distance2(point, &centroids[k * PQ_WIDTH..][..width]))
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map_or(0, |(k, _)| k)
+}
@@ -0,0 +1,11 @@
+//! Rerankers rescore a query's best hits by reading the query and each
+//! hit's text together. That ranks better than comparing embeddings made
+//! apart, but costs a model run per hit, so only the top hits are
+//! reranked. A server offers one when it loads a cross-encoder model.
+
+pub trait Reranker: Send + Sync {
+    /// Name it is reported and logged by.
+    fn name(&self) -> &str;
+    /// Relevance of `text` to `query`; higher is more relevant.
+    fn score(&self, query: &str, text: &str) -> f32;
+}
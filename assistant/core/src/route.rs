@@ -0,0 +1,184 @@
+//! Routing: picks a profile for chat requests that do not name one, from
+//! rules over a coarse request category or the prompt text.
+
+use regex::Regex;
+use serde_json::Value;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Category {
+    Code,
+    Email,
+    Scheduling,
+    General,
+}
+
+impl Category {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Category::Code => "code",
+            Category::Email => "email",
+            Category::Scheduling => "scheduling",
+            Category::General => "general",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        [
+            Category::Code,
+            Category::Email,
+            Category::Scheduling,
+            Category::General,
+        ]
+        .into_iter()
+        .find(|c| c.as_str() == name)
+    }
+}
+
+/// Lowercase cue words per category. A prompt goes to the category with
+/// the most cues; ties and no cues at all mean `General`.
+const CUES: &[(Category, &[&str])] = &[
+    (
+        Category::Code,
+        &[
+            "```",
+            "fn ",
+            "def ",
+            "class ",
+            "import ",
+            "function",
+            "compile",
+            "compiler",
+            "stack trace",
+            "traceback",
+            "exception",
+            "refactor",
+            "regex",
+            "bug",
+            "rust",
+            "python",
+            "javascript",
+            "typescript",
+            "sql",
+            "unit test",
+        ],
+    ),
+    (
+        Category::Email,
+        &[
+            "email",
+            "e-mail",
+            "inbox",
+            "reply to",
+            "draft a reply",
+            "subject:",
+            "cc ",
+            "unsubscribe",
+            "newsletter",
+            "mail",
+        ],
+    ),
+    (
+        Category::Scheduling,
+        &[
+            "meeting",
+            "schedule",
+            "calendar",
+            "reschedule",
+            "availability",
+            "available",
+            "appointment",
+            "tomorrow at",
+            "next week",
+            "book a",
+            "invite",
+        ],
+    ),
+];
+
+/// Heuristic classifier; cheap enough to run on every request.
+pub fn classify(prompt: &str) -> Category {
+    let prompt = prompt.to_lowercase();
+    let mut best = (Category::General, 0);
+    let mut tied = false;
+    for (category, cues) in CUES {
+        let score = cues.iter().filter(|cue| prompt.contains(*cue)).count();
+        if score > best.1 {
+            best = (*category, score);
+            tied = false;
+        } else if score > 0 && score == best.1 {
+            tied = true;
+        }
+    }
+    if tied {
+        Category::General
+    } else {
+        best.0
+    }
+}
+
+#[derive(Debug)]
+enum Matcher {
+    Category(Category),
+    Pattern(Regex),
+}
+
+#[derive(Debug)]
+struct Rule {
+    matcher: Matcher,
+    profile: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Router {
+    rules: Vec<Rule>,
+}
+
+/// Where a request was routed.
+#[derive(Clone, Debug)]
+pub struct Route {
+    pub category: Category,
+    /// The first matching rule's profile, if any rule matched.
+    pub profile: Option<String>,
+}
+
+impl Router {
+    /// Parses `{"rules": [{"category": "code", "profile": "coder"},
+    /// {"pattern": "(?i)invoice", "profile": "billing"}, ...]}`.
+    /// Rules are tried in order and the first match wins.
+    pub fn from_config(config: &Value) -> Result<Self, String> {
+        let Some(items) = config["rules"].as_array() else {
+            return Err("routes config needs a \"rules\" list".into());
+        };
+        let mut rules = Vec::with_capacity(items.len());
+        for item in items {
+            let profile = item["profile"]
+                .as_str()
+                .ok_or("each rule needs a \"profile\"")?
+                .to_string();
+            let matcher = match (item["category"].as_str(), item["pattern"].as_str()) {
+                (Some(name), None) => Matcher::Category(
+                    Category::parse(name).ok_or_else(|| format!("unknown category {name:?}"))?,
+                ),
+                (None, Some(pattern)) => {
+                    Matcher::Pattern(Regex::new(pattern).map_err(|e| e.to_string())?)
+                }
+                _ => return Err("each rule needs one of \"category\" or \"pattern\"".into()),
+            };
+            rules.push(Rule { matcher, profile });
+        }
+        Ok(Router { rules })
+    }
+
+    pub fn route(&self, prompt: &str) -> Route {
+        let category = classify(prompt);
+        let profile = self
+            .rules
+            .iter()
+            .find(|rule| match &rule.matcher {
+                Matcher::Category(c) => *c == category,
+                Matcher::Pattern(re) => re.is_match(prompt),
+            })
+            .map(|rule| rule.profile.clone());
+        Route { category, profile }
+    }
+}
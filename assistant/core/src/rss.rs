@@ -0,0 +1,294 @@
+//! RSS/Atom feed connector. Fetches the configured feeds on an interval,
+//! keeps each entry once (by GUID) and splits article text into chunks.
+//! New entries are returned from each sync as documents, with their feed,
+//! title and date as metadata, for the registry to index.
+
+use crate::assistant::Document;
+use crate::connector::{Connector, ConnectorError, Health, SyncReport, ToolSpec};
+use crate::docid;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+const DEFAULT_INTERVAL_SECS: u64 = 30 * 60;
+const DEFAULT_CHUNK_CHARS: usize = 1000;
+/// Articles kept for `feed_latest`; older ones are dropped first. They
+/// stay in the index.
+const MAX_ARTICLES: usize = 1000;
+/// GUIDs remembered as seen. Feeds only list their recent entries, so one
+/// forgotten and seen again is rare, and only indexed again in place.
+const MAX_SEEN: usize = 10_000;
+
+struct Article {
+    guid: String,
+    feed: String,
+    title: String,
+    link: Option<String>,
+    published: Option<DateTime<Utc>>,
+    fetched_at: DateTime<Utc>,
+    chunks: Vec<String>,
+}
+
+#[derive(Default)]
+struct FeedState {
+    seen: HashSet<String>,
+    /// `seen`, oldest first.
+    seen_order: VecDeque<String>,
+    articles: VecDeque<Article>,
+    last_sync: Option<DateTime<Utc>>,
+    /// Per-feed errors from the most recent sync.
+    errors: Vec<String>,
+}
+
+pub struct RssConnector {
+    feeds: Vec<String>,
+    interval: Duration,
+    chunk_chars: usize,
+    client: reqwest::Client,
+    state: Mutex<FeedState>,
+}
+
+impl RssConnector {
+    pub fn boxed() -> Box<dyn Connector> {
+        Box::new(RssConnector {
+            feeds: Vec::new(),
+            interval: Duration::from_secs(DEFAULT_INTERVAL_SECS),
+            chunk_chars: DEFAULT_CHUNK_CHARS,
+            client: reqwest::Client::new(),
+            state: Mutex::new(FeedState::default()),
+        })
+    }
+
+    async fn fetch(&self, url: &str) -> Result<feed_rs::model::Feed, String> {
+        let body = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| format!("{url}: {e}"))?
+            .bytes()
+            .await
+            .map_err(|e| format!("{url}: {e}"))?;
+        feed_rs::parser::parse(&body[..]).map_err(|e| format!("{url}: {e}"))
+    }
+
+    fn latest(&self, args: &Value) -> Result<Value, ConnectorError> {
+        let since = match args["since"].as_str() {
+            Some(s) => Some(
+                DateTime::parse_from_rfc3339(s)
+                    .map_err(|e| ConnectorError::InvalidArgs(format!("since: {e}")))?
+                    .with_timezone(&Utc),
+            ),
+            None => None,
+        };
+        let limit = args["limit"].as_u64().unwrap_or(20) as usize;
+        let state = self.state.lock().unwrap();
+        let mut hits: Vec<&Article> = state
+            .articles
+            .iter()
+            .filter(|a| since.is_none_or(|t| a.published.unwrap_or(a.fetched_at) >= t))
+            .collect();
+        hits.sort_by_key(|a| std::cmp::Reverse(a.published.unwrap_or(a.fetched_at)));
+        let items: Vec<Value> = hits
+            .into_iter()
+            .take(limit)
+            .map(|a| {
+                json!({
+                    "guid": a.guid,
+                    "source": a.feed,
+                    "title": a.title,
+                    "link": a.link,
+                    "date": a.published.unwrap_or(a.fetched_at).to_rfc3339(),
+                    "chunks": a.chunks,
+                })
+            })
+            .collect();
+        Ok(Value::Array(items))
+    }
+}
+
+#[tonic::async_trait]
+impl Connector for RssConnector {
+    fn name(&self) -> &str {
+        "rss"
+    }
+
+    fn configure(&mut self, config: &Value) -> Result<(), ConnectorError> {
+        let feeds = config["feeds"].as_array().ok_or_else(|| {
+            ConnectorError::InvalidConfig("rss: \"feeds\" must be a list of URLs".into())
+        })?;
+        self.feeds = feeds
+            .iter()
+            .filter_map(|f| f.as_str().map(str::to_string))
+            .collect();
+        if let Some(secs) = config["interval_secs"].as_u64() {
+            self.interval = Duration::from_secs(secs.max(60));
+        }
+        if let Some(chars) = config["chunk_chars"].as_u64() {
+            self.chunk_chars = (chars as usize).max(100);
+        }
+        Ok(())
+    }
+
+    fn sync_interval(&self) -> Option<Duration> {
+        Some(self.interval)
+    }
+
+    async fn sync(&self) -> Result<SyncReport, ConnectorError> {
+        let mut report = SyncReport::default();
+        let mut errors = Vec::new();
+        let mut fresh = Vec::new();
+        for url in &self.feeds {
+            match self.fetch(url).await {
+                Ok(feed) => {
+                    report.fetched += feed.entries.len();
+                    fresh.extend(feed.entries.into_iter().map(|e| (url.clone(), e)));
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+
+        let now = Utc::now();
+        let mut state = self.state.lock().unwrap();
+        for (feed, entry) in fresh {
+            if !state.seen.insert(entry.id.clone()) {
+                continue;
+            }
+            state.seen_order.push_back(entry.id.clone());
+            if state.seen_order.len() > MAX_SEEN {
+                if let Some(oldest) = state.seen_order.pop_front() {
+                    state.seen.remove(&oldest);
+                }
+            }
+            let body = entry
+                .content
+                .and_then(|c| c.body)
+                .or(entry.summary.map(|s| s.content))
+                .unwrap_or_default();
+            let article = Article {
+                guid: entry.id,
+                feed,
+                title: entry.title.map(|t| t.content).unwrap_or_default(),
+                link: entry.links.into_iter().next().map(|l| l.href),
+                published: entry.published.or(entry.updated),
+                fetched_at: now,
+                chunks: chunk_text(&strip_tags(&body), self.chunk_chars),
+            };
+            report.documents.push(document(&article));
+            state.articles.push_back(article);
+            if state.articles.len() > MAX_ARTICLES {
+                state.articles.pop_front();
+            }
+            report.updated += 1;
+        }
+        state.last_sync = Some(now);
+        let all_failed = !self.feeds.is_empty() && errors.len() == self.feeds.len();
+        state.errors = errors;
+        if all_failed {
+            return Err(ConnectorError::Failed(state.errors.join("; ")));
+        }
+        Ok(report)
+    }
+
+    fn list_tools(&self) -> Vec<ToolSpec> {
+        vec![ToolSpec {
+            name: "feed_latest".into(),
+            description: "List recent feed articles, newest first. Args: since (RFC 3339), limit."
+                .into(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "since": { "type": "string", "format": "date-time" },
+                    "limit": { "type": "integer", "minimum": 1 },
+                },
+            }),
+            destructive: false,
+            requires_consent: false,
+            // Articles only change on sync, which clears the cache.
+            cache_ttl: Some(Duration::from_secs(300)),
+        }]
+    }
+
+    async fn call_tool(&self, tool: &str, args: Value) -> Result<Value, ConnectorError> {
+        match tool {
+            "feed_latest" => self.latest(&args),
+            _ => Err(ConnectorError::UnknownTool(tool.to_string())),
+        }
+    }
+
+    async fn health(&self) -> Health {
+        let state = self.state.lock().unwrap();
+        match (state.last_sync, state.errors.len()) {
+            (None, _) => Health::Degraded("not synced yet".into()),
+            (Some(_), 0) => Health::Ok,
+            (Some(_), n) if n == self.feeds.len() => Health::Down(state.errors.join("; ")),
+            (Some(_), _) => Health::Degraded(state.errors.join("; ")),
+        }
+    }
+}
+
+/// `article` as a document under its link, or its GUID if it has none,
+/// with its title ahead of its text.
+fn document(article: &Article) -> Document {
+    let (id, provenance) = docid::url(article.link.as_deref().unwrap_or(&article.guid), None);
+    let date = article.published.unwrap_or(article.fetched_at).to_rfc3339();
+    Document {
+        id,
+        text: format!("{}\n\n{}", article.title, article.chunks.join(" ")),
+        source: provenance.source,
+        mime_type: "text/plain".to_string(),
+        metadata: [
+            ("kind".to_string(), "article".to_string()),
+            ("feed".to_string(), article.feed.clone()),
+            ("title".to_string(), article.title.clone()),
+            ("date".to_string(), date),
+        ]
+        .into(),
+        ..Default::default()
+    }
+}
+
+/// Drops markup from feed HTML, keeping text and collapsing whitespace.
+fn strip_tags(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                out.push(' ');
+            }
+            _ if !in_tag => out.push(c),
+            _ => {}
+        }
+    }
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Splits text into chunks of at most `max_chars`, breaking on whitespace.
+fn chunk_text(text: &str, max_chars: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut len = 0;
+    for word in text.split_whitespace() {
+        let word_len = word.chars().count();
+        if len > 0 && len + 1 + word_len > max_chars {
+            chunks.push(std::mem::take(&mut current));
+            len = 0;
+        }
+        if len > 0 {
+            current.push(' ');
+            len += 1;
+        }
+        current.push_str(word);
+        len += word_len;
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
@@ -0,0 +1,318 @@
+//! Tool runs: an ordered list of steps, each a set of independent tool
+//! calls. Calls within a step execute concurrently (up to a per-run cap)
+//! and their observations are merged before the next step starts.
+//! A run stops early when it exhausts its [`Budget`].
+
+use crate::assemble::approx_tokens;
+use crate::connector::{ConnectorError, ConnectorRegistry};
+use crate::injection::{self, Warning};
+use futures_util::{stream, StreamExt};
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_CONCURRENCY: usize = 4;
+
+#[derive(Clone, Debug)]
+pub struct ToolCall {
+    pub tool: String,
+    pub args: Value,
+}
+
+impl ToolCall {
+    pub fn to_json(&self) -> Value {
+        json!({ "tool": self.tool, "args": self.args })
+    }
+}
+
+/// Hard limits on a run; `None` means unlimited.
+#[derive(Clone, Debug, Default)]
+pub struct Budget {
+    pub max_tool_calls: Option<usize>,
+    pub max_wall: Option<Duration>,
+    /// Limit on observation tokens, i.e. tool output a model would have
+    /// to read back.
+    pub max_tokens: Option<usize>,
+}
+
+impl Budget {
+    /// Parses `{"max_tool_calls", "max_wall_ms", "max_tokens"}`.
+    pub fn from_config(config: &Value) -> Self {
+        Budget {
+            max_tool_calls: config["max_tool_calls"].as_u64().map(|n| n as usize),
+            max_wall: config["max_wall_ms"].as_u64().map(Duration::from_millis),
+            max_tokens: config["max_tokens"].as_u64().map(|n| n as usize),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RunOptions {
+    /// Most tool calls in flight at once within a step.
+    pub max_concurrency: usize,
+    pub budget: Budget,
+    /// Simulate destructive tools instead of calling them.
+    pub dry_run: bool,
+    /// Screening of tool output for prompt injection; set from the
+    /// request's profile, not the payload.
+    pub injection: injection::Action,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        RunOptions {
+            max_concurrency: DEFAULT_MAX_CONCURRENCY,
+            budget: Budget::default(),
+            dry_run: false,
+            injection: injection::Action::default(),
+        }
+    }
+}
+
+pub struct Run {
+    pub steps: Vec<Vec<ToolCall>>,
+    pub options: RunOptions,
+}
+
+impl Run {
+    /// Parses `{"steps": [[{"tool", "args"}, ...], ...], "max_concurrency": n}`.
+    /// A single call may also be given as `{"calls": [...]}` for one step,
+    /// and limits as `"budget": {...}` (see [`Budget::from_config`]).
+    pub fn from_payload(payload: &Value) -> Result<Self, String> {
+        let steps = match (payload.get("steps"), payload.get("calls")) {
+            (Some(steps), _) => steps
+                .as_array()
+                .ok_or("\"steps\" must be a list")?
+                .iter()
+                .map(parse_calls)
+                .collect::<Result<Vec<_>, _>>()?,
+            (None, Some(calls)) => vec![parse_calls(calls)?],
+            (None, None) => return Err("run needs \"steps\" or \"calls\"".into()),
+        };
+        let mut options = RunOptions::default();
+        if let Some(n) = payload["max_concurrency"].as_u64() {
+            options.max_concurrency = (n as usize).max(1);
+        }
+        options.budget = Budget::from_config(&payload["budget"]);
+        options.dry_run = payload["dry_run"].as_bool().unwrap_or(false);
+        Ok(Run { steps, options })
+    }
+}
+
+fn parse_calls(calls: &Value) -> Result<Vec<ToolCall>, String> {
+    calls
+        .as_array()
+        .ok_or("each step must be a list of calls")?
+        .iter()
+        .map(|call| {
+            let tool = call["tool"].as_str().ok_or("call is missing \"tool\"")?;
+            Ok(ToolCall {
+                tool: tool.to_string(),
+                args: call["args"].clone(),
+            })
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug)]
+pub struct Observation {
+    pub tool: String,
+    pub result: Result<Value, (i32, String)>,
+    pub elapsed: Duration,
+    /// True when a dry run stood in for the real call.
+    pub simulated: bool,
+}
+
+impl Observation {
+    pub fn to_json(&self) -> Value {
+        let mut out = json!({
+            "tool": self.tool,
+            "ms": self.elapsed.as_millis() as u64,
+        });
+        if self.simulated {
+            out["simulated"] = json!(true);
+        }
+        match &self.result {
+            Ok(value) => out["result"] = value.clone(),
+            Err((status, message)) => {
+                out["status"] = json!(status);
+                out["error"] = json!(message);
+            }
+        }
+        out
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Usage {
+    pub tool_calls: usize,
+    pub wall: Duration,
+    pub tokens: usize,
+}
+
+impl Usage {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "tool_calls": self.tool_calls,
+            "wall_ms": self.wall.as_millis() as u64,
+            "tokens": self.tokens,
+        })
+    }
+}
+
+/// Terminal event for a run stopped by its budget.
+#[derive(Clone, Debug)]
+pub struct BudgetExceeded {
+    /// `"tool_calls"`, `"wall_time"` or `"tokens"`.
+    pub budget: &'static str,
+    pub limit: u64,
+    pub used: u64,
+}
+
+impl BudgetExceeded {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "event": "budget_exceeded",
+            "budget": self.budget,
+            "limit": self.limit,
+            "used": self.used,
+        })
+    }
+}
+
+pub struct Outcome {
+    pub steps: Vec<Vec<Observation>>,
+    pub usage: Usage,
+    /// Destructive calls a dry run skipped, in the order they came up.
+    pub actions: Vec<ToolCall>,
+    /// Set when the run stopped before finishing all steps.
+    pub exceeded: Option<BudgetExceeded>,
+    /// Tool results screened as possible prompt injection.
+    pub warnings: Vec<Warning>,
+}
+
+/// Executes a run step by step. Observations come back in call order
+/// regardless of completion order.
+///
+/// Budgets are checked between steps. A step that would go over the tool
+/// call limit runs only the calls that fit. Calls still in flight when the
+/// wall-time budget runs out are abandoned with status 504.
+pub async fn execute(registry: &ConnectorRegistry, run: &Run) -> Outcome {
+    let budget = &run.options.budget;
+    let clock = Instant::now();
+    let deadline = budget
+        .max_wall
+        .map(|wall| tokio::time::Instant::from_std(clock + wall));
+    let mut outcome = Outcome {
+        steps: Vec::with_capacity(run.steps.len()),
+        usage: Usage::default(),
+        actions: Vec::new(),
+        exceeded: None,
+        warnings: Vec::new(),
+    };
+    for calls in &run.steps {
+        let allowed = budget.max_tool_calls.map_or(calls.len(), |max| {
+            calls
+                .len()
+                .min(max.saturating_sub(outcome.usage.tool_calls))
+        });
+        let pending: Vec<_> = calls[..allowed]
+            .iter()
+            .map(|call| observe(registry, call, deadline, run.options.dry_run))
+            .collect();
+        let mut observations: Vec<Observation> = stream::iter(pending)
+            .buffered(run.options.max_concurrency)
+            .collect()
+            .await;
+        for (call, observation) in observations.iter_mut().enumerate() {
+            let Ok(result) = &mut observation.result else {
+                continue;
+            };
+            let patterns = injection::screen_value(result, run.options.injection);
+            if !patterns.is_empty() {
+                let warning = Warning {
+                    source: injection::Source::Tool {
+                        tool: observation.tool.clone(),
+                        step: outcome.steps.len(),
+                        call,
+                    },
+                    patterns,
+                    neutralized: run.options.injection == injection::Action::Neutralize,
+                };
+                warning.log();
+                outcome.warnings.push(warning);
+            }
+        }
+        outcome.usage.tool_calls += observations.len();
+        outcome.actions.extend(
+            calls
+                .iter()
+                .zip(&observations)
+                .filter(|(_, o)| o.simulated)
+                .map(|(call, _)| call.clone()),
+        );
+        outcome.usage.tokens += observations
+            .iter()
+            .filter_map(|o| o.result.as_ref().ok())
+            .map(|value| approx_tokens(&value.to_string()))
+            .sum::<usize>();
+        outcome.usage.wall = clock.elapsed();
+        if !observations.is_empty() {
+            outcome.steps.push(observations);
+        }
+        if allowed < calls.len() {
+            outcome.exceeded = Some(BudgetExceeded {
+                budget: "tool_calls",
+                limit: budget.max_tool_calls.unwrap_or_default() as u64,
+                used: outcome.usage.tool_calls as u64,
+            });
+            break;
+        }
+        if let Some(max) = budget.max_tokens.filter(|&max| outcome.usage.tokens > max) {
+            outcome.exceeded = Some(BudgetExceeded {
+                budget: "tokens",
+                limit: max as u64,
+                used: outcome.usage.tokens as u64,
+            });
+            break;
+        }
+        if let Some(wall) = budget.max_wall.filter(|&wall| outcome.usage.wall >= wall) {
+            outcome.exceeded = Some(BudgetExceeded {
+                budget: "wall_time",
+                limit: wall.as_millis() as u64,
+                used: outcome.usage.wall.as_millis() as u64,
+            });
+            break;
+        }
+    }
+    outcome
+}
+
+async fn observe(
+    registry: &ConnectorRegistry,
+    call: &ToolCall,
+    deadline: Option<tokio::time::Instant>,
+    dry_run: bool,
+) -> Observation {
+    let clock = Instant::now();
+    if dry_run && registry.tool(&call.tool).is_some_and(|t| t.destructive) {
+        return Observation {
+            tool: call.tool.clone(),
+            result: Ok(json!({ "simulated": true, "would_call": call.to_json() })),
+            elapsed: clock.elapsed(),
+            simulated: true,
+        };
+    }
+    let pending = registry.call_tool(&call.tool, call.args.clone());
+    let result = match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, pending)
+            .await
+            .unwrap_or_else(|_| Err(ConnectorError::Timeout(call.tool.clone()))),
+        None => pending.await,
+    };
+    Observation {
+        tool: call.tool.clone(),
+        result: result.map_err(|e| (e.status(), e.to_string())),
+        elapsed: clock.elapsed(),
+        simulated: false,
+    }
+}
@@ -0,0 +1,230 @@
+//! Chat sessions persisted as `<dir>/<session_id>.json`, one file per
+//! session holding its title and completed turns.
+//!
+//! A session started in a workspace belongs to it: it can only be read,
+//! continued or renamed from that workspace, and is only listed there.
+//! Sessions started outside any workspace are likewise kept out of them.
+
+use crate::text;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Turn {
+    pub prompt: String,
+    /// Content as generated (after post-processing).
+    pub answer: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub reasoning: String,
+    /// Stream deltas produced; there is no tokenizer yet, so this counts
+    /// whitespace-separated pieces.
+    pub tokens: usize,
+    pub model: String,
+    /// RFC 3339 time the turn started.
+    pub started_at: String,
+    pub duration_ms: u64,
+    #[serde(default)]
+    pub citations: Vec<String>,
+    /// False when the client went away before the stream finished.
+    pub delivered: bool,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    /// Human-readable name for session lists. Set from the first exchange
+    /// unless the session is renamed.
+    #[serde(default)]
+    pub title: String,
+    /// Workspace the session was started in; empty for none.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub workspace: String,
+    pub turns: Vec<Turn>,
+}
+
+impl Session {
+    /// Fails with `PermissionDenied` unless the session belongs to
+    /// `workspace`.
+    pub fn check_workspace(&self, workspace: &str) -> io::Result<()> {
+        if self.workspace == workspace {
+            return Ok(());
+        }
+        let owner = match self.workspace.as_str() {
+            "" => "no workspace".to_string(),
+            name => format!("workspace {name}"),
+        };
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("session {} belongs to {owner}", self.id),
+        ))
+    }
+}
+
+/// A session as listed, without its turns.
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub title: String,
+    pub turns: usize,
+    /// When the last turn started; lists are sorted by it, newest first.
+    pub updated_at: String,
+}
+
+/// Most words and characters in a generated title.
+const TITLE_WORDS: usize = 6;
+const TITLE_CHARS: usize = 60;
+
+/// Title for a session that opened with `prompt`. There is no model
+/// backend to summarize the exchange, so this takes the first words of
+/// the prompt's first line.
+pub fn title_for(prompt: &str) -> String {
+    let line = prompt.lines().map(str::trim).find(|l| !l.is_empty());
+    let mut title = String::new();
+    for word in line
+        .unwrap_or_default()
+        .split_whitespace()
+        .take(TITLE_WORDS)
+    {
+        if !title.is_empty() && title.chars().count() + word.chars().count() >= TITLE_CHARS {
+            break;
+        }
+        if !title.is_empty() {
+            title.push(' ');
+        }
+        title.push_str(word);
+    }
+    text::truncate(&title, TITLE_CHARS)
+        .trim_end_matches(|c: char| c.is_ascii_punctuation())
+        .to_string()
+}
+
+pub struct SessionStore {
+    dir: PathBuf,
+    /// Serializes read-modify-write of session files.
+    lock: Mutex<()>,
+}
+
+impl SessionStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        SessionStore {
+            dir: dir.into(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn path(&self, id: &str) -> io::Result<PathBuf> {
+        let valid = !id.is_empty()
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'));
+        if !valid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid session id: {id:?}"),
+            ));
+        }
+        Ok(self.dir.join(format!("{id}.json")))
+    }
+
+    /// Returns the session if it belongs to `workspace`, or `NotFound` if
+    /// it has no turns yet.
+    pub fn get_in(&self, id: &str, workspace: &str) -> io::Result<Session> {
+        let session = self.get(id)?;
+        session.check_workspace(workspace)?;
+        Ok(session)
+    }
+
+    /// Returns the session, or `NotFound` if it has no turns yet.
+    pub fn get(&self, id: &str) -> io::Result<Session> {
+        let data = std::fs::read(self.path(id)?).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => {
+                io::Error::new(io::ErrorKind::NotFound, format!("no session named {id}"))
+            }
+            _ => e,
+        })?;
+        serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Saves `turn`, titling the session after it if it is the first. A
+    /// new session is started in `workspace`; an existing one must
+    /// belong to it.
+    pub fn append(&self, id: &str, workspace: &str, turn: Turn) -> io::Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        let mut session = match self.get_in(id, workspace) {
+            Ok(session) => session,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Session {
+                id: id.to_string(),
+                workspace: workspace.to_string(),
+                ..Session::default()
+            },
+            Err(e) => return Err(e),
+        };
+        if session.title.is_empty() {
+            session.title = title_for(&turn.prompt);
+        }
+        session.turns.push(turn);
+        self.save(&session)
+    }
+
+    /// Sets the title of an existing session in `workspace`.
+    pub fn rename(&self, id: &str, workspace: &str, title: &str) -> io::Result<()> {
+        let title = title.trim();
+        if title.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a session title cannot be empty",
+            ));
+        }
+        let _guard = self.lock.lock().unwrap();
+        let mut session = self.get_in(id, workspace)?;
+        session.title = title.to_string();
+        self.save(&session)
+    }
+
+    /// Every saved session in `workspace`, most recently used first.
+    pub fn list(&self, workspace: &str) -> io::Result<Vec<SessionInfo>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut out = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let Some(id) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            // Files that no longer parse are skipped rather than failing
+            // the whole list.
+            let Ok(session) = self.get(id) else {
+                continue;
+            };
+            if session.workspace != workspace {
+                continue;
+            }
+            out.push(SessionInfo {
+                updated_at: session
+                    .turns
+                    .last()
+                    .map(|t| t.started_at.clone())
+                    .unwrap_or_default(),
+                turns: session.turns.len(),
+                title: session.title,
+                id: session.id,
+            });
+        }
+        out.sort_by(|a, b| b.updated_at.cmp(&a.updated_at).then(a.id.cmp(&b.id)));
+        Ok(out)
+    }
+
+    fn save(&self, session: &Session) -> io::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let data = serde_json::to_vec_pretty(session)?;
+        std::fs::write(self.path(&session.id)?, data)
+    }
+}
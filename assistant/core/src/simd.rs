@@ -0,0 +1,244 @@
+//! Vector kernels behind [`Metric::score`](crate::metric::Metric::score),
+//! the inner loop of every query. On x86_64 they use AVX2 and FMA when the
+//! CPU has them, checked once at run time; on aarch64 they use NEON, which
+//! every such CPU has. Elsewhere, and for the last few floats, they fall
+//! back to plain loops.
+//!
+//! The vector paths add up in a different order than the plain loops, so
+//! results can differ in the last bits.
+
+/// Sums of `a·b`, `a·a` and `b·b`, as cosine needs them.
+pub fn dot3(a: &[f32], b: &[f32]) -> (f32, f32, f32) {
+    let n = a.len().min(b.len());
+    let (a, b) = (&a[..n], &b[..n]);
+    #[cfg(target_arch = "x86_64")]
+    if x86::available() {
+        // SAFETY: the CPU has AVX2 and FMA, checked above.
+        return unsafe { x86::dot3(a, b) };
+    }
+    // SAFETY: NEON is part of the aarch64 baseline.
+    #[cfg(target_arch = "aarch64")]
+    return unsafe { arm::dot3(a, b) };
+    #[cfg(not(target_arch = "aarch64"))]
+    scalar::dot3(a, b)
+}
+
+/// `a·b`.
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len().min(b.len());
+    let (a, b) = (&a[..n], &b[..n]);
+    #[cfg(target_arch = "x86_64")]
+    if x86::available() {
+        // SAFETY: as in `dot3`.
+        return unsafe { x86::dot(a, b) };
+    }
+    // SAFETY: as in `dot3`.
+    #[cfg(target_arch = "aarch64")]
+    return unsafe { arm::dot(a, b) };
+    #[cfg(not(target_arch = "aarch64"))]
+    scalar::dot(a, b)
+}
+
+/// Squared L2 distance between `a` and `b`.
+pub fn distance2(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len().min(b.len());
+    let (a, b) = (&a[..n], &b[..n]);
+    #[cfg(target_arch = "x86_64")]
+    if x86::available() {
+        // SAFETY: as in `dot3`.
+        return unsafe { x86::distance2(a, b) };
+    }
+    // SAFETY: as in `dot3`.
+    #[cfg(target_arch = "aarch64")]
+    return unsafe { arm::distance2(a, b) };
+    #[cfg(not(target_arch = "aarch64"))]
+    scalar::distance2(a, b)
+}
+
+/// `a·b` with `b` given as bytes, for scoring int8-quantized vectors.
+pub fn dot_u8(a: &[f32], b: &[u8]) -> f32 {
+    let n = a.len().min(b.len());
+    let (a, b) = (&a[..n], &b[..n]);
+    #[cfg(target_arch = "x86_64")]
+    if x86::available() {
+        // SAFETY: as in `dot3`.
+        return unsafe { x86::dot_u8(a, b) };
+    }
+    // SAFETY: as in `dot3`.
+    #[cfg(target_arch = "aarch64")]
+    return unsafe { arm::dot_u8(a, b) };
+    #[cfg(not(target_arch = "aarch64"))]
+    scalar::dot_u8(a, b)
+}
+
+/// Plain loops, for other targets and the floats after the last full
+/// vector register.
+mod scalar {
+    pub fn dot3(a: &[f32], b: &[f32]) -> (f32, f32, f32) {
+        let (mut ab, mut aa, mut bb) = (0.0f32, 0.0f32, 0.0f32);
+        for (x, y) in a.iter().zip(b) {
+            ab += x * y;
+            aa += x * x;
+            bb += y * y;
+        }
+        (ab, aa, bb)
+    }
+
+    pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+        a.iter().zip(b).map(|(x, y)| x * y).sum()
+    }
+
+    pub fn distance2(a: &[f32], b: &[f32]) -> f32 {
+        a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+    }
+
+    pub fn dot_u8(a: &[f32], b: &[u8]) -> f32 {
+        a.iter().zip(b).map(|(x, &y)| x * y as f32).sum()
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use super::scalar;
+    use std::arch::x86_64::*;
+    use std::sync::OnceLock;
+
+    /// Floats per AVX register.
+    const LANES: usize = 8;
+
+    pub fn available() -> bool {
+        static AVAILABLE: OnceLock<bool> = OnceLock::new();
+        *AVAILABLE
+            .get_or_init(|| is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma"))
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    unsafe fn sum(v: __m256) -> f32 {
+        let half = _mm_add_ps(_mm256_castps256_ps128(v), _mm256_extractf128_ps(v, 1));
+        let quarter = _mm_add_ps(half, _mm_movehl_ps(half, half));
+        _mm_cvtss_f32(_mm_add_ss(quarter, _mm_shuffle_ps(quarter, quarter, 1)))
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn dot3(a: &[f32], b: &[f32]) -> (f32, f32, f32) {
+        let full = a.len() / LANES * LANES;
+        let (mut ab, mut aa, mut bb) = (
+            _mm256_setzero_ps(),
+            _mm256_setzero_ps(),
+            _mm256_setzero_ps(),
+        );
+        for at in (0..full).step_by(LANES) {
+            let x = _mm256_loadu_ps(a.as_ptr().add(at));
+            let y = _mm256_loadu_ps(b.as_ptr().add(at));
+            ab = _mm256_fmadd_ps(x, y, ab);
+            aa = _mm256_fmadd_ps(x, x, aa);
+            bb = _mm256_fmadd_ps(y, y, bb);
+        }
+        let (tab, taa, tbb) = scalar::dot3(&a[full..], &b[full..]);
+        (sum(ab) + tab, sum(aa) + taa, sum(bb) + tbb)
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn dot(a: &[f32], b: &[f32]) -> f32 {
+        let full = a.len() / (2 * LANES) * (2 * LANES);
+        // Two accumulators, so consecutive FMAs do not wait on each other.
+        let (mut s0, mut s1) = (_mm256_setzero_ps(), _mm256_setzero_ps());
+        for at in (0..full).step_by(2 * LANES) {
+            let (pa, pb) = (a.as_ptr().add(at), b.as_ptr().add(at));
+            s0 = _mm256_fmadd_ps(_mm256_loadu_ps(pa), _mm256_loadu_ps(pb), s0);
+            s1 = _mm256_fmadd_ps(
+                _mm256_loadu_ps(pa.add(LANES)),
+                _mm256_loadu_ps(pb.add(LANES)),
+                s1,
+            );
+        }
+        sum(_mm256_add_ps(s0, s1)) + scalar::dot(&a[full..], &b[full..])
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn distance2(a: &[f32], b: &[f32]) -> f32 {
+        let full = a.len() / LANES * LANES;
+        let mut total = _mm256_setzero_ps();
+        for at in (0..full).step_by(LANES) {
+            let d = _mm256_sub_ps(
+                _mm256_loadu_ps(a.as_ptr().add(at)),
+                _mm256_loadu_ps(b.as_ptr().add(at)),
+            );
+            total = _mm256_fmadd_ps(d, d, total);
+        }
+        sum(total) + scalar::distance2(&a[full..], &b[full..])
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn dot_u8(a: &[f32], b: &[u8]) -> f32 {
+        let full = a.len() / LANES * LANES;
+        let mut total = _mm256_setzero_ps();
+        for at in (0..full).step_by(LANES) {
+            let bytes = _mm_loadl_epi64(b.as_ptr().add(at).cast());
+            let y = _mm256_cvtepi32_ps(_mm256_cvtepu8_epi32(bytes));
+            total = _mm256_fmadd_ps(_mm256_loadu_ps(a.as_ptr().add(at)), y, total);
+        }
+        sum(total) + scalar::dot_u8(&a[full..], &b[full..])
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arm {
+    use super::scalar;
+    use std::arch::aarch64::*;
+
+    /// Floats per NEON register.
+    const LANES: usize = 4;
+
+    pub unsafe fn dot3(a: &[f32], b: &[f32]) -> (f32, f32, f32) {
+        let full = a.len() / LANES * LANES;
+        let (mut ab, mut aa, mut bb) = (vdupq_n_f32(0.0), vdupq_n_f32(0.0), vdupq_n_f32(0.0));
+        for at in (0..full).step_by(LANES) {
+            let x = vld1q_f32(a.as_ptr().add(at));
+            let y = vld1q_f32(b.as_ptr().add(at));
+            ab = vfmaq_f32(ab, x, y);
+            aa = vfmaq_f32(aa, x, x);
+            bb = vfmaq_f32(bb, y, y);
+        }
+        let (tab, taa, tbb) = scalar::dot3(&a[full..], &b[full..]);
+        (
+            vaddvq_f32(ab) + tab,
+            vaddvq_f32(aa) + taa,
+            vaddvq_f32(bb) + tbb,
+        )
+    }
+
+    pub unsafe fn dot(a: &[f32], b: &[f32]) -> f32 {
+        let full = a.len() / LANES * LANES;
+        let mut total = vdupq_n_f32(0.0);
+        for at in (0..full).step_by(LANES) {
+            let x = vld1q_f32(a.as_ptr().add(at));
+            let y = vld1q_f32(b.as_ptr().add(at));
+            total = vfmaq_f32(total, x, y);
+        }
+        vaddvq_f32(total) + scalar::dot(&a[full..], &b[full..])
+    }
+
+    pub unsafe fn distance2(a: &[f32], b: &[f32]) -> f32 {
+        let full = a.len() / LANES * LANES;
+        let mut total = vdupq_n_f32(0.0);
+        for at in (0..full).step_by(LANES) {
+            let d = vsubq_f32(vld1q_f32(a.as_ptr().add(at)), vld1q_f32(b.as_ptr().add(at)));
+            total = vfmaq_f32(total, d, d);
+        }
+        vaddvq_f32(total) + scalar::distance2(&a[full..], &b[full..])
+    }
+
+    pub unsafe fn dot_u8(a: &[f32], b: &[u8]) -> f32 {
+        let full = a.len() / (2 * LANES) * (2 * LANES);
+        let mut total = vdupq_n_f32(0.0);
+        for at in (0..full).step_by(2 * LANES) {
+            let wide = vmovl_u8(vld1_u8(b.as_ptr().add(at)));
+            let low = vcvtq_f32_u32(vmovl_u16(vget_low_u16(wide)));
+            let high = vcvtq_f32_u32(vmovl_u16(vget_high_u16(wide)));
+            total = vfmaq_f32(total, vld1q_f32(a.as_ptr().add(at)), low);
+            total = vfmaq_f32(total, vld1q_f32(a.as_ptr().add(at + LANES)), high);
+        }
+        vaddvq_f32(total) + scalar::dot_u8(&a[full..], &b[full..])
+    }
+}
@@ -0,0 +1,259 @@
+//! Where a collection's entries are kept. A [`DocStore`] holds each entry
+//! with its embedding under its id.
+//!
+//! Collections are stored as an index file and its write-ahead log by
+//! default, and [`VectorIndex`] implements the trait over them. With the
+//! `rocksdb` feature a collection can be created with `storage: rocksdb`
+//! instead: [`RocksStore`] keeps its entries in a RocksDB database at
+//! `<dir>/<name>.rocksdb`, which takes each write without rewriting the
+//! rest, for very large collections written to often. The index still
+//! holds its entries in memory to search them; it writes to the store
+//! instead of its log, and reads the store once when opened.
+
+use crate::index::{Doc, VectorIndex};
+use std::io;
+
+pub trait DocStore: Send + Sync {
+    /// The entry with this id, with its embedding.
+    fn get(&self, id: &str) -> io::Result<Option<Doc>>;
+
+    /// Adds the entries, with their embeddings, replacing those with the
+    /// same ids, in a single write.
+    fn put(&mut self, docs: &[Doc]) -> io::Result<()>;
+
+    /// Removes the entries with these ids, in a single write. Ids that are
+    /// not stored are ignored.
+    fn delete(&mut self, ids: &[String]) -> io::Result<()>;
+
+    /// Up to `limit` entries, with their embeddings, in the store's own
+    /// order, from the one after id `after` on, or from the first if it
+    /// is empty.
+    fn scan(&self, after: &str, limit: usize) -> io::Result<Vec<Doc>>;
+}
+
+impl DocStore for VectorIndex {
+    fn get(&self, id: &str) -> io::Result<Option<Doc>> {
+        Ok(self
+            .position(id)
+            .and_then(|at| self.entries(at, 1, true).pop()))
+    }
+
+    fn put(&mut self, docs: &[Doc]) -> io::Result<()> {
+        self.insert_entries(docs.to_vec())
+    }
+
+    fn delete(&mut self, ids: &[String]) -> io::Result<()> {
+        self.remove(ids.to_vec()).map(|_| ())
+    }
+
+    fn scan(&self, after: &str, limit: usize) -> io::Result<Vec<Doc>> {
+        let at = match after {
+            "" => 0,
+            after => match self.position(after) {
+                Some(at) => at + 1,
+                None => return Ok(Vec::new()),
+            },
+        };
+        Ok(self.entries(at, limit, true))
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+pub use rocks::RocksStore;
+
+#[cfg(feature = "rocksdb")]
+mod rocks {
+    use super::DocStore;
+    use crate::index::Doc;
+    use rocksdb::{Direction, IteratorMode, Options, WriteBatch, WriteOptions, DB};
+    use std::io;
+    use std::path::Path;
+
+    /// Entries in a RocksDB database, keyed by id. Each value is the
+    /// length of the entry's JSON as a little-endian `u32`, the JSON, and
+    /// the embedding as little-endian `f32`s.
+    pub struct RocksStore {
+        db: DB,
+    }
+
+    fn error(e: rocksdb::Error) -> io::Error {
+        io::Error::other(e)
+    }
+
+    fn encode(doc: &Doc) -> io::Result<Vec<u8>> {
+        let json = serde_json::to_vec(doc)?;
+        let mut value = Vec::with_capacity(4 + json.len() + doc.embedding.len() * 4);
+        value.extend_from_slice(&(json.len() as u32).to_le_bytes());
+        value.extend_from_slice(&json);
+        for x in &doc.embedding {
+            value.extend_from_slice(&x.to_le_bytes());
+        }
+        Ok(value)
+    }
+
+    fn decode(value: &[u8]) -> io::Result<Doc> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "truncated entry");
+        let (len, rest) = value.split_first_chunk::<4>().ok_or_else(invalid)?;
+        let len = u32::from_le_bytes(*len) as usize;
+        if rest.len() < len || !(rest.len() - len).is_multiple_of(4) {
+            return Err(invalid());
+        }
+        let (json, embedding) = rest.split_at(len);
+        let mut doc: Doc = serde_json::from_slice(json)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        doc.embedding = embedding
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        Ok(doc)
+    }
+
+    impl RocksStore {
+        /// Opens the database at `path`, creating it if there is none.
+        pub fn open(path: &Path) -> io::Result<Self> {
+            let mut options = Options::default();
+            options.create_if_missing(true);
+            let db = DB::open(&options, path).map_err(error)?;
+            Ok(RocksStore { db })
+        }
+
+        /// Writes are synced before they are acknowledged, as the log's
+        /// are.
+        fn write(&self, batch: WriteBatch) -> io::Result<()> {
+            let mut options = WriteOptions::default();
+            options.set_sync(true);
+            self.db.write_opt(batch, &options).map_err(error)
+        }
+    }
+
+    impl DocStore for RocksStore {
+        fn get(&self, id: &str) -> io::Result<Option<Doc>> {
+            match self.db.get(id).map_err(error)? {
+                Some(value) => decode(&value).map(Some),
+                None => Ok(None),
+            }
+        }
+
+        fn put(&mut self, docs: &[Doc]) -> io::Result<()> {
+            let mut batch = WriteBatch::default();
+            for doc in docs {
+                batch.put(&doc.id, encode(doc)?);
+            }
+            self.write(batch)
+        }
+
+        fn delete(&mut self, ids: &[String]) -> io::Result<()> {
+            let mut batch = WriteBatch::default();
+            for id in ids {
+                batch.delete(id);
+            }
+            self.write(batch)
+        }
+
+        fn scan(&self, after: &str, limit: usize) -> io::Result<Vec<Doc>> {
+            let mode = IteratorMode::From(after.as_bytes(), Direction::Forward);
+            let mut docs = Vec::new();
+            for item in self.db.iterator(mode) {
+                let (key, value) = item.map_err(error)?;
+                if *key == *after.as_bytes() {
+                    continue;
+                }
+                if docs.len() == limit {
+                    break;
+                }
+                docs.push(decode(&value)?);
+            }
+            Ok(docs)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embed::{Embedder, HashEmbedder};
+    use crate::index::QueryOptions;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    /// Entries in a map shared with the test, which reads it back.
+    #[derive(Clone, Default)]
+    struct MapStore(Arc<Mutex<BTreeMap<String, Doc>>>);
+
+    impl DocStore for MapStore {
+        fn get(&self, id: &str) -> io::Result<Option<Doc>> {
+            Ok(self.0.lock().unwrap().get(id).cloned())
+        }
+
+        fn put(&mut self, docs: &[Doc]) -> io::Result<()> {
+            let mut map = self.0.lock().unwrap();
+            for doc in docs {
+                map.insert(doc.id.clone(), doc.clone());
+            }
+            Ok(())
+        }
+
+        fn delete(&mut self, ids: &[String]) -> io::Result<()> {
+            let mut map = self.0.lock().unwrap();
+            for id in ids {
+                map.remove(id);
+            }
+            Ok(())
+        }
+
+        fn scan(&self, after: &str, limit: usize) -> io::Result<Vec<Doc>> {
+            let map = self.0.lock().unwrap();
+            Ok(map
+                .iter()
+                .filter(|(id, _)| after.is_empty() || id.as_str() > after)
+                .take(limit)
+                .map(|(_, doc)| doc.clone())
+                .collect())
+        }
+    }
+
+    #[test]
+    fn writes_go_to_the_store_and_come_back_from_it() {
+        let embedder: Arc<dyn Embedder> = Arc::new(HashEmbedder::parse("hash-64").unwrap());
+        let store = MapStore::default();
+        let mut index =
+            VectorIndex::with_store(Box::new(store.clone()), Arc::clone(&embedder)).unwrap();
+        for i in 0..5 {
+            let text = format!("note number {i} about topic {i}");
+            index
+                .upsert(&format!("n{i}"), &text, Default::default(), BTreeMap::new())
+                .unwrap();
+        }
+        index.delete_all(&["n3".to_string()]).unwrap();
+        assert_eq!(store.scan("", 10).unwrap().len(), 4);
+        assert!(store.get("n3").unwrap().is_none());
+        let stored = store.get("n2").unwrap().unwrap();
+        assert_eq!(
+            stored.embedding,
+            embedder.embed("note number 2 about topic 2")
+        );
+
+        let reopened = VectorIndex::with_store(Box::new(store.clone()), embedder).unwrap();
+        assert_eq!(reopened.len(), 4);
+        let options = QueryOptions {
+            k: 1,
+            ..Default::default()
+        };
+        let hits = reopened.query("note number 4 about topic 4", &options);
+        assert_eq!(hits[0].id, "n4");
+    }
+
+    #[test]
+    fn the_index_scans_in_storage_order() {
+        let mut index = VectorIndex::in_memory(Arc::new(HashEmbedder::parse("hash-16").unwrap()));
+        for id in ["b", "a", "c"] {
+            index
+                .upsert(id, id, Default::default(), BTreeMap::new())
+                .unwrap();
+        }
+        let ids = |docs: Vec<Doc>| docs.into_iter().map(|d| d.id).collect::<Vec<_>>();
+        assert_eq!(ids(index.scan("", 2).unwrap()), ["b", "a"]);
+        assert_eq!(ids(index.scan("a", 2).unwrap()), ["c"]);
+        assert_eq!(index.get("c").unwrap().unwrap().embedding.len(), 16);
+    }
+}
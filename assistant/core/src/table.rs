@@ -0,0 +1,211 @@
+//! Tables: CSV files and Excel workbooks. A workbook loads as CSV text,
+//! one section per sheet. Tables are stored in chunks of whole rows, each
+//! row spelled out with its column names, so a hit on "rent" brings the
+//! row it is in along with what each of its numbers means. Chunks record
+//! the sheet and the rows they hold.
+
+use crate::chunk::{self, ChunkParams};
+use calamine::{DataRef, Reader, Xlsx};
+use std::io;
+
+pub const XLSX: &str = "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+
+/// Starts each sheet's section of a workbook's text, followed by the
+/// sheet's name on the same line.
+const SHEET: char = '\u{c}';
+
+/// Bytes of text a workbook may load as, so a small file of compressed
+/// cells cannot exhaust memory.
+pub const MAX_TEXT_BYTES: usize = 64 * 1024 * 1024;
+
+/// Rows of a table stored as one chunk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rows {
+    pub text: String,
+    /// Sheet of a workbook the rows are on.
+    pub sheet: Option<String>,
+    /// Row numbers as a spreadsheet shows them, the header being row 1.
+    pub first: usize,
+    pub last: usize,
+}
+
+/// The text of the Excel workbook in `bytes`. Each sheet is a line with a
+/// form feed and its name, then its cells as CSV. Rows keep their
+/// numbers, empty ones included, so they can be cited as the sheet shows
+/// them.
+pub fn xlsx_text(bytes: &[u8]) -> Result<String, String> {
+    let mut workbook = Xlsx::new(io::Cursor::new(bytes)).map_err(|e| e.to_string())?;
+    let mut text = Vec::new();
+    for name in workbook.sheet_names() {
+        let mut cells = workbook
+            .worksheet_cells_reader(&name)
+            .map_err(|e| format!("sheet {name}: {e}"))?;
+        text.push(SHEET as u8);
+        text.extend(name.replace(['\r', '\n', SHEET], " ").as_bytes());
+        text.push(b'\n');
+        let mut sheet = csv::WriterBuilder::new()
+            .flexible(true)
+            .from_writer(&mut text);
+        let (mut at, mut row) = (0, Vec::new());
+        while let Some(found) = cells
+            .next_cell()
+            .map_err(|e| format!("sheet {name}: {e}"))?
+        {
+            let (r, c) = found.get_position();
+            let value = cell(found.get_value());
+            if value.is_empty() {
+                continue;
+            }
+            while at < r {
+                sheet
+                    .write_record(std::mem::take(&mut row))
+                    .map_err(|e| e.to_string())?;
+                at += 1;
+            }
+            row.resize(row.len().max(c as usize), String::new());
+            row.push(value);
+            if sheet.get_ref().len() > MAX_TEXT_BYTES {
+                return Err(format!("expands to more than {MAX_TEXT_BYTES} bytes"));
+            }
+        }
+        if !row.is_empty() {
+            sheet.write_record(row).map_err(|e| e.to_string())?;
+        }
+        sheet.flush().map_err(|e| e.to_string())?;
+    }
+    String::from_utf8(text).map_err(|e| e.to_string())
+}
+
+/// A cell's value as it reads in the sheet, near enough: dates as
+/// `YYYY-MM-DD` with the time if it has one, and numbers without a
+/// trailing `.0`. Form feeds are dropped so no cell can start a sheet.
+fn cell(value: &DataRef) -> String {
+    let text = match value {
+        DataRef::Empty => return String::new(),
+        DataRef::String(s) | DataRef::DateTimeIso(s) | DataRef::DurationIso(s) => s.clone(),
+        DataRef::SharedString(s) => s.to_string(),
+        DataRef::Int(n) => n.to_string(),
+        DataRef::Float(n) => n.to_string(),
+        DataRef::Bool(b) => b.to_string(),
+        DataRef::DateTime(t) if t.is_datetime() => {
+            let (y, mo, d, h, mi, s, _) = t.to_ymd_hms_milli();
+            match (h, mi, s) {
+                (0, 0, 0) => format!("{y:04}-{mo:02}-{d:02}"),
+                _ => format!("{y:04}-{mo:02}-{d:02} {h:02}:{mi:02}:{s:02}"),
+            }
+        }
+        DataRef::DateTime(t) => t.as_f64().to_string(),
+        DataRef::Error(e) => e.to_string(),
+    };
+    text.replace(SHEET, "")
+}
+
+/// Splits table `text`, of `mime_type`, into chunks of whole rows of at
+/// most `params.size` characters where possible; a row longer than that
+/// is split like prose. Rows do not overlap. Each chunk starts with its
+/// sheet's name, if it is from a workbook, and has one row per line:
+/// `Row 7: Date: 2024-03-01; Amount: 42.50; Payee: Grocer`. The first row
+/// with any cells names the columns. `None` for a type that is not a
+/// table, or a table that cannot be parsed or has no rows under its
+/// header, which are chunked as plain text instead.
+pub fn split(text: &str, mime_type: &str, params: ChunkParams) -> Option<Vec<Rows>> {
+    let sections = match mime_type {
+        "text/csv" => vec![(None, text)],
+        XLSX => sheets(text),
+        _ => return None,
+    };
+    let mut chunks = Vec::new();
+    for (sheet, csv) in sections {
+        split_section(sheet, csv, params, &mut chunks)?;
+    }
+    (!chunks.is_empty()).then_some(chunks)
+}
+
+/// A workbook's sheets, by name.
+fn sheets(text: &str) -> Vec<(Option<&str>, &str)> {
+    text.split(SHEET)
+        .skip(1)
+        .map(|section| {
+            let (name, csv) = section.split_once('\n').unwrap_or((section, ""));
+            (Some(name), csv)
+        })
+        .collect()
+}
+
+fn split_section(
+    sheet: Option<&str>,
+    csv: &str,
+    params: ChunkParams,
+    chunks: &mut Vec<Rows>,
+) -> Option<()> {
+    let mut records = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(csv.as_bytes());
+    let heading = sheet.map(|name| format!("Sheet {name}\n"));
+    let mut columns: Option<Vec<String>> = None;
+    let mut current: Option<Rows> = None;
+    for (n, record) in records.records().enumerate() {
+        let record = record.ok()?;
+        let row = n + 1;
+        let Some(columns) = &columns else {
+            if record.iter().any(|field| !field.trim().is_empty()) {
+                columns = Some(
+                    record
+                        .iter()
+                        .enumerate()
+                        .map(|(i, name)| match name.trim() {
+                            "" => format!("column {}", i + 1),
+                            name => name.to_string(),
+                        })
+                        .collect(),
+                );
+            }
+            continue;
+        };
+        let cells: Vec<String> = record
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| !value.trim().is_empty())
+            .map(|(i, value)| match columns.get(i) {
+                Some(column) => format!("{column}: {}", value.trim()),
+                None => format!("column {}: {}", i + 1, value.trim()),
+            })
+            .collect();
+        if cells.is_empty() {
+            continue;
+        }
+        let line = format!("Row {row}: {}\n", cells.join("; "));
+        if let Some(rows) = &mut current {
+            if rows.text.chars().count() + line.chars().count() <= params.size {
+                rows.text.push_str(&line);
+                rows.last = row;
+                continue;
+            }
+        }
+        chunks.extend(current.take().map(trimmed));
+        let text = format!("{}{line}", heading.as_deref().unwrap_or_default());
+        if text.chars().count() <= params.size {
+            current = Some(Rows {
+                text,
+                sheet: sheet.map(str::to_string),
+                first: row,
+                last: row,
+            });
+            continue;
+        }
+        chunks.extend(chunk::split(&text, params).into_iter().map(|part| Rows {
+            text: part.to_string(),
+            sheet: sheet.map(str::to_string),
+            first: row,
+            last: row,
+        }));
+    }
+    chunks.extend(current.map(trimmed));
+    Some(())
+}
+
+fn trimmed(mut rows: Rows) -> Rows {
+    rows.text.truncate(rows.text.trim_end().len());
+    rows
+}
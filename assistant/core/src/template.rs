@@ -0,0 +1,150 @@
+//! Prompt templates with `{{variable}}` placeholders, plus the
+//! server-side store that lets a team share them.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum TemplateError {
+    Unclosed(usize),
+    Missing(Vec<String>),
+    InvalidName(String),
+    NotFound(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::Unclosed(at) => write!(f, "unclosed {{{{ at byte {at}"),
+            TemplateError::Missing(names) => write!(f, "missing variables: {}", names.join(", ")),
+            TemplateError::InvalidName(name) => write!(f, "invalid template name: {name:?}"),
+            TemplateError::NotFound(name) => write!(f, "no template named {name}"),
+            TemplateError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl From<io::Error> for TemplateError {
+    fn from(e: io::Error) -> Self {
+        TemplateError::Io(e)
+    }
+}
+
+/// Splits a template into literal text and placeholder names.
+fn parse(template: &str) -> Result<Vec<(&str, Option<&str>)>, TemplateError> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    let mut offset = 0;
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..]
+            .find("}}")
+            .ok_or(TemplateError::Unclosed(offset + start))?;
+        let name = rest[start + 2..start + end].trim();
+        parts.push((&rest[..start], Some(name)));
+        offset += start + end + 2;
+        rest = &rest[start + end + 2..];
+    }
+    parts.push((rest, None));
+    Ok(parts)
+}
+
+/// Names of the variables a template uses, sorted and deduplicated.
+pub fn variables(template: &str) -> Result<Vec<String>, TemplateError> {
+    let names: BTreeSet<&str> = parse(template)?
+        .into_iter()
+        .filter_map(|(_, n)| n)
+        .collect();
+    Ok(names.into_iter().map(str::to_string).collect())
+}
+
+/// Substitutes every `{{name}}`. All variables must be provided.
+pub fn render(template: &str, vars: &BTreeMap<String, String>) -> Result<String, TemplateError> {
+    let parts = parse(template)?;
+    let missing: BTreeSet<&str> = parts
+        .iter()
+        .filter_map(|(_, n)| *n)
+        .filter(|n| !vars.contains_key(*n))
+        .collect();
+    if !missing.is_empty() {
+        return Err(TemplateError::Missing(
+            missing.into_iter().map(str::to_string).collect(),
+        ));
+    }
+    let mut out = String::with_capacity(template.len());
+    for (text, name) in parts {
+        out.push_str(text);
+        if let Some(name) = name {
+            out.push_str(&vars[name]);
+        }
+    }
+    Ok(out)
+}
+
+/// Templates stored as `<dir>/<name>.tmpl`, so they can also be shared by
+/// dropping files into the directory.
+pub struct TemplateStore {
+    dir: PathBuf,
+}
+
+impl TemplateStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        TemplateStore { dir: dir.into() }
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, TemplateError> {
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid {
+            return Err(TemplateError::InvalidName(name.to_string()));
+        }
+        Ok(self.dir.join(format!("{name}.tmpl")))
+    }
+
+    pub fn get(&self, name: &str) -> Result<String, TemplateError> {
+        std::fs::read_to_string(self.path(name)?).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => TemplateError::NotFound(name.to_string()),
+            _ => TemplateError::Io(e),
+        })
+    }
+
+    /// Stores a template after checking that it parses.
+    pub fn put(&self, name: &str, body: &str) -> Result<Vec<String>, TemplateError> {
+        let vars = variables(body)?;
+        let path = self.path(name)?;
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(path, body)?;
+        Ok(vars)
+    }
+
+    /// Template names with the variables each one expects.
+    pub fn list(&self) -> Result<Vec<(String, Vec<String>)>, TemplateError> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut out = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("tmpl") {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            // Files that no longer parse are listed without variables.
+            let vars = variables(&std::fs::read_to_string(&path)?).unwrap_or_default();
+            out.push((name.to_string(), vars));
+        }
+        out.sort();
+        Ok(out)
+    }
+}
@@ -0,0 +1,71 @@
+//! Cutting text short for display: previews, excerpts, titles, log lines
+//! and the CLI's columns. Cuts fall between grapheme clusters, so no
+//! character is split, nor an accented letter, flag or emoji sequence
+//! made of several. Widths are terminal columns, in which CJK characters
+//! and most emoji take two.
+
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// Marks where text was cut.
+pub const ELLIPSIS: char = '…';
+
+/// `text` cut to at most `max` grapheme clusters.
+pub fn truncate(text: &str, max: usize) -> &str {
+    match text.grapheme_indices(true).nth(max) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    }
+}
+
+/// `text` cut to at most `max` bytes, between grapheme clusters.
+pub fn truncate_bytes(text: &str, max: usize) -> &str {
+    if text.len() <= max {
+        return text;
+    }
+    let end = text
+        .grapheme_indices(true)
+        .map(|(at, g)| at + g.len())
+        .take_while(|&end| end <= max)
+        .last()
+        .unwrap_or(0);
+    &text[..end]
+}
+
+/// `text` cut to at most `max` grapheme clusters, the last an ellipsis
+/// if it was cut.
+pub fn ellipsize(text: &str, max: usize) -> String {
+    match text.grapheme_indices(true).nth(max) {
+        None => text.to_string(),
+        Some(_) if max == 0 => String::new(),
+        Some(_) => format!("{}{ELLIPSIS}", truncate(text, max - 1)),
+    }
+}
+
+/// Terminal columns `text` takes.
+pub fn width(text: &str) -> usize {
+    text.width()
+}
+
+/// `text` cut to take at most `columns` terminal columns, the last an
+/// ellipsis if it was cut.
+pub fn fit(text: &str, columns: usize) -> String {
+    if width(text) <= columns {
+        return text.to_string();
+    }
+    if columns == 0 {
+        return String::new();
+    }
+    let mut out = String::new();
+    let mut used = 0;
+    for g in text.graphemes(true) {
+        let w = width(g);
+        if used + w >= columns {
+            break;
+        }
+        out.push_str(g);
+        used += w;
+    }
+    out.push(ELLIPSIS);
+    out
+}
@@ -0,0 +1,418 @@
+//! Files pushed to the core over gRPC in pieces, for clients that do not
+//! share its file system. An upload's bytes are appended to
+//! `<dir>/<id>.part` in order, and its name and expected size kept in
+//! `<dir>/<id>.json`; once the last piece is in, the part file is renamed
+//! to `<dir>/<id>`. A client whose stream broke asks how many bytes
+//! arrived and sends the rest from there.
+//!
+//! Uploads are staging, not storage: they are read for indexing or for
+//! attaching to a session, and removed a day after they were last
+//! written to.
+
+use crate::indexer::timestamp;
+use crate::loader::{self, Loaded};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::hash::{BuildHasher, RandomState};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tonic::Status;
+
+/// Largest file an upload can hold.
+pub const MAX_UPLOAD_BYTES: u64 = 1024 * 1024 * 1024;
+/// How long an upload is kept after it was last written to.
+const KEEP: Duration = Duration::from_secs(24 * 60 * 60);
+/// How often uploads past [`KEEP`] are looked for.
+const SWEEP: Duration = Duration::from_secs(3600);
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Upload {
+    pub id: String,
+    /// File name the client gave, used for its media type.
+    pub name: String,
+    /// Size the client said the file has, checked when it completes; 0 if
+    /// it did not say.
+    #[serde(default)]
+    pub total_size: u64,
+    /// Workspace it was uploaded in; requests in other workspaces cannot
+    /// see it.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub workspace: String,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: i64,
+    /// Bytes stored so far, where the next piece goes. Read from the file.
+    #[serde(skip)]
+    pub received: u64,
+    #[serde(skip)]
+    pub complete: bool,
+}
+
+impl Upload {
+    pub fn to_message(&self) -> crate::assistant::UploadResult {
+        crate::assistant::UploadResult {
+            upload_id: self.id.clone(),
+            name: self.name.clone(),
+            received: self.received,
+            total_size: self.total_size,
+            complete: self.complete,
+            created_at: timestamp(Some(self.created_at)),
+            ..Default::default()
+        }
+    }
+}
+
+pub struct UploadStore {
+    dir: PathBuf,
+    /// Uploads a stream is writing to, which other streams must not.
+    writing: Mutex<HashSet<String>>,
+}
+
+impl UploadStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        UploadStore {
+            dir: dir.into(),
+            writing: Mutex::default(),
+        }
+    }
+
+    fn path(&self, id: &str, ext: &str) -> io::Result<PathBuf> {
+        let valid = !id.is_empty()
+            && id.len() <= 128
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'));
+        if !valid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid upload id: {id:?}"),
+            ));
+        }
+        Ok(self.dir.join(format!("{id}{ext}")))
+    }
+
+    /// The upload, or `NotFound` if there is none visible from `workspace`.
+    pub fn get(&self, id: &str, workspace: &str) -> io::Result<Upload> {
+        let not_found = || io::Error::new(io::ErrorKind::NotFound, format!("no upload {id}"));
+        let data = std::fs::read(self.path(id, ".json")?).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => not_found(),
+            _ => e,
+        })?;
+        let mut upload: Upload = serde_json::from_slice(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if upload.workspace != workspace {
+            return Err(not_found());
+        }
+        match std::fs::metadata(self.path(id, "")?) {
+            Ok(meta) => {
+                upload.received = meta.len();
+                upload.complete = true;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                upload.received = match std::fs::metadata(self.path(id, ".part")?) {
+                    Ok(meta) => meta.len(),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+                    Err(e) => return Err(e),
+                };
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(upload)
+    }
+
+    /// Opens upload `id` for writing, starting it if it does not exist; an
+    /// empty id starts one under a new id. `name` and `total_size` only
+    /// apply to a new upload. Only one writer per upload at a time.
+    pub fn open(
+        &self,
+        id: &str,
+        name: &str,
+        total_size: u64,
+        workspace: &str,
+    ) -> io::Result<UploadWriter<'_>> {
+        if total_size > MAX_UPLOAD_BYTES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("uploads are limited to {MAX_UPLOAD_BYTES} bytes"),
+            ));
+        }
+        std::fs::create_dir_all(&self.dir)?;
+        let upload = match id {
+            "" => self.start(name, total_size, workspace)?,
+            id => match self.get(id, workspace) {
+                Ok(upload) => upload,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    // An upload from another workspace is not taken over.
+                    if self.path(id, ".json")?.exists() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            format!("upload id {id} is taken"),
+                        ));
+                    }
+                    let upload = Upload {
+                        id: id.to_string(),
+                        name: name.to_string(),
+                        total_size,
+                        workspace: workspace.to_string(),
+                        created_at: now_ms(),
+                        ..Default::default()
+                    };
+                    std::fs::write(self.path(id, ".json")?, serde_json::to_vec_pretty(&upload)?)?;
+                    upload
+                }
+                Err(e) => return Err(e),
+            },
+        };
+        if !self.writing.lock().unwrap().insert(upload.id.clone()) {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("upload {} is being written by another stream", upload.id),
+            ));
+        }
+        let file = match upload.complete {
+            true => None,
+            false => {
+                let part = self.path(&upload.id, ".part");
+                let opened =
+                    part.and_then(|part| OpenOptions::new().create(true).append(true).open(part));
+                match opened {
+                    Ok(file) => Some(file),
+                    Err(e) => {
+                        self.writing.lock().unwrap().remove(&upload.id);
+                        return Err(e);
+                    }
+                }
+            }
+        };
+        Ok(UploadWriter {
+            store: self,
+            upload,
+            file,
+        })
+    }
+
+    /// Starts an upload under a new id. Ids sort by creation time; the
+    /// random suffix keeps uploads started in the same millisecond apart.
+    fn start(&self, name: &str, total_size: u64, workspace: &str) -> io::Result<Upload> {
+        let created_at = now_ms();
+        loop {
+            let suffix = RandomState::new().hash_one(created_at) & 0xffff;
+            let upload = Upload {
+                id: format!("upload-{created_at:x}-{suffix:04x}"),
+                name: name.to_string(),
+                total_size,
+                workspace: workspace.to_string(),
+                created_at,
+                ..Default::default()
+            };
+            let file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(self.path(&upload.id, ".json")?);
+            match file {
+                Ok(mut file) => {
+                    file.write_all(&serde_json::to_vec_pretty(&upload)?)?;
+                    return Ok(upload);
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// A complete upload and the path of its contents.
+    pub fn file(&self, id: &str, workspace: &str) -> io::Result<(Upload, PathBuf)> {
+        let upload = self.get(id, workspace)?;
+        if !upload.complete {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "upload {id} is incomplete: {} of {} bytes",
+                    upload.received, upload.total_size
+                ),
+            ));
+        }
+        let path = self.path(id, "")?;
+        Ok((upload, path))
+    }
+
+    /// A complete upload's contents as text, by the loader for its media
+    /// type. Types without a loader, such as PDFs and audio, fail with
+    /// [`Unsupported`](crate::loader::Unsupported), so their text has to
+    /// be extracted before uploading.
+    pub fn load(&self, id: &str, workspace: &str) -> io::Result<(Upload, Loaded)> {
+        let (upload, path) = self.file(id, workspace)?;
+        let loaded = loader::load(&upload.name, &std::fs::read(path)?)?;
+        Ok((upload, loaded))
+    }
+
+    /// Removes uploads not written to for [`KEEP`]. Returns how many.
+    pub fn expire(&self) -> io::Result<usize> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let now = SystemTime::now();
+        let mut removed = 0;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let Some(id) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            if self.writing.lock().unwrap().contains(id) {
+                continue;
+            }
+            let files = [self.path(id, "")?, self.path(id, ".part")?, path.clone()];
+            let last_written = files
+                .iter()
+                .filter_map(|f| std::fs::metadata(f).and_then(|m| m.modified()).ok())
+                .max();
+            if last_written.is_some_and(|at| now.duration_since(at).unwrap_or_default() < KEEP) {
+                continue;
+            }
+            for file in &files {
+                match std::fs::remove_file(file) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Starts a background task removing old uploads every hour, until the
+    /// store is dropped.
+    pub fn spawn_sweeper(self: &Arc<Self>) {
+        let store: Weak<Self> = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SWEEP);
+            loop {
+                interval.tick().await;
+                let Some(store) = store.upgrade() else {
+                    return;
+                };
+                match store.expire() {
+                    Ok(0) => {}
+                    Ok(n) => log::info!("{n} old uploads removed"),
+                    Err(e) => log::error!("removing old uploads failed: {e}"),
+                }
+            }
+        });
+    }
+}
+
+/// An upload open for appending. Dropping it lets other streams write to
+/// the upload again; what was appended stays.
+pub struct UploadWriter<'a> {
+    store: &'a UploadStore,
+    upload: Upload,
+    /// The part file; `None` once the upload is complete.
+    file: Option<File>,
+}
+
+impl UploadWriter<'_> {
+    pub fn upload(&self) -> &Upload {
+        &self.upload
+    }
+
+    /// Appends `data`, which must start at `offset`: the bytes received so
+    /// far. A complete upload only takes empty pieces at its end.
+    pub fn append(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let upload = &mut self.upload;
+        if offset != upload.received {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "upload {} has {} bytes; send from offset {}, not {offset}",
+                    upload.id, upload.received, upload.received
+                ),
+            ));
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+        let Some(file) = &mut self.file else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("upload {} is already complete", upload.id),
+            ));
+        };
+        let size = upload.received + data.len() as u64;
+        let limit = match upload.total_size {
+            0 => MAX_UPLOAD_BYTES,
+            total => total,
+        };
+        if size > limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("upload {} would exceed its {limit} bytes", upload.id),
+            ));
+        }
+        if let Err(e) = file.write_all(data) {
+            // Part of the piece may have been written; resume after it.
+            upload.received = file.metadata().map_or(upload.received, |m| m.len());
+            return Err(e);
+        }
+        upload.received = size;
+        Ok(())
+    }
+
+    /// Marks the upload complete, once it has all of its expected bytes.
+    pub fn finish(mut self) -> io::Result<Upload> {
+        if self.upload.complete {
+            return Ok(self.upload.clone());
+        }
+        let upload = &mut self.upload;
+        if upload.total_size > 0 && upload.received != upload.total_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "upload {} is incomplete: {} of {} bytes",
+                    upload.id, upload.received, upload.total_size
+                ),
+            ));
+        }
+        if let Some(file) = self.file.take() {
+            file.sync_all()?;
+        }
+        std::fs::rename(
+            self.store.path(&upload.id, ".part")?,
+            self.store.path(&upload.id, "")?,
+        )?;
+        upload.complete = true;
+        Ok(upload.clone())
+    }
+}
+
+impl Drop for UploadWriter<'_> {
+    fn drop(&mut self) {
+        self.store.writing.lock().unwrap().remove(&self.upload.id);
+    }
+}
+
+/// The gRPC status for an upload error.
+pub(crate) fn status(e: io::Error) -> Status {
+    if let Some(unsupported) = loader::unsupported(&e) {
+        return unsupported.to_status();
+    }
+    match e.kind() {
+        io::ErrorKind::NotFound => Status::not_found(e.to_string()),
+        io::ErrorKind::InvalidInput => Status::invalid_argument(e.to_string()),
+        io::ErrorKind::AlreadyExists => Status::already_exists(e.to_string()),
+        io::ErrorKind::WouldBlock => Status::aborted(e.to_string()),
+        _ => Status::internal(e.to_string()),
+    }
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
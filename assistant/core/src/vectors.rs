@@ -0,0 +1,145 @@
+//! Embeddings of an index's entries, kept apart from the entries
+//! themselves. Vectors saved in the index file are memory-mapped rather
+//! than read in, so the OS pages them in as queries touch them and can drop
+//! them again under memory pressure. Vectors written since the file was
+//! saved are held in memory until the next compaction saves them too.
+//!
+//! Each entry has a slot saying where its vector is: slots below the
+//! number of mapped vectors are in the file, in saved order; the rest
+//! index the in-memory vectors. Replacing or removing an entry only
+//! changes slots, so the old vector stays where it is until compaction.
+
+use memmap2::Mmap;
+use std::io;
+
+/// Vectors saved in an index file, mapped.
+struct Mapped {
+    map: Mmap,
+    /// Offset of the first vector.
+    at: usize,
+    count: usize,
+}
+
+#[derive(Default)]
+pub struct Vectors {
+    dim: usize,
+    file: Option<Mapped>,
+    /// Vectors not in the file, `dim` floats each.
+    heap: Vec<f32>,
+    /// Number of vectors in `heap`.
+    stored: usize,
+    /// Per entry, in entry order, where its vector is.
+    slots: Vec<usize>,
+}
+
+impl Vectors {
+    /// An empty store of vectors with `dim` floats.
+    pub fn new(dim: usize) -> Self {
+        Vectors {
+            dim,
+            ..Vectors::default()
+        }
+    }
+
+    /// The `count` vectors of `dim` floats that start `at` bytes into
+    /// `map`, as little-endian `f32`s, read in place; the map may go on
+    /// after them. They are copied out
+    /// instead where the layout cannot be used directly: on big-endian
+    /// machines, or when they are not 4-byte aligned, as in index files
+    /// from before version 4.
+    pub fn mapped(map: Mmap, at: usize, count: usize, dim: usize) -> io::Result<Self> {
+        let end = count
+            .checked_mul(dim * 4)
+            .and_then(|len| len.checked_add(at))
+            .filter(|&end| end <= map.len())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "index file is truncated"))?;
+        if cfg!(target_endian = "big") || !at.is_multiple_of(4) {
+            return Ok(Vectors::from_bytes(&map[at..end], count, dim));
+        }
+        #[cfg(unix)]
+        let _ = map.advise(memmap2::Advice::Random);
+        Ok(Vectors {
+            dim,
+            file: Some(Mapped { map, at, count }),
+            slots: (0..count).collect(),
+            ..Vectors::default()
+        })
+    }
+
+    /// `count` vectors of `dim` floats read from little-endian `f32`s.
+    pub fn from_bytes(bytes: &[u8], count: usize, dim: usize) -> Self {
+        let heap = bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        Vectors {
+            dim,
+            file: None,
+            heap,
+            stored: count,
+            slots: (0..count).collect(),
+        }
+    }
+
+    /// Floats per vector.
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Number of vectors read in place from the file.
+    fn in_file(&self) -> usize {
+        self.file.as_ref().map_or(0, |f| f.count)
+    }
+
+    /// The vector of entry `at`.
+    pub fn get(&self, at: usize) -> &[f32] {
+        let slot = self.slots[at];
+        match &self.file {
+            Some(file) if slot < file.count => {
+                let start = file.at + slot * self.dim * 4;
+                let bytes = &file.map[start..start + self.dim * 4];
+                // SAFETY: `mapped` only keeps the map on little-endian
+                // machines with the vectors 4-byte aligned in it, the map
+                // itself is page-aligned, and any bit pattern is an f32.
+                unsafe { std::slice::from_raw_parts(bytes.as_ptr().cast::<f32>(), self.dim) }
+            }
+            _ => {
+                let start = (slot - self.in_file()) * self.dim;
+                &self.heap[start..start + self.dim]
+            }
+        }
+    }
+
+    /// Adds a vector for a new last entry.
+    pub fn push(&mut self, vector: &[f32]) {
+        let slot = self.store(vector);
+        self.slots.push(slot);
+    }
+
+    /// Replaces the vector of entry `at`.
+    pub fn set(&mut self, at: usize, vector: &[f32]) {
+        self.slots[at] = self.store(vector);
+    }
+
+    /// Keeps the vectors of the entries for which `keep` is true, as the
+    /// entries themselves were filtered.
+    pub fn retain(&mut self, keep: &[bool]) {
+        let mut keep = keep.iter();
+        self.slots.retain(|_| *keep.next().unwrap_or(&true));
+    }
+
+    fn store(&mut self, vector: &[f32]) -> usize {
+        assert_eq!(vector.len(), self.dim, "vector has the wrong dimensions");
+        self.heap.extend_from_slice(vector);
+        self.stored += 1;
+        self.in_file() + self.stored - 1
+    }
+}
@@ -0,0 +1,171 @@
+//! Append-only write-ahead log next to an index file. Each write is
+//! appended as one JSON line and synced before it is acknowledged, so the
+//! index file itself only needs rewriting once the log grows long.
+//!
+//! A line only counts once it is complete: a crash mid-append leaves a
+//! partial last line, which is ignored, along with anything after it.
+//! Opening the log cuts such a tail off, so later appends start on a line
+//! of their own.
+
+use crate::index::Doc;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Record {
+    /// Adds the entry or replaces the one with its id.
+    Upsert {
+        doc: Box<Doc>,
+        /// The entry's embedding, which may not be the collection
+        /// embedder's for the text, as for imported entries. Lines logged
+        /// before it was recorded have none; their text is embedded again.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        embedding: Vec<f32>,
+    },
+    Delete {
+        ids: Vec<String>,
+    },
+}
+
+pub struct Wal {
+    path: PathBuf,
+    file: Option<File>,
+    /// Records in the log.
+    len: usize,
+}
+
+impl Wal {
+    /// Opens the log at `path` and returns the records it holds, cutting
+    /// off anything after the last complete one.
+    pub fn open(path: PathBuf) -> io::Result<(Self, Vec<Record>)> {
+        let records = match std::fs::read(&path) {
+            Ok(data) => {
+                let (records, end) = parse(&data);
+                if end < data.len() {
+                    let file = OpenOptions::new().write(true).open(&path)?;
+                    file.set_len(end as u64)?;
+                    file.sync_data()?;
+                }
+                records
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let wal = Wal {
+            path,
+            file: None,
+            len: records.len(),
+        };
+        Ok((wal, records))
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Appends `records` and waits for them to reach the disk.
+    pub fn append(&mut self, records: &[Record]) -> io::Result<()> {
+        let mut lines = Vec::new();
+        for record in records {
+            serde_json::to_writer(&mut lines, record)?;
+            lines.push(b'\n');
+        }
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                let dir = match self.path.parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => dir,
+                    _ => Path::new("."),
+                };
+                std::fs::create_dir_all(dir)?;
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?;
+                // A new log is only found after a crash once its folder
+                // entry is on disk.
+                File::open(dir)?.sync_all()?;
+                self.file.insert(file)
+            }
+        };
+        file.write_all(&lines)?;
+        file.sync_data()?;
+        self.len += records.len();
+        Ok(())
+    }
+
+    /// Empties the log, once everything in it has been saved elsewhere.
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.file = None;
+        self.len = 0;
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Complete records, up to the first partial or unreadable line, and
+/// the length of `data` they take.
+fn parse(data: &[u8]) -> (Vec<Record>, usize) {
+    let mut records = Vec::new();
+    let mut rest = data;
+    while let Some(end) = rest.iter().position(|&b| b == b'\n') {
+        match serde_json::from_slice(&rest[..end]) {
+            Ok(record) => records.push(record),
+            Err(_) => break,
+        }
+        rest = &rest[end + 1..];
+    }
+    (records, data.len() - rest.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delete(id: &str) -> Record {
+        Record::Delete {
+            ids: vec![id.to_string()],
+        }
+    }
+
+    fn ids(records: &[Record]) -> Vec<&str> {
+        records
+            .iter()
+            .map(|record| match record {
+                Record::Delete { ids } => ids[0].as_str(),
+                Record::Upsert { doc, .. } => doc.id.as_str(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn torn_tail_does_not_swallow_later_appends() {
+        let dir = std::env::temp_dir().join(format!("wal-torn-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("index.wal");
+        std::fs::write(&path, br#"{"op":"delete","ids":["a"]}"#).unwrap();
+
+        let (mut wal, records) = Wal::open(path.clone()).unwrap();
+        assert!(records.is_empty());
+        wal.append(&[delete("b")]).unwrap();
+        drop(wal);
+
+        let (mut wal, records) = Wal::open(path.clone()).unwrap();
+        assert_eq!(ids(&records), ["b"]);
+        wal.append(&[delete("c")]).unwrap();
+        drop(wal);
+
+        let (_, records) = Wal::open(path).unwrap();
+        assert_eq!(ids(&records), ["b", "c"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,120 @@
+//! Change detection for watched folders. There is no file-system
+//! notification backend, so folders are walked on a timer and each file's
+//! size and modification time compared with the previous walk.
+
+use crate::privacy::Privacy;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Files larger than this are not indexed.
+pub const MAX_FILE_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Files found changed or gone since the previous walk.
+#[derive(Debug, Default)]
+pub struct Changes {
+    /// New or modified files.
+    pub changed: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// The files under a set of folders, as of the last walk.
+#[derive(Debug)]
+pub struct Watcher {
+    roots: Vec<PathBuf>,
+    seen: HashMap<PathBuf, (u64, SystemTime)>,
+}
+
+impl Watcher {
+    pub fn new(roots: Vec<PathBuf>) -> Self {
+        Watcher {
+            roots,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Walks the folders and reports what changed since the last walk; the
+    /// first walk reports every file. Hidden files and folders, symlinks
+    /// and files over [`MAX_FILE_BYTES`] are skipped. A folder that cannot
+    /// be read keeps its files as they were, so an unmounted drive does
+    /// not read as every file deleted.
+    pub fn scan(&mut self) -> Changes {
+        let mut found = HashMap::new();
+        let mut unreadable = Vec::new();
+        for root in &self.roots {
+            match root.canonicalize() {
+                Ok(root) => walk(&root, &mut found, &mut unreadable),
+                Err(e) => {
+                    let root_name = Privacy::global().redact(root.display());
+                    log::warn!("cannot watch {root_name}: {e}");
+                    unreadable.push(root.clone());
+                }
+            }
+        }
+        for (path, stamp) in &self.seen {
+            if unreadable.iter().any(|root| path.starts_with(root)) {
+                found.entry(path.clone()).or_insert(*stamp);
+            }
+        }
+        let mut changes = Changes::default();
+        for (path, stamp) in &found {
+            if self.seen.get(path) != Some(stamp) {
+                changes.changed.push(path.clone());
+            }
+        }
+        for path in self.seen.keys() {
+            if !found.contains_key(path) {
+                changes.removed.push(path.clone());
+            }
+        }
+        changes.changed.sort();
+        changes.removed.sort();
+        self.seen = found;
+        changes
+    }
+
+    /// Forgets `paths`, so the next walk reports them as changed again.
+    pub fn forget(&mut self, paths: &[PathBuf]) {
+        for path in paths {
+            self.seen.remove(path);
+        }
+    }
+}
+
+fn walk(
+    dir: &Path,
+    found: &mut HashMap<PathBuf, (u64, SystemTime)>,
+    unreadable: &mut Vec<PathBuf>,
+) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::warn!(
+                "cannot watch {}: {e}",
+                Privacy::global().redact(dir.display())
+            );
+            unreadable.push(dir.to_path_buf());
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let Ok(meta) = entry.metadata() else {
+            continue;
+        };
+        if meta.is_dir() {
+            walk(&entry.path(), found, unreadable);
+        } else if meta.is_file() && meta.len() <= MAX_FILE_BYTES {
+            let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            found.insert(entry.path(), (meta.len(), modified));
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! Workspaces: named contexts such as "work", "personal" or "thesis", each
+//! binding index collections, watched folders, tools and a profile.
+//! Requests select one with `Request.workspace` (the indexer reads it from
+//! `workspace` metadata), and what they can reach is then limited to it:
+//! queries only read its collections, tool calls only use its tools, and
+//! chat sessions started in it stay in it.
+
+use crate::collection::Collections;
+use crate::profile::ToolPermissions;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Clone, Debug, Default)]
+pub struct Workspace {
+    pub name: String,
+    /// Collections its requests may use. The first is used when a request
+    /// names none, and is where watched files are indexed.
+    pub collections: Vec<String>,
+    /// Folders whose files are kept indexed in the first collection.
+    pub watch: Vec<PathBuf>,
+    /// Tools its requests may call, on top of their profile's permissions.
+    pub tools: ToolPermissions,
+    /// Profile its requests run under when they name none.
+    pub profile: Option<String>,
+}
+
+impl Workspace {
+    fn from_config(name: &str, config: &Value) -> Result<Self, String> {
+        if !valid_name(name) {
+            return Err(format!("invalid workspace name: {name:?}"));
+        }
+        let strings = |key: &str| -> Result<Vec<String>, String> {
+            match &config[key] {
+                Value::Null => Ok(Vec::new()),
+                Value::Array(items) => items
+                    .iter()
+                    .map(|item| {
+                        item.as_str()
+                            .filter(|s| !s.is_empty())
+                            .map(str::to_string)
+                            .ok_or_else(|| format!("{name}: {key} must list non-empty strings"))
+                    })
+                    .collect(),
+                _ => Err(format!("{name}: {key} must be a list")),
+            }
+        };
+        let mut collections = strings("collections")?;
+        if collections.is_empty() {
+            collections.push(name.to_string());
+        }
+        let watch: Vec<PathBuf> = strings("watch")?.into_iter().map(PathBuf::from).collect();
+        if let Some(path) = watch.iter().find(|p| !p.is_absolute()) {
+            return Err(format!(
+                "{name}: watched path {} must be absolute",
+                path.display()
+            ));
+        }
+        let tools = match config.get("tools") {
+            Some(section) => {
+                ToolPermissions::from_config(section).map_err(|e| format!("{name}: {e}"))?
+            }
+            None => ToolPermissions::default(),
+        };
+        let profile = match &config["profile"] {
+            Value::Null => None,
+            Value::String(profile) => Some(profile.clone()),
+            _ => return Err(format!("{name}: profile must be a profile name")),
+        };
+        Ok(Workspace {
+            name: name.to_string(),
+            collections,
+            watch,
+            tools,
+            profile,
+        })
+    }
+
+    /// The collection requests use when they name none.
+    pub fn default_collection(&self) -> &str {
+        &self.collections[0]
+    }
+
+    /// Whether `collection` is one of the workspace's, aliases resolved.
+    pub fn covers(&self, collection: &str, collections: &Collections) -> bool {
+        let collection = collections.resolve(collection);
+        self.collections
+            .iter()
+            .any(|c| collections.resolve(c) == collection)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut out = json!({
+            "name": self.name,
+            "collections": self.collections,
+            "watch": self.watch,
+        });
+        if let Some(allow) = &self.tools.allow {
+            out["tools"] = json!(allow);
+        }
+        if let Some(profile) = &self.profile {
+            out["profile"] = json!(profile);
+        }
+        out
+    }
+}
+
+/// Workspace names go in session files and gRPC metadata, so they are
+/// kept to the characters session ids allow.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+}
+
+/// A request named a workspace that is not configured.
+#[derive(Debug)]
+pub struct UnknownWorkspace(pub String);
+
+impl fmt::Display for UnknownWorkspace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no workspace named {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownWorkspace {}
+
+#[derive(Debug, Default)]
+pub struct Workspaces {
+    workspaces: BTreeMap<String, Workspace>,
+}
+
+impl Workspaces {
+    /// Parses `{"<name>": {"collections", "watch", "tools", "profile"}, ...}`.
+    /// `collections` defaults to one named after the workspace.
+    pub fn from_config(config: &Value) -> Result<Self, String> {
+        let Some(entries) = config.as_object() else {
+            return Err("workspaces config must be an object".into());
+        };
+        let workspaces = entries
+            .iter()
+            .map(|(name, section)| Ok((name.clone(), Workspace::from_config(name, section)?)))
+            .collect::<Result<_, String>>()?;
+        Ok(Workspaces { workspaces })
+    }
+
+    /// The workspace named `name`, or `None` for an empty name, which
+    /// selects no workspace.
+    pub fn get(&self, name: &str) -> Result<Option<&Workspace>, UnknownWorkspace> {
+        if name.is_empty() {
+            return Ok(None);
+        }
+        self.workspaces
+            .get(name)
+            .map(Some)
+            .ok_or_else(|| UnknownWorkspace(name.to_string()))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Workspace> {
+        self.workspaces.values()
+    }
+}